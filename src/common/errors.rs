@@ -0,0 +1,166 @@
+use std::fmt;
+use std::error::Error;
+
+use crate::common::names::Name;
+use crate::hir::trees as hir;
+use crate::mir::trees as mir;
+use crate::hir::ops::Bop;
+
+// Errors reported by the lowering and translation passes.
+// These are returned to the embedder rather than aborting the process.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CodegenError {
+    // A call or closure application whose function type is not a function type.
+    NotAFunctionType { ty: hir::Type },
+    // A closure application in the def name whose function type ty is not a function type.
+    ApplyNonFunction { name: Name, ty: hir::Type },
+    // An operator the backend does not implement.
+    UnsupportedOp { op: String },
+    // A temporary that was used without being allocated.
+    MissingTemp { name: Name },
+    // A type that does not match the type required by its context.
+    TypeMismatch { expected: mir::Type, found: mir::Type },
+    // A signed or unsigned op applied to a variable declared with the other signedness.
+    SignMismatch { name: Name, op: Bop },
+    // A proc whose final block has no terminator, in strict mode.
+    MissingTerminator { name: Name },
+    // A temporary used at two different types in the same proc.
+    ConflictingTempTypes { name: Name, ty1: mir::Type, ty2: mir::Type },
+    // A statement between a terminator and the next label, when these aren't dropped.
+    UnreachableStatement { name: Name },
+    // A Ret whose value doesn't have the proc's return type.
+    ReturnTypeMismatch { name: Name, expected: mir::Type, found: mir::Type },
+    // A global that is neither defined in nor imported into the module.
+    UndefinedGlobal { name: Name },
+    // A function that is neither defined in nor declared by the module.
+    UndefinedFunction { name: Name },
+    // A StructLoadIdx whose type isn't a struct with a field at index.
+    InvalidFieldIndex { ty: hir::Type, index: usize },
+    // A Convert between types that have no conversion, e.g. a pointer and a number.
+    NoConversion { from: mir::Type, to: mir::Type },
+    // A global alignment that isn't a power of two or is looser than its type's.
+    InvalidAlignment { name: Name, alignment: u32 },
+    // Labels that are jumped to in a proc but never defined there.
+    UndefinedLabels { name: Name, labels: Vec<Name> },
+    // A user-defined global or function whose name is reserved, for LLVM intrinsics or
+    // for the module initializer, rivo_init.
+    ReservedName { name: Name },
+    // An Invoke or LandingPad in a proc translated without unwinding enabled.
+    UnwindingDisabled { name: Name },
+    // An Invoke or LandingPad in a proc translated without a personality function.
+    MissingPersonality { name: Name },
+    // Names used by more than one proc in a root.
+    DuplicateProcs { names: Vec<Name> },
+    // A proc that reads temp, which is neither one of its params nor assigned in its body.
+    UndefinedTemp { name: Name, temp: Name },
+    // A lambda in the def name that captures vars, when lambdas aren't lifted.
+    LambdaCaptures { name: Name, vars: Vec<Name> },
+    // A proc that returns temp, which may hold the address of one of its Alloca buffers.
+    ReturnsStackAddress { name: Name, temp: Name },
+    // A must_tail call in proc name to a function whose type isn't the proc's own.
+    MustTailSignatureMismatch { name: Name, expected: mir::Type, found: mir::Type },
+    // A must_tail call in proc name that isn't immediately followed by a return of its result.
+    MustTailNotFollowedByRet { name: Name },
+    // A must_tail call in proc name whose argument temp may point into the proc's stack frame.
+    MustTailPassesStackAddress { name: Name, temp: Name },
+    // A proc or global name with Default visibility and dso_local, which LLVM's C API can't emit.
+    DefaultVisibilityDsoLocal { name: Name },
+    // A struct initializer with fields inits for a global of type ty, which isn't a struct with that many fields.
+    StructInitMismatch { ty: mir::Type, fields: usize },
+    // A thread translating chunk of the procs in parallel panicked.
+    TranslationThreadPanicked { chunk: usize },
+    // The bitcode of name, a chunk translated on another thread, couldn't be read back.
+    InvalidBitcode { name: String },
+    // The modules translated in parallel couldn't be linked into module name.
+    LinkFailed { name: String },
+    // Variables the def name may read before assigning them. See hir::analysis.
+    UninitializedVars { name: Name, vars: Vec<Name> },
+}
+
+impl fmt::Display for CodegenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CodegenError::NotAFunctionType { ty } => write!(f, "expected function type, got {:?}", ty),
+            CodegenError::ApplyNonFunction { name, ty } => write!(f, "{} applies a value of non-function type {:?}", name, ty),
+            CodegenError::UnsupportedOp { op } => write!(f, "unsupported operator {}", op),
+            CodegenError::MissingTemp { name } => write!(f, "no alloca for temporary {}", name),
+            CodegenError::TypeMismatch { expected, found } => write!(f, "type mismatch: expected {:?}, got {:?}", expected, found),
+            CodegenError::SignMismatch { name, op } => write!(f, "{:?} used on {}, which has the other signedness", op, name),
+            CodegenError::MissingTerminator { name } => write!(f, "final block of {} is not terminated", name),
+            CodegenError::ConflictingTempTypes { name, ty1, ty2 } => write!(f, "temporary {} used at both {:?} and {:?}", name, ty1, ty2),
+            CodegenError::UnreachableStatement { name } => write!(f, "unreachable statement after a terminator in {}", name),
+            CodegenError::ReturnTypeMismatch { name, expected, found } => write!(f, "{} returns {:?}, but its return type is {:?}", name, found, expected),
+            CodegenError::UndefinedGlobal { name } => write!(f, "global {} is neither defined nor imported", name),
+            CodegenError::InvalidFieldIndex { ty, index } => write!(f, "no field {} in type {:?}", index, ty),
+            CodegenError::NoConversion { from, to } => write!(f, "no conversion from {:?} to {:?}", from, to),
+            CodegenError::InvalidAlignment { name, alignment } => write!(f, "invalid alignment {} for global {}", alignment, name),
+            CodegenError::UndefinedFunction { name } => write!(f, "function {} is neither defined nor declared", name),
+            CodegenError::ReservedName { name } => write!(f, "{} is a reserved name", name),
+            CodegenError::UnwindingDisabled { name } => write!(f, "{} uses invoke or landingpad, but unwinding is not enabled", name),
+            CodegenError::MissingPersonality { name } => write!(f, "{} uses invoke or landingpad, but no personality function is set", name),
+            CodegenError::UndefinedTemp { name, temp } => write!(f, "{} reads {}, which is not a param and is never assigned", name, temp),
+            CodegenError::LambdaCaptures { name, vars } => {
+                let vs: Vec<String> = vars.iter().map(|v| v.to_string()).collect();
+                write!(f, "lambda in {} captures {}, which needs lambda lifting", name, vs.join(", "))
+            },
+            CodegenError::ReturnsStackAddress { name, temp } => write!(f, "{} returns {}, which may point into its own stack frame", name, temp),
+            CodegenError::MustTailSignatureMismatch { name, expected, found } => write!(f, "musttail call in {} to {:?}, but {} has type {:?}", name, found, name, expected),
+            CodegenError::MustTailNotFollowedByRet { name } => write!(f, "musttail call in {} is not immediately followed by a return of its result", name),
+            CodegenError::MustTailPassesStackAddress { name, temp } => write!(f, "musttail call in {} passes {}, which may point into the caller's stack frame", name, temp),
+            CodegenError::DefaultVisibilityDsoLocal { name } => write!(f, "{} is dso_local with default visibility, which can't be emitted; make it hidden or protected", name),
+            CodegenError::StructInitMismatch { ty, fields } => write!(f, "struct initializer with {} fields for a global of type {:?}", fields, ty),
+            CodegenError::TranslationThreadPanicked { chunk } => write!(f, "the thread translating chunk {} panicked", chunk),
+            CodegenError::InvalidBitcode { name } => write!(f, "could not read the bitcode of {}", name),
+            CodegenError::LinkFailed { name } => write!(f, "could not link translated procs into {}", name),
+            CodegenError::UninitializedVars { name, vars } => {
+                let vs: Vec<String> = vars.iter().map(|v| v.to_string()).collect();
+                write!(f, "{} may read {} before assigning it", name, vs.join(", "))
+            },
+            CodegenError::DuplicateProcs { names } => {
+                let ns: Vec<String> = names.iter().map(|n| n.to_string()).collect();
+                write!(f, "procs defined more than once: {}", ns.join(", "))
+            },
+            CodegenError::UndefinedLabels { name, labels } => {
+                let ls: Vec<String> = labels.iter().map(|l| l.to_string()).collect();
+                write!(f, "undefined labels in {}: {}", name, ls.join(", "))
+            },
+        }
+    }
+}
+
+impl Error for CodegenError {}
+
+// Errors from gen::compile, by the stage that reported them.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CompileError {
+    // The optional checks before lowering: signedness and definite assignment.
+    Check(CodegenError),
+    // Closure conversion and lambda lifting, or lowering lambdas to function pointers.
+    Lift(CodegenError),
+    // Lowering HIR to MIR and LIR.
+    Lower(CodegenError),
+    // Translating LIR to LLVM.
+    Translate(CodegenError),
+}
+
+impl CompileError {
+    // The error, whichever stage reported it.
+    pub fn error(&self) -> &CodegenError {
+        match self {
+            CompileError::Check(e) | CompileError::Lift(e) | CompileError::Lower(e) | CompileError::Translate(e) => e,
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompileError::Check(e) => write!(f, "check: {}", e),
+            CompileError::Lift(e) => write!(f, "lambda lifting: {}", e),
+            CompileError::Lower(e) => write!(f, "lowering: {}", e),
+            CompileError::Translate(e) => write!(f, "translation: {}", e),
+        }
+    }
+}
+
+impl Error for CompileError {}
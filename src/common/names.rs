@@ -80,6 +80,12 @@ impl Name {
         Name::new(&s)
     }
 
+    // A name for something generated from base, e.g., foo$lifted for a lambda lifted out of foo.
+    // The result isn't fresh, so pass it to fresh (or FreshNameGenerator::derived) as a prefix.
+    pub fn derived(base: &Name, suffix: &str) -> Name {
+        Name::new(&format!("{}${}", base, suffix))
+    }
+
     pub fn hashit(&self) -> u64 {
         use std::collections::hash_map::DefaultHasher;
         use std::hash::{Hash, Hasher};
@@ -121,4 +127,9 @@ impl FreshNameGenerator {
         self.next += 1;
         x
     }
+
+    // A fresh name that starts with Name::derived(base, suffix).
+    pub fn derived(&mut self, base: &Name, suffix: &str) -> Name {
+        self.fresh(&Name::derived(base, suffix).to_string())
+    }
 }
@@ -10,10 +10,26 @@ pub struct Translate;
 
 impl Translate {
     pub fn translate(r: &mir::Root) -> lir::Root {
-        let externs = r.externs.iter().map(|p| lir::Param { name: p.name, ty: p.ty.clone() }).collect();
+        // MIR externs are both functions and globals; LIR declares them differently.
+        let (funs, globals): (Vec<&mir::Param>, Vec<&mir::Param>) = r.externs.iter().partition(|p| Translate::is_function(&p.ty));
+        let externs = funs.into_iter().map(Translate::translate_param).collect();
+        let imports = globals.into_iter().map(Translate::translate_param).collect();
         let datas = r.data.iter().map(|p| ProcTranslator::new().translate_data(p)).collect();
         let procs = r.procs.iter().map(|p| ProcTranslator::new().translate_proc(p)).collect();
-        lir::Root { externs, data: datas, procs }
+        lir::Root { externs, imports, data: datas, procs }
+    }
+
+    fn is_function(ty: &mir::Type) -> bool {
+        match ty {
+            mir::Type::Fun { .. } => true,
+            mir::Type::Ptr { ty: box mir::Type::Fun { .. } } => true,
+            _ => false,
+        }
+    }
+
+    // MIR doesn't know about aliasing, so no attributes are set.
+    fn translate_param(p: &mir::Param) -> lir::Param {
+        lir::Param { name: p.name, ty: p.ty.clone(), noalias: false, readonly: false, nonnull: false }
     }
 }
 
@@ -36,7 +52,21 @@ impl ProcTranslator {
         lir::Data {
             ty: p.ty.clone(),
             name: p.name.clone(),
-            init: p.init.clone(),
+            init: ProcTranslator::translate_init(&p.init),
+            thread_local: p.thread_local,
+            visibility: lir::Visibility::Default,
+            dso_local: false,
+            section: None,
+            alignment: None,
+            packed: false,
+        }
+    }
+
+    fn translate_init(init: &mir::Init) -> lir::Init {
+        match init {
+            mir::Init::Lit { lit } => lir::Init::Lit { lit: lit.clone() },
+            mir::Init::Struct { fields } => lir::Init::Struct { fields: fields.iter().map(ProcTranslator::translate_init).collect() },
+            mir::Init::GlobalAddr { name } => lir::Init::ElementAddr { name: *name, indices: vec![0] },
         }
     }
 
@@ -54,8 +84,12 @@ impl ProcTranslator {
         lir::Proc {
             ret_type: p.ret_type.clone(),
             name: p.name.clone(),
-            params: p.params.clone(),
+            params: p.params.iter().map(Translate::translate_param).collect(),
             body: ss,
+            visibility: lir::Visibility::Default,
+            dso_local: false,
+            noinline: false,
+            optnone: false,
         }
     }
 
@@ -66,7 +100,7 @@ impl ProcTranslator {
                     lir::Stm::Nop
                 ]
             },
-            mir::Stm::CJump { cond, if_true, if_false } => {
+            mir::Stm::CJump { cond, if_true, if_false, hint } => {
                 let mut ss = Vec::new();
                 let t = self.translate_exp_into(&*cond, &mut ss);
                 ss.push(
@@ -74,13 +108,14 @@ impl ProcTranslator {
                         cmp: t,
                         if_true: *if_true,
                         if_false: *if_false,
+                        hint: *hint,
                     }
                 );
                 ss
             },
-            mir::Stm::Jump { label } => {
+            mir::Stm::Jump { label, loop_hints } => {
                 vec![
-                    lir::Stm::Jump { label: *label }
+                    lir::Stm::Jump { label: *label, loop_hints: loop_hints.clone() }
                 ]
             },
             mir::Stm::Label { label } => {
@@ -111,8 +146,9 @@ impl ProcTranslator {
             },
             mir::Stm::Store { ty, ptr, value } => {
                 let mut ss = Vec::new();
-                let p = self.translate_exp_into(&*ptr, &mut ss);
-                let v = self.translate_exp_into(&*value, &mut ss);
+                let mut ts = self.translate_exps_into(&[&*ptr, &*value], &mut ss);
+                let v = ts.pop().unwrap();
+                let p = ts.pop().unwrap();
 
                 ss.push(
                     lir::Stm::Store {
@@ -125,6 +161,59 @@ impl ProcTranslator {
         }
     }
 
+    // Translate es left to right. A Temp operand isn't read until the statement that uses
+    // it, after the statements of the operands to its right, and those can assign it
+    // (e.g., the body of a Block from a HIR Seq). If so, copy it first so it's read in order.
+    fn translate_exps_into(&mut self, es: &[&mir::Exp], ss: &mut Vec<lir::Stm>) -> Vec<lir::Exp> {
+        let mut ts: Vec<lir::Exp> = Vec::new();
+
+        for e in es {
+            let mut ess = Vec::new();
+            let t = self.translate_exp_into(e, &mut ess);
+
+            for prev in ts.iter_mut() {
+                let (name, ty) = match prev {
+                    lir::Exp::Temp { name, ty } => (*name, ty.clone()),
+                    _ => continue,
+                };
+                if ess.iter().any(|s| ProcTranslator::assigns(s, name)) {
+                    let copy = lir::Exp::Temp { ty, name: self.new_temp() };
+                    ss.push(lir::Stm::Move { dst: copy.clone(), src: prev.clone() });
+                    *prev = copy;
+                }
+            }
+
+            ss.append(&mut ess);
+            ts.push(t);
+        }
+
+        ts
+    }
+
+    fn assigns(s: &lir::Stm, x: Name) -> bool {
+        let dst = match s {
+            lir::Stm::Load { dst, .. } => dst,
+            lir::Stm::Move { dst, .. } => dst,
+            lir::Stm::Call { dst, .. } => dst,
+            lir::Stm::Binary { dst, .. } => dst,
+            lir::Stm::Unary { dst, .. } => dst,
+            lir::Stm::Fma { dst, .. } => dst,
+            lir::Stm::Cast { dst, .. } => dst,
+            lir::Stm::Select { dst, .. } => dst,
+            lir::Stm::New { dst, .. } => dst,
+            lir::Stm::NewHybrid { dst, .. } => dst,
+            lir::Stm::Alloca { dst, .. } => dst,
+            lir::Stm::GetStructElementAddr { dst, .. } => dst,
+            lir::Stm::GetArrayElementAddr { dst, .. } => dst,
+            _ => return false,
+        };
+
+        match dst {
+            lir::Exp::Temp { name, .. } => *name == x,
+            _ => false,
+        }
+    }
+
     fn translate_exp_into(&mut self, e: &mir::Exp, ss: &mut Vec<lir::Stm>) -> lir::Exp {
         let dst_ty = e.get_type();
         let dst = lir::Exp::Temp { ty: dst_ty, name: self.new_temp() };
@@ -137,19 +226,17 @@ impl ProcTranslator {
                 self.translate_exp_into(&*exp, ss)
             },
             mir::Exp::Call { fun_type, fun, args } => {
-                let f = self.translate_exp_into(&*fun, ss);
-
-                let mut arg_regs = Vec::new();
-                for arg in args {
-                    let t = self.translate_exp_into(&*arg, ss);
-                    arg_regs.push(t);
-                }
+                let mut es = vec![&**fun];
+                es.extend(args.iter());
+                let mut arg_regs = self.translate_exps_into(&es, ss);
+                let f = arg_regs.remove(0);
 
                 ss.push(
                     lir::Stm::Call {
                         dst: dst.clone(),
                         fun: f,
                         args: arg_regs,
+                        must_tail: false,
                     }
                 );
 
@@ -169,9 +256,37 @@ impl ProcTranslator {
 
                 dst
             },
+            mir::Exp::BuildStruct { fields } => {
+                let es: Vec<&mir::Exp> = fields.iter().collect();
+                let fs = self.translate_exps_into(&es, ss);
+
+                ss.push(
+                    lir::Stm::BuildStruct {
+                        dst: dst.clone(),
+                        ty: e.get_type(),
+                        fields: fs
+                    }
+                );
+
+                dst
+            },
+            mir::Exp::ExtractStructElement { agg, field } => {
+                let a = self.translate_exp_into(&*agg, ss);
+
+                ss.push(
+                    lir::Stm::ExtractStructElement {
+                        dst: dst.clone(),
+                        agg: a,
+                        field: *field
+                    }
+                );
+
+                dst
+            },
             mir::Exp::GetArrayElementAddr { base_ty, ptr, index } => {
-                let p = self.translate_exp_into(&*ptr, ss);
-                let i = self.translate_exp_into(&*index, ss);
+                let mut ts = self.translate_exps_into(&[&*ptr, &*index], ss);
+                let i = ts.pop().unwrap();
+                let p = ts.pop().unwrap();
 
                 ss.push(
                     lir::Stm::GetArrayElementAddr {
@@ -197,8 +312,9 @@ impl ProcTranslator {
                 dst
             },
             mir::Exp::Binary { op, e1, e2 } => {
-                let t1 = self.translate_exp_into(&*e1, ss);
-                let t2 = self.translate_exp_into(&*e2, ss);
+                let mut ts = self.translate_exps_into(&[&*e1, &*e2], ss);
+                let t2 = ts.pop().unwrap();
+                let t1 = ts.pop().unwrap();
 
                 ss.push(
                     lir::Stm::Binary {
@@ -224,6 +340,41 @@ impl ProcTranslator {
 
                 dst
             },
+            mir::Exp::Fma { ty, a, b, c } => {
+                let mut ts = self.translate_exps_into(&[&*a, &*b, &*c], ss);
+                let t3 = ts.pop().unwrap();
+                let t2 = ts.pop().unwrap();
+                let t1 = ts.pop().unwrap();
+
+                ss.push(
+                    lir::Stm::Fma {
+                        dst: dst.clone(),
+                        ty: ty.clone(),
+                        a: t1,
+                        b: t2,
+                        c: t3,
+                    }
+                );
+
+                dst
+            },
+            mir::Exp::Select { cond, if_true, if_false } => {
+                let mut ts = self.translate_exps_into(&[&*cond, &*if_true, &*if_false], ss);
+                let t2 = ts.pop().unwrap();
+                let t1 = ts.pop().unwrap();
+                let c = ts.pop().unwrap();
+
+                ss.push(
+                    lir::Stm::Select {
+                        dst: dst.clone(),
+                        cond: c,
+                        if_true: t1,
+                        if_false: t2,
+                    }
+                );
+
+                dst
+            },
             mir::Exp::Cast { ty, exp } => {
                 let t = self.translate_exp_into(&*exp, ss);
 
@@ -247,7 +398,7 @@ impl ProcTranslator {
 
                 dst
             },
-            mir::Exp::NewHybrid { ty, length } => {
+            mir::Exp::NewHybrid { ty, length, zero } => {
                 let t = self.translate_exp_into(&*length, ss);
 
                 ss.push(
@@ -255,6 +406,7 @@ impl ProcTranslator {
                         dst: dst.clone(),
                         ty: ty.clone(),
                         length: t,
+                        zero: *zero,
                     }
                 );
 
@@ -1,11 +1,20 @@
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::thread;
 
 use crate::llvm;
 use crate::common::names::*;
+use crate::common::errors::CodegenError;
 use crate::mir::trees as mir;
 use crate::mir::ops::*;
 use crate::lir::trees as lir;
+use crate::lir::defined::check_temps_defined;
+use crate::lir::escape::check_stack_escapes;
+use crate::lir::tail::check_must_tail_calls;
 
 #[allow(non_upper_case_globals)]
 static mut depth: usize = 0;
@@ -19,7 +28,7 @@ macro_rules! intrinsic {
                     args: vec![$ty1]
                 },
                 name: Name::new($name)
-            }),
+            })?,
             &[$v1],
             &$self.fresh_name())
     };
@@ -31,7 +40,7 @@ macro_rules! intrinsic {
                     args: vec![$ty1, $ty2]
                 },
                 name: Name::new($name)
-            }),
+            })?,
             &[$v1, $v2],
             &$self.fresh_name())
     };
@@ -43,14 +52,89 @@ macro_rules! intrinsic {
                     args: vec![$ty1, $ty2, $ty3]
                 },
                 name: Name::new($name)
-            }),
+            })?,
             &[$v1, $v2, $v3],
             &$self.fresh_name())
     };
 }
 
+// LLVM types lowered from LIR types, so each type used by a module is built once rather than
+// for every temp and operand that has it. The types belong to the translator's context.
+struct TypeCache {
+    types: RefCell<HashMap<lir::Type, llvm::Type>>,
+    // The number of types lowered, i.e., cache misses.
+    lowered: Cell<usize>,
+}
+
+impl TypeCache {
+    fn new() -> TypeCache {
+        TypeCache { types: RefCell::new(HashMap::new()), lowered: Cell::new(0) }
+    }
+
+    fn get(&self, context: &llvm::Context, ty: &lir::Type) -> llvm::Type {
+        if let Some(t) = self.types.borrow().get(ty) {
+            return *t;
+        }
+        let t = Translate::to_type(context, ty);
+        self.lowered.set(self.lowered.get() + 1);
+        self.types.borrow_mut().insert(ty.clone(), t);
+        t
+    }
+}
+
+// Options that control how Translate lowers LIR. Each ProcTranslator borrows them.
+#[derive(Clone, Debug)]
+pub struct TranslateOptions {
+    // If set, a proc whose last block falls off the end is an error rather than
+    // being terminated with unreachable.
+    pub strict_terminators: bool,
+    // If set, statements between a terminator and the next label are dropped.
+    // Otherwise they are an error.
+    pub drop_unreachable: bool,
+    // If set, the unreachable added to a proc that falls off the end is preceded by a
+    // call to rivo_unreachable, which aborts with a diagnostic. For debugging frontends.
+    pub debug_unreachable: bool,
+    // Words to reserve before each New and NewHybrid allocation, e.g., for a GC header.
+    // The allocated pointer points past them. See jit::object_header.
+    // If set, the box_* functions are defined in the module so boxes get the header too.
+    pub box_header_words: usize,
+    // BuildStruct and ExtractStructElement on structs up to this size use insertvalue
+    // and extractvalue. Larger structs are accessed in memory with getelementptr.
+    pub max_register_aggregate_bytes: usize,
+    // The TLS model used for thread-local globals.
+    pub tls_model: llvm::TlsModel,
+    // If set, procs may use Invoke and LandingPad. These need a personality function.
+    pub unwinding: bool,
+    // The personality function of procs that use Invoke or LandingPad. See Translate::set_personality.
+    pub personality: Option<Name>,
+    // The target CPU and features for emit_assembly. Empty means generic.
+    pub cpu: String,
+    pub features: String,
+}
+
+impl TranslateOptions {
+    pub fn new() -> TranslateOptions {
+        TranslateOptions {
+            strict_terminators: false,
+            drop_unreachable: true,
+            debug_unreachable: false,
+            box_header_words: 0,
+            max_register_aggregate_bytes: 16,
+            tls_model: llvm::TlsModel::GeneralDynamic,
+            unwinding: false,
+            personality: None,
+            cpu: String::new(),
+            features: String::new(),
+        }
+    }
+}
+
 pub struct Translate {
+    // Modules from translate use types and values from the context, so they can only be used
+    // until it's disposed. Using one after that panics (see llvm::Module::check_live).
     pub context: llvm::Context,
+    pub options: TranslateOptions,
+    types: TypeCache,
 }
 
 impl Translate {
@@ -58,6 +142,8 @@ impl Translate {
         crate::llvm::init();
         Translate {
             context: llvm::Context::new(),
+            options: TranslateOptions::new(),
+            types: TypeCache::new(),
         }
     }
 
@@ -65,6 +151,8 @@ impl Translate {
         crate::llvm::init();
         Translate {
             context: context,
+            options: TranslateOptions::new(),
+            types: TypeCache::new(),
         }
     }
 
@@ -73,52 +161,406 @@ impl Translate {
 
         module.add_function("panic", llvm::Type::function(llvm::Type::void(), &[], false));
         module.add_function("yieldpoint", llvm::Type::function(llvm::Type::void(), &[], false));
+        let abort = module.add_function("rivo_unreachable", llvm::Type::function(llvm::Type::void(), &[byte_ptr, self.context.i32_type()], false));
+        abort.add_function_attribute(&self.context, "noreturn");
         module.add_function("malloc", llvm::Type::function(byte_ptr, &[self.context.i64_type()], false));
-        module.add_function("box_i32", llvm::Type::function(byte_ptr, &[self.context.i32_type()], false));
-        module.add_function("box_i64", llvm::Type::function(byte_ptr, &[self.context.i64_type()], false));
-        module.add_function("box_f32", llvm::Type::function(byte_ptr, &[self.context.float_type()], false));
-        module.add_function("box_f64", llvm::Type::function(byte_ptr, &[self.context.double_type()], false));
-        module.add_function("unbox_i32", llvm::Type::function(self.context.i32_type(), &[byte_ptr], false));
-        module.add_function("unbox_i64", llvm::Type::function(self.context.i64_type(), &[byte_ptr], false));
-        module.add_function("unbox_f32", llvm::Type::function(self.context.float_type(), &[byte_ptr], false));
-        module.add_function("unbox_f64", llvm::Type::function(self.context.double_type(), &[byte_ptr], false));
+        module.add_function("llvm.memset.p0i8.i64", llvm::Type::function(llvm::Type::void(), &[byte_ptr, self.context.i8_type(), self.context.i64_type(), self.context.i1_type()], false));
+        module.add_function("llvm.fma.f32", llvm::Type::function(self.context.float_type(), &[self.context.float_type(), self.context.float_type(), self.context.float_type()], false));
+        module.add_function("llvm.fma.f64", llvm::Type::function(self.context.double_type(), &[self.context.double_type(), self.context.double_type(), self.context.double_type()], false));
+        module.add_function("llvm.expect.i1", llvm::Type::function(self.context.i1_type(), &[self.context.i1_type(), self.context.i1_type()], false));
+        module.add_function("llvm.assume", llvm::Type::function(llvm::Type::void(), &[self.context.i1_type()], false));
+        for op in &["smin", "umin", "smax", "umax"] {
+            module.add_function(&format!("llvm.{}.i32", op), llvm::Type::function(self.context.i32_type(), &[self.context.i32_type(), self.context.i32_type()], false));
+            module.add_function(&format!("llvm.{}.i64", op), llvm::Type::function(self.context.i64_type(), &[self.context.i64_type(), self.context.i64_type()], false));
+        }
+        for op in &["sadd", "ssub", "smul"] {
+            for (bits, ty) in &[(32, self.context.i32_type()), (64, self.context.i64_type())] {
+                let ret = self.context.structure_type(&[*ty, self.context.i1_type()], false);
+                module.add_function(&format!("llvm.{}.with.overflow.i{}", op, bits), llvm::Type::function(ret, &[*ty, *ty], false));
+            }
+        }
+        module.add_function("llvm.memmove.p0i8.p0i8.i64", llvm::Type::function(llvm::Type::void(), &[byte_ptr, byte_ptr, self.context.i64_type(), self.context.i1_type()], false));
+        module.add_function("rivo_register_finalizer", llvm::Type::function(llvm::Type::void(), &[byte_ptr], false));
+        let box_ptr = self.box_type();
+        module.add_function("box_i32", llvm::Type::function(box_ptr, &[self.context.i32_type()], false));
+        module.add_function("box_i64", llvm::Type::function(box_ptr, &[self.context.i64_type()], false));
+        module.add_function("box_f32", llvm::Type::function(box_ptr, &[self.context.float_type()], false));
+        module.add_function("box_f64", llvm::Type::function(box_ptr, &[self.context.double_type()], false));
+        module.add_function("unbox_i32", llvm::Type::function(self.context.i32_type(), &[box_ptr], false));
+        module.add_function("unbox_i64", llvm::Type::function(self.context.i64_type(), &[box_ptr], false));
+        module.add_function("unbox_f32", llvm::Type::function(self.context.float_type(), &[box_ptr], false));
+        module.add_function("unbox_f64", llvm::Type::function(self.context.double_type(), &[box_ptr], false));
+    }
+
+    // mir_gen calls the box_* and unbox_* functions with its Box type, a Ref to an empty struct.
+    fn box_type(&self) -> llvm::Type {
+        self.types.get(&self.context, &mir::Type::Ref { ty: Box::new(mir::Type::Struct { fields: vec![] }) })
+    }
+
+    // The runtime's box_* functions don't know about box_header_words, so define them here
+    // as internal functions that allocate like New: malloc room for the header and the value,
+    // and return a pointer past the header.
+    fn define_boxers(&self, module: llvm::Module, builder: &llvm::Builder) {
+        let header_bytes = (self.options.box_header_words * Translate::sizeof(&mir::Type::word())) as i64;
+        let malloc = module.get_named_function("malloc");
+        let box_ptr = self.box_type();
+
+        for (name, ty) in &[("box_i32", mir::Type::I32), ("box_i64", mir::Type::I64), ("box_f32", mir::Type::F32), ("box_f64", mir::Type::F64)] {
+            let fun = module.get_named_function(name);
+            fun.set_linkage(llvm::Linkage::Internal);
+            let slot_ty = self.context.pointer_type(self.types.get(&self.context, ty));
+
+            builder.position_at_end(self.context.append_bb(fun, "entry"));
+            let size = llvm::Value::i64(header_bytes + Translate::sizeof(ty) as i64);
+            let p = builder.call(malloc, &[size], "p");
+            let obj = builder.get_in_bounds_element_pointer(p, &[llvm::Value::i64(header_bytes)], "obj");
+            let slot = builder.bitcast(obj, slot_ty, "slot");
+            builder.store(fun.get_param(0), slot);
+            let b = builder.bitcast(obj, box_ptr, "box");
+            builder.ret(b);
+        }
+    }
+
+    // Use the function name, e.g. __gxx_personality_v0, as the personality of procs that
+    // unwind. If it isn't an extern or proc, it is declared as i32 (...).
+    pub fn set_personality(&mut self, name: Name) {
+        self.options.personality = Some(name);
+    }
+
+    // The CPU to emit code for, e.g. skylake.
+    pub fn set_cpu(&mut self, cpu: &str) {
+        self.options.cpu = cpu.to_string();
+    }
+
+    // Target features to enable or disable, e.g. +avx2,-sse4a.
+    pub fn set_features(&mut self, features: &str) {
+        self.options.features = features.to_string();
+    }
+
+    // The number of distinct types translated so far, for measuring the type cache.
+    pub fn types_lowered(&self) -> usize {
+        self.types.lowered.get()
+    }
+
+    // Assembly text for a module from translate, for the target triple (or the host) and the CPU and features set above.
+    pub fn emit_assembly(&self, module: &llvm::Module, triple: Option<&str>) -> Result<String, String> {
+        module.emit_assembly_for_cpu(triple, &self.options.cpu, &self.options.features)
+    }
+
+    pub fn translate(&self, name: &str, r: &lir::Root) -> Result<llvm::Module, CodegenError> {
+        self.translate_chunk(name, r, 0, 1)
+    }
+
+    // Names starting with llvm. are reserved for intrinsics. A user global with such a name
+    // would be confused with the intrinsic declared by add_runtime_functions.
+    fn check_reserved_names(r: &lir::Root) -> Result<(), CodegenError> {
+        let names = r.externs.iter().map(|d| d.name)
+            .chain(r.imports.iter().map(|d| d.name))
+            .chain(r.data.iter().map(|d| d.name))
+            .chain(r.procs.iter().map(|p| p.name));
+
+        for name in names {
+            if name.to_string().starts_with("llvm.") {
+                return Err(CodegenError::ReservedName { name });
+            }
+        }
+
+        Ok(())
+    }
+
+    // add_function would rename a second proc with the same name rather than fail,
+    // so calls to either would go to the first.
+    fn check_unique_procs(r: &lir::Root) -> Result<(), CodegenError> {
+        let mut seen = HashSet::new();
+        let mut duplicates = vec![];
+
+        for p in &r.procs {
+            if ! seen.insert(p.name) && ! duplicates.contains(&p.name) {
+                duplicates.push(p.name);
+            }
+        }
+
+        if duplicates.is_empty() {
+            Ok(())
+        }
+        else {
+            Err(CodegenError::DuplicateProcs { names: duplicates })
+        }
+    }
+
+    // Translate r on nthreads threads, then link the results into one module.
+    // Every LLVM call still takes LLVM_LOCK, so only the work outside LLVM runs in parallel.
+    pub fn translate_parallel(&self, name: &str, r: &lir::Root, nthreads: usize) -> Result<llvm::Module, CodegenError> {
+        let nthreads = std::cmp::max(nthreads, 1);
+        let root = Arc::new(r.clone());
+
+        let handles: Vec<_> = (0..nthreads).map(|chunk| {
+            let root = root.clone();
+            let name = format!("{}.{}", name, chunk);
+            let options = self.options.clone();
+
+            // LLVM values can't be sent between threads, so each thread
+            // uses its own context and hands back bitcode.
+            thread::spawn(move || -> Result<Vec<u8>, CodegenError> {
+                // The module and the context are disposed however the thread returns.
+                let context = llvm::OwnedContext::new();
+                let mut t = Translate::new_in_context(*context);
+                t.options = options;
+                let m = llvm::OwnedModule::new(t.translate_chunk(&name, &root, chunk, nthreads)?);
+                Ok(m.write_bitcode_to_memory())
+            })
+        }).collect();
+
+        // Join every thread before returning, even if one fails.
+        let results: Vec<_> = handles.into_iter().map(|h| h.join()).collect();
+
+        let mut chunks = Vec::new();
+        for (chunk, result) in results.into_iter().enumerate() {
+            match result {
+                Ok(bytes) => chunks.push(bytes?),
+                Err(_) => return Err(CodegenError::TranslationThreadPanicked { chunk }),
+            }
+        }
+
+        // Module::new uses the global context, so parse the chunks there too.
+        let parse = |chunk: usize, bytes: &[u8]| {
+            llvm::Module::parse_bitcode(llvm::Context::global(), bytes)
+                .ok_or_else(|| CodegenError::InvalidBitcode { name: format!("{}.{}", name, chunk) })
+        };
+
+        let module = llvm::OwnedModule::new(parse(0, &chunks[0])?);
+        for (chunk, bytes) in chunks.iter().enumerate().skip(1) {
+            // link consumes m, whether or not it succeeds.
+            let m = parse(chunk, bytes)?;
+            if ! module.link(m) {
+                return Err(CodegenError::LinkFailed { name: name.to_string() });
+            }
+        }
+
+        Ok(module.into_module())
     }
 
-    pub fn translate(&self, name: &str, r: &lir::Root) -> llvm::Module {
+    // Translate the procs whose index is chunk modulo nchunks. The other procs are only declared.
+    // Data is defined in chunk 0 and declared in the others.
+    fn translate_chunk(&self, name: &str, r: &lir::Root, chunk: usize, nchunks: usize) -> Result<llvm::Module, CodegenError> {
+        Translate::check_reserved_names(r)?;
+        Translate::check_unique_procs(r)?;
+
+        self.context.check_live();
+
         let builder = self.context.new_builder();
-        let module = llvm::Module::new(name);
+        // Disposed if translation fails part way.
+        let module = llvm::OwnedModule::new(llvm::Module::new(name).bound_to(&self.context));
 
-        self.add_runtime_functions(module);
+        self.add_runtime_functions(*module);
+        if self.options.box_header_words > 0 {
+            self.define_boxers(*module, &builder);
+        }
 
         let mut funs = Vec::new();
 
+        // The functions and globals by name, so references to them in proc bodies
+        // don't each format the name and look it up in the module.
+        let mut symbols = HashMap::new();
+
         for d in &r.externs {
-            let ty = Translate::to_type(&self.context, &d.ty);
+            let ty = self.types.get(&self.context, &d.ty);
             let function = module.add_function(&d.name.to_string(), ty);
             function.dump(); eprintln!();
+            symbols.insert(d.name, function);
+        }
+
+        // Without an initializer, a global is an external declaration.
+        for d in &r.imports {
+            let ty = self.types.get(&self.context, &d.ty);
+            let global = module.add_global(&d.name.to_string(), ty);
+            symbols.insert(d.name, global);
         }
 
         for d in &r.data {
-            let ty = Translate::to_type(&self.context, &d.ty);
+            let ty = self.data_type(d);
             let global = module.add_global(&d.name.to_string(), ty);
-            let init = Translate::lit_to_value(&self.context, &d.init);
-            global.set_initializer(init);
-            global.dump(); eprintln!();
+            if d.thread_local {
+                global.set_thread_local(self.options.tls_model);
+            }
+            global.set_visibility(Translate::to_visibility(d.name, d.visibility, d.dso_local)?);
+            if let Some(section) = &d.section {
+                global.set_section(section);
+            }
+            if let Some(bytes) = d.alignment {
+                // The type's own alignment is the default, so only a stricter one makes sense.
+                let natural = if d.packed { 1 } else { d.ty.align_of(Translate::wordsize()) };
+                if !bytes.is_power_of_two() || (bytes as usize) < natural {
+                    return Err(CodegenError::InvalidAlignment { name: d.name, alignment: bytes });
+                }
+                global.set_alignment(bytes);
+            }
+            symbols.insert(d.name, global);
         }
 
         for p in &r.procs {
-            let t = ProcTranslator::new(&self.context, &module, &builder);
-            let fun = t.init_proc(p);
+            let t = ProcTranslator::new(&self.context, &self.types, &module, &builder, &symbols, &self.options);
+            let fun = t.init_proc(p)?;
             funs.push(fun);
         }
 
         for (p, fun) in r.procs.iter().zip(funs.iter()) {
-            let t = ProcTranslator::new(&self.context, &module, &builder);
-            t.translate_proc(p, *fun);
+            symbols.insert(p.name, *fun);
+        }
+
+        // Add the initializers after all the globals and functions are declared, since they can refer to them.
+        if chunk == 0 {
+            for d in &r.data {
+                let global = symbols[&d.name];
+                let init = match (&d.init, &d.ty) {
+                    (lir::Init::Struct { fields }, _) if d.packed => {
+                        let vs = self.struct_init_to_values(*module, &symbols, fields, &d.ty)?;
+                        llvm::Value::const_struct(&vs, true)
+                    },
+                    _ => self.init_to_value(*module, &symbols, &d.init, &d.ty)?,
+                };
+                global.set_initializer(init);
+                global.dump(); eprintln!();
+            }
+        }
+
+        // The module initializer (see mir_gen::Translate::translate_lifted) runs when the module is loaded.
+        if chunk == 0 && r.procs.iter().any(|p| p.name == Name::new("rivo_init")) {
+            module.add_global_ctor(symbols[&Name::new("rivo_init")], 65535);
+        }
+
+        for (i, (p, fun)) in r.procs.iter().zip(funs.iter()).enumerate() {
+            if i % nchunks != chunk {
+                continue;
+            }
+
+            let t = ProcTranslator::new(&self.context, &self.types, &module, &builder, &symbols, &self.options);
+            t.translate_proc(p, *fun)?;
+        }
+
+        Ok(module.into_module())
+    }
+
+    // Hash of a proc's LIR, used by retranslate to skip procs that haven't changed.
+    pub fn proc_hash(p: &lir::Proc) -> u64 {
+        let mut h = DefaultHasher::new();
+        p.hash(&mut h);
+        h.finish()
+    }
+
+    // The hashes of every proc in r, as they are after translate.
+    pub fn proc_hashes(r: &lir::Root) -> HashMap<Name, u64> {
+        r.procs.iter().map(|p| (p.name, Translate::proc_hash(p))).collect()
+    }
+
+    // Update a module previously produced by translate to match an edited root.
+    // Only procs whose hash differs from the one in hashes are translated again; the
+    // others are kept as they are. The externs and data must not have changed.
+    // Updates hashes and returns the names of the procs that were re-emitted.
+    pub fn retranslate(&self, module: llvm::Module, r: &lir::Root, hashes: &mut HashMap<Name, u64>) -> Result<Vec<Name>, CodegenError> {
+        let builder = self.context.new_builder();
+
+        // Only the re-emitted procs are cached; other symbols are looked up in the module.
+        let mut symbols = HashMap::new();
+
+        let mut changed = Vec::new();
+
+        for p in &r.procs {
+            let h = Translate::proc_hash(p);
+            if hashes.get(&p.name) == Some(&h) {
+                continue;
+            }
+
+            // Check that the proc can be declared before touching the old function.
+            Translate::to_visibility(p.name, p.visibility, p.dso_local)?;
+
+            // Move the old function out of the way so the new one can take its name,
+            // then point the old function's callers at the new one.
+            let old = module.find_function(&p.name.to_string());
+            if let Some(old) = old {
+                old.set_name("");
+            }
+
+            let t = ProcTranslator::new(&self.context, &self.types, &module, &builder, &symbols, &self.options);
+            let fun = t.init_proc(p)?;
+
+            if let Some(old) = old {
+                let ty = old.type_of();
+                let new = if fun.type_of() == ty { fun } else { fun.const_bitcast(ty) };
+                old.replace_all_uses_with(new);
+                old.delete_function();
+            }
+
+            changed.push((p, fun, h));
+        }
+
+        for (p, fun, h) in &changed {
+            symbols.insert(p.name, *fun);
         }
 
-        builder.dispose();
-        module
+        for (p, fun, h) in &changed {
+            let t = ProcTranslator::new(&self.context, &self.types, &module, &builder, &symbols, &self.options);
+            t.translate_proc(p, *fun)?;
+            hashes.insert(p.name, *h);
+        }
+
+        Ok(changed.iter().map(|(p, _, _)| p.name).collect())
+    }
+
+    // The LLVM type of the global d. Only packed structs differ from the type of d.ty.
+    fn data_type(&self, d: &lir::Data) -> llvm::Type {
+        match &d.ty {
+            lir::Type::Struct { fields } if d.packed => {
+                let tys: Vec<llvm::Type> = fields.iter().map(|f| self.types.get(&self.context, f)).collect();
+                self.context.structure_type(&tys, true)
+            },
+            ty => self.types.get(&self.context, ty),
+        }
+    }
+
+    // The constant init, for a global of type ty.
+    fn init_to_value(&self, module: llvm::Module, symbols: &HashMap<Name, llvm::Value>, init: &lir::Init, ty: &lir::Type) -> Result<llvm::Value, CodegenError> {
+        match init {
+            lir::Init::Lit { lit: mir::Lit::FunctionAddr { ty, name } } => {
+                // Runtime functions aren't in the symbol table, but are declared in the module.
+                let fun = match symbols.get(name) {
+                    Some(v) => *v,
+                    None => module.find_function(&name.to_string()).ok_or(CodegenError::UndefinedFunction { name: *name })?,
+                };
+                Ok(fun.const_bitcast(self.types.get(&self.context, ty)))
+            },
+            lir::Init::Lit { lit: mir::Lit::GlobalAddr { ty, name } } => {
+                let global = symbols.get(name).ok_or(CodegenError::UndefinedGlobal { name: *name })?;
+                Ok(global.const_bitcast(self.types.get(&self.context, ty)))
+            },
+            lir::Init::Lit { lit } => {
+                Ok(Translate::lit_to_value(&self.context, lit))
+            },
+            lir::Init::Struct { fields } => {
+                let vs = self.struct_init_to_values(module, symbols, fields, ty)?;
+                Ok(llvm::Value::const_named_struct(self.types.get(&self.context, ty), &vs))
+            },
+            lir::Init::ElementAddr { name, indices } => {
+                let global = symbols.get(name).ok_or(CodegenError::UndefinedGlobal { name: *name })?;
+                // Struct field indices must be i32, so use i32 for all of them.
+                let is: Vec<llvm::Value> = indices.iter().map(|i| llvm::Value::i32(*i as i32)).collect();
+                // E.g., a static array is a struct with the length and each element, but it's used as a hybrid.
+                Ok(llvm::Value::const_gep(*global, &is).const_bitcast(self.types.get(&self.context, ty)))
+            },
+        }
+    }
+
+    // The values of the fields of a struct initializer, which must have one init per field of ty.
+    fn struct_init_to_values(&self, module: llvm::Module, symbols: &HashMap<Name, llvm::Value>, fields: &Vec<lir::Init>, ty: &lir::Type) -> Result<Vec<llvm::Value>, CodegenError> {
+        let field_types = match ty {
+            lir::Type::Struct { fields: field_types } if field_types.len() == fields.len() => field_types,
+            _ => return Err(CodegenError::StructInitMismatch { ty: ty.clone(), fields: fields.len() }),
+        };
+        let mut vs = Vec::new();
+        for (f, t) in fields.iter().zip(field_types.iter()) {
+            vs.push(self.init_to_value(module, symbols, f, t)?);
+        }
+        Ok(vs)
     }
 
     fn lit_to_value(context: &llvm::Context, lit: &mir::Lit) -> llvm::Value {
@@ -145,6 +587,9 @@ impl Translate {
             mir::Lit::I64 { value } => {
                 llvm::Value::i64(*value)
             },
+            mir::Lit::I128 { value } => {
+                llvm::Value::i128(*value)
+            },
             mir::Lit::F32 { value } => {
                 llvm::Value::float(*value)
             },
@@ -152,37 +597,35 @@ impl Translate {
                 llvm::Value::double(*value)
             },
             mir::Lit::Sizeof { ty } => {
-                // TODO
+                let n = Translate::sizeof(ty);
+                match mir::Type::word() {
+                    mir::Type::I32 => llvm::Value::i32(n as i32),
+                    mir::Type::I64 => llvm::Value::i64(n as i64),
+                    _ => unimplemented!(),
+                }
+            },
+            mir::Lit::Alignof { ty } => {
+                let n = ty.align_of(Translate::wordsize());
                 match mir::Type::word() {
-                    mir::Type::I32 => llvm::Value::i32(4),
-                    mir::Type::I64 => llvm::Value::i64(8),
+                    mir::Type::I32 => llvm::Value::i32(n as i32),
+                    mir::Type::I64 => llvm::Value::i64(n as i64),
                     _ => unimplemented!(),
                 }
             },
+            mir::Lit::FunctionAddr { .. } | mir::Lit::GlobalAddr { .. } => {
+                // These need the module's symbols, so init_to_value and to_value handle them
+                // before calling this.
+                unreachable!("symbol address {:?} translated without a module", lit)
+            },
         }
     }
 
     fn sizeof_exp(ty: &lir::Type) -> (lir::Exp, lir::Exp) {
         match ty {
             lir::Type::Hybrid { fields, box variant } => {
-                let mut n = 0;
-                for f in fields {
-                    let s = Translate::sizeof(&f);
-                    // Align
-                    if s != 0 {
-                        while n % s != 0 {
-                            n += 1;
-                        }
-                        n += s;
-                    }
-                }
-                let v = Translate::sizeof(&variant);
-                // Align
-                if v != 0 {
-                    while n % v != 0 {
-                        n += 1;
-                    }
-                }
+                let wordsize = Translate::wordsize();
+                let n = lir::Type::align_up(lir::Type::fields_end(fields, wordsize), variant.align_of(wordsize));
+                let v = variant.size_of(wordsize);
 
                 let fixed_size = lir::Exp::Lit { lit: lir::Lit::I64 { value: n as i64 } };
                 let variant_size = lir::Exp::Lit { lit: lir::Lit::I64 { value: v as i64 } };
@@ -192,66 +635,47 @@ impl Translate {
         }
     }
 
+    fn wordsize() -> usize {
+        match mir::Type::word() {
+            mir::Type::I64 => 8,
+            _ => 4,
+        }
+    }
+
     fn sizeof(ty: &lir::Type) -> usize {
+        ty.size_of(Translate::wordsize())
+    }
+
+    // The LLVM C API can't set dso_local directly. Hidden and protected symbols are implicitly
+    // dso_local, but a default visibility symbol can't be made dso_local without changing its
+    // visibility, so that's an error rather than a silently different symbol.
+    fn to_visibility(name: Name, visibility: lir::Visibility, dso_local: bool) -> Result<llvm::Visibility, CodegenError> {
+        match visibility {
+            lir::Visibility::Default if dso_local => Err(CodegenError::DefaultVisibilityDsoLocal { name }),
+            lir::Visibility::Default => Ok(llvm::Visibility::Default),
+            lir::Visibility::Hidden => Ok(llvm::Visibility::Hidden),
+            lir::Visibility::Protected => Ok(llvm::Visibility::Protected),
+        }
+    }
+
+    // Following the System V ABI, structs up to two words are passed by value as LLVM
+    // aggregates, which LLVM splits into registers. Larger structs are passed in memory,
+    // as a byval pointer to a copy made for the callee.
+    fn passed_in_memory(ty: &lir::Type) -> bool {
         match ty {
-            lir::Type::I1 => 1,
-            lir::Type::I8 => 1,
-            lir::Type::I16 => 2,
-            lir::Type::I32 => 4,
-            lir::Type::I64 => 8,
-            lir::Type::F32 => 4,
-            lir::Type::F64 => 8,
-            lir::Type::Void => 0,
-            lir::Type::Ptr { box ty } => if mir::Type::word() == mir::Type::I64 { 8 } else { 4 },
-            lir::Type::Ref { box ty } => if mir::Type::word() == mir::Type::I64 { 8 } else { 4 },
-            lir::Type::IRef { box ty } => if mir::Type::word() == mir::Type::I64 { 8 } else { 4 },
-            lir::Type::Hybrid { fields, box variant } => {
-                let mut n = 0;
-                for f in fields {
-                    let s = Translate::sizeof(&f);
-                    // Align
-                    if s != 0 {
-                        while n % s != 0 {
-                            n += 1;
-                        }
-                        n += s;
-                    }
-                }
-                let v = Translate::sizeof(&variant);
-                // Align
-                if v != 0 {
-                    while n % v != 0 {
-                        n += 1;
-                    }
-                    n += v;
-                }
-                n
-            },
-            lir::Type::Struct { fields } => {
-                let mut n = 0;
-                for f in fields {
-                    let s = Translate::sizeof(&f);
-                    // Align
-                    if s != 0 {
-                        while n % s != 0 {
-                            n += 1;
-                        }
-                        n += s;
-                    }
-                }
-                n
-            },
-            lir::Type::Union { variants } => {
-                let mut n = 0;
-                for f in variants {
-                    let s = Translate::sizeof(&f);
-                    if n < s {
-                        n = s;
-                    }
-                }
-                n
-            },
-            lir::Type::Fun { ret, args } => unimplemented!(),
+            lir::Type::Struct { .. } => Translate::sizeof(ty) > 2 * Translate::wordsize(),
+            _ => false,
+        }
+    }
+
+    // The LLVM type of a parameter, which is a pointer if the argument is passed in memory.
+    fn to_param_type(context: &llvm::Context, ty: &lir::Type) -> llvm::Type {
+        let t = Translate::to_type(context, ty);
+        if Translate::passed_in_memory(ty) {
+            context.pointer_type(t)
+        }
+        else {
+            t
         }
     }
 
@@ -262,6 +686,7 @@ impl Translate {
             lir::Type::I16 => context.i16_type(),
             lir::Type::I32 => context.i32_type(),
             lir::Type::I64 => context.i64_type(),
+            lir::Type::I128 => context.i128_type(),
             lir::Type::F32 => context.float_type(),
             lir::Type::F64 => context.double_type(),
             lir::Type::Void => context.void_type(),
@@ -297,7 +722,7 @@ impl Translate {
             },
             lir::Type::Fun { ret, args } => {
                 let r = Translate::to_type(context, ret);
-                let ps: Vec<llvm::Type> = args.iter().map(|a| Translate::to_type(context, a)).collect();
+                let ps: Vec<llvm::Type> = args.iter().map(|a| Translate::to_param_type(context, a)).collect();
                 context.function_type(r, &ps, false)
             },
         }
@@ -306,61 +731,172 @@ impl Translate {
 
 struct ProcTranslator<'a> {
     context: &'a llvm::Context,
+    types: &'a TypeCache,
     module: &'a llvm::Module,
     builder: &'a llvm::Builder,
+    symbols: &'a HashMap<Name, llvm::Value>,
+    options: &'a TranslateOptions,
 }
 
 struct BodyTranslator<'a> {
     context: &'a llvm::Context,
+    types: &'a TypeCache,
     module: &'a llvm::Module,
     builder: &'a llvm::Builder,
     fun: &'a llvm::Value,
     labels: HashMap<Name, llvm::BB>,
     temps: HashMap<Name, llvm::Value>, // maps from temp name to the alloca that created it.
     params: HashMap<Name, llvm::Value>, // maps from temp name to the alloca that created it.
+    stored: HashMap<Name, llvm::Value>, // maps from temp name to the last value stored to it in the current block.
+    symbols: &'a HashMap<Name, llvm::Value>, // maps from function or global name to its value.
+    name: Name,
+    ret_type: mir::Type,
+    options: &'a TranslateOptions,
 }
 
 impl<'a> ProcTranslator<'a> {
-    fn new(context: &'a llvm::Context, module: &'a llvm::Module, builder: &'a llvm::Builder) -> Self {
-        ProcTranslator { context, module, builder }
+    fn new(context: &'a llvm::Context, types: &'a TypeCache, module: &'a llvm::Module, builder: &'a llvm::Builder, symbols: &'a HashMap<Name, llvm::Value>, options: &'a TranslateOptions) -> Self {
+        ProcTranslator { context, types, module, builder, symbols, options }
+    }
+
+    fn unwinds(s: &lir::Stm) -> bool {
+        match s {
+            lir::Stm::Invoke { .. } | lir::Stm::LandingPad { .. } => true,
+            _ => false,
+        }
+    }
+
+    fn personality_fn(&self, name: Name) -> llvm::Value {
+        match self.symbols.get(&name) {
+            Some(v) => *v,
+            None => match self.module.find_function(&name.to_string()) {
+                Some(v) => v,
+                None => {
+                    let ty = llvm::Type::function(self.context.i32_type(), &[], true);
+                    self.module.add_function(&name.to_string(), ty)
+                },
+            },
+        }
     }
 
     fn to_type(&self, ty: &lir::Type) -> llvm::Type {
-        Translate::to_type(self.context, ty)
+        self.types.get(self.context, ty)
     }
 
-    fn init_proc(&self, p: &lir::Proc) -> llvm::Value {
+    fn init_proc(&self, p: &lir::Proc) -> Result<llvm::Value, CodegenError> {
         let ty = self.to_type(&p.ret_type);
-        let tys: Vec<llvm::Type> = p.params.iter().map(|p| self.to_type(&p.ty)).collect();
+        let tys: Vec<llvm::Type> = p.params.iter().map(|p| Translate::to_param_type(self.context, &p.ty)).collect();
         let fun_ty = llvm::Type::function(ty, &tys, false);
-        self.module.add_function(&p.name.to_string(), fun_ty)
+        let fun = self.module.add_function(&p.name.to_string(), fun_ty);
+        fun.set_visibility(Translate::to_visibility(p.name, p.visibility, p.dso_local)?);
+        if p.noinline || p.optnone {
+            fun.add_function_attribute(self.context, "noinline");
+        }
+        if p.optnone {
+            fun.add_function_attribute(self.context, "optnone");
+        }
+        Ok(fun)
     }
 
-    fn translate_proc(&self, p: &lir::Proc, fun: llvm::Value) {
+    fn translate_proc(&self, p: &lir::Proc, fun: llvm::Value) -> Result<(), CodegenError> {
+        // fun is declared from p.params by init_proc, so binding them by index below is in
+        // range. A param the body reads but p doesn't declare is just an undefined temp.
+        check_temps_defined(p)?;
+
+        // A pointer into an Alloca buffer dangles once the proc returns.
+        check_stack_escapes(p)?;
+        check_must_tail_calls(p)?;
+
         let mut params = HashMap::new();
 
         for (i, p) in p.params.iter().enumerate() {
             params.insert(p.name, fun.get_param(i));
+
+            if p.noalias {
+                fun.add_param_attribute(self.context, i, "noalias");
+            }
+            if p.readonly {
+                fun.add_param_attribute(self.context, i, "readonly");
+            }
+            if p.nonnull {
+                fun.add_param_attribute(self.context, i, "nonnull");
+            }
+            if Translate::passed_in_memory(&p.ty) {
+                fun.add_param_attribute(self.context, i, "byval");
+            }
+        }
+
+        // Landing pads are only valid in a function with a personality.
+        if p.body.iter().any(ProcTranslator::unwinds) {
+            if ! self.options.unwinding {
+                return Err(CodegenError::UnwindingDisabled { name: p.name });
+            }
+            match self.options.personality {
+                Some(name) => fun.set_personality(self.personality_fn(name)),
+                None => return Err(CodegenError::MissingPersonality { name: p.name }),
+            }
         }
 
         let mut t = BodyTranslator {
             context: &self.context,
+            types: self.types,
             module: &self.module,
             builder: &self.builder,
             fun: &fun,
             labels: HashMap::new(),
             temps: HashMap::new(),
             params: params.clone(),
+            stored: HashMap::new(),
+            symbols: self.symbols,
+            name: p.name,
+            ret_type: p.ret_type.clone(),
+            options: self.options,
+        };
+
+        t.translate(&p.params, &p.body)?;
+        self.hoist_static_allocas(fun);
+        Ok(())
+    }
+
+    // LLVM only promotes allocas to registers, and only shares stack slots between them,
+    // if they're in the entry block. Alloca statements can be anywhere, so move the
+    // constant-sized ones to the top of the entry block, like the temps' allocas.
+    // Dynamic allocas have to follow the computation of their count, so they stay put.
+    fn hoist_static_allocas(&self, fun: llvm::Value) {
+        let bbs = fun.basic_blocks();
+        let top = match bbs.first().and_then(|bb| bb.first_instruction()) {
+            Some(insn) => insn,
+            None => return,
         };
 
-        t.translate(&p.body);
+        for bb in &bbs[1..] {
+            for insn in bb.instructions() {
+                if insn.is_alloca() && insn.get_operand(0).is_constant() {
+                    insn.remove_from_parent();
+                    self.builder.position_before(top);
+                    self.builder.insert(insn);
+                }
+            }
+        }
     }
 }
 
 impl<'a> BodyTranslator<'a> {
-    fn translate(&mut self, body: &Vec<lir::Stm>) {
-        // Create the first BB.
-        let entry = self.context.append_bb(self.fun.clone(), "entry");
+    fn translate(&mut self, params: &Vec<lir::Param>, body: &Vec<lir::Stm>) -> Result<(), CodegenError> {
+        // to_bb would create an empty block for a label that's never defined.
+        self.check_labels(body)?;
+
+        // Create the first BB. If the body starts with a label, that label's block is the
+        // entry, rather than an empty entry block that falls through to it. LLVM doesn't
+        // allow branches to the entry block, though, so not if the label is a jump target.
+        let first_label = match body.first() {
+            Some(lir::Stm::Label { label }) if ! BodyTranslator::is_jump_target(body, *label) => Some(*label),
+            _ => None,
+        };
+        let entry = match first_label {
+            Some(label) => self.to_bb(label),
+            None => self.context.append_bb(self.fun.clone(), "entry"),
+        };
         self.builder.position_at_end(entry);
 
         // Collect temporaries.
@@ -369,9 +905,25 @@ impl<'a> BodyTranslator<'a> {
             TempFinder::add_temps_for_stm(s, &mut temps);
         }
 
-        // Emit an alloca for each temporary, except params.
+        // A param may be used at a type other than the one it was declared with.
+        // The declared type is the one in the function signature, so use it instead.
+        temps.retain(|(x, _)| self.params.get(x).is_none());
+        for p in params {
+            temps.insert((p.name, p.ty.clone()));
+        }
+
+        // Temps are looked up by name alone, so a name used at two types would
+        // get two allocas and silently use either one.
+        let mut temp_types: HashMap<Name, &mir::Type> = HashMap::new();
         for (x, xty) in &temps {
-            if self.params.get(&x).is_some() {
+            if let Some(ty) = temp_types.insert(*x, xty) {
+                return Err(CodegenError::ConflictingTempTypes { name: *x, ty1: ty.clone(), ty2: xty.clone() });
+            }
+        }
+
+        // Emit an alloca for each temporary, except params and void temps.
+        for (x, xty) in &temps {
+            if self.params.get(&x).is_some() || *xty == mir::Type::Void {
                 continue;
             }
 
@@ -381,10 +933,29 @@ impl<'a> BodyTranslator<'a> {
             self.temps.insert(*x, insn.clone());
         }
 
+        // A param passed in memory points to the callee's own copy, so use it like an alloca.
+        for p in params {
+            if Translate::passed_in_memory(&p.ty) {
+                if let Some(v) = self.params.remove(&p.name) {
+                    self.temps.insert(p.name, v);
+                }
+            }
+        }
+
         // Now, translate each statement.
-        let mut last_was_jump = false;
+        // If the first label is the entry block, there's no previous block to branch from.
+        let mut last_was_jump = first_label.is_some();
+        // A must_tail call returns its result itself, so the Ret after it is already translated.
+        let mut last_was_tail_call = false;
 
         for s in body {
+            if last_was_tail_call {
+                last_was_tail_call = false;
+                if let lir::Stm::Ret { .. } = s {
+                    continue;
+                }
+            }
+
             match s {
                 lir::Stm::Label { label } => {
                     let bb = self.to_bb(*label);
@@ -396,34 +967,127 @@ impl<'a> BodyTranslator<'a> {
                     }
 
                     self.builder.position_at_end(bb);
+
+                    // Values stored in the previous block don't dominate this one.
+                    self.stored.clear();
+
+                    last_was_jump = false;
                 },
                 lir::Stm::Nop => {
                     // skip it.
                 },
+                _ if last_was_jump => {
+                    // Nothing can reach this statement, and LLVM doesn't allow
+                    // instructions after the block's terminator.
+                    if ! self.options.drop_unreachable {
+                        return Err(CodegenError::UnreachableStatement { name: self.name });
+                    }
+                },
                 _ => {
                     last_was_jump = match s {
                         lir::Stm::Jump { .. } => true,
                         lir::Stm::CJump { .. } => true,
+                        lir::Stm::Invoke { .. } => true,
                         lir::Stm::Ret { .. } => true,
+                        lir::Stm::Call { must_tail: true, .. } => true,
+                        _ => false,
+                    };
+                    last_was_tail_call = match s {
+                        lir::Stm::Call { must_tail, .. } => *must_tail,
                         _ => false,
                     };
 
-                    self.translate_stm(s);
+                    self.translate_stm(s)?;
                 }
             }
         }
 
         if ! last_was_jump {
+            if self.options.strict_terminators {
+                return Err(CodegenError::MissingTerminator { name: self.name });
+            }
+
             // If the last instruction was not a jump, add an unreachable insn.
+            if self.options.debug_unreachable {
+                self.call_rivo_unreachable(body.len());
+            }
             self.builder.unreachable();
         }
+
+        Ok(())
+    }
+
+    fn is_jump_target(body: &Vec<lir::Stm>, label: Name) -> bool {
+        body.iter().any(|s| match s {
+            lir::Stm::Jump { label: l, loop_hints } => *l == label,
+            lir::Stm::CJump { cmp, if_true, if_false, hint } => *if_true == label || *if_false == label,
+            lir::Stm::Invoke { normal, unwind, .. } => *normal == label || *unwind == label,
+            _ => false,
+        })
+    }
+
+    fn check_labels(&self, body: &Vec<lir::Stm>) -> Result<(), CodegenError> {
+        let mut defined = HashSet::new();
+        for s in body {
+            if let lir::Stm::Label { label } = s {
+                defined.insert(*label);
+            }
+        }
+
+        let mut undefined = vec![];
+        let mut check = |label: &Name| {
+            if ! defined.contains(label) && ! undefined.contains(label) {
+                undefined.push(*label);
+            }
+        };
+
+        for s in body {
+            match s {
+                lir::Stm::Jump { label, loop_hints } => check(label),
+                lir::Stm::CJump { cmp, if_true, if_false, hint } => {
+                    check(if_true);
+                    check(if_false);
+                },
+                lir::Stm::Invoke { normal, unwind, .. } => {
+                    check(normal);
+                    check(unwind);
+                },
+                _ => {},
+            }
+        }
+
+        if undefined.is_empty() {
+            Ok(())
+        }
+        else {
+            Err(CodegenError::UndefinedLabels { name: self.name, labels: undefined })
+        }
+    }
+
+    fn loop_hint(&self, hint: LoopHint) -> llvm::MetadataNode {
+        match hint {
+            LoopHint::Unroll(n) => {
+                self.context.md_node(&[self.context.md_string("llvm.loop.unroll.count"), self.context.md_i32(n as i32)])
+            },
+            LoopHint::Vectorize(enable) => {
+                self.context.md_node(&[self.context.md_string("llvm.loop.vectorize.enable"), self.context.md_bool(enable)])
+            },
+        }
+    }
+
+    // LIR has no source positions, so report the proc name and the number of statements
+    // in its body, i.e., the point where control fell off the end.
+    fn call_rivo_unreachable(&self, line: usize) {
+        let abort = self.module.find_function("rivo_unreachable").unwrap();
+        let file = self.builder.global_string_ptr(&self.name.to_string(), &self.fresh_name());
+        self.builder.call(abort, &[file, llvm::Value::i32(line as i32)], "");
     }
 
     fn fresh_name(&self) -> String {
         Name::fresh("t.llvm").to_string()
     }
 
-    fn to_value(&mut self, e: &lir::Exp) -> llvm::Value {
+    fn to_value(&mut self, e: &lir::Exp) -> Result<llvm::Value, CodegenError> {
         match e {
             lir::Exp::GlobalAddr { name, ty } => {
                 self.to_addr(e)
@@ -431,39 +1095,198 @@ impl<'a> BodyTranslator<'a> {
             lir::Exp::FunctionAddr { name, ty } => {
                 self.to_addr(e)
             },
+            lir::Exp::Temp { ty: mir::Type::Void, .. } => {
+                // Void temps have no alloca, so read them like the void literal.
+                Ok(Translate::lit_to_value(&self.context, &mir::Lit::Void))
+            },
             lir::Exp::Temp { name, ty } => {
                 match self.params.get(&name) {
                     Some(v) => {
                         // Params should not be loaded.
                         let insn = v.clone();
                         eprintln!("; {:?}", e); eprint!("; "); insn.dump(); eprintln!();
-                        insn
+                        Ok(insn)
                     },
                     None => {
-                        let a = self.to_addr(e);
+                        // Reuse the value if it was stored earlier in this block.
+                        if let Some(v) = self.stored.get(&name) {
+                            return Ok(*v)
+                        }
+                        let a = self.to_addr(e)?;
                         let insn = self.builder.load(a, &self.fresh_name());
-                        insn
+                        Ok(insn)
                     }
                 }
             },
+            lir::Exp::Lit { lit: mir::Lit::FunctionAddr { ty, name } } => {
+                self.to_addr(&lir::Exp::FunctionAddr { ty: ty.clone(), name: *name })
+            },
+            lir::Exp::Lit { lit: mir::Lit::GlobalAddr { ty, name } } => {
+                self.to_addr(&lir::Exp::GlobalAddr { ty: ty.clone(), name: *name })
+            },
             lir::Exp::Lit { lit } => {
-                Translate::lit_to_value(&self.context, lit)
+                Ok(Translate::lit_to_value(&self.context, lit))
             }
         }
     }
 
-    fn to_addr(&mut self, e: &lir::Exp) -> llvm::Value {
+    // A literal is translated at its own type, whatever the type of the location it's
+    // stored into, so catch a mismatch here rather than letting LLVM reject the store.
+    fn check_lit(&self, expected: &mir::Type, e: &lir::Exp) -> Result<(), CodegenError> {
+        use crate::mir::typed::*;
+        match e {
+            // Null and void literals are converted to whatever type is needed.
+            lir::Exp::Lit { lit: mir::Lit::Null { .. } } => Ok(()),
+            lir::Exp::Lit { lit: mir::Lit::Void } => Ok(()),
+            lir::Exp::Lit { lit } if lit.get_type() != *expected => {
+                Err(CodegenError::TypeMismatch { expected: expected.clone(), found: lit.get_type() })
+            },
+            _ => Ok(()),
+        }
+    }
+
+    // Zero-extend the i1 v to ty, the type of the temp it's stored to.
+    fn widen_bool(&self, v: llvm::Value, ty: &mir::Type) -> Result<llvm::Value, CodegenError> {
+        match ty {
+            mir::Type::I1 => Ok(v),
+            mir::Type::I8 | mir::Type::I16 | mir::Type::I32 | mir::Type::I64 | mir::Type::I128 => {
+                Ok(self.builder.zext(v, self.to_type(ty), &self.fresh_name()))
+            },
+            _ => Err(CodegenError::TypeMismatch { expected: mir::Type::I1, found: ty.clone() }),
+        }
+    }
+
+    // The bits of float a as a signed integer of type ty, with the bits other than the sign
+    // flipped when a is negative. Signed comparison of these keys is IEEE 754 totalOrder.
+    // sign_shift is the width of ty minus 1.
+    fn total_order_key(&self, a: llvm::Value, ty: llvm::Type, sign_shift: llvm::Value, one: llvm::Value) -> llvm::Value {
+        let bits = self.builder.bitcast(a, ty, &self.fresh_name());
+        let sign = self.builder.ashr(bits, sign_shift, &self.fresh_name());
+        let mask = self.builder.lshr(sign, one, &self.fresh_name());
+        self.builder.xor(bits, mask, &self.fresh_name())
+    }
+
+    fn in_registers(&self, ty: &lir::Type) -> bool {
+        Translate::sizeof(ty) <= self.options.max_register_aggregate_bytes
+    }
+
+    // Allocate size bytes, plus box_header_words words before them, and return a pointer
+    // to the size bytes. If zero is set, the whole allocation, header included, is zeroed.
+    // The array length is written by the caller afterward.
+    fn malloc(&mut self, size: llvm::Value, zero: bool) -> Result<llvm::Value, CodegenError> {
+        let header_bytes = (self.options.box_header_words * Translate::sizeof(&mir::Type::word())) as i64;
+        let total = if header_bytes == 0 { size } else { self.builder.add(size, llvm::Value::i64(header_bytes), &self.fresh_name()) };
+
+        let f = self.to_value(&lir::Exp::FunctionAddr { ty: mir::Type::Void, name: Name::new("malloc") })?;
+        let e = self.builder.call(f, &[total], &self.fresh_name());
+        if zero {
+            let memset = self.module.get_named_function("llvm.memset.p0i8.i64");
+            self.builder.call(memset, &[e, llvm::Value::i8(0), total, llvm::Value::i1(false)], "");
+        }
+
+        if header_bytes == 0 {
+            Ok(e)
+        }
+        else {
+            Ok(self.builder.get_in_bounds_element_pointer(e, &[llvm::Value::i64(header_bytes)], &self.fresh_name()))
+        }
+    }
+
+    fn to_args(&mut self, args: &Vec<lir::Exp>) -> Result<Vec<llvm::Value>, CodegenError> {
+        use crate::mir::typed::*;
+        let mut vs = Vec::new();
+        for a in args {
+            if Translate::passed_in_memory(&a.get_type()) {
+                vs.push(self.to_byval_arg(a)?);
+            }
+            else {
+                vs.push(self.to_value(a)?);
+            }
+        }
+        Ok(vs)
+    }
+
+    fn add_byval_attributes(&self, call: llvm::Value, args: &Vec<lir::Exp>) {
+        use crate::mir::typed::*;
+        for (i, a) in args.iter().enumerate() {
+            if Translate::passed_in_memory(&a.get_type()) {
+                call.add_call_param_attribute(self.context, i, "byval");
+            }
+        }
+    }
+
+    // A pointer to a struct argument passed in memory. byval copies it for the callee,
+    // so a temp's alloca can be passed as is.
+    fn to_byval_arg(&mut self, a: &lir::Exp) -> Result<llvm::Value, CodegenError> {
+        use crate::mir::typed::*;
+        match a {
+            lir::Exp::Temp { name, .. } if self.temps.contains_key(name) => self.to_addr(a),
+            _ => {
+                let v = self.to_value(a)?;
+                let p = self.entry_alloca(self.to_type(&a.get_type()));
+                self.builder.store(v, p);
+                Ok(p)
+            },
+        }
+    }
+
+    // An alloca at the top of the entry block, with the temps' allocas, so LLVM can promote
+    // it and share its stack slot. The builder is left at the end of the current block.
+    fn entry_alloca(&mut self, ty: llvm::Type) -> llvm::Value {
+        let current = self.builder.get_insert_block();
+        let entry = self.fun.basic_blocks()[0];
+        match entry.first_instruction() {
+            Some(insn) => self.builder.position_before(insn),
+            None => self.builder.position_at_end(entry),
+        }
+        let p = self.builder.alloca(ty, &self.fresh_name());
+        self.builder.position_at_end(current);
+        p
+    }
+
+    fn store_temp(&mut self, dst: &lir::Exp, v: llvm::Value) -> Result<llvm::Value, CodegenError> {
+        // A void temp, e.g., the dst of a call to a void function, has nowhere to store to.
+        if let lir::Exp::Temp { ty: mir::Type::Void, .. } = dst {
+            return Ok(v);
+        }
+        let x = self.to_addr(dst)?;
+        if let lir::Exp::Temp { name, ty } = dst {
+            self.stored.insert(*name, v);
+        }
+        Ok(self.builder.store(v, x))
+    }
+
+    fn to_addr(&mut self, e: &lir::Exp) -> Result<llvm::Value, CodegenError> {
         match e {
             lir::Exp::GlobalAddr { name, ty } => {
-                self.module.get_named_global(&name.to_string())
+                match self.symbols.get(name) {
+                    Some(v) => Ok(*v),
+                    None => match self.module.find_global(&name.to_string()) {
+                        Some(v) => Ok(v),
+                        None => Err(CodegenError::UndefinedGlobal { name: *name }),
+                    },
+                }
             },
             lir::Exp::FunctionAddr { name, ty } => {
-                self.module.get_named_function(&name.to_string())
+                // Runtime functions and intrinsics aren't in the symbol table.
+                match self.symbols.get(name) {
+                    Some(v) => Ok(*v),
+                    None => {
+                        let s = name.to_string();
+                        match self.module.find_function(&s) {
+                            Some(v) => Ok(v),
+                            // Intrinsics not declared by add_runtime_functions are declared at the type they're used at.
+                            None if s.starts_with("llvm.") => Ok(self.module.add_function(&s, self.to_type(ty))),
+                            None => Err(CodegenError::UndefinedFunction { name: *name }),
+                        }
+                    },
+                }
             },
             lir::Exp::Temp { name, ty } => {
                 match self.temps.get(&name) {
-                    Some(v) => *v,
-                    None => unreachable!(),  // shouldn't happen since we pre-filled the temps table.
+                    Some(v) => Ok(*v),
+                    // Shouldn't happen since we pre-filled the temps table.
+                    None => Err(CodegenError::MissingTemp { name: *name }),
                 }
             },
             lir::Exp::Lit { lit } => {
@@ -472,6 +1295,12 @@ impl<'a> BodyTranslator<'a> {
         }
     }
 
+    // The error for an op the backend doesn't implement. Each op has its own arm that
+    // returns this, rather than a wildcard, so adding an op forces a decision here.
+    fn unsupported_op<T: std::fmt::Debug>(&self, op: T) -> CodegenError {
+        CodegenError::UnsupportedOp { op: format!("{:?}", op) }
+    }
+
     fn to_bb(&mut self, label: Name) -> llvm::BB {
         match self.labels.get(&label) {
             Some(bb) => bb.clone(),
@@ -484,26 +1313,53 @@ impl<'a> BodyTranslator<'a> {
     }
 
     fn to_type(&self, ty: &lir::Type) -> llvm::Type {
-        Translate::to_type(self.context, ty)
+        self.types.get(self.context, ty)
     }
 
-    fn translate_stm(&mut self, stm: &lir::Stm) {
+    fn translate_stm(&mut self, stm: &lir::Stm) -> Result<(), CodegenError> {
         println!("stm = {:#?}", stm);
 
         let insn = match stm {
-            lir::Stm::CJump { cmp, if_true, if_false } => {
-                let i = self.to_value(cmp);
+            lir::Stm::CJump { cmp, if_true, if_false, hint } => {
+                let i = self.to_value(cmp)?;
                 let t = self.to_bb(*if_true);
                 let e = self.to_bb(*if_false);
-                self.builder.cond_br(i, t, e)
+                let br = self.builder.cond_br(i, t, e);
+
+                // Same weights clang uses for __builtin_expect.
+                let weights = match hint {
+                    Some(BranchHint::Likely) => Some((2000, 1)),
+                    Some(BranchHint::Unlikely) => Some((1, 2000)),
+                    None => None,
+                };
+
+                if let Some((wt, wf)) = weights {
+                    let node = self.context.md_node(&[self.context.md_string("branch_weights"), self.context.md_i32(wt), self.context.md_i32(wf)]);
+                    br.set_metadata("prof", node);
+                }
+
+                br
             },
-            lir::Stm::Jump { label } => {
+            lir::Stm::Jump { label, loop_hints } => {
                 let l = self.to_bb(*label);
-                self.builder.br(l)
+                let br = self.builder.br(l);
+
+                if ! loop_hints.is_empty() {
+                    let hints: Vec<llvm::MetadataNode> = loop_hints.iter().map(|h| self.loop_hint(*h)).collect();
+                    let node = self.context.md_loop_id(&hints);
+                    br.set_metadata("llvm.loop", node);
+                }
+
+                br
             },
             lir::Stm::Ret { exp } => {
                 use crate::mir::typed::*;
-                let v = self.to_value(exp);
+                // Compare the LLVM types, since, e.g., Ref and Ptr types are both just pointers.
+                let ty = exp.get_type();
+                if ty != self.ret_type && self.to_type(&ty) != self.to_type(&self.ret_type) {
+                    return Err(CodegenError::ReturnTypeMismatch { name: self.name, expected: self.ret_type.clone(), found: ty });
+                }
+                let v = self.to_value(exp)?;
                 if exp.get_type() == mir::Type::Void {
                     self.builder.ret_void()
                 }
@@ -512,53 +1368,116 @@ impl<'a> BodyTranslator<'a> {
                 }
             },
             lir::Stm::Store { dst_addr, src } => {
-                let v = self.to_value(src);
-                let p = self.to_value(dst_addr);
+                use crate::mir::typed::*;
+                match dst_addr.get_type() {
+                    mir::Type::Ptr { ty } | mir::Type::Ref { ty } | mir::Type::IRef { ty } => self.check_lit(&ty, src)?,
+                    _ => {},
+                }
+                let v = self.to_value(src)?;
+                let p = self.to_value(dst_addr)?;
                 self.builder.store(v, p)
             },
             lir::Stm::Load { dst, src_addr } => {
-                let p = self.to_value(src_addr);
+                let p = self.to_value(src_addr)?;
                 let v = self.builder.load(p, &self.fresh_name());
-                let x = self.to_addr(dst);
-                self.builder.store(v, x)
+                self.store_temp(dst, v)?
             },
             lir::Stm::Move { dst, src } => {
                 use crate::mir::typed::*;
                 if dst.get_type() == mir::Type::Void {
-                    self.to_value(src)
+                    self.to_value(src)?
+                }
+                else {
+                    self.check_lit(&dst.get_type(), src)?;
+                    let v = self.to_value(src)?;
+                    self.store_temp(dst, v)?
+                }
+            },
+            lir::Stm::Call { dst, fun, args, must_tail } => {
+                use crate::mir::typed::*;
+                // fun is either a FunctionAddr or a Temp holding a function pointer
+                // (e.g., the fun field of a closure). Use to_value, not to_addr, so a
+                // Temp is loaded rather than calling through its alloca.
+                let f = self.to_value(fun)?;
+                let vs = self.to_args(args)?;
+
+                if *must_tail {
+                    // check_must_tail_calls has checked that fun has this proc's signature
+                    // and the call is followed by a Ret of dst, so return v directly rather
+                    // than through dst's alloca, leaving nothing between the call and the ret.
+                    let v = self.builder.musttail_call(f, &vs, &self.fresh_name());
+                    self.add_byval_attributes(v, args);
+                    if dst.get_type() == mir::Type::Void {
+                        self.builder.ret_void()
+                    }
+                    else {
+                        self.builder.ret(v)
+                    }
                 }
                 else {
-                    let x = self.to_addr(dst);
-                    let v = self.to_value(src);
-                    self.builder.store(v, x)
+                    let v = self.builder.call(f, &vs, &self.fresh_name());
+                    self.add_byval_attributes(v, args);
+                    self.store_temp(dst, v)?
                 }
             },
-            lir::Stm::Call { dst, fun, args } => {
-                let f = self.to_value(fun);
-                let vs: Vec<llvm::Value> = args.iter().map(|a| self.to_value(a)).collect();
-                let v = self.builder.call(f, &vs, &self.fresh_name());
-                let x = self.to_addr(dst);
-                self.builder.store(v, x)
+            lir::Stm::Invoke { dst, fun, args, normal, unwind } => {
+                let f = self.to_value(fun)?;
+                let vs = self.to_args(args)?;
+
+                // The result is only available on the normal edge, so store it in a block of
+                // its own there rather than in normal, which may have other predecessors.
+                let cont = self.context.append_bb(self.fun.clone(), &self.fresh_name());
+                let u = self.to_bb(*unwind);
+                let v = self.builder.invoke(f, &vs, cont, u, &self.fresh_name());
+                self.add_byval_attributes(v, args);
+
+                self.builder.position_at_end(cont);
+                self.stored.clear();
+                self.store_temp(dst, v)?;
+
+                let n = self.to_bb(*normal);
+                self.builder.br(n)
+            },
+            lir::Stm::LandingPad { dst, cleanup, catches } => {
+                use crate::mir::typed::*;
+                let ty = self.to_type(&dst.get_type());
+                let v = self.builder.landing_pad(ty, catches.len(), &self.fresh_name());
+                v.set_cleanup(*cleanup);
+                for c in catches {
+                    let c = self.to_value(c)?;
+                    v.add_clause(c);
+                }
+                self.store_temp(dst, v)?
             },
             lir::Stm::Binary { dst, op, e1, e2 } => {
-                let a1 = self.to_value(e1);
-                let a2 = self.to_value(e2);
+                let a1 = self.to_value(e1)?;
+                let a2 = self.to_value(e2)?;
                 let v = match op {
                     Bop::Add_i32 => self.builder.add(a1, a2, &self.fresh_name()),
                     Bop::Add_i64 => self.builder.add(a1, a2, &self.fresh_name()),
+                    Bop::Add_i128 => self.builder.add(a1, a2, &self.fresh_name()),
                     Bop::Add_f32 => self.builder.fadd(a1, a2, &self.fresh_name()),
                     Bop::Add_f64 => self.builder.fadd(a1, a2, &self.fresh_name()),
 
                     Bop::Sub_i32 => self.builder.sub(a1, a2, &self.fresh_name()),
                     Bop::Sub_i64 => self.builder.sub(a1, a2, &self.fresh_name()),
+                    Bop::Sub_i128 => self.builder.sub(a1, a2, &self.fresh_name()),
                     Bop::Sub_f32 => self.builder.fsub(a1, a2, &self.fresh_name()),
                     Bop::Sub_f64 => self.builder.fsub(a1, a2, &self.fresh_name()),
 
                     Bop::Mul_i32 => self.builder.mul(a1, a2, &self.fresh_name()),
                     Bop::Mul_i64 => self.builder.mul(a1, a2, &self.fresh_name()),
+                    Bop::Mul_i128 => self.builder.mul(a1, a2, &self.fresh_name()),
                     Bop::Mul_f32 => self.builder.fmul(a1, a2, &self.fresh_name()),
                     Bop::Mul_f64 => self.builder.fmul(a1, a2, &self.fresh_name()),
 
+                    Bop::Add_nsw_i32 => self.builder.nsw_add(a1, a2, &self.fresh_name()),
+                    Bop::Add_nsw_i64 => self.builder.nsw_add(a1, a2, &self.fresh_name()),
+                    Bop::Sub_nsw_i32 => self.builder.nsw_sub(a1, a2, &self.fresh_name()),
+                    Bop::Sub_nsw_i64 => self.builder.nsw_sub(a1, a2, &self.fresh_name()),
+                    Bop::Mul_nsw_i32 => self.builder.nsw_mul(a1, a2, &self.fresh_name()),
+                    Bop::Mul_nsw_i64 => self.builder.nsw_mul(a1, a2, &self.fresh_name()),
+
                     Bop::Div_s_i32 => self.builder.sdiv(a1, a2, &self.fresh_name()),
                     Bop::Div_s_i64 => self.builder.sdiv(a1, a2, &self.fresh_name()),
                     Bop::Div_u_i32 => self.builder.udiv(a1, a2, &self.fresh_name()),
@@ -575,33 +1494,61 @@ impl<'a> BodyTranslator<'a> {
 
                     Bop::And_i32 => self.builder.and(a1, a2, &self.fresh_name()),
                     Bop::And_i64 => self.builder.and(a1, a2, &self.fresh_name()),
+                    Bop::And_i128 => self.builder.and(a1, a2, &self.fresh_name()),
 
                     Bop::Or_i32 => self.builder.or(a1, a2, &self.fresh_name()),
                     Bop::Or_i64 => self.builder.or(a1, a2, &self.fresh_name()),
+                    Bop::Or_i128 => self.builder.or(a1, a2, &self.fresh_name()),
 
                     Bop::Xor_i32 => self.builder.xor(a1, a2, &self.fresh_name()),
                     Bop::Xor_i64 => self.builder.xor(a1, a2, &self.fresh_name()),
+                    Bop::Xor_i128 => self.builder.xor(a1, a2, &self.fresh_name()),
 
                     Bop::Shl_i32 => self.builder.shl(a1, a2, &self.fresh_name()),
                     Bop::Shl_i64 => self.builder.shl(a1, a2, &self.fresh_name()),
+                    Bop::Shl_i128 => self.builder.shl(a1, a2, &self.fresh_name()),
 
                     Bop::Shr_u_i32 => self.builder.lshr(a1, a2, &self.fresh_name()),
                     Bop::Shr_u_i64 => self.builder.lshr(a1, a2, &self.fresh_name()),
+                    Bop::Shr_u_i128 => self.builder.lshr(a1, a2, &self.fresh_name()),
 
                     Bop::Shr_i32 => self.builder.ashr(a1, a2, &self.fresh_name()),
                     Bop::Shr_i64 => self.builder.ashr(a1, a2, &self.fresh_name()),
+                    Bop::Shr_i128 => self.builder.ashr(a1, a2, &self.fresh_name()),
 
                     Bop::Eq_ptr => self.builder.icmp(llvm::IntPredicate::EQ, a1, a2, &self.fresh_name()),
                     Bop::Eq_z => self.builder.icmp(llvm::IntPredicate::EQ, a1, a2, &self.fresh_name()),
                     Bop::Eq_i32 => self.builder.icmp(llvm::IntPredicate::EQ, a1, a2, &self.fresh_name()),
                     Bop::Eq_i64 => self.builder.icmp(llvm::IntPredicate::EQ, a1, a2, &self.fresh_name()),
+                    Bop::Eq_i128 => self.builder.icmp(llvm::IntPredicate::EQ, a1, a2, &self.fresh_name()),
                     Bop::Eq_f32 => self.builder.fcmp(llvm::RealPredicate::OrderedEQ, a1, a2, &self.fresh_name()),
                     Bop::Eq_f64 => self.builder.fcmp(llvm::RealPredicate::OrderedEQ, a1, a2, &self.fresh_name()),
+                    Bop::BitEq_f32 => {
+                        let b1 = self.builder.bitcast(a1, self.context.i32_type(), &self.fresh_name());
+                        let b2 = self.builder.bitcast(a2, self.context.i32_type(), &self.fresh_name());
+                        self.builder.icmp(llvm::IntPredicate::EQ, b1, b2, &self.fresh_name())
+                    },
+                    Bop::BitEq_f64 => {
+                        let b1 = self.builder.bitcast(a1, self.context.i64_type(), &self.fresh_name());
+                        let b2 = self.builder.bitcast(a2, self.context.i64_type(), &self.fresh_name());
+                        self.builder.icmp(llvm::IntPredicate::EQ, b1, b2, &self.fresh_name())
+                    },
+                    Bop::TotalLt_f32 => {
+                        let k1 = self.total_order_key(a1, self.context.i32_type(), llvm::Value::i32(31), llvm::Value::i32(1));
+                        let k2 = self.total_order_key(a2, self.context.i32_type(), llvm::Value::i32(31), llvm::Value::i32(1));
+                        self.builder.icmp(llvm::IntPredicate::SignedLT, k1, k2, &self.fresh_name())
+                    },
+                    Bop::TotalLt_f64 => {
+                        let k1 = self.total_order_key(a1, self.context.i64_type(), llvm::Value::i64(63), llvm::Value::i64(1));
+                        let k2 = self.total_order_key(a2, self.context.i64_type(), llvm::Value::i64(63), llvm::Value::i64(1));
+                        self.builder.icmp(llvm::IntPredicate::SignedLT, k1, k2, &self.fresh_name())
+                    },
 
                     Bop::Ne_ptr => self.builder.icmp(llvm::IntPredicate::NE, a1, a2, &self.fresh_name()),
                     Bop::Ne_z => self.builder.icmp(llvm::IntPredicate::NE, a1, a2, &self.fresh_name()),
                     Bop::Ne_i32 => self.builder.icmp(llvm::IntPredicate::NE, a1, a2, &self.fresh_name()),
                     Bop::Ne_i64 => self.builder.icmp(llvm::IntPredicate::NE, a1, a2, &self.fresh_name()),
+                    Bop::Ne_i128 => self.builder.icmp(llvm::IntPredicate::NE, a1, a2, &self.fresh_name()),
                     Bop::Ne_f32 => self.builder.fcmp(llvm::RealPredicate::OrderedNE, a1, a2, &self.fresh_name()),
                     Bop::Ne_f64 => self.builder.fcmp(llvm::RealPredicate::OrderedNE, a1, a2, &self.fresh_name()),
 
@@ -638,6 +1585,15 @@ impl<'a> BodyTranslator<'a> {
                     Bop::Max_f32 => intrinsic!(self, "llvm.maximum.f32", a1, a2, (mir::Type::F32, mir::Type::F32) -> mir::Type::F32),
                     Bop::Max_f64 => intrinsic!(self, "llvm.maximum.f64", a1, a2, (mir::Type::F64, mir::Type::F64) -> mir::Type::F64),
 
+                    Bop::Min_s_i32 => intrinsic!(self, "llvm.smin.i32", a1, a2, (mir::Type::I32, mir::Type::I32) -> mir::Type::I32),
+                    Bop::Min_s_i64 => intrinsic!(self, "llvm.smin.i64", a1, a2, (mir::Type::I64, mir::Type::I64) -> mir::Type::I64),
+                    Bop::Min_u_i32 => intrinsic!(self, "llvm.umin.i32", a1, a2, (mir::Type::I32, mir::Type::I32) -> mir::Type::I32),
+                    Bop::Min_u_i64 => intrinsic!(self, "llvm.umin.i64", a1, a2, (mir::Type::I64, mir::Type::I64) -> mir::Type::I64),
+                    Bop::Max_s_i32 => intrinsic!(self, "llvm.smax.i32", a1, a2, (mir::Type::I32, mir::Type::I32) -> mir::Type::I32),
+                    Bop::Max_s_i64 => intrinsic!(self, "llvm.smax.i64", a1, a2, (mir::Type::I64, mir::Type::I64) -> mir::Type::I64),
+                    Bop::Max_u_i32 => intrinsic!(self, "llvm.umax.i32", a1, a2, (mir::Type::I32, mir::Type::I32) -> mir::Type::I32),
+                    Bop::Max_u_i64 => intrinsic!(self, "llvm.umax.i64", a1, a2, (mir::Type::I64, mir::Type::I64) -> mir::Type::I64),
+
                     Bop::Copysign_f32 => intrinsic!(self, "llvm.copysign.f32", a1, a2, (mir::Type::F32, mir::Type::F32) -> mir::Type::F32),
                     Bop::Copysign_f64 => intrinsic!(self, "llvm.copysign.f64", a1, a2, (mir::Type::F64, mir::Type::F64) -> mir::Type::F64),
 
@@ -653,32 +1609,57 @@ impl<'a> BodyTranslator<'a> {
                     Bop::Rotr_i32 => intrinsic!(self, "llvm.fshr.i32", a1, a1, a2, (mir::Type::I32, mir::Type::I32, mir::Type::I32) -> mir::Type::I32),
                     Bop::Rotr_i64 => intrinsic!(self, "llvm.fshr.i64", a1, a1, a2, (mir::Type::I64, mir::Type::I64, mir::Type::I64) -> mir::Type::I64),
 
-                    Bop::Atan2_f32 => unimplemented!(),
-                    Bop::Atan2_f64 => unimplemented!(),
+                    Bop::Atan2_f32 => return Err(self.unsupported_op(op)),
+                    Bop::Atan2_f64 => return Err(self.unsupported_op(op)),
+                };
+
+                // Comparisons give an i1, but a frontend that represents booleans as ints
+                // may put the result in a wider temp.
+                let v = {
+                    use crate::mir::typed::*;
+                    if op.get_type() == mir::Type::I1 {
+                        self.widen_bool(v, &dst.get_type())?
+                    }
+                    else {
+                        v
+                    }
                 };
 
-                let x = self.to_addr(dst);
-                self.builder.store(v, x)
+                self.store_temp(dst, v)?
             },
             lir::Stm::Unary { dst, op, exp } => {
-                let e = self.to_value(exp);
+                let e = self.to_value(exp)?;
                 let v = match op {
                     Uop::Not_z => self.builder.not(e, &self.fresh_name()),
+                    Uop::Expect_z { expected } => intrinsic!(self, "llvm.expect.i1", e, llvm::Value::i1(*expected), (mir::Type::I1, mir::Type::I1) -> mir::Type::I1),
                     Uop::Neg_f32 => self.builder.fneg(e, &self.fresh_name()),
                     Uop::Neg_f64 => self.builder.fneg(e, &self.fresh_name()),
 
                     Uop::Ctz_i32 => intrinsic!(self, "llvm.cttz.i32", e, (mir::Type::I32) -> mir::Type::I32),
                     Uop::Clz_i32 => intrinsic!(self, "llvm.ctlz.i32", e, (mir::Type::I32) -> mir::Type::I32),
                     Uop::Popcount_i32 => intrinsic!(self, "llvm.ctpop.i32", e, (mir::Type::I32) -> mir::Type::I32),
-                    Uop::Eqz_i32 => self.builder.icmp(llvm::IntPredicate::EQ, e, llvm::Value::i32(0), &self.fresh_name()),
+                    Uop::Eqz_i32 => {
+                        // icmp gives an i1, but Eqz evaluates to the operand type.
+                        let b = self.builder.icmp(llvm::IntPredicate::EQ, e, llvm::Value::i32(0), &self.fresh_name());
+                        self.builder.zext(b, self.context.i32_type(), &self.fresh_name())
+                    },
                     Uop::Complement_i32 => self.builder.xor(e, llvm::Value::i32(-1), &self.fresh_name()),
 
                     Uop::Ctz_i64 => intrinsic!(self, "llvm.cttz.i64", e, (mir::Type::I64) -> mir::Type::I64),
                     Uop::Clz_i64 => intrinsic!(self, "llvm.ctlz.i64", e, (mir::Type::I64) -> mir::Type::I64),
                     Uop::Popcount_i64 => intrinsic!(self, "llvm.ctpop.i64", e, (mir::Type::I64) -> mir::Type::I64),
-                    Uop::Eqz_i64 => self.builder.icmp(llvm::IntPredicate::EQ, e, llvm::Value::i64(0), &self.fresh_name()),
+                    Uop::Eqz_i64 => {
+                        let b = self.builder.icmp(llvm::IntPredicate::EQ, e, llvm::Value::i64(0), &self.fresh_name());
+                        self.builder.zext(b, self.context.i64_type(), &self.fresh_name())
+                    },
                     Uop::Complement_i64 => self.builder.xor(e, llvm::Value::i64(-1), &self.fresh_name()),
 
+                    Uop::Complement_word => match mir::Type::word() {
+                        mir::Type::I32 => self.builder.xor(e, llvm::Value::i32(-1), &self.fresh_name()),
+                        mir::Type::I64 => self.builder.xor(e, llvm::Value::i64(-1), &self.fresh_name()),
+                        _ => unreachable!(),
+                    },
+
                     Uop::Abs_f32 => intrinsic!(self, "llvm.fabs.f32", e, (mir::Type::F32) -> mir::Type::F32),
 
                     Uop::Ceil_f32 => intrinsic!(self, "llvm.ceil.f32", e, (mir::Type::F32) -> mir::Type::F32),
@@ -690,25 +1671,25 @@ impl<'a> BodyTranslator<'a> {
                     Uop::Log_f32 => intrinsic!(self, "llvm.log.f32", e, (mir::Type::F32) -> mir::Type::F32),
                     Uop::Sqrt_f32 => intrinsic!(self, "llvm.sqrt.f32", e, (mir::Type::F32) -> mir::Type::F32),
                     Uop::Pow_f32 => intrinsic!(self, "llvm.pow.f32", e, (mir::Type::F32) -> mir::Type::F32),
-                    Uop::Logb_f32 => unimplemented!(),
+                    Uop::Logb_f32 => return Err(self.unsupported_op(op)),
                     Uop::Sin_f32 => intrinsic!(self, "llvm.sin.f32", e, (mir::Type::F32) -> mir::Type::F32),
                     Uop::Cos_f32 => intrinsic!(self, "llvm.cos.f32", e, (mir::Type::F32) -> mir::Type::F32),
                     Uop::Tan_f32 => intrinsic!(self, "llvm.tan.f32", e, (mir::Type::F32) -> mir::Type::F32),
-                    Uop::Asin_f32 => unimplemented!(),
-                    Uop::Acos_f32 => unimplemented!(),
-                    Uop::Atan_f32 => unimplemented!(),
-                    Uop::Sinh_f32 => unimplemented!(),
-                    Uop::Cosh_f32 => unimplemented!(),
-                    Uop::Tanh_f32 => unimplemented!(),
-                    Uop::Asinh_f32 => unimplemented!(),
-                    Uop::Acosh_f32 => unimplemented!(),
-                    Uop::Atanh_f32 => unimplemented!(),
-
-                    Uop::IsNan_f32 => unimplemented!(),
-                    Uop::IsInf_f32 => unimplemented!(),
-                    Uop::IsDenormalized_f32 => unimplemented!(),
-                    Uop::IsNegativeZero_f32 => unimplemented!(),
-                    Uop::IsIEEE_f32 => unimplemented!(),
+                    Uop::Asin_f32 => return Err(self.unsupported_op(op)),
+                    Uop::Acos_f32 => return Err(self.unsupported_op(op)),
+                    Uop::Atan_f32 => return Err(self.unsupported_op(op)),
+                    Uop::Sinh_f32 => return Err(self.unsupported_op(op)),
+                    Uop::Cosh_f32 => return Err(self.unsupported_op(op)),
+                    Uop::Tanh_f32 => return Err(self.unsupported_op(op)),
+                    Uop::Asinh_f32 => return Err(self.unsupported_op(op)),
+                    Uop::Acosh_f32 => return Err(self.unsupported_op(op)),
+                    Uop::Atanh_f32 => return Err(self.unsupported_op(op)),
+
+                    Uop::IsNan_f32 => return Err(self.unsupported_op(op)),
+                    Uop::IsInf_f32 => return Err(self.unsupported_op(op)),
+                    Uop::IsDenormalized_f32 => return Err(self.unsupported_op(op)),
+                    Uop::IsNegativeZero_f32 => return Err(self.unsupported_op(op)),
+                    Uop::IsIEEE_f32 => return Err(self.unsupported_op(op)),
 
                     Uop::Abs_f64 => intrinsic!(self, "llvm.fabs.f64", e, (mir::Type::F64) -> mir::Type::F64),
 
@@ -721,25 +1702,25 @@ impl<'a> BodyTranslator<'a> {
                     Uop::Log_f64 => intrinsic!(self, "llvm.log.f64", e, (mir::Type::F64) -> mir::Type::F64),
                     Uop::Sqrt_f64 => intrinsic!(self, "llvm.sqrt.f64", e, (mir::Type::F64) -> mir::Type::F64),
                     Uop::Pow_f64 => intrinsic!(self, "llvm.pos.f64", e, (mir::Type::F64) -> mir::Type::F64),
-                    Uop::Logb_f64 => unimplemented!(),
+                    Uop::Logb_f64 => return Err(self.unsupported_op(op)),
                     Uop::Sin_f64 => intrinsic!(self, "llvm.sin.f64", e, (mir::Type::F64) -> mir::Type::F64),
                     Uop::Cos_f64 => intrinsic!(self, "llvm.cos.f64", e, (mir::Type::F64) -> mir::Type::F64),
                     Uop::Tan_f64 => intrinsic!(self, "llvm.tan.f64", e, (mir::Type::F64) -> mir::Type::F64),
-                    Uop::Asin_f64 => unimplemented!(),
-                    Uop::Acos_f64 => unimplemented!(),
-                    Uop::Atan_f64 => unimplemented!(),
-                    Uop::Sinh_f64 => unimplemented!(),
-                    Uop::Cosh_f64 => unimplemented!(),
-                    Uop::Tanh_f64 => unimplemented!(),
-                    Uop::Asinh_f64 => unimplemented!(),
-                    Uop::Acosh_f64 => unimplemented!(),
-                    Uop::Atanh_f64 => unimplemented!(),
-
-                    Uop::IsNan_f64 => unimplemented!(),
-                    Uop::IsInf_f64 => unimplemented!(),
-                    Uop::IsDenormalized_f64 => unimplemented!(),
-                    Uop::IsNegativeZero_f64 => unimplemented!(),
-                    Uop::IsIEEE_f64 => unimplemented!(),
+                    Uop::Asin_f64 => return Err(self.unsupported_op(op)),
+                    Uop::Acos_f64 => return Err(self.unsupported_op(op)),
+                    Uop::Atan_f64 => return Err(self.unsupported_op(op)),
+                    Uop::Sinh_f64 => return Err(self.unsupported_op(op)),
+                    Uop::Cosh_f64 => return Err(self.unsupported_op(op)),
+                    Uop::Tanh_f64 => return Err(self.unsupported_op(op)),
+                    Uop::Asinh_f64 => return Err(self.unsupported_op(op)),
+                    Uop::Acosh_f64 => return Err(self.unsupported_op(op)),
+                    Uop::Atanh_f64 => return Err(self.unsupported_op(op)),
+
+                    Uop::IsNan_f64 => return Err(self.unsupported_op(op)),
+                    Uop::IsInf_f64 => return Err(self.unsupported_op(op)),
+                    Uop::IsDenormalized_f64 => return Err(self.unsupported_op(op)),
+                    Uop::IsNegativeZero_f64 => return Err(self.unsupported_op(op)),
+                    Uop::IsIEEE_f64 => return Err(self.unsupported_op(op)),
 
                     Uop::Wrap_i64_i32 => self.builder.trunc(e, self.to_type(&mir::Type::I32), &self.fresh_name()),
 
@@ -755,6 +1736,18 @@ impl<'a> BodyTranslator<'a> {
                     Uop::Extend_s_i32_i64 => self.builder.sext(e, self.to_type(&mir::Type::I64), &self.fresh_name()),
                     Uop::Extend_u_i32_i64 => self.builder.zext(e, self.to_type(&mir::Type::I64), &self.fresh_name()),
 
+                    Uop::Wrap_i32_i8 => self.builder.trunc(e, self.to_type(&mir::Type::I8), &self.fresh_name()),
+                    Uop::Wrap_i32_i16 => self.builder.trunc(e, self.to_type(&mir::Type::I16), &self.fresh_name()),
+                    Uop::Extend_s_i8_i32 => self.builder.sext(e, self.to_type(&mir::Type::I32), &self.fresh_name()),
+                    Uop::Extend_u_i8_i32 => self.builder.zext(e, self.to_type(&mir::Type::I32), &self.fresh_name()),
+                    Uop::Extend_s_i16_i32 => self.builder.sext(e, self.to_type(&mir::Type::I32), &self.fresh_name()),
+                    Uop::Extend_u_i16_i32 => self.builder.zext(e, self.to_type(&mir::Type::I32), &self.fresh_name()),
+
+                    Uop::Nez_i32_z => self.builder.icmp(llvm::IntPredicate::NE, e, llvm::Value::i32(0), &self.fresh_name()),
+                    Uop::Extend_u_z_i32 => self.builder.zext(e, self.to_type(&mir::Type::I32), &self.fresh_name()),
+                    Uop::Nez_i64_z => self.builder.icmp(llvm::IntPredicate::NE, e, llvm::Value::i64(0), &self.fresh_name()),
+                    Uop::Extend_u_z_i64 => self.builder.zext(e, self.to_type(&mir::Type::I64), &self.fresh_name()),
+
                     Uop::Reinterpret_f32_i32 => self.builder.bitcast(e, self.to_type(&mir::Type::I32), &self.fresh_name()),
                     Uop::Reinterpret_f64_i64 => self.builder.bitcast(e, self.to_type(&mir::Type::I64), &self.fresh_name()),
                     Uop::Reinterpret_i32_f32 => self.builder.bitcast(e, self.to_type(&mir::Type::F32), &self.fresh_name()),
@@ -770,64 +1763,149 @@ impl<'a> BodyTranslator<'a> {
                     Uop::Convert_u_i64_f64 => self.builder.ui_to_fp(e, self.to_type(&mir::Type::F64), &self.fresh_name()),
 
                     Uop::Demote_f64_f32 => self.builder.fptrunc(e, self.to_type(&mir::Type::F32), &self.fresh_name()),
-                    Uop::Promote_f32_f64 => self.builder.fpext(e, self.to_type(&mir::Type::F32), &self.fresh_name()),
+                    Uop::Promote_f32_f64 => self.builder.fpext(e, self.to_type(&mir::Type::F64), &self.fresh_name()),
                 };
 
-                let x = self.to_addr(dst);
-                self.builder.store(v, x)
+                self.store_temp(dst, v)?
+            },
+            lir::Stm::Fma { dst, ty, a, b, c } => {
+                let a1 = self.to_value(a)?;
+                let a2 = self.to_value(b)?;
+                let a3 = self.to_value(c)?;
+                let v = match ty {
+                    mir::Type::F32 => intrinsic!(self, "llvm.fma.f32", a1, a2, a3, (mir::Type::F32, mir::Type::F32, mir::Type::F32) -> mir::Type::F32),
+                    mir::Type::F64 => intrinsic!(self, "llvm.fma.f64", a1, a2, a3, (mir::Type::F64, mir::Type::F64, mir::Type::F64) -> mir::Type::F64),
+                    _ => return Err(CodegenError::UnsupportedOp { op: format!("fma {:?}", ty) }),
+                };
+                self.store_temp(dst, v)?
+            },
+            lir::Stm::Select { dst, cond, if_true, if_false } => {
+                let c = self.to_value(cond)?;
+                let v1 = self.to_value(if_true)?;
+                let v2 = self.to_value(if_false)?;
+                let v = self.builder.select(c, v1, v2, &self.fresh_name());
+                self.store_temp(dst, v)?
+            },
+            lir::Stm::Assume { cond } => {
+                use crate::mir::typed::*;
+                if cond.get_type() != mir::Type::I1 {
+                    return Err(CodegenError::TypeMismatch { expected: mir::Type::I1, found: cond.get_type() });
+                }
+                let c = self.to_value(cond)?;
+                let assume = self.module.get_named_function("llvm.assume");
+                self.builder.call(assume, &[c], "")
             },
             lir::Stm::Cast { dst, ty, exp } => {
                 let t = self.to_type(ty);
-                let e = self.to_value(exp);
+                let e = self.to_value(exp)?;
                 let v = self.builder.bitcast(e, t, &self.fresh_name());
-                let x = self.to_addr(dst);
-                self.builder.store(v, x)
+                self.store_temp(dst, v)?
             },
             lir::Stm::New { dst, ty } => {
-                let t = self.to_type(ty);
-                let (size, _) = Translate::sizeof_exp(ty);
-                let f = self.to_value(&lir::Exp::FunctionAddr { ty: ty.clone(), name: Name::new("malloc") });
-                let vs: Vec<llvm::Value> = vec![self.to_value(&size)];
-                let e = self.builder.call(f, &vs, &self.fresh_name());
-                let v = self.builder.bitcast(e, t, &self.fresh_name());
-                let x = self.to_addr(dst);
-                self.builder.store(v, x)
+                use crate::mir::typed::*;
+                // dst is a Ref to ty.
+                let t = self.to_type(&dst.get_type());
+                if Translate::sizeof(ty) == 0 && self.options.box_header_words == 0 {
+                    // Nothing to allocate for a zero-sized type, e.g., an empty closure environment.
+                    // The object is never loaded from or stored to, so any non-null pointer will do.
+                    let v = llvm::Value::i64(Translate::sizeof(&mir::Type::word()) as i64).const_int_to_ptr(t);
+                    self.store_temp(dst, v)?
+                }
+                else {
+                    let (size, _) = Translate::sizeof_exp(ty);
+                    let size = self.to_value(&size)?;
+                    let e = self.malloc(size, false)?;
+                    let v = self.builder.bitcast(e, t, &self.fresh_name());
+                    self.store_temp(dst, v)?
+                }
             },
-            lir::Stm::NewHybrid { dst, ty, length } => {
+            lir::Stm::NewHybrid { dst, ty, length, zero } => {
                 let t = self.to_type(ty);
                 let (fixed_size, variant_size) = Translate::sizeof_exp(ty);
-                let e = self.to_value(length);
-                let variant = self.builder.mul(self.to_value(&variant_size), self.to_value(length), &self.fresh_name());
-                let size = self.builder.add(self.to_value(&fixed_size), variant, &self.fresh_name());
-                let f = self.to_value(&lir::Exp::FunctionAddr { ty: ty.clone(), name: Name::new("malloc") });
-                let vs: Vec<llvm::Value> = vec![size];
-                let e = self.builder.call(f, &vs, &self.fresh_name());
+                let e = self.to_value(length)?;
+                let variant = self.builder.mul(self.to_value(&variant_size)?, self.to_value(length)?, &self.fresh_name());
+                let size = self.builder.add(self.to_value(&fixed_size)?, variant, &self.fresh_name());
+                let e = self.malloc(size, *zero)?;
                 let v = self.builder.bitcast(e, t, &self.fresh_name());
-                let x = self.to_addr(dst);
-                self.builder.store(v, x)
+                self.store_temp(dst, v)?
+            },
+            lir::Stm::BuildStruct { dst, ty, fields } => {
+                if self.in_registers(ty) {
+                    let mut v = llvm::Value::undef(self.to_type(ty));
+                    for (i, f) in fields.iter().enumerate() {
+                        let fv = self.to_value(f)?;
+                        v = self.builder.insert_value(v, fv, i, &self.fresh_name());
+                    }
+                    self.store_temp(dst, v)?
+                }
+                else {
+                    // Store each field straight into dst's alloca.
+                    let p = self.to_addr(dst)?;
+                    if let lir::Exp::Temp { name, ty } = dst {
+                        self.stored.remove(name);
+                    }
+                    let mut insn = p;
+                    for (i, f) in fields.iter().enumerate() {
+                        let fv = self.to_value(f)?;
+                        let fp = self.builder.get_struct_element_pointer(p, i, &self.fresh_name());
+                        insn = self.builder.store(fv, fp);
+                    }
+                    insn
+                }
+            },
+            lir::Stm::ExtractStructElement { dst, agg, field } => {
+                use crate::mir::typed::*;
+                match agg {
+                    lir::Exp::Temp { name, ty } if self.temps.contains_key(name) && ! self.in_registers(ty) => {
+                        // Load just the field from agg's alloca.
+                        let p = self.to_addr(agg)?;
+                        let fp = self.builder.get_struct_element_pointer(p, *field, &self.fresh_name());
+                        let v = self.builder.load(fp, &self.fresh_name());
+                        self.store_temp(dst, v)?
+                    },
+                    _ => {
+                        let a = self.to_value(agg)?;
+                        let v = self.builder.extract_value(a, *field, &self.fresh_name());
+                        self.store_temp(dst, v)?
+                    },
+                }
             },
             lir::Stm::GetStructElementAddr { dst, struct_ty, ptr, field } => {
-                let p = self.to_value(ptr);
+                let p = self.to_value(ptr)?;
                 // let base = llvm::Value::i32(0); // struct fields are i32
                 // let i = llvm::Value::i32(*field as i32);
                 // let v = self.builder.get_in_bounds_element_pointer(p, &[base, i], &self.fresh_name());
                 let v = self.builder.get_struct_element_pointer(p, *field, &self.fresh_name());
-                let x = self.to_addr(dst);
-                self.builder.store(v, x)
+                self.store_temp(dst, v)?
             },
             lir::Stm::GetArrayElementAddr { dst, base_ty, ptr, index } => {
-                let a = self.to_value(ptr);
-                let i = self.to_value(index);
+                use crate::mir::typed::*;
+                let a = self.to_value(ptr)?;
+                let i = self.to_value(index)?;
                     // Get the pointer to the base of the data.
                     // let b = self.builder.get_struct_element_pointer(a, 1, &self.fresh_name());
                     // Get the pointer to the array element.
                     // let v = self.builder.get_in_bounds_element_pointer(b, &[i], &self.fresh_name());
                 // Get the pointer to the array element, indexing by 1 to get the base of the array
-                // and then by i to get the element.
+                // and then by i to get the element. A pointer straight to the elements is just indexed by i.
                 let base = llvm::Value::i32(1); // struct fields are i32
-                let v = self.builder.get_in_bounds_element_pointer(a, &[base, i], &self.fresh_name());
-                let x = self.to_addr(dst);
-                self.builder.store(v, x)
+                let v = match ptr.get_type() {
+                    mir::Type::Ptr { ty } if *ty == *base_ty => self.builder.get_in_bounds_element_pointer(a, &[i], &self.fresh_name()),
+                    _ => self.builder.get_in_bounds_element_pointer(a, &[base, i], &self.fresh_name()),
+                };
+                self.store_temp(dst, v)?
+            },
+            lir::Stm::Alloca { dst, ty, count } => {
+                use crate::mir::typed::*;
+                let ptr_ty = mir::Type::Ptr { ty: Box::new(ty.clone()) };
+                if dst.get_type() != ptr_ty {
+                    return Err(CodegenError::TypeMismatch { expected: ptr_ty, found: dst.get_type() });
+                }
+                // If count is constant, hoist_static_allocas moves this to the entry block.
+                let t = self.to_type(ty);
+                let n = self.to_value(count)?;
+                let v = self.builder.array_alloca(t, n, &self.fresh_name());
+                self.store_temp(dst, v)?
             },
 
             // These should be handled by the caller.
@@ -838,6 +1916,8 @@ impl<'a> BodyTranslator<'a> {
                 unreachable!()
             },
         };
+
+        Ok(())
     }
 }
 
@@ -856,10 +1936,10 @@ impl TempFinder {
     fn add_temps_for_stm(s: &lir::Stm, temps: &mut HashSet<(Name, mir::Type)>) {
         match s {
             lir::Stm::Nop => {},
-            lir::Stm::CJump { cmp, if_true, if_false } => {
+            lir::Stm::CJump { cmp, if_true, if_false, hint } => {
                 TempFinder::add_temps_for_exp(cmp, temps);
             },
-            lir::Stm::Jump { label } => {},
+            lir::Stm::Jump { label, loop_hints } => {},
             lir::Stm::Ret { exp } => {
                 TempFinder::add_temps_for_exp(exp, temps);
             },
@@ -875,13 +1955,26 @@ impl TempFinder {
                 TempFinder::add_temps_for_exp(dst, temps);
                 TempFinder::add_temps_for_exp(src, temps);
             },
-            lir::Stm::Call { dst, fun, args } => {
+            lir::Stm::Call { dst, fun, args, .. } => {
+                TempFinder::add_temps_for_exp(dst, temps);
+                TempFinder::add_temps_for_exp(fun, temps);
+                for arg in args {
+                    TempFinder::add_temps_for_exp(arg, temps);
+                }
+            },
+            lir::Stm::Invoke { dst, fun, args, normal, unwind } => {
                 TempFinder::add_temps_for_exp(dst, temps);
                 TempFinder::add_temps_for_exp(fun, temps);
                 for arg in args {
                     TempFinder::add_temps_for_exp(arg, temps);
                 }
             },
+            lir::Stm::LandingPad { dst, cleanup, catches } => {
+                TempFinder::add_temps_for_exp(dst, temps);
+                for c in catches {
+                    TempFinder::add_temps_for_exp(c, temps);
+                }
+            },
             lir::Stm::Binary { dst, op, e1, e2 } => {
                 TempFinder::add_temps_for_exp(dst, temps);
                 TempFinder::add_temps_for_exp(e1, temps);
@@ -891,18 +1984,47 @@ impl TempFinder {
                 TempFinder::add_temps_for_exp(dst, temps);
                 TempFinder::add_temps_for_exp(exp, temps);
             },
+            lir::Stm::Fma { dst, ty, a, b, c } => {
+                TempFinder::add_temps_for_exp(dst, temps);
+                TempFinder::add_temps_for_exp(a, temps);
+                TempFinder::add_temps_for_exp(b, temps);
+                TempFinder::add_temps_for_exp(c, temps);
+            },
             lir::Stm::Cast { dst, ty, exp } => {
                 TempFinder::add_temps_for_exp(dst, temps);
                 TempFinder::add_temps_for_exp(exp, temps);
             },
+            lir::Stm::Select { dst, cond, if_true, if_false } => {
+                TempFinder::add_temps_for_exp(dst, temps);
+                TempFinder::add_temps_for_exp(cond, temps);
+                TempFinder::add_temps_for_exp(if_true, temps);
+                TempFinder::add_temps_for_exp(if_false, temps);
+            },
+            lir::Stm::Assume { cond } => {
+                TempFinder::add_temps_for_exp(cond, temps);
+            },
             lir::Stm::New { dst, ty } => {
                 TempFinder::add_temps_for_exp(dst, temps);
             },
-            lir::Stm::NewHybrid { dst, ty, length } => {
+            lir::Stm::NewHybrid { dst, ty, length, zero } => {
                 TempFinder::add_temps_for_exp(dst, temps);
                 TempFinder::add_temps_for_exp(length, temps);
             },
+            lir::Stm::Alloca { dst, ty, count } => {
+                TempFinder::add_temps_for_exp(dst, temps);
+                TempFinder::add_temps_for_exp(count, temps);
+            },
             lir::Stm::Label { label } => {},
+            lir::Stm::BuildStruct { dst, ty, fields } => {
+                TempFinder::add_temps_for_exp(dst, temps);
+                for f in fields {
+                    TempFinder::add_temps_for_exp(f, temps);
+                }
+            },
+            lir::Stm::ExtractStructElement { dst, agg, field } => {
+                TempFinder::add_temps_for_exp(dst, temps);
+                TempFinder::add_temps_for_exp(agg, temps);
+            },
             lir::Stm::GetStructElementAddr { dst, struct_ty, ptr, field: usize } => {
                 TempFinder::add_temps_for_exp(dst, temps);
                 TempFinder::add_temps_for_exp(ptr, temps);
@@ -1,25 +1,49 @@
+use std::collections::HashSet;
+
 use crate::hir::trees as hir;
 use crate::mir::trees as mir;
 use crate::mir::typed::*;
 use crate::hir::ops::*;
 use crate::common::names::*;
+use crate::common::errors::CodegenError;
+use crate::hir::cc::{LambdaLift, EnvPosition};
+use crate::hir::children::{exp_children, stm_children};
 use super::runtime_api as api;
 
 pub struct Translate;
 
 impl Translate {
-    pub fn translate(r: &hir::Root) -> mir::Root {
+    pub fn translate(r: &hir::Root) -> Result<mir::Root, CodegenError> {
+        Translate::translate_with_finalizers(r, &HashSet::new())
+    }
+
+    // Like translate, but boxing a value whose type is in finalizers also registers
+    // the box with the runtime, which runs the type's finalizer when the box is freed.
+    pub fn translate_with_finalizers(r: &hir::Root, finalizers: &HashSet<mir::Type>) -> Result<mir::Root, CodegenError> {
+        Translate::translate_with_options(r, finalizers, EnvPosition::Last)
+    }
+
+    // Like translate, but closures take their environment at env_pos in their parameters.
+    pub fn translate_with_env_position(r: &hir::Root, env_pos: EnvPosition) -> Result<mir::Root, CodegenError> {
+        Translate::translate_with_options(r, &HashSet::new(), env_pos)
+    }
+
+    fn translate_with_options(r: &hir::Root, finalizers: &HashSet<mir::Type>, env_pos: EnvPosition) -> Result<mir::Root, CodegenError> {
         // Lambda lift
-        use crate::hir::cc::*;
-        let cc = LambdaLift::lambda_lift(&r);
+        let cc = LambdaLift::lambda_lift_with_env(&r, env_pos)?;
 
         println!("LIFTED {:#?}", cc);
 
-        // TODO: generate an initializer function for the module.
-        // The initializer runs when the module is loaded.
-        // The initializer initializes any data in the module.
-        // The initializer creates the memoization tables for the module.
-        let mut pt = ProcTranslator::new();
+        Translate::translate_lifted(&cc, finalizers, Overflow::Wrap)
+    }
+
+    // Translate HIR that has already been closure converted and lambda lifted.
+    // Integer Add, Sub, and Mul follow the overflow policy.
+    pub fn translate_lifted(cc: &hir::Root, finalizers: &HashSet<mir::Type>, overflow: Overflow) -> Result<mir::Root, CodegenError> {
+        // Globals with non-constant initializers are initialized by rivo_init, which
+        // llvm_gen registers as a constructor so it runs when the module is loaded.
+        // TODO: the initializer should also create the memoization tables for the module.
+        let mut pt = ProcTranslator::new(finalizers.clone(), overflow);
 
         let mut externs = Vec::new();
         let mut datas = Vec::new();
@@ -27,18 +51,22 @@ impl Translate {
         let mut inits = Vec::new();
 
         for p in &cc.defs {
+            // The generated initializer would clash with a user def of the same name.
             match p {
-                hir::Def::VarDef { ty, name, exp } => {
-                    let (data, opt_init) = pt.translate_data(ty, *name, &**exp);
+                hir::Def::VarDef { name, .. } | hir::Def::FunDef { name, .. } | hir::Def::ExternDef { name, .. } if *name == Name::new("rivo_init") => {
+                    return Err(CodegenError::ReservedName { name: *name });
+                },
+                _ => {},
+            }
 
-                    if let Some(init) = opt_init {
+            match p {
+                hir::Def::VarDef { ty, name, exp, thread_local } => {
+                    if let Some(init) = pt.translate_data(ty, *name, &**exp, *thread_local, &mut datas)? {
                         inits.push(init);
                     }
-
-                    datas.push(data);
                 },
                 hir::Def::FunDef { ret_type, name, params, body } => {
-                    let proc = pt.translate_proc(ret_type, *name, params, &**body);
+                    let proc = pt.translate_proc(ret_type, *name, params, &**body)?;
                     procs.push(proc);
                 },
                 hir::Def::ExternDef { ty, name } => {
@@ -47,11 +75,11 @@ impl Translate {
             }
         }
 
-        if true || ! inits.is_empty() {
+        if ! inits.is_empty() {
             procs.push(
                 mir::Proc {
                     ret_type: mir::Type::Void,
-                    name: Name::new("init_module"),
+                    name: Name::new("rivo_init"),
                     params: vec![],
                     body: Box::new(
                         mir::Exp::Block {
@@ -78,23 +106,53 @@ impl Translate {
         //     [mir::Param { name: malloc.name, ty: malloc.ty }].iter()
         // );
 
-        mir::Root { externs, data: datas, procs }
+        Ok(mir::Root { externs, data: datas, procs })
     }
 
-    fn default_for_type(ty: &mir::Type) -> mir::Lit {
-        match ty {
-            mir::Type::I1 => mir::Lit::I1 { value: false },
-            mir::Type::I8 => mir::Lit::I8 { value: 0 },
-            mir::Type::I16 => mir::Lit::I16 { value: 0 },
-            mir::Type::I32 => mir::Lit::I32 { value: 0 },
-            mir::Type::I64 => mir::Lit::I64 { value: 0 },
-            mir::Type::F32 => mir::Lit::F32 { value: 0.0 },
-            mir::Type::F64 => mir::Lit::F64 { value: 0.0 },
-            mir::Type::Void => mir::Lit::Void,
-            mir::Type::Ptr { .. } => mir::Lit::Null { ty: ty.clone() },
-            mir::Type::Ref { .. } => mir::Lit::Null { ty: ty.clone() },
-            _ => panic!("no default value for type {:?}", ty),
+    // The Uop converting from one number type to another.
+    fn conversion_op(from: &mir::Type, to: &mir::Type, signedness: Signedness) -> Option<Uop> {
+        let signed = signedness == Signedness::Signed;
+        let op = match (from, to) {
+            (mir::Type::I32, mir::Type::I64) => if signed { Uop::Extend_s_i32_i64 } else { Uop::Extend_u_i32_i64 },
+            (mir::Type::I64, mir::Type::I32) => Uop::Wrap_i64_i32,
+
+            (mir::Type::I32, mir::Type::I8) => Uop::Wrap_i32_i8,
+            (mir::Type::I32, mir::Type::I16) => Uop::Wrap_i32_i16,
+            (mir::Type::I8, mir::Type::I32) => if signed { Uop::Extend_s_i8_i32 } else { Uop::Extend_u_i8_i32 },
+            (mir::Type::I16, mir::Type::I32) => if signed { Uop::Extend_s_i16_i32 } else { Uop::Extend_u_i16_i32 },
+
+            (mir::Type::I1, mir::Type::I32) => Uop::Extend_u_z_i32,
+            (mir::Type::I32, mir::Type::I1) => Uop::Nez_i32_z,
+            (mir::Type::I1, mir::Type::I64) => Uop::Extend_u_z_i64,
+            (mir::Type::I64, mir::Type::I1) => Uop::Nez_i64_z,
+
+            (mir::Type::F32, mir::Type::I32) => if signed { Uop::Trunc_s_f32_i32 } else { Uop::Trunc_u_f32_i32 },
+            (mir::Type::F64, mir::Type::I32) => if signed { Uop::Trunc_s_f64_i32 } else { Uop::Trunc_u_f64_i32 },
+            (mir::Type::F32, mir::Type::I64) => if signed { Uop::Trunc_s_f32_i64 } else { Uop::Trunc_u_f32_i64 },
+            (mir::Type::F64, mir::Type::I64) => if signed { Uop::Trunc_s_f64_i64 } else { Uop::Trunc_u_f64_i64 },
+
+            (mir::Type::I32, mir::Type::F32) => if signed { Uop::Convert_s_i32_f32 } else { Uop::Convert_u_i32_f32 },
+            (mir::Type::I64, mir::Type::F32) => if signed { Uop::Convert_s_i64_f32 } else { Uop::Convert_u_i64_f32 },
+            (mir::Type::I32, mir::Type::F64) => if signed { Uop::Convert_s_i32_f64 } else { Uop::Convert_u_i32_f64 },
+            (mir::Type::I64, mir::Type::F64) => if signed { Uop::Convert_s_i64_f64 } else { Uop::Convert_u_i64_f64 },
+
+            (mir::Type::F64, mir::Type::F32) => Uop::Demote_f64_f32,
+            (mir::Type::F32, mir::Type::F64) => Uop::Promote_f32_f64,
+
+            _ => return None,
+        };
+        Some(op)
+    }
+
+    // The Uops converting from one number type to another, in order. Types without a direct
+    // conversion, such as i8 and i16, go through i32.
+    fn conversion_ops(from: &mir::Type, to: &mir::Type, signedness: Signedness) -> Option<Vec<Uop>> {
+        if let Some(op) = Translate::conversion_op(from, to, signedness) {
+            return Some(vec![op]);
         }
+        let op1 = Translate::conversion_op(from, &mir::Type::I32, signedness)?;
+        let op2 = Translate::conversion_op(&mir::Type::I32, to, signedness)?;
+        Some(vec![op1, op2])
     }
 
     // Translates a function type only, but without wrapping in a ptr type.
@@ -124,6 +182,18 @@ impl Translate {
         }
     }
 
+    // Whether a global initialized to exp can be given an LLVM constant initializer,
+    // rather than being initialized by rivo_init: a literal, or a struct or array literal
+    // of constants.
+    pub fn is_constant_initializer(exp: &hir::Exp) -> bool {
+        match exp {
+            hir::Exp::Lit { .. } => true,
+            hir::Exp::StructLit { fields } => fields.iter().all(|f| Translate::is_constant_initializer(&f.exp)),
+            hir::Exp::ArrayLit { ty, exps } => exps.iter().all(Translate::is_constant_initializer),
+            _ => false,
+        }
+    }
+
     pub fn translate_type(ty: &hir::Type) -> mir::Type {
         match ty {
             hir::Type::Bool => mir::Type::I1,
@@ -184,11 +254,18 @@ impl Translate {
     }
 }
 
-struct ProcTranslator;
+struct ProcTranslator {
+    // Types whose boxes need a finalizer registered.
+    finalizers: HashSet<mir::Type>,
+    overflow: Overflow,
+    // Let-bound struct literals that are only loaded from, so they're built as struct
+    // values rather than allocated. llvm_gen keeps small ones in registers.
+    registers: HashSet<Name>,
+}
 
 impl ProcTranslator {
-    fn new() -> Self {
-        ProcTranslator {}
+    fn new(finalizers: HashSet<mir::Type>, overflow: Overflow) -> Self {
+        ProcTranslator { finalizers, overflow, registers: HashSet::new() }
     }
 
     fn new_temp(&mut self) -> Name {
@@ -199,48 +276,68 @@ impl ProcTranslator {
         Name::fresh("L.mir")
     }
 
-    fn translate_data(&mut self, ty: &hir::Type, name: Name, body: &hir::Exp) -> (mir::Data, Option<mir::Stm>) {
-        let mir_body = self.translate_exp(body);
+    // Add the global name to datas, with any static objects its initializer needs.
+    // If the initializer is constant, the global is initialized to it directly.
+    // Otherwise, initialize to the default value (null, 0, etc)
+    // and return an initializer statement to add to the rivo_init procedure.
+    fn translate_data(&mut self, ty: &hir::Type, name: Name, body: &hir::Exp, thread_local: bool, datas: &mut Vec<mir::Data>) -> Result<Option<mir::Stm>, CodegenError> {
         let mir_ty = Translate::translate_type(ty);
 
-        // If the initializer expression is a literal, leave as is.
-        // Otherwise, initialize to the default value (null, 0, etc)
-        // and return an initializer statement to add to init_module procedure.
-        match mir_body {
-            mir::Exp::Lit { lit } => {
-                (
-                    mir::Data {
-                        ty: mir_ty,
-                        name,
-                        init: lit
-                    },
-                    None
-                )
-            },
-            _ => {
-                let init = mir::Stm::Store {
-                    ty: mir_ty.clone(),
-                    ptr: Box::new(mir::Exp::GlobalAddr { name, ty: mir::Type::Ptr { ty: Box::new(mir_ty.clone()) } }),
-                    value: Box::new(mir_body),
-                };
+        if Translate::is_constant_initializer(body) {
+            let init = self.translate_constant(body, datas)?;
+            datas.push(mir::Data { ty: mir_ty, name, init, thread_local });
+            return Ok(None);
+        }
 
-                let default = Translate::default_for_type(&mir_ty);
+        let init = mir::Stm::Store {
+            ty: mir_ty.clone(),
+            ptr: Box::new(mir::Exp::GlobalAddr { name, ty: mir::Type::Ptr { ty: Box::new(mir_ty.clone()) } }),
+            value: Box::new(self.translate_exp(body)?),
+        };
 
-                (
-                    mir::Data {
-                        ty: mir_ty,
-                        name: name,
-                        init: default,
-                    },
-                    Some(init)
-                )
-            }
-        }
+        let default = mir_ty.zero_lit().unwrap_or_else(|| panic!("no default value for type {:?}", mir_ty));
+
+        datas.push(mir::Data { ty: mir_ty, name, init: mir::Init::Lit { lit: default }, thread_local });
+        Ok(Some(init))
+    }
+
+    // The initializer for a constant (see is_constant_initializer).
+    // A struct or array literal is allocated statically, as a global added to datas,
+    // and evaluates to that global's address.
+    // TODO: static objects have no header, so they can't be used with box_header_words.
+    fn translate_constant(&mut self, exp: &hir::Exp, datas: &mut Vec<mir::Data>) -> Result<mir::Init, CodegenError> {
+        let (ty, fields) = match exp {
+            hir::Exp::Lit { .. } => {
+                match self.translate_exp(exp)? {
+                    mir::Exp::Lit { lit } => return Ok(mir::Init::Lit { lit }),
+                    e => panic!("literal translated to {:?}", e),
+                }
+            },
+            hir::Exp::StructLit { fields } => {
+                let ty = mir::Type::Struct { fields: fields.iter().map(|f| Translate::translate_type(&f.param.ty)).collect() };
+                let inits = fields.iter().map(|f| self.translate_constant(&f.exp, datas)).collect::<Result<_, _>>()?;
+                (ty, inits)
+            },
+            hir::Exp::ArrayLit { ty, exps } => {
+                // Lay out the array as a struct with the length, then each element.
+                let mut field_types = vec![mir::Type::I32];
+                field_types.extend(exps.iter().map(|_| Translate::translate_type(ty)));
+                let mut inits = vec![mir::Init::Lit { lit: mir::Lit::I32 { value: exps.len() as i32 } }];
+                for e in exps {
+                    inits.push(self.translate_constant(e, datas)?);
+                }
+                (mir::Type::Struct { fields: field_types }, inits)
+            },
+            e => panic!("not a constant initializer: {:?}", e),
+        };
 
+        let name = Name::fresh("static.mir");
+        datas.push(mir::Data { ty, name, init: mir::Init::Struct { fields }, thread_local: false });
+        Ok(mir::Init::GlobalAddr { name })
     }
 
-    fn translate_proc(&mut self, ty: &hir::Type, name: Name, params: &Vec<hir::Param>, body: &hir::Exp) -> mir::Proc {
-        let mir_body = self.translate_exp(body);
+    fn translate_proc(&mut self, ty: &hir::Type, name: Name, params: &Vec<hir::Param>, body: &hir::Exp) -> Result<mir::Proc, CodegenError> {
+        let mir_body = self.translate_exp(body)?;
         let mir_ty = Translate::translate_type(ty);
 
         let body_ty = mir_body.get_type();
@@ -248,7 +345,9 @@ impl ProcTranslator {
         println!("body_ty = {:#?}", body_ty);
         println!("mir_ty = {:#?}", mir_ty);
 
-        assert_eq!(mir_ty, body_ty);
+        if mir_ty != body_ty {
+            return Err(CodegenError::TypeMismatch { expected: mir_ty, found: body_ty });
+        }
 
         let mir_params = params.iter().map(|param|
             mir::Param { name: param.name, ty: Translate::translate_type(&param.ty) }
@@ -280,49 +379,178 @@ impl ProcTranslator {
                 exp: Box::new(mir_body)
             };
 
-            mir::Proc {
+            Ok(mir::Proc {
                 ret_type: mir_ty,
                 name,
                 params: mir_params,
                 body: Box::new(body_with_yield)
-            }
+            })
         }
         else {
-            mir::Proc {
+            Ok(mir::Proc {
                 ret_type: mir_ty,
                 name,
                 params: mir_params,
                 body: Box::new(mir_body)
-            }
+            })
         }
 
     }
 
     fn get_field_index(struct_ty: &hir::Type, field: Name) -> (usize, hir::Type) {
+        if let (Some(i), Some(ty)) = (struct_ty.field_index(&field), struct_ty.field_type(&field)) {
+            return (i, ty.clone())
+        }
+
+        panic!("invalid field name {} for type {:?}", field, struct_ty)
+    }
+
+    fn get_field_type(struct_ty: &hir::Type, index: usize) -> Result<hir::Type, CodegenError> {
         match struct_ty {
             hir::Type::Struct { fields } => {
-                for (i, f) in fields.iter().enumerate() {
-                    match f {
-                        hir::Param { ty, name } => {
-                            if *name == field {
-                                return (i, ty.clone())
-                            }
-                        }
-                    }
+                if let Some(f) = fields.get(index) {
+                    return Ok(f.ty.clone())
                 }
             },
             _ => {},
         }
 
-        panic!("invalid field name {} for type {:?}", field, struct_ty)
+        Err(CodegenError::InvalidFieldIndex { ty: struct_ty.clone(), index })
+    }
+
+    // Whether every use of x in e is as the base of a StructLoad or StructLoadIdx, so the struct
+    // bound to x is never mutated and its address never escapes. Rebinding x also counts as a
+    // use, since the loads after it wouldn't be of the same struct.
+    fn only_loaded(x: Name, e: &hir::Exp) -> bool {
+        match e {
+            hir::Exp::StructLoad { base: box hir::Exp::Var { name, .. }, .. } if *name == x => true,
+            hir::Exp::StructLoadIdx { base: box hir::Exp::Var { name, .. }, .. } if *name == x => true,
+            hir::Exp::Var { name, .. } if *name == x => false,
+            hir::Exp::Let { inits, .. } if inits.iter().any(|init| init.param.name == x) => false,
+            hir::Exp::Lambda { params, .. } if params.iter().any(|p| p.name == x) => false,
+            _ => {
+                let (es, ss) = exp_children(e);
+                es.into_iter().all(|e| ProcTranslator::only_loaded(x, e)) && ss.into_iter().all(|s| ProcTranslator::only_loaded_stm(x, s))
+            },
+        }
+    }
+
+    fn only_loaded_stm(x: Name, s: &hir::Stm) -> bool {
+        match s {
+            hir::Stm::Assign { lhs, .. } if *lhs == x => false,
+            _ => {
+                let (es, ss) = stm_children(s);
+                es.into_iter().all(|e| ProcTranslator::only_loaded(x, e)) && ss.into_iter().all(|s| ProcTranslator::only_loaded_stm(x, s))
+            },
+        }
+    }
+
+    // The fields of a struct literal as a struct value.
+    fn translate_struct_value(&mut self, fields: &Vec<hir::Field>) -> Result<mir::Exp, CodegenError> {
+        Ok(mir::Exp::BuildStruct {
+            fields: fields.iter().map(|f| self.translate_exp(&*f.exp)).collect::<Result<_, _>>()?,
+        })
     }
 
-    fn translate_stm(&mut self, stm: &hir::Stm) -> Vec<mir::Stm> {
-        match stm {
+    // Field i of the struct base. A struct value is read with extractvalue rather than through memory.
+    fn translate_struct_load(&mut self, struct_ty: mir::Type, field_ty: mir::Type, base: &hir::Exp, i: usize) -> Result<mir::Exp, CodegenError> {
+        match base {
+            hir::Exp::Var { name, .. } if self.registers.contains(name) => {
+                Ok(mir::Exp::ExtractStructElement {
+                    agg: Box::new(mir::Exp::Temp { name: *name, ty: struct_ty }),
+                    field: i
+                })
+            },
+            hir::Exp::StructLit { fields } => {
+                Ok(mir::Exp::ExtractStructElement {
+                    agg: Box::new(self.translate_struct_value(fields)?),
+                    field: i
+                })
+            },
+            _ => {
+                let p = self.translate_exp(base)?;
+
+                Ok(mir::Exp::Load {
+                    ty: field_ty,
+                    ptr: Box::new(
+                        // Address of a struct field entry.
+                        mir::Exp::GetStructElementAddr {
+                            struct_ty,
+                            ptr: Box::new(p),
+                            field: i
+                        }
+                    ),
+                })
+            },
+        }
+    }
+
+    // Expressions that can be evaluated unconditionally without changing behavior.
+    fn is_pure(e: &hir::Exp) -> bool {
+        match e {
+            hir::Exp::Lit { .. } => true,
+            hir::Exp::Var { .. } => true,
+            hir::Exp::Function { .. } => true,
+            hir::Exp::Global { .. } => true,
+            _ => false,
+        }
+    }
+
+    fn translate_new_array(&mut self, ty: &hir::Type, length: &hir::Exp, zero: bool) -> Result<mir::Exp, CodegenError> {
+        let len = self.new_temp();
+        let array = self.new_temp();
+
+        let n = self.translate_exp(length)?;
+
+        let base_ty = Translate::translate_type(ty);
+
+        let array_type = mir::Type::Hybrid {
+            fields: vec![mir::Type::I32],
+            variant: Box::new(base_ty.clone())
+        };
+
+        let array_ptr_type = mir::Type::Ref {
+            ty: Box::new(array_type.clone())
+        };
+
+        let byte_ptr_type = mir::Type::Ref {
+            ty: Box::new(
+                mir::Type::I8
+            )
+        };
+
+        // To allocate, we compute the size of the array, then call malloc.
+        // Unless the caller asked for an uninitialized array, the body is zeroed.
+        let alloc = mir::Exp::NewHybrid {
+            ty: array_type.clone(),
+            length: Box::new(mir::Exp::Temp { name: len, ty: mir::Type::word() }),
+            zero,
+        };
+
+        Ok(mir::Exp::Block {
+            body: vec![
+                mir::Stm::Move { ty: mir::Type::word(), lhs: len, rhs: Box::new(n) },
+                mir::Stm::Move { ty: array_ptr_type.clone(), lhs: array, rhs: Box::new(alloc) },
+                mir::Stm::Store {
+                    ty: mir::Type::word(),
+                    ptr: box mir::Exp::GetStructElementAddr {
+                        struct_ty: array_type,
+                        ptr: Box::new(mir::Exp::Temp { name: array, ty: array_ptr_type.clone() }),
+                        field: 0,
+                    },
+                    value: Box::new(mir::Exp::Temp { name: len, ty: mir::Type::word() }),
+                }
+            ],
+            exp: Box::new(mir::Exp::Temp { name: array, ty: array_ptr_type.clone() })
+        })
+    }
+
+    fn translate_stm(&mut self, stm: &hir::Stm) -> Result<Vec<mir::Stm>, CodegenError> {
+        let stms = match stm {
             hir::Stm::Eval { exp } => {
                 // Just assign into a fresh temp and rely on regalloc to eliminate.
                 let t = self.new_temp();
-                let v = self.translate_exp(&*exp);
+                let v = self.translate_exp(&*exp)?;
                 vec![
                     mir::Stm::Move {
                         ty: v.get_type(),
@@ -336,7 +564,7 @@ impl ProcTranslator {
                     mir::Stm::Move {
                         ty: Translate::translate_type(ty),
                         lhs: *lhs,
-                        rhs: Box::new(self.translate_exp(&*rhs))
+                        rhs: Box::new(self.translate_exp(&*rhs)?)
                     }
                 ]
             },
@@ -370,9 +598,10 @@ impl ProcTranslator {
                             )
                         }
                     ),
+                    hint: None,
                 };
 
-                self.translate_stm(&ite)
+                self.translate_stm(&ite)?
             },
             hir::Stm::ArrayAssign { bounds_check: true, ty, array, index, value } => {
                 let a = self.new_temp();
@@ -412,13 +641,13 @@ impl ProcTranslator {
                     )
                 };
 
-                self.translate_stm(&hir::Stm::Eval { exp: Box::new(assign) })
+                self.translate_stm(&hir::Stm::Eval { exp: Box::new(assign) })?
             },
             hir::Stm::ArrayAssign { bounds_check: false, ty, array, index, value } => {
                 let base_ty = Translate::translate_type(ty);
-                let a = self.translate_exp(&*array);
-                let i = self.translate_exp(&*index);
-                let v = self.translate_exp(&*value);
+                let a = self.translate_exp(&*array)?;
+                let i = self.translate_exp(&*index)?;
+                let v = self.translate_exp(&*value)?;
 
                 vec![
                     mir::Stm::Store {
@@ -438,6 +667,137 @@ impl ProcTranslator {
                     }
                 ]
             },
+            hir::Stm::ArrayCopy { bounds_check: true, ty, dst_array, dst_index, src_array, src_index, count } => {
+                // Bind the operands so each is evaluated once, then check both ranges before copying.
+                let array_ty = hir::Type::Array { ty: Box::new(ty.clone()) };
+
+                let da = self.new_temp();
+                let di = self.new_temp();
+                let sa = self.new_temp();
+                let si = self.new_temp();
+                let n = self.new_temp();
+
+                let var = |name: Name, ty: &hir::Type| Box::new(hir::Exp::Var { ty: ty.clone(), name });
+                let word = |e: Box<hir::Exp>| Box::new(hir::Exp::Unary { op: Uop::Extend_u_i32_i64, exp: e });
+
+                // index + count <= length, computed in words so the sum can't wrap.
+                let in_bounds = |array: Name, index: Name| hir::Exp::Binary {
+                    op: Bop::Le_u_i64,
+                    e1: Box::new(hir::Exp::Binary {
+                        op: Bop::Add_i64,
+                        e1: word(var(index, &hir::Type::I32)),
+                        e2: word(var(n, &hir::Type::I32)),
+                    }),
+                    e2: Box::new(hir::Exp::ArrayLength { array: var(array, &array_ty) }),
+                };
+
+                let fail = hir::Stm::Eval {
+                    exp: Box::new(
+                        hir::Exp::Call {
+                            fun_type: hir::Type::Fun { ret: Box::new(hir::Type::Void), args: vec![] },
+                            name: Name::new("panic"),
+                            args: vec![]
+                        }
+                    )
+                };
+
+                let copy = hir::Stm::ArrayCopy {
+                    bounds_check: false,
+                    ty: ty.clone(),
+                    dst_array: var(da, &array_ty),
+                    dst_index: var(di, &hir::Type::I32),
+                    src_array: var(sa, &array_ty),
+                    src_index: var(si, &hir::Type::I32),
+                    count: var(n, &hir::Type::I32),
+                };
+
+                let ite = hir::Stm::IfElse {
+                    cond: Box::new(in_bounds(da, di)),
+                    if_true: Box::new(
+                        hir::Stm::IfElse {
+                            cond: Box::new(in_bounds(sa, si)),
+                            if_true: Box::new(copy),
+                            if_false: Box::new(fail.clone()),
+                            hint: None,
+                        }
+                    ),
+                    if_false: Box::new(fail),
+                    hint: None,
+                };
+
+                let field = |name: Name, ty: &hir::Type, exp: &Box<hir::Exp>| hir::Field {
+                    param: hir::Param { ty: ty.clone(), name },
+                    exp: exp.clone(),
+                };
+
+                let body = hir::Exp::Let {
+                    inits: vec![
+                        field(da, &array_ty, dst_array),
+                        field(di, &hir::Type::I32, dst_index),
+                        field(sa, &array_ty, src_array),
+                        field(si, &hir::Type::I32, src_index),
+                        field(n, &hir::Type::I32, count),
+                    ],
+                    body: Box::new(
+                        hir::Exp::Seq {
+                            body: Box::new(ite),
+                            // Just eval to false. We'll discard this value.
+                            exp: Box::new(hir::Exp::Lit { lit: hir::Lit::Bool { value: false } })
+                        }
+                    )
+                };
+
+                self.translate_stm(&hir::Stm::Eval { exp: Box::new(body) })?
+            },
+            hir::Stm::ArrayCopy { bounds_check: false, ty, dst_array, dst_index, src_array, src_index, count } => {
+                let base_ty = Translate::translate_type(ty);
+                let array_ty = Translate::translate_type(&hir::Type::Array { ty: box ty.clone() });
+                let byte_ptr_type = mir::Type::Ref { ty: Box::new(mir::Type::I8) };
+
+                // Address of an element as a byte pointer, for memmove.
+                let addr = |a: mir::Exp, i: mir::Exp| mir::Exp::Cast {
+                    ty: byte_ptr_type.clone(),
+                    exp: box mir::Exp::GetArrayElementAddr {
+                        base_ty: base_ty.clone(),
+                        ptr: box mir::Exp::GetStructElementAddr {
+                            struct_ty: array_ty.clone(),
+                            ptr: Box::new(a),
+                            field: 1,
+                        },
+                        index: Box::new(i),
+                    }
+                };
+
+                let dst = addr(self.translate_exp(&*dst_array)?, self.translate_exp(&*dst_index)?);
+                let src = addr(self.translate_exp(&*src_array)?, self.translate_exp(&*src_index)?);
+                let n = self.translate_exp(&*count)?;
+
+                // The element size is known here, so multiply by it directly.
+                let wordsize = match mir::Type::word() {
+                    mir::Type::I64 => 8,
+                    _ => 4,
+                };
+                let bytes = mir::Exp::Binary {
+                    op: Bop::Mul_i64,
+                    e1: Box::new(mir::Exp::Unary { op: Uop::Extend_u_i32_i64, exp: Box::new(n) }),
+                    e2: Box::new(mir::Exp::Lit { lit: mir::Lit::I64 { value: base_ty.size_of(wordsize) as i64 } }),
+                };
+
+                // memmove rather than memcpy since the ranges may overlap.
+                let call = mir::Exp::Call {
+                    fun_type: api::memmove_type(),
+                    fun: Box::new(api::memmove()),
+                    args: vec![dst, src, bytes, mir::Exp::Lit { lit: mir::Lit::I1 { value: false } }],
+                };
+
+                vec![
+                    mir::Stm::Move {
+                        ty: mir::Type::Void,
+                        lhs: self.new_temp(),
+                        rhs: Box::new(call)
+                    }
+                ]
+            },
             hir::Stm::StructAssign { ty, base, field, value } => {
                 let struct_ptr_ty = Translate::translate_type(ty);
                 let struct_ty = Translate::translate_struct_type(ty);
@@ -445,8 +805,8 @@ impl ProcTranslator {
                 let (i, hfield_ty) = ProcTranslator::get_field_index(ty, *field);
                 let field_ty = Translate::translate_type(&hfield_ty);
 
-                let p = self.translate_exp(&*base);
-                let v = self.translate_exp(&*value);
+                let p = self.translate_exp(&*base)?;
+                let v = self.translate_exp(&*value)?;
 
                 vec![
                     mir::Stm::Store {
@@ -465,74 +825,120 @@ impl ProcTranslator {
             hir::Stm::Return { exp } => {
                 vec![
                     mir::Stm::Return {
-                        exp: Box::new(self.translate_exp(&*exp))
+                        exp: Box::new(self.translate_exp(&*exp)?)
                     },
                 ]
             },
-            hir::Stm::IfElse { cond, if_true, if_false } => {
+            hir::Stm::IfElse { cond, if_true, if_false, hint } => {
                 let bottom = self.new_label();
                 let l_if_true = self.new_label();
                 let l_if_false = self.new_label();
-                let e = self.translate_exp(&*cond);
-                let mut m1 = self.translate_stm(&*if_true);
-                let mut m2 = self.translate_stm(&*if_false);
+                let e = self.translate_exp(&*cond)?;
+                let mut m1 = self.translate_stm(&*if_true)?;
+                let mut m2 = self.translate_stm(&*if_false)?;
 
                 let mut stms = Vec::new();
                 stms.push(mir::Stm::CJump {
                     cond: Box::new(e),
                     if_true: l_if_true,
-                    if_false: l_if_false
+                    if_false: l_if_false,
+                    hint: *hint,
                 });
                 stms.push(mir::Stm::Label { label: l_if_true });
                 stms.append(&mut m1);
-                stms.push(mir::Stm::Jump { label: bottom });
+                stms.push(mir::Stm::Jump { label: bottom, loop_hints: vec![] });
                 stms.push(mir::Stm::Label { label: l_if_false });
                 stms.append(&mut m2);
-                stms.push(mir::Stm::Jump { label: bottom });
+                stms.push(mir::Stm::Jump { label: bottom, loop_hints: vec![] });
                 stms.push(mir::Stm::Label { label: bottom });
                 stms
             },
-            hir::Stm::IfThen { cond, if_true } => {
+            hir::Stm::IfThen { cond, if_true, hint } => {
                 let bottom = self.new_label();
                 let l_if_true = self.new_label();
-                let e = self.translate_exp(&*cond);
-                let mut m = self.translate_stm(&*if_true);
+                let e = self.translate_exp(&*cond)?;
+                let mut m = self.translate_stm(&*if_true)?;
 
                 let mut stms = Vec::new();
                 stms.push(mir::Stm::CJump {
                     cond: Box::new(e),
                     if_true: l_if_true,
-                    if_false: bottom
+                    if_false: bottom,
+                    hint: *hint,
                 });
                 stms.push(mir::Stm::Label { label: l_if_true });
                 stms.append(&mut m);
-                stms.push(mir::Stm::Jump { label: bottom });
+                stms.push(mir::Stm::Jump { label: bottom, loop_hints: vec![] });
                 stms.push(mir::Stm::Label { label: bottom });
                 stms
             },
-            hir::Stm::While { cond, body } => {
+            hir::Stm::While { cond, body, hints } => {
                 let bottom = self.new_label();
                 let top = self.new_label();
                 let l_body = self.new_label();
-                let e = self.translate_exp(&*cond);
-                let mut m = self.translate_stm(&*body);
+                let e = self.translate_exp(&*cond)?;
+                let mut m = self.translate_stm(&*body)?;
 
                 let mut stms = Vec::new();
                 stms.push(mir::Stm::Label { label: top });
                 stms.push(mir::Stm::CJump {
                     cond: Box::new(e),
                     if_true: l_body,
-                    if_false: bottom
+                    if_false: bottom,
+                    hint: None,
                 });
                 stms.push(mir::Stm::Label { label: l_body });
                 stms.append(&mut m);
-                stms.push(mir::Stm::Jump { label: top });
+                stms.push(mir::Stm::Jump { label: top, loop_hints: hints.clone() });
                 stms.push(mir::Stm::Label { label: bottom });
                 stms
             },
             hir::Stm::Block { body } => {
-                body.iter().flat_map(|s| self.translate_stm(s)).collect()
+                let mut stms = Vec::new();
+                for s in body {
+                    stms.append(&mut self.translate_stm(s)?);
+                }
+                stms
             },
+        };
+
+        Ok(stms)
+    }
+
+    // srem is undefined on INT_MIN % -1, even though the remainder is 0.
+    // Since x % 1 is also 0 for every x, replace a divisor of -1 with 1.
+    fn not_minus_one(&mut self, ty: mir::Type, e: mir::Exp) -> mir::Exp {
+        // If e is definitely not -1, just return e.
+        match e {
+            mir::Exp::Lit { lit: mir::Lit::I32 { value: n } } if n != -1 => { return e; },
+            mir::Exp::Lit { lit: mir::Lit::I64 { value: n } } if n != -1 => { return e; },
+            _ => {},
+        }
+
+        let t = self.new_temp();
+        let (cmp, minus_one, one) = match ty {
+            mir::Type::I32 => (Bop::Eq_i32, mir::Lit::I32 { value: -1 }, mir::Lit::I32 { value: 1 }),
+            mir::Type::I64 => (Bop::Eq_i64, mir::Lit::I64 { value: -1 }, mir::Lit::I64 { value: 1 }),
+            _ => panic!("unexpected integer type"),
+        };
+
+        mir::Exp::Block {
+            body: vec![
+                mir::Stm::Move { ty: ty.clone(), lhs: t, rhs: Box::new(e) },
+            ],
+            exp: Box::new(
+                mir::Exp::Select {
+                    cond: Box::new(
+                        mir::Exp::Binary {
+                            op: cmp,
+                            e1: Box::new(mir::Exp::Temp { ty: ty.clone(), name: t }),
+                            e2: Box::new(mir::Exp::Lit { lit: minus_one }),
+                        }
+                    ),
+                    if_true: Box::new(mir::Exp::Lit { lit: one }),
+                    if_false: Box::new(mir::Exp::Temp { ty, name: t }),
+                }
+            ),
         }
     }
 
@@ -572,6 +978,7 @@ impl ProcTranslator {
                     ),
                     if_true,
                     if_false,
+                    hint: None,
                 },
                 mir::Stm::Label { label: if_true },
                 mir::Stm::Move {
@@ -592,6 +999,79 @@ impl ProcTranslator {
         }
     }
 
+    fn may_overflow(op: Bop) -> bool {
+        match op {
+            Bop::Add_i32 | Bop::Sub_i32 | Bop::Mul_i32 => true,
+            Bop::Add_i64 | Bop::Sub_i64 | Bop::Mul_i64 => true,
+            _ => false,
+        }
+    }
+
+    // The variant of an op that may_overflow whose signed overflow is undefined.
+    fn no_signed_wrap(op: Bop) -> Bop {
+        match op {
+            Bop::Add_i32 => Bop::Add_nsw_i32,
+            Bop::Sub_i32 => Bop::Sub_nsw_i32,
+            Bop::Mul_i32 => Bop::Mul_nsw_i32,
+            Bop::Add_i64 => Bop::Add_nsw_i64,
+            Bop::Sub_i64 => Bop::Sub_nsw_i64,
+            Bop::Mul_i64 => Bop::Mul_nsw_i64,
+            _ => panic!("{:?} can't overflow", op),
+        }
+    }
+
+    // Compute e1 op e2, calling panic if the signed result overflows.
+    fn trap_on_overflow(&mut self, op: Bop, e1: mir::Exp, e2: mir::Exp) -> mir::Exp {
+        let ty = op.get_type();
+        let pair_ty = mir::Type::Struct { fields: vec![ty.clone(), mir::Type::I1] };
+        let pair = self.new_temp();
+        let overflow = self.new_label();
+        let ok = self.new_label();
+        let scratch = self.new_temp();
+
+        let field = |field| mir::Exp::ExtractStructElement {
+            agg: Box::new(mir::Exp::Temp { ty: pair_ty.clone(), name: pair }),
+            field
+        };
+
+        mir::Exp::Block {
+            body: vec![
+                mir::Stm::Move {
+                    ty: pair_ty.clone(),
+                    lhs: pair,
+                    rhs: Box::new(
+                        mir::Exp::Call {
+                            fun_type: api::with_overflow_type(ty.clone()),
+                            fun: Box::new(api::with_overflow(op)),
+                            args: vec![e1, e2]
+                        }
+                    )
+                },
+                mir::Stm::CJump {
+                    cond: Box::new(field(1)),
+                    if_true: overflow,
+                    if_false: ok,
+                    hint: None,
+                },
+                mir::Stm::Label { label: overflow },
+                mir::Stm::Move {
+                    ty: mir::Type::Void,
+                    lhs: scratch,
+                    rhs: Box::new(
+                        mir::Exp::Call {
+                            fun_type: mir::Type::Fun { ret: Box::new(mir::Type::Void), args: vec![] },
+                            fun: Box::new(api::panic()),
+                            args: vec![]
+                        }
+                    )
+                },
+                mir::Stm::Label { label: ok },
+                mir::Stm::Nop,
+            ],
+            exp: Box::new(field(0))
+        }
+    }
+
     fn simplify(e: &hir::Exp) -> &hir::Exp {
         match &e {
             // box(unbox(e)) == e
@@ -632,70 +1112,30 @@ impl ProcTranslator {
     }
 
 
-    fn translate_exp(&mut self, e: &hir::Exp) -> mir::Exp {
+    fn translate_exp(&mut self, e: &hir::Exp) -> Result<mir::Exp, CodegenError> {
         println!("HIR(1) {:#?}", e);
-        let m = self.translate_exp_without_check(e);
+        let m = self.translate_exp_without_check(e)?;
         println!("HIR(2) {:#?}", e);
         println!("MIR {:#?}", m);
         m.get_type(); // type check
-        m
+        Ok(m)
     }
 
-    fn translate_exp_without_check(&mut self, e: &hir::Exp) -> mir::Exp {
+    fn translate_exp_without_check(&mut self, e: &hir::Exp) -> Result<mir::Exp, CodegenError> {
         let simplified_exp = ProcTranslator::simplify(e);
 
-        match simplified_exp {
+        let m = match simplified_exp {
             hir::Exp::NewArray { ty, length } => {
-                let len = self.new_temp();
-                let array = self.new_temp();
-
-                let n = self.translate_exp(&*length);
-
-                let base_ty = Translate::translate_type(ty);
-
-                let array_type = mir::Type::Hybrid {
-                    fields: vec![mir::Type::I32],
-                    variant: Box::new(base_ty.clone())
-                };
-
-                let array_ptr_type = mir::Type::Ref {
-                    ty: Box::new(array_type.clone())
-                };
-
-                let byte_ptr_type = mir::Type::Ref {
-                    ty: Box::new(
-                        mir::Type::I8
-                    )
-                };
-
-                // To allocate, we compute the size of the array, then call malloc.
-                let alloc = mir::Exp::NewHybrid {
-                    ty: array_type.clone(),
-                    length: Box::new(mir::Exp::Temp { name: len, ty: mir::Type::word() }),
-                };
-
-                mir::Exp::Block {
-                    body: vec![
-                        mir::Stm::Move { ty: mir::Type::word(), lhs: len, rhs: Box::new(n) },
-                        mir::Stm::Move { ty: array_ptr_type.clone(), lhs: array, rhs: Box::new(alloc) },
-                        mir::Stm::Store {
-                            ty: mir::Type::word(),
-                            ptr: box mir::Exp::GetStructElementAddr {
-                                struct_ty: array_type,
-                                ptr: Box::new(mir::Exp::Temp { name: array, ty: array_ptr_type.clone() }),
-                                field: 0,
-                            },
-                            value: Box::new(mir::Exp::Temp { name: len, ty: mir::Type::word() }),
-                        }
-                    ],
-                    exp: Box::new(mir::Exp::Temp { name: array, ty: array_ptr_type.clone() })
-                }
+                self.translate_new_array(ty, length, true)?
+            },
+            hir::Exp::NewArrayUninit { ty, length } => {
+                self.translate_new_array(ty, length, false)?
             },
-
             hir::Exp::ArrayLit { ty, exps } => {
                 // Do new array, then assign into the array.
                 // Or memcpy if expressions are all literals.
-                let new_array = hir::Exp::NewArray { ty: ty.clone(), length: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: exps.len() as i32 } }) };
+                // Every element is assigned below, so skip zeroing.
+                let new_array = hir::Exp::NewArrayUninit { ty: ty.clone(), length: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: exps.len() as i32 } }) };
                 let array_type = hir::Type::Array { ty: Box::new(ty.clone()) };
                 let t = self.new_temp();
                 let array_var = hir::Exp::Var { ty: array_type.clone(), name: t };
@@ -732,9 +1172,11 @@ impl ProcTranslator {
                     )
                 };
 
-                self.translate_exp(&init)
+                self.translate_exp(&init)?
             }
             hir::Exp::StructLit { fields } => {
+                // Allocate the struct, then store each field into it.
+                // Lambda lifting turns closures into {fun, env} struct literals, so they come here too.
                 let field_types: Vec<mir::Type> = fields.iter().map(|f| Translate::translate_type(&f.param.ty)).collect();
                 let struct_ty = mir::Type::Struct { fields: field_types.clone() };
                 let struct_ptr_ty = mir::Type::Ref { ty: Box::new(mir::Type::Struct { fields: field_types.clone() }) };
@@ -761,7 +1203,7 @@ impl ProcTranslator {
                 };
 
                 for (i, (field, field_ty)) in fields.iter().zip(field_types.iter()).enumerate() {
-                    let v = self.translate_exp(&*field.exp);
+                    let v = self.translate_exp(&*field.exp)?;
 
                     ss.push(
                         mir::Stm::Store {
@@ -792,11 +1234,14 @@ impl ProcTranslator {
 
                 mir::Exp::Call {
                     fun_type: fun_ty,
-                    fun: Box::new(self.translate_exp(&*fun)),
-                    args: args.iter().map(|e| self.translate_exp(e)).collect(),
+                    fun: Box::new(self.translate_exp(&*fun)?),
+                    args: args.iter().map(|e| self.translate_exp(e)).collect::<Result<_, _>>()?,
                 }
             },
             hir::Exp::Call { fun_type, name, args } => {
+                // The call carries the callee's type, so the callee needn't be lowered first,
+                // and defs can call each other in any order. llvm_gen declares every proc
+                // before translating any body.
                 let fun_ty = Translate::translate_fun_type(fun_type);
                 let fun_ptr_ty = Translate::translate_type(fun_type);
                 let f = mir::Exp::FunctionAddr { name: *name, ty: fun_ptr_ty };
@@ -806,7 +1251,7 @@ impl ProcTranslator {
                 mir::Exp::Call {
                     fun_type: fun_ty,
                     fun: Box::new(f),
-                    args: args.iter().map(|e| self.translate_exp(e)).collect(),
+                    args: args.iter().map(|e| self.translate_exp(e)).collect::<Result<_, _>>()?,
                 }
             },
             hir::Exp::Var { name, ty } => {
@@ -841,12 +1286,12 @@ impl ProcTranslator {
                         array: array.clone(),
                         index: index.clone()
                     }
-                )
+                )?
             },
             hir::Exp::ArrayLoad { bounds_check: false, ty, array, index } => {
                 let base_ty = Translate::translate_type(ty);
-                let a = self.translate_exp(&*array);
-                let i = self.translate_exp(&*index);
+                let a = self.translate_exp(&*array)?;
+                let i = self.translate_exp(&*index)?;
 
                 let base = mir::Exp::GetStructElementAddr {
                     struct_ty: Translate::translate_type(&hir::Type::Array { ty: box ty.clone() }),
@@ -870,22 +1315,18 @@ impl ProcTranslator {
                 let (i, hfield_ty) = ProcTranslator::get_field_index(ty, *field);
                 let field_ty = Translate::translate_type(&hfield_ty);
 
-                let p = self.translate_exp(&*base);
+                self.translate_struct_load(struct_ty, field_ty, &*base, i)?
+            },
+            hir::Exp::StructLoadIdx { ty, base, index } => {
+                // Check the index first, since translate_struct_type requires a struct type.
+                let hfield_ty = ProcTranslator::get_field_type(ty, *index)?;
+                let struct_ty = Translate::translate_struct_type(ty);
+                let field_ty = Translate::translate_type(&hfield_ty);
 
-                mir::Exp::Load {
-                    ty: field_ty,
-                    ptr: Box::new(
-                        // Address of a struct field entry.
-                        mir::Exp::GetStructElementAddr {
-                            struct_ty: struct_ty.clone(),
-                            ptr: Box::new(p),
-                            field: i
-                        }
-                    ),
-                }
+                self.translate_struct_load(struct_ty, field_ty, &*base, *index)?
             },
             hir::Exp::ArrayLength { array } => {
-                let a = self.translate_exp(&*array);
+                let a = self.translate_exp(&*array)?;
 
                 mir::Exp::Load {
                     ty: mir::Type::word(),
@@ -910,14 +1351,19 @@ impl ProcTranslator {
             hir::Exp::Lit { lit: hir::Lit::Bool { value: false } } => mir::Exp::Lit { lit: mir::Lit::I1 { value: false } },
             hir::Exp::Lit { lit: hir::Lit::Bool { value: true } } => mir::Exp::Lit { lit: mir::Lit::I1 { value: true } },
 
+            // HIR has no i128 type, so these can't be well typed there.
+            hir::Exp::Binary { op, .. } if op.is_i128() => {
+                return Err(CodegenError::UnsupportedOp { op: format!("{:?}", op) });
+            },
+
             // Short-circuiting &&
             hir::Exp::Binary { op: Bop::And_z, e1, e2 } => {
                 // e1 && e2 == if e1 then e2 else false
                 let t = self.new_temp();
                 let bottom = self.new_label();
                 let if_true = self.new_label();
-                let m1 = self.translate_exp(&*e1);
-                let m2 = self.translate_exp(&*e2);
+                let m1 = self.translate_exp(&*e1)?;
+                let m2 = self.translate_exp(&*e2)?;
 
                 mir::Exp::Block {
                     body: vec![
@@ -932,6 +1378,7 @@ impl ProcTranslator {
                             cond: Box::new(m1),
                             if_true: if_true,
                             if_false: bottom,
+                            hint: None,
                         },
                         // T:
                         mir::Stm::Label { label: if_true },
@@ -953,8 +1400,8 @@ impl ProcTranslator {
                 let t = self.new_temp();
                 let bottom = self.new_label();
                 let if_false = self.new_label();
-                let m1 = self.translate_exp(&*e1);
-                let m2 = self.translate_exp(&*e2);
+                let m1 = self.translate_exp(&*e1)?;
+                let m2 = self.translate_exp(&*e2)?;
 
                 mir::Exp::Block {
                     body: vec![
@@ -969,6 +1416,7 @@ impl ProcTranslator {
                             cond: Box::new(m1),
                             if_true: bottom,
                             if_false: if_false,
+                            hint: None,
                         },
                         // F:
                         mir::Stm::Label { label: if_false },
@@ -988,57 +1436,77 @@ impl ProcTranslator {
             // Div and rem need to check for 0.
             hir::Exp::Binary { op: op @ Bop::Div_s_i32, e1, e2 } => mir::Exp::Binary {
                 op: *op,
-                e1: Box::new(self.translate_exp(&*e1)),
-                e2: { let e = self.translate_exp(&*e2); Box::new(self.nonzero(mir::Type::I32, e)) },
+                e1: Box::new(self.translate_exp(&*e1)?),
+                e2: { let e = self.translate_exp(&*e2)?; Box::new(self.nonzero(mir::Type::I32, e)) },
             },
             hir::Exp::Binary { op: op @ Bop::Div_s_i64, e1, e2 } => mir::Exp::Binary {
                 op: *op,
-                e1: Box::new(self.translate_exp(&*e1)),
-                e2: { let e = self.translate_exp(&*e2); Box::new(self.nonzero(mir::Type::I64, e)) },
+                e1: Box::new(self.translate_exp(&*e1)?),
+                e2: { let e = self.translate_exp(&*e2)?; Box::new(self.nonzero(mir::Type::I64, e)) },
             },
             hir::Exp::Binary { op: op @ Bop::Div_u_i32, e1, e2 } => mir::Exp::Binary {
                 op: *op,
-                e1: Box::new(self.translate_exp(&*e1)),
-                e2: { let e = self.translate_exp(&*e2); Box::new(self.nonzero(mir::Type::I32, e)) },
+                e1: Box::new(self.translate_exp(&*e1)?),
+                e2: { let e = self.translate_exp(&*e2)?; Box::new(self.nonzero(mir::Type::I32, e)) },
             },
             hir::Exp::Binary { op: op @ Bop::Div_u_i64, e1, e2 } => mir::Exp::Binary {
                 op: *op,
-                e1: Box::new(self.translate_exp(&*e1)),
-                e2: { let e = self.translate_exp(&*e2); Box::new(self.nonzero(mir::Type::I64, e)) },
+                e1: Box::new(self.translate_exp(&*e1)?),
+                e2: { let e = self.translate_exp(&*e2)?; Box::new(self.nonzero(mir::Type::I64, e)) },
             },
+            // Signed rem also needs to avoid INT_MIN % -1.
             hir::Exp::Binary { op: op @ Bop::Rem_s_i32, e1, e2 } => mir::Exp::Binary {
                 op: *op,
-                e1: Box::new(self.translate_exp(&*e1)),
-                e2: { let e = self.translate_exp(&*e2); Box::new(self.nonzero(mir::Type::I32, e)) },
+                e1: Box::new(self.translate_exp(&*e1)?),
+                e2: { let e = self.translate_exp(&*e2)?; let e = self.nonzero(mir::Type::I32, e); Box::new(self.not_minus_one(mir::Type::I32, e)) },
             },
             hir::Exp::Binary { op: op @ Bop::Rem_s_i64, e1, e2 } => mir::Exp::Binary {
                 op: *op,
-                e1: Box::new(self.translate_exp(&*e1)),
-                e2: { let e = self.translate_exp(&*e2); Box::new(self.nonzero(mir::Type::I64, e)) },
+                e1: Box::new(self.translate_exp(&*e1)?),
+                e2: { let e = self.translate_exp(&*e2)?; let e = self.nonzero(mir::Type::I64, e); Box::new(self.not_minus_one(mir::Type::I64, e)) },
             },
             hir::Exp::Binary { op: op @ Bop::Rem_u_i32, e1, e2 } => mir::Exp::Binary {
                 op: *op,
-                e1: Box::new(self.translate_exp(&*e1)),
-                e2: { let e = self.translate_exp(&*e2); Box::new(self.nonzero(mir::Type::I32, e)) },
+                e1: Box::new(self.translate_exp(&*e1)?),
+                e2: { let e = self.translate_exp(&*e2)?; Box::new(self.nonzero(mir::Type::I32, e)) },
             },
             hir::Exp::Binary { op: op @ Bop::Rem_u_i64, e1, e2 } => mir::Exp::Binary {
                 op: *op,
-                e1: Box::new(self.translate_exp(&*e1)),
-                e2: { let e = self.translate_exp(&*e2); Box::new(self.nonzero(mir::Type::I64, e)) },
+                e1: Box::new(self.translate_exp(&*e1)?),
+                e2: { let e = self.translate_exp(&*e2)?; Box::new(self.nonzero(mir::Type::I64, e)) },
+            },
+
+            hir::Exp::Binary { op, e1, e2 } if self.overflow == Overflow::Trap && ProcTranslator::may_overflow(*op) => {
+                let e1 = self.translate_exp(&*e1)?;
+                let e2 = self.translate_exp(&*e2)?;
+                self.trap_on_overflow(*op, e1, e2)
+            },
+            hir::Exp::Binary { op, e1, e2 } if self.overflow == Overflow::Undef && ProcTranslator::may_overflow(*op) => mir::Exp::Binary {
+                op: ProcTranslator::no_signed_wrap(*op),
+                e1: Box::new(self.translate_exp(&*e1)?),
+                e2: Box::new(self.translate_exp(&*e2)?)
             },
 
             // Other binary operations are just translated as is.
             hir::Exp::Binary { op, e1, e2 } => mir::Exp::Binary {
                 op: *op,
-                e1: Box::new(self.translate_exp(&*e1)),
-                e2: Box::new(self.translate_exp(&*e2))
+                e1: Box::new(self.translate_exp(&*e1)?),
+                e2: Box::new(self.translate_exp(&*e2)?)
             },
 
             // Other unary operations are just translated as is.
             hir::Exp::Unary { op, exp } => {
                 mir::Exp::Unary {
                     op: *op,
-                    exp: Box::new(self.translate_exp(&*exp))
+                    exp: Box::new(self.translate_exp(&*exp)?)
+                }
+            },
+            hir::Exp::Fma { ty, a, b, c } => {
+                mir::Exp::Fma {
+                    ty: Translate::translate_type(ty),
+                    a: Box::new(self.translate_exp(&*a)?),
+                    b: Box::new(self.translate_exp(&*b)?),
+                    c: Box::new(self.translate_exp(&*c)?),
                 }
             },
 
@@ -1046,17 +1514,61 @@ impl ProcTranslator {
             hir::Exp::Box { ty, exp } => {
                 use crate::mir::typed::Typed;
                 let mir_ty = Translate::translate_type(&ty);
-                let arg = self.translate_exp(&*exp);
-                mir::Exp::Call {
-                    fun_type: mir::Type::Fun { ret: Box::new(Translate::translate_type(&hir::Type::Box)), args: vec![mir_ty.clone()] },
+                let box_ty = Translate::translate_type(&hir::Type::Box);
+                let arg = self.translate_exp(&*exp)?;
+
+                if let hir::Type::Struct { fields } = ty {
+                    if fields.is_empty() {
+                        // An empty struct has no contents to copy into a box, so use the struct's pointer.
+                        return Ok(mir::Exp::Cast { ty: box_ty, exp: Box::new(arg) });
+                    }
+                }
+
+                let boxed = mir::Exp::Call {
+                    fun_type: mir::Type::Fun { ret: Box::new(box_ty.clone()), args: vec![mir_ty.clone()] },
                     fun: Box::new(api::boxer(&mir_ty)),
                     args: vec![arg]
+                };
+
+                if ! self.finalizers.contains(&mir_ty) {
+                    return Ok(boxed);
+                }
+
+                // rivo_register_finalizer takes the box as a byte pointer.
+                let byte_ptr = mir::Type::Ref { ty: Box::new(mir::Type::I8) };
+                let t = self.new_temp();
+                let scratch = self.new_temp();
+
+                mir::Exp::Block {
+                    body: vec![
+                        mir::Stm::Move { ty: box_ty.clone(), lhs: t, rhs: Box::new(boxed) },
+                        mir::Stm::Move {
+                            ty: mir::Type::Void,
+                            lhs: scratch,
+                            rhs: Box::new(
+                                mir::Exp::Call {
+                                    fun_type: mir::Type::Fun { ret: Box::new(mir::Type::Void), args: vec![byte_ptr.clone()] },
+                                    fun: Box::new(api::register_finalizer()),
+                                    args: vec![mir::Exp::Cast { ty: byte_ptr, exp: Box::new(mir::Exp::Temp { ty: box_ty.clone(), name: t }) }]
+                                }
+                            )
+                        },
+                    ],
+                    exp: Box::new(mir::Exp::Temp { ty: box_ty, name: t })
                 }
             },
             hir::Exp::Unbox { ty, exp } => {
                 use crate::mir::typed::Typed;
                 let mir_ty = Translate::translate_type(&ty);
-                let arg = self.translate_exp(&*exp);
+                let arg = self.translate_exp(&*exp)?;
+
+                if let hir::Type::Struct { fields } = ty {
+                    if fields.is_empty() {
+                        // Boxing an empty struct just casts its pointer (see Box above), so cast it back.
+                        return Ok(mir::Exp::Cast { ty: mir_ty, exp: Box::new(arg) });
+                    }
+                }
+
                 mir::Exp::Call {
                     fun_type: mir::Type::Fun { ret: Box::new(mir_ty.clone()), args: vec![Translate::translate_type(&hir::Type::Box)] },
                     fun: Box::new(api::unboxer(&mir_ty)),
@@ -1065,32 +1577,111 @@ impl ProcTranslator {
             },
             hir::Exp::Cast { ty, exp } => {
                 let mir_ty = Translate::translate_type(&ty);
-                mir::Exp::Cast { ty: mir_ty, exp: Box::new(self.translate_exp(&*exp)) }
+                mir::Exp::Cast { ty: mir_ty, exp: Box::new(self.translate_exp(&*exp)?) }
+            },
+            hir::Exp::Convert { ty, signedness, exp } => {
+                use crate::mir::typed::Typed;
+                let mir_ty = Translate::translate_type(&ty);
+                let m = self.translate_exp(&*exp)?;
+                let from = m.get_type();
+
+                if from == mir_ty {
+                    return Ok(m);
+                }
+
+                match Translate::conversion_ops(&from, &mir_ty, *signedness) {
+                    Some(ops) => ops.into_iter().fold(m, |exp, op| mir::Exp::Unary { op, exp: Box::new(exp) }),
+                    None => return Err(CodegenError::NoConversion { from, to: mir_ty }),
+                }
             },
 
             hir::Exp::Seq { body, exp } => {
-                let mir_body = self.translate_stm(&*body);
-                let mir_exp = self.translate_exp(&*exp);
+                // The body's effects must come before exp, which may read what body assigns.
+                // lir_gen keeps the Block's statements before its exp, and copies operands
+                // to the left of the Block that the statements assign.
+                let mir_body = self.translate_stm(&*body)?;
+                let mir_exp = self.translate_exp(&*exp)?;
                 mir::Exp::Block {
                     body: mir_body,
                     exp: Box::new(mir_exp),
                 }
             },
+            hir::Exp::Cond { ty, cond, if_true, if_false } => {
+                let mir_ty = Translate::translate_type(ty);
+                let c = self.translate_exp(&*cond)?;
+                let e1 = self.translate_exp(&*if_true)?;
+                let e2 = self.translate_exp(&*if_false)?;
+
+                for e in &[&e1, &e2] {
+                    if e.get_type() != mir_ty {
+                        return Err(CodegenError::TypeMismatch { expected: mir_ty, found: e.get_type() });
+                    }
+                }
+
+                // If neither branch has effects, evaluate both and select.
+                if ProcTranslator::is_pure(&*if_true) && ProcTranslator::is_pure(&*if_false) {
+                    return Ok(mir::Exp::Select {
+                        cond: Box::new(c),
+                        if_true: Box::new(e1),
+                        if_false: Box::new(e2),
+                    })
+                }
+
+                let t = self.new_temp();
+                let bottom = self.new_label();
+                let l_if_true = self.new_label();
+                let l_if_false = self.new_label();
+
+                mir::Exp::Block {
+                    body: vec![
+                        mir::Stm::CJump {
+                            cond: Box::new(c),
+                            if_true: l_if_true,
+                            if_false: l_if_false,
+                            hint: None,
+                        },
+                        mir::Stm::Label { label: l_if_true },
+                        mir::Stm::Move { ty: mir_ty.clone(), lhs: t, rhs: Box::new(e1) },
+                        mir::Stm::Jump { label: bottom, loop_hints: vec![] },
+                        mir::Stm::Label { label: l_if_false },
+                        mir::Stm::Move { ty: mir_ty.clone(), lhs: t, rhs: Box::new(e2) },
+                        mir::Stm::Jump { label: bottom, loop_hints: vec![] },
+                        mir::Stm::Label { label: bottom },
+                    ],
+                    exp: Box::new(mir::Exp::Temp { name: t, ty: mir_ty })
+                }
+            },
             hir::Exp::Let { inits, body } => {
-                let mir_inits = inits.iter().map(|init| {
-                    let e = self.translate_exp(&*init.exp);
-                    mir::Stm::Move {
-                        ty: Translate::translate_type(&init.param.ty),
-                        lhs: init.param.name,
+                let mut registers = vec![];
+                let mir_inits = inits.iter().enumerate().map(|(k, init)| {
+                    let x = init.param.name;
+                    let (ty, e) = match &*init.exp {
+                        hir::Exp::StructLit { fields } if inits[k+1..].iter().all(|i| ProcTranslator::only_loaded(x, &*i.exp)) && ProcTranslator::only_loaded(x, body) => {
+                            self.registers.insert(x);
+                            registers.push(x);
+                            let e = self.translate_struct_value(fields)?;
+                            (e.get_type(), e)
+                        },
+                        e => (Translate::translate_type(&init.param.ty), self.translate_exp(e)?),
+                    };
+                    Ok(mir::Stm::Move {
+                        ty,
+                        lhs: x,
                         rhs: Box::new(e)
-                    }
-                }).collect();
-                let mir_body = self.translate_exp(&*body);
+                    })
+                }).collect::<Result<_, CodegenError>>()?;
+                let mir_body = self.translate_exp(&*body)?;
+                // The same name might be bound to a struct in memory elsewhere.
+                for x in registers {
+                    self.registers.remove(&x);
+                }
                 mir::Exp::Block {
                     body: mir_inits,
                     exp: Box::new(mir_body),
                 }
             }
-        }
+        };
+
+        Ok(m)
     }
 }
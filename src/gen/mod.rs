@@ -3,30 +3,168 @@ mod lir_gen;
 mod llvm_gen;
 mod runtime_api;
 
+pub use self::llvm_gen::TranslateOptions;
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+
 use crate::hir::trees as hir;
+use crate::hir::cc::{LambdaLift, EnvPosition};
+use crate::hir::ops::{Signedness, Overflow};
+use crate::hir::signs::SignCheck;
+use crate::hir::bounds::{BoundsChecks, apply_bounds_checks};
+use crate::hir::lambdas::lower_lambdas;
+use crate::hir::inline::inline_calls;
+use crate::hir::analysis::check_init;
+use crate::mir::trees as mir;
 use crate::lir::trees as lir;
+use crate::lir::licm;
+use crate::lir::coalesce;
 use crate::llvm;
+use crate::common::names::Name;
+use crate::common::errors::{CodegenError, CompileError};
+
+pub struct CompileOptions {
+    // The module is created in this context.
+    pub context: llvm::Context,
+    // Where lifted closures take their environment.
+    pub env_pos: EnvPosition,
+    // If not set, lambdas are lowered to plain function pointers instead of being closure
+    // converted and lifted, and may not capture locals. See hir::lambdas.
+    pub lift_lambdas: bool,
+    // How many levels of nested calls to inline. 0 turns inlining off. See hir::inline.
+    pub max_inline_depth: usize,
+    // The most HIR nodes inlining may add to each function.
+    pub inline_budget: usize,
+    // Boxing a value of one of these types registers its finalizer. See mir_gen.
+    pub finalizers: HashSet<mir::Type>,
+    // If set, check that no function or global reads a variable before assigning it.
+    // See hir::analysis.
+    pub check_init: bool,
+    // If set, check ops against the signedness the frontend declared for each variable.
+    pub signs: Option<HashMap<Name, Signedness>>,
+    // What integer Add, Sub, and Mul do on overflow.
+    pub overflow: Overflow,
+    // Whether array accesses are bounds checked, overriding the frontend's flags.
+    pub bounds_checks: BoundsChecks,
+    // How LIR is translated to LLVM, e.g., strict_terminators and box_header_words.
+    pub translate: TranslateOptions,
+    // If set, called with the name and text of each intermediate form: "lifted" HIR,
+    // "mir", "lir", and the final "llvm" IR. Closure conversion and lambda lifting
+    // run as one pass, so there is no separate closure converted form.
+    pub dump: Option<Box<dyn Fn(&str, &str)>>,
+}
+
+impl CompileOptions {
+    pub fn new(context: llvm::Context) -> CompileOptions {
+        CompileOptions {
+            context,
+            env_pos: EnvPosition::Last,
+            lift_lambdas: true,
+            max_inline_depth: 0,
+            inline_budget: 200,
+            finalizers: HashSet::new(),
+            check_init: false,
+            signs: None,
+            overflow: Overflow::Wrap,
+            bounds_checks: BoundsChecks::Respect,
+            translate: TranslateOptions::new(),
+            dump: None,
+        }
+    }
+}
+
+// Compile HIR to an LLVM module: check definite assignment and signedness if asked, apply
+// the bounds check policy, inline, closure convert and lambda lift (or, without lift_lambdas,
+// lower lambdas to function pointers), lower to MIR and LIR, then translate to LLVM.
+// There's no type check; ill-typed HIR is reported by the stage that trips over it.
+pub fn compile(h: &hir::Root, name: &str, opts: &CompileOptions) -> Result<llvm::Module, CompileError> {
+    if opts.check_init {
+        for d in &h.defs {
+            if let Err(vars) = check_init(d) {
+                let name = match d {
+                    hir::Def::VarDef { name, .. } | hir::Def::FunDef { name, .. } | hir::Def::ExternDef { name, .. } => *name,
+                };
+                return Err(CompileError::Check(CodegenError::UninitializedVars { name, vars }));
+            }
+        }
+    }
+
+    if let Some(signs) = &opts.signs {
+        SignCheck::check(h, signs).map_err(CompileError::Check)?;
+    }
+
+    let h = &apply_bounds_checks(h, opts.bounds_checks);
+    let h = &inline_calls(h, opts.max_inline_depth, opts.inline_budget);
+
+    let dump = |stage: &str, text: String| {
+        if let Some(f) = &opts.dump {
+            f(stage, &text);
+        }
+    };
+
+    let cc = if opts.lift_lambdas {
+        LambdaLift::lambda_lift_with_env(h, opts.env_pos).map_err(CompileError::Lift)?
+    }
+    else {
+        lower_lambdas(h).map_err(CompileError::Lift)?
+    };
+    dump("lifted", format!("{:#?}", cc));
+
+    let m = mir_gen::Translate::translate_lifted(&cc, &opts.finalizers, opts.overflow).map_err(CompileError::Lower)?;
+    dump("mir", format!("{:#?}", m));
 
-pub fn translate_in_context(name: &str, h: &hir::Root, context: llvm::Context) -> llvm::Module {
-    let l = translate_lir(name, h);
+    let l = lir_gen::Translate::translate(&m);
+    let l = licm::hoist_allocations(&l);
+    let l = coalesce::coalesce_temps(&l);
+    dump("lir", format!("{:#?}", l));
+
+    let mut t = llvm_gen::Translate::new_in_context(opts.context);
+    t.options = opts.translate.clone();
+    let module = t.translate(name, &l).map_err(CompileError::Translate)?;
+    if opts.dump.is_some() {
+        dump("llvm", module.print_to_string());
+    }
+
+    Ok(module)
+}
+
+pub fn translate_in_context(name: &str, h: &hir::Root, context: llvm::Context) -> Result<llvm::Module, CodegenError> {
+    let l = translate_lir(name, h)?;
 
     let t = llvm_gen::Translate::new_in_context(context);
-    let m = t.translate(name, &l);
+    let m = t.translate(name, &l)?;
+    m.dump();
+
+    Ok(m)
+}
+
+// Translate already-lowered LIR, e.g., built with lir::Builder.
+pub fn translate_from_lir_in_context(name: &str, l: &lir::Root, context: llvm::Context) -> Result<llvm::Module, CodegenError> {
+    translate_from_lir_with_options(name, l, context, &TranslateOptions::new())
+}
+
+pub fn translate_from_lir_with_options(name: &str, l: &lir::Root, context: llvm::Context, options: &TranslateOptions) -> Result<llvm::Module, CodegenError> {
+    let mut t = llvm_gen::Translate::new_in_context(context);
+    t.options = options.clone();
+    let m = t.translate(name, l)?;
     m.dump();
 
-    m
+    Ok(m)
 }
 
-pub fn translate_lir(name: &str, h: &hir::Root) -> lir::Root {
+pub fn translate_lir(name: &str, h: &hir::Root) -> Result<lir::Root, CodegenError> {
     println!("HIR {:#?}", h);
 
-    let m = mir_gen::Translate::translate(h);
+    let m = mir_gen::Translate::translate(h)?;
     println!("MIR {:#?}", m);
 
     let l = lir_gen::Translate::translate(&m);
+    let l = licm::hoist_allocations(&l);
+    let l = coalesce::coalesce_temps(&l);
     println!("LIR {:#?}", l);
 
-    l
+    Ok(l)
 }
 
 #[cfg(test)]
@@ -35,6 +173,36 @@ mod tests {
     use crate::hir::trees as hir;
     use crate::hir::ops::*;
     use crate::common::names::*;
+    use crate::mir::trees as mir;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    // Counts the allocations made on each thread, so tests running in parallel don't
+    // see each other's.
+    struct CountingAlloc;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = Cell::new(0);
+    }
+
+    unsafe impl GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            // The thread-local may already be destroyed while the thread exits.
+            let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+    fn allocations() -> usize {
+        ALLOCATIONS.with(|n| n.get())
+    }
 
     #[test]
     fn fn_returns_0i64() {
@@ -52,7 +220,7 @@ mod tests {
         };
 
         let context = llvm::Context::new();
-        translate_in_context("test_fn_returns_0i64", &h, context);
+        translate_in_context("test_fn_returns_0i64", &h, context).unwrap();
         context.dispose();
     }
 
@@ -88,6 +256,7 @@ mod tests {
                                             )
                                         }
                                     ),
+                                    hint: None,
                                 }
                             ),
                             exp: Box::new(
@@ -122,7 +291,7 @@ mod tests {
         };
 
         let context = llvm::Context::new();
-        translate_in_context("test_fact_i64", &h, context);
+        translate_in_context("test_fact_i64", &h, context).unwrap();
         context.dispose();
     }
 
@@ -144,7 +313,7 @@ mod tests {
         };
 
         let context = llvm::Context::new();
-        translate_in_context("test_identity_i64", &h, context);
+        translate_in_context("test_identity_i64", &h, context).unwrap();
         context.dispose();
     }
 
@@ -185,7 +354,2741 @@ mod tests {
         };
 
         let context = llvm::Context::new();
-        translate_in_context("test_struct_load", &h, context);
+        translate_in_context("test_struct_load", &h, context).unwrap();
+        context.dispose();
+    }
+
+    #[test]
+    fn no_load_after_store_in_block() {
+        // A single block, so every temp is stored before it is used.
+        let h = hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I64,
+                    name: Name::new("square_succ"),
+                    params: vec![
+                        hir::Param { ty: hir::Type::I64, name: Name::new("n") }
+                    ],
+                    body: Box::new(
+                        hir::Exp::Let {
+                            inits: vec![
+                                hir::Field {
+                                    param: hir::Param {
+                                        ty: hir::Type::I64,
+                                        name: Name::new("x"),
+                                    },
+                                    exp: Box::new(hir::Exp::Binary {
+                                        op: Bop::Add_i64,
+                                        e1: Box::new(hir::Exp::Var { ty: hir::Type::I64, name: Name::new("n") }),
+                                        e2: Box::new(hir::Exp::Lit { lit: hir::Lit::I64 { value: 1 } }),
+                                    }),
+                                }
+                            ],
+                            body: Box::new(
+                                hir::Exp::Binary {
+                                    op: Bop::Mul_i64,
+                                    e1: Box::new(hir::Exp::Var { ty: hir::Type::I64, name: Name::new("x") }),
+                                    e2: Box::new(hir::Exp::Var { ty: hir::Type::I64, name: Name::new("x") }),
+                                }
+                            ),
+                        }
+                    ),
+                }
+            ]
+        };
+
+        let context = llvm::Context::new();
+        let m = translate_in_context("test_no_load_after_store_in_block", &h, context).unwrap();
+        let ir = m.print_to_string();
+        assert!(! ir.contains(" = load "), "unexpected load in {}", ir);
+        context.dispose();
+    }
+
+    #[test]
+    fn missing_ret_strict_terminators() {
+        // No Ret, so the only block falls off the end.
+        let l = lir::Root {
+            externs: vec![],
+            imports: vec![],
+            data: vec![],
+            procs: vec![
+                lir::Proc {
+                    ret_type: mir::Type::I32,
+                    name: Name::new("no_ret"),
+                    params: vec![],
+                    visibility: lir::Visibility::Default,
+                    dso_local: false,
+                    noinline: false,
+                    optnone: false,
+                    body: vec![],
+                }
+            ]
+        };
+
+        let context = llvm::Context::new();
+        let mut t = llvm_gen::Translate::new_in_context(context);
+        t.options.strict_terminators = true;
+        let r = t.translate("test_missing_ret_strict", &l);
+        assert_eq!(r.err(), Some(CodegenError::MissingTerminator { name: Name::new("no_ret") }));
+        context.dispose();
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+        let m = t.translate("test_missing_ret", &l).unwrap();
+        assert!(m.print_to_string().contains("unreachable"));
+        context.dispose();
+    }
+
+    #[test]
+    fn call_through_fun_ptr_temp() {
+        let fun_ptr_ty = mir::Type::Ptr { ty: Box::new(mir::Type::Fun { ret: Box::new(mir::Type::I32), args: vec![] }) };
+        let f = lir::Exp::Temp { ty: fun_ptr_ty.clone(), name: Name::new("f") };
+        let r = lir::Exp::Temp { ty: mir::Type::I32, name: Name::new("r") };
+
+        let l = lir::Root {
+            externs: vec![],
+            imports: vec![],
+            data: vec![],
+            procs: vec![
+                lir::Proc {
+                    ret_type: mir::Type::I32,
+                    name: Name::new("one"),
+                    params: vec![],
+                    visibility: lir::Visibility::Default,
+                    dso_local: false,
+                    noinline: false,
+                    optnone: false,
+                    body: vec![
+                        lir::Stm::Ret { exp: lir::Exp::Lit { lit: mir::Lit::I32 { value: 1 } } }
+                    ],
+                },
+                lir::Proc {
+                    ret_type: mir::Type::I32,
+                    name: Name::new("main"),
+                    params: vec![],
+                    visibility: lir::Visibility::Default,
+                    dso_local: false,
+                    noinline: false,
+                    optnone: false,
+                    body: vec![
+                        lir::Stm::Move { dst: f.clone(), src: lir::Exp::FunctionAddr { ty: fun_ptr_ty.clone(), name: Name::new("one") } },
+                        // Start a new block so the call has to reload f.
+                        lir::Stm::Label { label: Name::new("L") },
+                        lir::Stm::Call { dst: r.clone(), fun: f.clone(), args: vec![], must_tail: false },
+                        lir::Stm::Ret { exp: r.clone() },
+                    ],
+                },
+            ]
+        };
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+        let m = t.translate("test_call_through_fun_ptr_temp", &l).unwrap();
+        let ir = m.print_to_string();
+        assert!(ir.contains("call i32 %"), "expected an indirect call in {}", ir);
+        context.dispose();
+    }
+
+    #[test]
+    fn noalias_param_attribute() {
+        let l = lir::Root {
+            externs: vec![],
+            imports: vec![],
+            data: vec![],
+            procs: vec![
+                lir::Proc {
+                    ret_type: mir::Type::I32,
+                    name: Name::new("f"),
+                    params: vec![
+                        lir::Param {
+                            ty: mir::Type::Ptr { ty: Box::new(mir::Type::I32) },
+                            name: Name::new("p"),
+                            noalias: true,
+                            readonly: false,
+                            nonnull: false,
+                        }
+                    ],
+                    visibility: lir::Visibility::Default,
+                    dso_local: false,
+                    noinline: false,
+                    optnone: false,
+                    body: vec![
+                        lir::Stm::Ret { exp: lir::Exp::Lit { lit: mir::Lit::I32 { value: 0 } } }
+                    ],
+                }
+            ]
+        };
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+        let m = t.translate("test_noalias_param_attribute", &l).unwrap();
+        let ir = m.print_to_string();
+        assert!(ir.contains("i32* noalias"), "expected a noalias param in {}", ir);
+        context.dispose();
+    }
+
+    #[test]
+    fn branch_hint_weights() {
+        let n = hir::Exp::Var { ty: hir::Type::I64, name: Name::new("n") };
+
+        let h = hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I64,
+                    name: Name::new("f"),
+                    params: vec![
+                        hir::Param { ty: hir::Type::I64, name: Name::new("n") }
+                    ],
+                    body: Box::new(
+                        hir::Exp::Seq {
+                            body: Box::new(
+                                hir::Stm::IfThen {
+                                    cond: Box::new(
+                                        hir::Exp::Binary {
+                                            op: Bop::Eq_i64,
+                                            e1: Box::new(n.clone()),
+                                            e2: Box::new(hir::Exp::Lit { lit: hir::Lit::I64 { value: 0 } }),
+                                        }
+                                    ),
+                                    if_true: Box::new(
+                                        hir::Stm::Return {
+                                            exp: Box::new(hir::Exp::Lit { lit: hir::Lit::I64 { value: 1 } })
+                                        }
+                                    ),
+                                    hint: Some(BranchHint::Unlikely),
+                                }
+                            ),
+                            exp: Box::new(n.clone()),
+                        }
+                    ),
+                }
+            ]
+        };
+
+        let context = llvm::Context::new();
+        let m = translate_in_context("test_branch_hint_weights", &h, context).unwrap();
+        let ir = m.print_to_string();
+        assert!(ir.contains("!prof"), "expected branch weights in {}", ir);
+        assert!(ir.contains("!\"branch_weights\", i32 1, i32 2000"), "expected unlikely weights in {}", ir);
+        context.dispose();
+    }
+
+    #[test]
+    fn loop_unroll_hint() {
+        let i = hir::Exp::Var { ty: hir::Type::I32, name: Name::new("i") };
+
+        let h = hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("count"),
+                    params: vec![
+                        hir::Param { ty: hir::Type::I32, name: Name::new("n") }
+                    ],
+                    body: Box::new(
+                        hir::Exp::Seq {
+                            body: Box::new(
+                                hir::Stm::Block {
+                                    body: vec![
+                                        hir::Stm::Assign {
+                                            ty: hir::Type::I32,
+                                            lhs: Name::new("i"),
+                                            rhs: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 0 } }),
+                                        },
+                                        hir::Stm::While {
+                                            cond: Box::new(
+                                                hir::Exp::Binary {
+                                                    op: Bop::Lt_s_i32,
+                                                    e1: Box::new(i.clone()),
+                                                    e2: Box::new(hir::Exp::Var { ty: hir::Type::I32, name: Name::new("n") }),
+                                                }
+                                            ),
+                                            body: Box::new(
+                                                hir::Stm::Assign {
+                                                    ty: hir::Type::I32,
+                                                    lhs: Name::new("i"),
+                                                    rhs: Box::new(hir::Exp::Binary {
+                                                        op: Bop::Add_i32,
+                                                        e1: Box::new(i.clone()),
+                                                        e2: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 1 } }),
+                                                    }),
+                                                }
+                                            ),
+                                            hints: vec![LoopHint::Unroll(4)],
+                                        }
+                                    ]
+                                }
+                            ),
+                            exp: Box::new(i.clone()),
+                        }
+                    ),
+                }
+            ]
+        };
+
+        let context = llvm::Context::new();
+        let m = translate_in_context("test_loop_unroll_hint", &h, context).unwrap();
+        let ir = m.print_to_string();
+        assert!(ir.contains("!llvm.loop"), "expected loop metadata in {}", ir);
+        assert!(ir.contains("!\"llvm.loop.unroll.count\", i32 4"), "expected unroll count in {}", ir);
+        context.dispose();
+    }
+
+    #[test]
+    fn retranslate_changed_proc() {
+        let fun_ptr_ty = mir::Type::Ptr { ty: Box::new(mir::Type::Fun { ret: Box::new(mir::Type::I32), args: vec![] }) };
+        let r = lir::Exp::Temp { ty: mir::Type::I32, name: Name::new("r") };
+
+        let constant = |value| lir::Proc {
+            ret_type: mir::Type::I32,
+            name: Name::new("constant"),
+            params: vec![],
+            visibility: lir::Visibility::Default,
+            dso_local: false,
+            noinline: false,
+            optnone: false,
+            body: vec![
+                lir::Stm::Ret { exp: lir::Exp::Lit { lit: mir::Lit::I32 { value } } }
+            ],
+        };
+
+        let main = lir::Proc {
+            ret_type: mir::Type::I32,
+            name: Name::new("main"),
+            params: vec![],
+            visibility: lir::Visibility::Default,
+            dso_local: false,
+            noinline: false,
+            optnone: false,
+            body: vec![
+                lir::Stm::Call { dst: r.clone(), fun: lir::Exp::FunctionAddr { ty: fun_ptr_ty.clone(), name: Name::new("constant") }, args: vec![], must_tail: false },
+                lir::Stm::Ret { exp: r.clone() },
+            ],
+        };
+
+        let l1 = lir::Root { externs: vec![], imports: vec![], data: vec![], procs: vec![constant(1), main.clone()] };
+        let l2 = lir::Root { externs: vec![], imports: vec![], data: vec![], procs: vec![constant(2), main.clone()] };
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+        let m = t.translate("test_retranslate_changed_proc", &l1).unwrap();
+        let count = m.function_count();
+        let mut hashes = llvm_gen::Translate::proc_hashes(&l1);
+
+        assert_eq!(t.retranslate(m, &l1, &mut hashes), Ok(vec![]));
+        assert_eq!(t.retranslate(m, &l2, &mut hashes), Ok(vec![Name::new("constant")]));
+
+        let ir = m.print_to_string();
+        assert!(ir.contains("ret i32 2"), "expected the new constant in {}", ir);
+        assert!(! ir.contains("ret i32 1"), "unexpected old constant in {}", ir);
+        assert!(ir.contains("call i32 @constant()"), "expected main to call the new constant in {}", ir);
+        assert_eq!(m.function_count(), count);
+        context.dispose();
+    }
+
+    #[test]
+    fn parallel_matches_sequential() {
+        let procs = (0..8).map(|i| lir::Proc {
+            ret_type: mir::Type::I32,
+            name: Name::new(&format!("p{}", i)),
+            params: vec![],
+            visibility: lir::Visibility::Default,
+            dso_local: false,
+            noinline: false,
+            optnone: false,
+            body: vec![
+                lir::Stm::Ret { exp: lir::Exp::Lit { lit: mir::Lit::I32 { value: i } } }
+            ],
+        }).collect();
+
+        let l = lir::Root {
+            externs: vec![],
+            imports: vec![],
+            data: vec![
+                lir::Data { ty: mir::Type::I64, name: Name::new("g"), init: lir::Init::Lit { lit: mir::Lit::I64 { value: 7 } }, thread_local: false, visibility: lir::Visibility::Default, dso_local: false, section: None, alignment: None, packed: false }
+            ],
+            procs,
+        };
+
+        let defines = |ir: &str| {
+            let mut ds: Vec<String> = ir.lines().filter(|s| s.starts_with("define") || s.starts_with("@g")).map(|s| s.to_string()).collect();
+            ds.sort();
+            ds
+        };
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+        let seq = t.translate("test_sequential", &l).unwrap().print_to_string();
+        let par = t.translate_parallel("test_parallel", &l, 3).unwrap().print_to_string();
+        assert_eq!(defines(&par), defines(&seq));
+        assert_eq!(defines(&par).len(), 9);
+        context.dispose();
+    }
+
+    #[test]
+    fn parallel_reports_chunk_errors() {
+        use crate::lir::builder::Builder;
+
+        // f() { p = alloca i32; return p } fails the stack escape check in whichever chunk translates it.
+        let mut f = Builder::new(Name::new("f"), mir::Type::Ptr { ty: Box::new(mir::Type::I32) });
+        let p = f.alloca(mir::Type::I32, lir::Exp::Lit { lit: mir::Lit::I32 { value: 1 } });
+        f.ret(p);
+        let mut l = answer_lir();
+        l.procs.push(f.finish());
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+        match t.translate_parallel("test_parallel_reports_chunk_errors", &l, 2) {
+            Err(CodegenError::ReturnsStackAddress { .. }) => {},
+            r => panic!("expected ReturnsStackAddress, got {:?}", r.err()),
+        }
+        context.dispose();
+    }
+
+    #[test]
+    fn temp_used_at_two_types() {
+        let x = Name::new("x");
+
+        let l = lir::Root {
+            externs: vec![],
+            imports: vec![],
+            data: vec![],
+            procs: vec![
+                lir::Proc {
+                    ret_type: mir::Type::I32,
+                    name: Name::new("f"),
+                    params: vec![],
+                    visibility: lir::Visibility::Default,
+                    dso_local: false,
+                    noinline: false,
+                    optnone: false,
+                    body: vec![
+                        lir::Stm::Move { dst: lir::Exp::Temp { ty: mir::Type::I32, name: x }, src: lir::Exp::Lit { lit: mir::Lit::I32 { value: 1 } } },
+                        lir::Stm::Move { dst: lir::Exp::Temp { ty: mir::Type::I64, name: x }, src: lir::Exp::Lit { lit: mir::Lit::I64 { value: 2 } } },
+                        lir::Stm::Ret { exp: lir::Exp::Lit { lit: mir::Lit::I32 { value: 0 } } },
+                    ],
+                }
+            ]
+        };
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+        match t.translate("test_temp_used_at_two_types", &l) {
+            Err(CodegenError::ConflictingTempTypes { name, .. }) => assert_eq!(name, x),
+            r => panic!("expected ConflictingTempTypes, got {:?}", r.err()),
+        }
+        context.dispose();
+    }
+
+    #[test]
+    fn param_used_at_other_type() {
+        let p = Name::new("p");
+        let byte_ptr = mir::Type::Ptr { ty: Box::new(mir::Type::I8) };
+        let byte_ref = mir::Type::Ref { ty: Box::new(mir::Type::I8) };
+
+        let l = lir::Root {
+            externs: vec![],
+            imports: vec![],
+            data: vec![],
+            procs: vec![
+                lir::Proc {
+                    ret_type: byte_ptr.clone(),
+                    name: Name::new("f"),
+                    params: vec![
+                        lir::Param { ty: byte_ptr.clone(), name: p, noalias: false, readonly: false, nonnull: false }
+                    ],
+                    visibility: lir::Visibility::Default,
+                    dso_local: false,
+                    noinline: false,
+                    optnone: false,
+                    body: vec![
+                        lir::Stm::Store { dst_addr: lir::Exp::Temp { ty: byte_ref.clone(), name: p }, src: lir::Exp::Lit { lit: mir::Lit::I8 { value: 0 } } },
+                        lir::Stm::Ret { exp: lir::Exp::Temp { ty: byte_ptr.clone(), name: p } },
+                    ],
+                }
+            ]
+        };
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+        let m = t.translate("test_param_used_at_other_type", &l).unwrap();
+        let ir = m.print_to_string();
+        assert!(! ir.contains("alloca"), "unexpected alloca for a param in {}", ir);
+        context.dispose();
+    }
+
+    #[test]
+    fn assume_before_division() {
+        let x = lir::Exp::Temp { ty: mir::Type::I32, name: Name::new("x") };
+        let c = lir::Exp::Temp { ty: mir::Type::I1, name: Name::new("c") };
+        let q = lir::Exp::Temp { ty: mir::Type::I32, name: Name::new("q") };
+
+        let l = lir::Root {
+            externs: vec![],
+            imports: vec![],
+            data: vec![],
+            procs: vec![
+                lir::Proc {
+                    ret_type: mir::Type::I32,
+                    name: Name::new("f"),
+                    params: vec![
+                        lir::Param { ty: mir::Type::I32, name: Name::new("x"), noalias: false, readonly: false, nonnull: false }
+                    ],
+                    visibility: lir::Visibility::Default,
+                    dso_local: false,
+                    noinline: false,
+                    optnone: false,
+                    body: vec![
+                        lir::Stm::Binary { dst: c.clone(), op: Bop::Gt_s_i32, e1: x.clone(), e2: lir::Exp::Lit { lit: mir::Lit::I32 { value: 0 } } },
+                        lir::Stm::Assume { cond: c.clone() },
+                        lir::Stm::Binary { dst: q.clone(), op: Bop::Div_s_i32, e1: lir::Exp::Lit { lit: mir::Lit::I32 { value: 100 } }, e2: x.clone() },
+                        lir::Stm::Ret { exp: q.clone() },
+                    ],
+                }
+            ]
+        };
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+        let m = t.translate("test_assume_before_division", &l).unwrap();
+        let ir = m.print_to_string();
+        assert!(ir.contains("call void @llvm.assume(i1 "), "expected an assume in {}", ir);
+        context.dispose();
+    }
+
+    #[test]
+    fn expect_true() {
+        let h = hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("f"),
+                    params: vec![
+                        hir::Param { ty: hir::Type::I32, name: Name::new("n") }
+                    ],
+                    body: Box::new(
+                        hir::Exp::Cond {
+                            ty: hir::Type::I32,
+                            cond: Box::new(hir::Exp::Unary {
+                                op: Uop::Expect_z { expected: true },
+                                exp: Box::new(hir::Exp::Binary {
+                                    op: Bop::Lt_s_i32,
+                                    e1: Box::new(hir::Exp::Var { ty: hir::Type::I32, name: Name::new("n") }),
+                                    e2: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 10 } }),
+                                }),
+                            }),
+                            if_true: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 1 } }),
+                            if_false: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 2 } }),
+                        }
+                    ),
+                }
+            ]
+        };
+
+        let context = llvm::Context::new();
+        let m = translate_in_context("test_expect_true", &h, context).unwrap();
+        let ir = m.print_to_string();
+        assert!(ir.contains("call i1 @llvm.expect.i1(i1 %"), "expected llvm.expect in {}", ir);
+        assert!(ir.contains(", i1 true)"), "expected the expected value in {}", ir);
+        context.dispose();
+    }
+
+    #[test]
+    fn global_element_addr() {
+        let table_ty = mir::Type::Struct { fields: vec![mir::Type::I32, mir::Type::I32, mir::Type::I32] };
+
+        let l = lir::Root {
+            externs: vec![],
+            imports: vec![],
+            // p comes first, so its initializer refers to a global that isn't defined yet.
+            data: vec![
+                lir::Data {
+                    ty: mir::Type::Ptr { ty: Box::new(mir::Type::I32) },
+                    name: Name::new("p"),
+                    init: lir::Init::ElementAddr { name: Name::new("table"), indices: vec![0, 2] },
+                    thread_local: false,
+                    visibility: lir::Visibility::Default,
+                    dso_local: false,
+                    section: None,
+                    alignment: None,
+                    packed: false,
+                },
+                lir::Data {
+                    ty: table_ty.clone(),
+                    name: Name::new("table"),
+                    init: lir::Init::Lit { lit: mir::Lit::Null { ty: table_ty.clone() } },
+                    thread_local: false,
+                    visibility: lir::Visibility::Default,
+                    dso_local: false,
+                    section: None,
+                    alignment: None,
+                    packed: false,
+                },
+            ],
+            procs: vec![],
+        };
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+        let m = t.translate("test_global_element_addr", &l).unwrap();
+        let ir = m.print_to_string();
+        assert!(ir.contains("@p = global i32* getelementptr inbounds"), "expected a constant gep in {}", ir);
+        assert!(ir.contains("@table, i32 0, i32 2)"), "expected element 2 of table in {}", ir);
+        context.dispose();
+    }
+
+    #[test]
+    fn thread_local_global() {
+        let h = hir::Root {
+            defs: vec![
+                hir::Def::VarDef {
+                    ty: hir::Type::I64,
+                    name: Name::new("counter"),
+                    exp: Box::new(hir::Exp::Lit { lit: hir::Lit::I64 { value: 0 } }),
+                    thread_local: true,
+                },
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I64,
+                    name: Name::new("get"),
+                    params: vec![],
+                    body: Box::new(hir::Exp::Global { ty: hir::Type::I64, name: Name::new("counter") }),
+                },
+            ]
+        };
+
+        let context = llvm::Context::new();
+        let m = translate_in_context("test_thread_local_global", &h, context).unwrap();
+        let ir = m.print_to_string();
+        assert!(ir.contains("@counter = thread_local global i64 0"), "expected a thread-local global in {}", ir);
+        context.dispose();
+    }
+
+    #[test]
+    fn jump_to_undefined_label() {
+        let missing = Name::new("L.missing");
+
+        let l = lir::Root {
+            externs: vec![],
+            imports: vec![],
+            data: vec![],
+            procs: vec![
+                lir::Proc {
+                    ret_type: mir::Type::I32,
+                    name: Name::new("f"),
+                    params: vec![],
+                    visibility: lir::Visibility::Default,
+                    dso_local: false,
+                    noinline: false,
+                    optnone: false,
+                    body: vec![
+                        lir::Stm::Jump { label: missing, loop_hints: vec![] },
+                        lir::Stm::Ret { exp: lir::Exp::Lit { lit: mir::Lit::I32 { value: 0 } } },
+                    ],
+                }
+            ]
+        };
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+        match t.translate("test_jump_to_undefined_label", &l) {
+            Err(CodegenError::UndefinedLabels { name, labels }) => {
+                assert_eq!(name, Name::new("f"));
+                assert_eq!(labels, vec![missing]);
+            },
+            r => panic!("expected UndefinedLabels, got {:?}", r.err()),
+        }
+        context.dispose();
+    }
+
+    #[test]
+    fn drop_statements_after_ret() {
+        let x = Name::new("x");
+
+        let l = lir::Root {
+            externs: vec![],
+            imports: vec![],
+            data: vec![],
+            procs: vec![
+                lir::Proc {
+                    ret_type: mir::Type::I32,
+                    name: Name::new("f"),
+                    params: vec![],
+                    visibility: lir::Visibility::Default,
+                    dso_local: false,
+                    noinline: false,
+                    optnone: false,
+                    body: vec![
+                        lir::Stm::Ret { exp: lir::Exp::Lit { lit: mir::Lit::I32 { value: 1 } } },
+                        lir::Stm::Move { dst: lir::Exp::Temp { ty: mir::Type::I32, name: x }, src: lir::Exp::Lit { lit: mir::Lit::I32 { value: 2 } } },
+                        lir::Stm::Ret { exp: lir::Exp::Temp { ty: mir::Type::I32, name: x } },
+                    ],
+                }
+            ]
+        };
+
+        let context = llvm::Context::new();
+        let mut t = llvm_gen::Translate::new_in_context(context);
+        let m = t.translate("test_drop_statements_after_ret", &l).unwrap();
+        let ir = m.print_to_string();
+        assert_eq!(ir.matches("ret i32").count(), 1, "expected a single ret in {}", ir);
+        assert!(! ir.contains("store i32 2"), "unreachable store not dropped in {}", ir);
+
+        t.options.drop_unreachable = false;
+        match t.translate("test_reject_statements_after_ret", &l) {
+            Err(CodegenError::UnreachableStatement { name }) => assert_eq!(name, Name::new("f")),
+            r => panic!("expected UnreachableStatement, got {:?}", r.err()),
+        }
+        context.dispose();
+    }
+
+    #[test]
+    fn symbol_references_do_not_allocate() {
+        use crate::lir::builder::Builder;
+
+        let n = 1000;
+        let g = Name::new("g");
+        let f = Name::new("f");
+        let g_ty = mir::Type::Ptr { ty: Box::new(mir::Type::I32) };
+        let f_ty = mir::Type::Ptr { ty: Box::new(mir::Type::Fun { ret: Box::new(mir::Type::I32), args: vec![] }) };
+
+        // f() = 0, and main moves n references to g and f, or n nulls of the same types, into temps.
+        let root = |symbols: bool| {
+            let mut callee = Builder::new(f, mir::Type::I32);
+            callee.ret(lir::Exp::Lit { lit: mir::Lit::I32 { value: 0 } });
+
+            let mut main = Builder::new(Name::new("main"), mir::Type::I32);
+            for _ in 0..n {
+                let p = main.temp(g_ty.clone());
+                let q = main.temp(f_ty.clone());
+                if symbols {
+                    main.mov(&p, lir::Exp::GlobalAddr { ty: g_ty.clone(), name: g });
+                    main.mov(&q, lir::Exp::FunctionAddr { ty: f_ty.clone(), name: f });
+                }
+                else {
+                    main.mov(&p, lir::Exp::Lit { lit: mir::Lit::Null { ty: g_ty.clone() } });
+                    main.mov(&q, lir::Exp::Lit { lit: mir::Lit::Null { ty: f_ty.clone() } });
+                }
+            }
+            main.ret(lir::Exp::Lit { lit: mir::Lit::I32 { value: 0 } });
+
+            lir::Root {
+                externs: vec![],
+                imports: vec![],
+                data: vec![
+                    lir::Data { ty: mir::Type::I32, name: g, init: lir::Init::Lit { lit: mir::Lit::I32 { value: 0 } }, thread_local: false, visibility: lir::Visibility::Default, dso_local: false, section: None, alignment: None, packed: false }
+                ],
+                procs: vec![callee.finish(), main.finish()],
+            }
+        };
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+        let count = |l: &lir::Root| {
+            let before = allocations();
+            t.translate("test_symbol_references_do_not_allocate", l).unwrap();
+            allocations() - before
+        };
+        let with_symbols = count(&root(true));
+        let with_nulls = count(&root(false));
+        // Looking up a symbol shouldn't format its name, so the references cost no more than the nulls.
+        assert!(with_symbols <= with_nulls, "{} allocations with {} symbol references, {} with nulls", with_symbols, 2 * n, with_nulls);
+        context.dispose();
+    }
+
+    #[test]
+    fn i32_lit_into_i64_temp() {
+        let x = Name::new("x");
+
+        let l = lir::Root {
+            externs: vec![],
+            imports: vec![],
+            data: vec![],
+            procs: vec![
+                lir::Proc {
+                    ret_type: mir::Type::I64,
+                    name: Name::new("f"),
+                    params: vec![],
+                    visibility: lir::Visibility::Default,
+                    dso_local: false,
+                    noinline: false,
+                    optnone: false,
+                    body: vec![
+                        lir::Stm::Move { dst: lir::Exp::Temp { ty: mir::Type::I64, name: x }, src: lir::Exp::Lit { lit: mir::Lit::I32 { value: 1 } } },
+                        lir::Stm::Ret { exp: lir::Exp::Temp { ty: mir::Type::I64, name: x } },
+                    ],
+                }
+            ]
+        };
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+        let r = t.translate("test_i32_lit_into_i64_temp", &l);
+        assert_eq!(r.err(), Some(CodegenError::TypeMismatch { expected: mir::Type::I64, found: mir::Type::I32 }));
+        context.dispose();
+    }
+
+    #[test]
+    fn bool_lit_lowers_to_i1() {
+        let h = hir::Root {
+            defs: vec![
+                hir::Def::VarDef {
+                    ty: hir::Type::Bool,
+                    name: Name::new("b"),
+                    exp: Box::new(hir::Exp::Lit { lit: hir::Lit::Bool { value: true } }),
+                    thread_local: false,
+                },
+                hir::Def::FunDef {
+                    ret_type: hir::Type::Bool,
+                    name: Name::new("yes"),
+                    params: vec![],
+                    body: Box::new(hir::Exp::Lit { lit: hir::Lit::Bool { value: true } }),
+                },
+            ]
+        };
+
+        let l = translate_lir("test_bool_lit_lowers_to_i1", &h).unwrap();
+        let yes = l.procs.iter().find(|p| p.name == Name::new("yes")).unwrap();
+        assert_eq!(yes.ret_type, mir::Type::I1);
+
+        let context = llvm::Context::new();
+        let m = translate_in_context("test_bool_lit_lowers_to_i1", &h, context).unwrap();
+        let ir = m.print_to_string();
+        assert!(ir.contains("@b = global i1 true"), "expected an i1 global in {}", ir);
+        assert!(ir.contains("ret i1 true"), "expected an i1 return in {}", ir);
+        context.dispose();
+    }
+
+    #[test]
+    fn new_reserves_header_word() {
+        let pair = mir::Type::Struct { fields: vec![mir::Type::I64, mir::Type::I64] };
+        let pair_ref = mir::Type::Ref { ty: Box::new(pair.clone()) };
+        let p = Name::new("p");
+
+        let l = lir::Root {
+            externs: vec![],
+            imports: vec![],
+            data: vec![],
+            procs: vec![
+                lir::Proc {
+                    ret_type: pair_ref.clone(),
+                    name: Name::new("f"),
+                    params: vec![],
+                    visibility: lir::Visibility::Default,
+                    dso_local: false,
+                    noinline: false,
+                    optnone: false,
+                    body: vec![
+                        lir::Stm::New { dst: lir::Exp::Temp { ty: pair_ref.clone(), name: p }, ty: pair.clone() },
+                        lir::Stm::Ret { exp: lir::Exp::Temp { ty: pair_ref.clone(), name: p } },
+                    ],
+                }
+            ]
+        };
+
+        let context = llvm::Context::new();
+        let mut t = llvm_gen::Translate::new_in_context(context);
+        t.options.box_header_words = 1;
+        let m = t.translate("test_new_reserves_header_word", &l).unwrap();
+        let ir = m.print_to_string();
+        // 16 bytes for the struct plus 8 for the header, and the object starts after the header.
+        assert!(ir.contains("@malloc(i64 24)"), "expected room for the header in {}", ir);
+        let gep = ir.lines().find(|l| l.contains("getelementptr inbounds i8, i8*")).expect("expected a pointer past the header");
+        assert!(gep.trim_end().ends_with("i64 8"), "expected an 8-byte offset in {}", gep);
+        assert_eq!(m.verify(), Ok(()));
+        context.dispose();
+    }
+
+    #[test]
+    fn box_reserves_header_word() {
+        let h = hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::Box,
+                    name: Name::new("f"),
+                    params: vec![],
+                    body: Box::new(hir::Exp::Box { ty: hir::Type::I64, exp: Box::new(hir::Exp::Lit { lit: hir::Lit::I64 { value: 1 } }) }),
+                }
+            ]
+        };
+
+        let l = translate_lir("test_box_reserves_header_word", &h).unwrap();
+
+        let context = llvm::Context::new();
+        let mut t = llvm_gen::Translate::new_in_context(context);
+        t.options.box_header_words = 1;
+        let m = t.translate("test_box_reserves_header_word", &l).unwrap();
+        let ir = m.print_to_string();
+        // box_i64 is defined in the module rather than left to the runtime, and allocates
+        // 8 bytes for the value plus 8 for the header.
+        assert!(ir.contains("define internal"), "expected box_i64 to be defined in {}", ir);
+        assert!(ir.contains("@malloc(i64 16)"), "expected room for the header in {}", ir);
+        assert_eq!(m.verify(), Ok(()));
+        context.dispose();
+
+        // Without a header, the runtime's box_i64 is used.
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+        let m = t.translate("test_box_without_header", &l).unwrap();
+        assert!(! m.print_to_string().contains("define internal"));
+        context.dispose();
+    }
+
+    #[test]
+    fn box_registers_finalizer() {
+        use std::collections::HashSet;
+
+        let h = hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::Box,
+                    name: Name::new("with_finalizer"),
+                    params: vec![],
+                    body: Box::new(hir::Exp::Box { ty: hir::Type::I64, exp: Box::new(hir::Exp::Lit { lit: hir::Lit::I64 { value: 1 } }) }),
+                },
+                hir::Def::FunDef {
+                    ret_type: hir::Type::Box,
+                    name: Name::new("plain"),
+                    params: vec![],
+                    body: Box::new(hir::Exp::Box { ty: hir::Type::I32, exp: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 1 } }) }),
+                },
+            ]
+        };
+
+        let mut finalizers = HashSet::new();
+        finalizers.insert(mir::Type::I64);
+
+        let m = mir_gen::Translate::translate_with_finalizers(&h, &finalizers).unwrap();
+        let l = lir_gen::Translate::translate(&m);
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+        let module = t.translate("test_box_registers_finalizer", &l).unwrap();
+        let ir = module.print_to_string();
+        assert_eq!(ir.matches("call void @rivo_register_finalizer").count(), 1, "expected one registration in {}", ir);
+        assert!(ir.contains("@box_i32"), "expected the plain box in {}", ir);
+        // The registration call's void result has no alloca or store.
+        assert!(! ir.contains("alloca void") && ! ir.contains("store void"), "unexpected void temp in {}", ir);
+        assert_eq!(module.verify(), Ok(()));
+        context.dispose();
+    }
+
+    #[test]
+    fn struct_in_registers() {
+        use crate::lir::builder::Builder;
+
+        // s = {3, 4}; return s.0 * 10 + s.1
+        let mut b = Builder::new(Name::new("f"), mir::Type::I32);
+        let s = b.build_struct(vec![
+            lir::Exp::Lit { lit: mir::Lit::I32 { value: 3 } },
+            lir::Exp::Lit { lit: mir::Lit::I32 { value: 4 } },
+        ]);
+        let x = b.extract(s.clone(), 0);
+        let y = b.extract(s, 1);
+        let x10 = b.binary(Bop::Mul_i32, x, lir::Exp::Lit { lit: mir::Lit::I32 { value: 10 } });
+        let r = b.binary(Bop::Add_i32, x10, y);
+        b.ret(r);
+
+        let l = lir::Root {
+            externs: vec![],
+            imports: vec![],
+            data: vec![],
+            procs: vec![b.finish()],
+        };
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+        let m = t.translate("test_struct_in_registers", &l).unwrap();
+        let ir = m.print_to_string();
+        assert!(ir.contains("insertvalue { i32, i32 }"), "expected insertvalue in {}", ir);
+        assert_eq!(ir.matches("extractvalue { i32, i32 }").count(), 2, "expected two extractvalues in {}", ir);
+        assert!(! ir.contains("call i8* @malloc"), "unexpected allocation in {}", ir);
+        context.dispose();
+    }
+
+    #[test]
+    fn hir_struct_in_registers() {
+        let struct_ty = hir::Type::Struct { fields: vec![
+            hir::Param { ty: hir::Type::I32, name: Name::new("f") },
+            hir::Param { ty: hir::Type::I32, name: Name::new("g") },
+        ] };
+        let s = Name::new("s");
+        let field = |name, value| hir::Field {
+            param: hir::Param { ty: hir::Type::I32, name: Name::new(name) },
+            exp: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value } }),
+        };
+        let load = |name| Box::new(hir::Exp::StructLoad { ty: struct_ty.clone(), base: Box::new(hir::Exp::Var { ty: struct_ty.clone(), name: s }), field: Name::new(name) });
+
+        // let s = { f: 3, g: 4 } in (stm; s.f + s.g)
+        let root = |stm| hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("main"),
+                    params: vec![],
+                    body: Box::new(hir::Exp::Let {
+                        inits: vec![
+                            hir::Field {
+                                param: hir::Param { ty: struct_ty.clone(), name: s },
+                                exp: Box::new(hir::Exp::StructLit { fields: vec![field("f", 3), field("g", 4)] }),
+                            }
+                        ],
+                        body: Box::new(hir::Exp::Seq {
+                            body: Box::new(stm),
+                            exp: Box::new(hir::Exp::Binary { op: Bop::Add_i32, e1: load("f"), e2: load("g") }),
+                        }),
+                    }),
+                }
+            ]
+        };
+
+        let context = llvm::Context::new();
+
+        // s is only loaded from, so it's never allocated.
+        let m = translate_in_context("test_hir_struct_in_registers", &root(hir::Stm::Block { body: vec![] }), context).unwrap();
+        let ir = m.print_to_string();
+        assert!(ir.contains("insertvalue { i32, i32 }"), "expected insertvalue in {}", ir);
+        assert_eq!(ir.matches("extractvalue { i32, i32 }").count(), 2, "expected two extractvalues in {}", ir);
+        assert!(! ir.contains("call i8* @malloc"), "unexpected allocation in {}", ir);
+
+        // s.f = 5 needs s in memory.
+        let assign = hir::Stm::StructAssign {
+            ty: struct_ty.clone(),
+            base: Box::new(hir::Exp::Var { ty: struct_ty.clone(), name: s }),
+            field: Name::new("f"),
+            value: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 5 } }),
+        };
+        let m = translate_in_context("test_hir_struct_in_memory", &root(assign), context).unwrap();
+        let ir = m.print_to_string();
+        assert!(! ir.contains("extractvalue"), "unexpected extractvalue in {}", ir);
+        assert!(ir.contains("call i8* @malloc"), "expected an allocation in {}", ir);
+        context.dispose();
+    }
+
+    #[test]
+    fn register_aggregate_threshold() {
+        use crate::lir::builder::Builder;
+
+        // Build a struct of n i32s and return its last field.
+        fn root(n: usize) -> lir::Root {
+            let mut b = Builder::new(Name::new("f"), mir::Type::I32);
+            let fields = (0..n).map(|i| lir::Exp::Lit { lit: mir::Lit::I32 { value: i as i32 } }).collect();
+            let s = b.build_struct(fields);
+            let x = b.extract(s, n - 1);
+            b.ret(x);
+            lir::Root { externs: vec![], imports: vec![], data: vec![], procs: vec![b.finish()] }
+        }
+
+        let context = llvm::Context::new();
+        let mut t = llvm_gen::Translate::new_in_context(context);
+        t.options.max_register_aggregate_bytes = 8;
+
+        // Every temp has an alloca, so look for how the fields are accessed instead.
+        let m = t.translate("test_register_aggregate_under", &root(2)).unwrap();
+        let ir = m.print_to_string();
+        assert!(ir.contains("insertvalue"), "expected a register struct in {}", ir);
+        assert!(! ir.contains("getelementptr"), "unexpected memory access in {}", ir);
+
+        let m = t.translate("test_register_aggregate_over", &root(3)).unwrap();
+        let ir = m.print_to_string();
+        assert!(! ir.contains("insertvalue"), "unexpected register struct in {}", ir);
+        assert!(ir.contains("getelementptr inbounds { i32, i32, i32 }"), "expected a memory struct in {}", ir);
+        context.dispose();
+    }
+
+    #[test]
+    fn ret_i32_from_i64_proc() {
+        let l = lir::Root {
+            externs: vec![],
+            imports: vec![],
+            data: vec![],
+            procs: vec![
+                lir::Proc {
+                    ret_type: mir::Type::I64,
+                    name: Name::new("f"),
+                    params: vec![],
+                    visibility: lir::Visibility::Default,
+                    dso_local: false,
+                    noinline: false,
+                    optnone: false,
+                    body: vec![
+                        lir::Stm::Ret { exp: lir::Exp::Lit { lit: mir::Lit::I32 { value: 1 } } },
+                    ],
+                }
+            ]
+        };
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+        let r = t.translate("test_ret_i32_from_i64_proc", &l);
+        assert_eq!(r.err(), Some(CodegenError::ReturnTypeMismatch { name: Name::new("f"), expected: mir::Type::I64, found: mir::Type::I32 }));
+        context.dispose();
+    }
+
+    #[test]
+    fn box_empty_struct_allocates_nothing() {
+        let h = hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::Box,
+                    name: Name::new("f"),
+                    params: vec![],
+                    body: Box::new(
+                        hir::Exp::Box {
+                            ty: hir::Type::Struct { fields: vec![] },
+                            exp: Box::new(hir::Exp::StructLit { fields: vec![] }),
+                        }
+                    ),
+                }
+            ]
+        };
+
+        let context = llvm::Context::new();
+        let m = translate_in_context("test_box_empty_struct_allocates_nothing", &h, context).unwrap();
+        let ir = m.print_to_string();
+        assert!(! ir.contains("call i8* @malloc"), "unexpected allocation in {}", ir);
+        assert!(! ir.lines().any(|l| l.contains(" call ") && l.contains("@box_")), "unexpected box call in {}", ir);
+        context.dispose();
+    }
+
+    #[test]
+    fn unbox_empty_struct_round_trip() {
+        let empty = hir::Type::Struct { fields: vec![] };
+
+        // f() = box {}; g() = unbox f()
+        // The call keeps simplify from folding the unbox into the box.
+        let h = hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::Box,
+                    name: Name::new("f"),
+                    params: vec![],
+                    body: Box::new(hir::Exp::Box { ty: empty.clone(), exp: Box::new(hir::Exp::StructLit { fields: vec![] }) }),
+                },
+                hir::Def::FunDef {
+                    ret_type: empty.clone(),
+                    name: Name::new("g"),
+                    params: vec![],
+                    body: Box::new(hir::Exp::Unbox {
+                        ty: empty.clone(),
+                        exp: Box::new(hir::Exp::Call {
+                            fun_type: hir::Type::Fun { ret: Box::new(hir::Type::Box), args: vec![] },
+                            name: Name::new("f"),
+                            args: vec![],
+                        }),
+                    }),
+                },
+            ]
+        };
+
+        let context = llvm::Context::new();
+        let m = translate_in_context("test_unbox_empty_struct_round_trip", &h, context).unwrap();
+        let ir = m.print_to_string();
+        assert!(! ir.lines().any(|l| l.contains(" call ") && l.contains("@unbox_")), "unexpected unbox call in {}", ir);
+        assert_eq!(m.verify(), Ok(()));
+        context.dispose();
+    }
+
+    #[test]
+    fn leading_label_is_entry() {
+        let start = Name::new("L.start");
+
+        let l = lir::Root {
+            externs: vec![],
+            imports: vec![],
+            data: vec![],
+            procs: vec![
+                lir::Proc {
+                    ret_type: mir::Type::I32,
+                    name: Name::new("f"),
+                    params: vec![],
+                    visibility: lir::Visibility::Default,
+                    dso_local: false,
+                    noinline: false,
+                    optnone: false,
+                    body: vec![
+                        lir::Stm::Label { label: start },
+                        lir::Stm::Ret { exp: lir::Exp::Lit { lit: mir::Lit::I32 { value: 0 } } },
+                    ],
+                }
+            ]
+        };
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+        let m = t.translate("test_leading_label_is_entry", &l).unwrap();
+        let ir = m.print_to_string();
+        assert!(! ir.contains("entry:"), "unexpected separate entry block in {}", ir);
+        assert!(! ir.contains("br label"), "unexpected fallthrough branch in {}", ir);
+        context.dispose();
+    }
+
+    #[test]
+    fn imported_global() {
+        let g = Name::new("other_g");
+        let x = Name::new("x");
+        let i32_ptr = mir::Type::Ptr { ty: Box::new(mir::Type::I32) };
+
+        let root = |imports| lir::Root {
+            externs: vec![],
+            imports,
+            data: vec![],
+            procs: vec![
+                lir::Proc {
+                    ret_type: mir::Type::I32,
+                    name: Name::new("f"),
+                    params: vec![],
+                    visibility: lir::Visibility::Default,
+                    dso_local: false,
+                    noinline: false,
+                    optnone: false,
+                    body: vec![
+                        lir::Stm::Load { dst: lir::Exp::Temp { ty: mir::Type::I32, name: x }, src_addr: lir::Exp::GlobalAddr { ty: i32_ptr.clone(), name: g } },
+                        lir::Stm::Ret { exp: lir::Exp::Temp { ty: mir::Type::I32, name: x } },
+                    ],
+                }
+            ]
+        };
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+
+        let imports = vec![lir::Param { ty: mir::Type::I32, name: g, noalias: false, readonly: false, nonnull: false }];
+        let m = t.translate("test_imported_global", &root(imports)).unwrap();
+        let ir = m.print_to_string();
+        assert!(ir.contains("@other_g = external global i32"), "expected an external declaration in {}", ir);
+
+        let r = t.translate("test_missing_global", &root(vec![]));
+        assert_eq!(r.err(), Some(CodegenError::UndefinedGlobal { name: g }));
+        context.dispose();
+    }
+
+    #[test]
+    fn closure_hoisted_out_of_loop() {
+        // i = 0; s = 0; while (i < 10) { s = s + (\y -> y + x)(i); i = i + 1 }; s
+        // The closure only captures x, so it can be allocated once before the loop.
+        let var = |name: &str| hir::Exp::Var { ty: hir::Type::I32, name: Name::new(name) };
+        let lit = |value: i32| hir::Exp::Lit { lit: hir::Lit::I32 { value } };
+        let add = |e1, e2| hir::Exp::Binary { op: Bop::Add_i32, e1: Box::new(e1), e2: Box::new(e2) };
+        let int_to_int = hir::Type::Fun { ret: Box::new(hir::Type::I32), args: vec![hir::Type::I32] };
+
+        let h = hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("sum"),
+                    params: vec![
+                        hir::Param { ty: hir::Type::I32, name: Name::new("x") }
+                    ],
+                    body: Box::new(hir::Exp::Seq {
+                        body: Box::new(hir::Stm::Block {
+                            body: vec![
+                                hir::Stm::Assign { ty: hir::Type::I32, lhs: Name::new("i"), rhs: Box::new(lit(0)) },
+                                hir::Stm::Assign { ty: hir::Type::I32, lhs: Name::new("s"), rhs: Box::new(lit(0)) },
+                                hir::Stm::While {
+                                    cond: Box::new(hir::Exp::Binary { op: Bop::Lt_s_i32, e1: Box::new(var("i")), e2: Box::new(lit(10)) }),
+                                    body: Box::new(hir::Stm::Block {
+                                        body: vec![
+                                            hir::Stm::Assign {
+                                                ty: hir::Type::I32,
+                                                lhs: Name::new("s"),
+                                                rhs: Box::new(add(
+                                                    var("s"),
+                                                    hir::Exp::Apply {
+                                                        fun_type: int_to_int.clone(),
+                                                        fun: Box::new(hir::Exp::Lambda {
+                                                            ret_type: hir::Type::I32,
+                                                            params: vec![
+                                                                hir::Param { ty: hir::Type::I32, name: Name::new("y") }
+                                                            ],
+                                                            body: Box::new(add(var("y"), var("x"))),
+                                                        }),
+                                                        args: vec![var("i")],
+                                                    },
+                                                )),
+                                            },
+                                            hir::Stm::Assign { ty: hir::Type::I32, lhs: Name::new("i"), rhs: Box::new(add(var("i"), lit(1))) },
+                                        ]
+                                    }),
+                                    hints: vec![],
+                                },
+                            ]
+                        }),
+                        exp: Box::new(var("s")),
+                    }),
+                }
+            ]
+        };
+
+        let l = translate_lir("test_closure_hoisted_out_of_loop", &h).unwrap();
+        let p = l.procs.iter().find(|p| p.name == Name::new("sum")).unwrap();
+
+        // The loop runs from its header label to the jump back to it.
+        let label_index = |label: &Name| p.body.iter().position(|s| match s { lir::Stm::Label { label: l } => l == label, _ => false });
+        let (header, latch) = p.body.iter().enumerate().filter_map(|(i, s)| match s {
+            lir::Stm::Jump { label, .. } => label_index(label).filter(|h| *h < i).map(|h| (h, i)),
+            _ => None,
+        }).next().unwrap();
+
+        let is_new = |s: &&lir::Stm| match s { lir::Stm::New { .. } => true, _ => false };
+
+        // Both the closure and its environment are allocated, but only before the loop.
+        assert_eq!(p.body[..header].iter().filter(is_new).count(), 2);
+        assert_eq!(p.body[header..=latch].iter().filter(is_new).count(), 0);
+    }
+
+    #[test]
+    fn hidden_dso_local_function() {
+        let proc = |name, visibility, dso_local| lir::Proc {
+            ret_type: mir::Type::I32,
+            name: Name::new(name),
+            params: vec![],
+            visibility,
+            dso_local,
+            noinline: false,
+            optnone: false,
+            body: vec![
+                lir::Stm::Ret { exp: lir::Exp::Lit { lit: mir::Lit::I32 { value: 0 } } }
+            ],
+        };
+
+        let l = lir::Root {
+            externs: vec![],
+            imports: vec![],
+            data: vec![],
+            procs: vec![
+                proc("internal_api", lir::Visibility::Hidden, true),
+                proc("exported", lir::Visibility::Protected, true),
+                proc("preemptible", lir::Visibility::Default, false),
+            ]
+        };
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+        let m = t.translate("test_hidden_dso_local_function", &l).unwrap();
+        let ir = m.print_to_string();
+
+        // LLVM doesn't print dso_local for hidden or protected symbols, since it's implied.
+        assert!(ir.contains("define hidden i32 @internal_api()"), "expected a hidden function in {}", ir);
+        assert!(ir.contains("define protected i32 @exported()"), "expected a protected function in {}", ir);
+        assert!(ir.contains("define i32 @preemptible()"), "expected a default visibility function in {}", ir);
+
+        // Default visibility can't be made dso_local, and isn't quietly made protected.
+        let l = lir::Root { externs: vec![], imports: vec![], data: vec![], procs: vec![proc("exported", lir::Visibility::Default, true)] };
+        let r = t.translate("test_default_dso_local_function", &l);
+        assert_eq!(r.err(), Some(CodegenError::DefaultVisibilityDsoLocal { name: Name::new("exported") }));
+        context.dispose();
+    }
+
+    #[test]
+    fn large_struct_argument_byval() {
+        use crate::lir::builder::Builder;
+
+        // A struct of three i64s is too big to pass in registers.
+        let struct_ty = mir::Type::Struct { fields: vec![mir::Type::I64; 3] };
+
+        let mut f = Builder::new(Name::new("f"), mir::Type::I64);
+        let s = f.param(struct_ty.clone());
+        let x = f.extract(s, 1);
+        f.ret(x);
+
+        let mut main = Builder::new(Name::new("main"), mir::Type::I64);
+        let s = main.build_struct((0..3).map(|i| lir::Exp::Lit { lit: mir::Lit::I64 { value: i } }).collect());
+        let fun = lir::Exp::FunctionAddr {
+            ty: mir::Type::Fun { ret: Box::new(mir::Type::I64), args: vec![struct_ty] },
+            name: Name::new("f"),
+        };
+        let r = main.call(mir::Type::I64, fun.clone(), vec![s]);
+        // A constant argument, outside the entry block, needs its own copy.
+        let next = main.label();
+        main.jump(next);
+        main.place(next);
+        let zero = lir::Exp::Lit { lit: mir::Lit::Null { ty: struct_ty.clone() } };
+        let r2 = main.call(mir::Type::I64, fun, vec![zero]);
+        let sum = main.binary(Bop::Add_i64, r, r2);
+        main.ret(sum);
+
+        let l = lir::Root { externs: vec![], imports: vec![], data: vec![], procs: vec![f.finish(), main.finish()] };
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+        let m = t.translate("test_large_struct_argument_byval", &l).unwrap();
+        let ir = m.print_to_string();
+        assert!(ir.contains("define i64 @f({ i64, i64, i64 }* byval"), "expected a byval param in {}", ir);
+        assert!(ir.contains("call i64 @f({ i64, i64, i64 }* byval"), "expected a byval argument in {}", ir);
+
+        // The copy's alloca is in the entry block.
+        let bbs = m.find_function("main").unwrap().basic_blocks();
+        assert!(bbs[0].instructions().iter().any(|insn| insn.is_alloca()), "expected allocas in the entry block of {}", ir);
+        assert!(bbs[1..].iter().all(|bb| ! bb.instructions().iter().any(|insn| insn.is_alloca())), "unexpected alloca outside the entry block of {}", ir);
+        assert_eq!(m.verify(), Ok(()));
+        context.dispose();
+    }
+
+    #[test]
+    fn reserved_global_name() {
+        let g = Name::new("llvm.fabs.f64");
+
+        let l = lir::Root {
+            externs: vec![],
+            imports: vec![],
+            data: vec![
+                lir::Data { ty: mir::Type::F64, name: g, init: lir::Init::Lit { lit: mir::Lit::F64 { value: 1.0 } }, thread_local: false, visibility: lir::Visibility::Default, dso_local: false, section: None, alignment: None, packed: false }
+            ],
+            procs: vec![],
+        };
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+        let r = t.translate("test_reserved_global_name", &l);
+        assert_eq!(r.err(), Some(CodegenError::ReservedName { name: g }));
+        context.dispose();
+    }
+
+    #[test]
+    fn invoke_unwinds_to_landing_pad() {
+        use crate::lir::builder::Builder;
+
+        // main() = invoke throws() to normal: return r; unwind: cleanup, return 0
+        let throws_ty = mir::Type::Fun { ret: Box::new(mir::Type::I32), args: vec![] };
+
+        let mut main = Builder::new(Name::new("main"), mir::Type::I32);
+        let normal = main.label();
+        let unwind = main.label();
+        let r = main.invoke(mir::Type::I32, lir::Exp::FunctionAddr { ty: throws_ty.clone(), name: Name::new("throws") }, vec![], normal, unwind);
+        main.place(normal);
+        main.ret(r);
+        main.place(unwind);
+        main.landing_pad(true, vec![]);
+        main.ret(lir::Exp::Lit { lit: mir::Lit::I32 { value: 0 } });
+
+        let l = lir::Root {
+            externs: vec![lir::Param { ty: throws_ty, name: Name::new("throws"), noalias: false, readonly: false, nonnull: false }],
+            imports: vec![],
+            data: vec![],
+            procs: vec![main.finish()],
+        };
+
+        let context = llvm::Context::new();
+        let mut t = llvm_gen::Translate::new_in_context(context);
+
+        let r = t.translate("test_invoke_disabled", &l);
+        assert_eq!(r.err(), Some(CodegenError::UnwindingDisabled { name: Name::new("main") }));
+
+        t.options.unwinding = true;
+        t.set_personality(Name::new("__gxx_personality_v0"));
+        let m = t.translate("test_invoke_unwinds_to_landing_pad", &l).unwrap();
+        let ir = m.print_to_string();
+        assert!(ir.contains("invoke i32 @throws()"), "expected an invoke in {}", ir);
+        assert!(ir.contains(&format!("unwind label %{}", unwind)), "expected an unwind edge to {} in {}", unwind, ir);
+        assert!(ir.contains("landingpad { i8*, i32 }"), "expected a landingpad in {}", ir);
+        assert!(ir.contains("cleanup"), "expected a cleanup clause in {}", ir);
+        context.dispose();
+    }
+
+    #[test]
+    fn personality_on_invoking_function() {
+        use crate::lir::builder::Builder;
+
+        let throws_ty = mir::Type::Fun { ret: Box::new(mir::Type::I32), args: vec![] };
+        let throws = lir::Exp::FunctionAddr { ty: throws_ty.clone(), name: Name::new("throws") };
+
+        // f() = invoke throws(), returning 0 if it throws.
+        let mut f = Builder::new(Name::new("f"), mir::Type::I32);
+        let normal = f.label();
+        let unwind = f.label();
+        let r = f.invoke(mir::Type::I32, throws.clone(), vec![], normal, unwind);
+        f.place(normal);
+        f.ret(r);
+        f.place(unwind);
+        f.landing_pad(true, vec![]);
+        f.ret(lir::Exp::Lit { lit: mir::Lit::I32 { value: 0 } });
+
+        // g() = throws(), which doesn't need a personality.
+        let mut g = Builder::new(Name::new("g"), mir::Type::I32);
+        let r = g.call(mir::Type::I32, throws, vec![]);
+        g.ret(r);
+
+        let l = lir::Root {
+            externs: vec![lir::Param { ty: throws_ty, name: Name::new("throws"), noalias: false, readonly: false, nonnull: false }],
+            imports: vec![],
+            data: vec![],
+            procs: vec![f.finish(), g.finish()],
+        };
+
+        let context = llvm::Context::new();
+        let mut t = llvm_gen::Translate::new_in_context(context);
+        t.options.unwinding = true;
+
+        let r = t.translate("test_missing_personality", &l);
+        assert_eq!(r.err(), Some(CodegenError::MissingPersonality { name: Name::new("f") }));
+
+        t.set_personality(Name::new("__gxx_personality_v0"));
+        let m = t.translate("test_personality_on_invoking_function", &l).unwrap();
+        let ir = m.print_to_string();
+        let define = |name: &str| ir.lines().find(|s| s.starts_with("define") && s.contains(name)).unwrap().to_string();
+        assert!(define("@f(").contains("personality") && define("@f(").contains("@__gxx_personality_v0"), "expected a personality on f in {}", ir);
+        assert!(! define("@g(").contains("personality"), "expected no personality on g in {}", ir);
+        assert!(ir.contains("declare i32 @__gxx_personality_v0(...)"), "expected the personality to be declared in {}", ir);
+        context.dispose();
+    }
+
+    #[test]
+    fn op_coverage() {
+        use crate::mir::typed::Typed;
+
+        // Which ops llvm_gen implements. There's no wildcard, so a new op has to be added
+        // here, and an op that stops translating fails the checks below.
+        fn uop_supported(op: Uop) -> bool {
+            match op {
+                Uop::Logb_f32 | Uop::Asin_f32 | Uop::Acos_f32 | Uop::Atan_f32 | Uop::Sinh_f32 | Uop::Cosh_f32 |
+                Uop::Tanh_f32 | Uop::Asinh_f32 | Uop::Acosh_f32 | Uop::Atanh_f32 | Uop::IsNan_f32 | Uop::IsInf_f32 |
+                Uop::IsDenormalized_f32 | Uop::IsNegativeZero_f32 | Uop::IsIEEE_f32 | Uop::Logb_f64 | Uop::Asin_f64 |
+                Uop::Acos_f64 | Uop::Atan_f64 | Uop::Sinh_f64 | Uop::Cosh_f64 | Uop::Tanh_f64 | Uop::Asinh_f64 |
+                Uop::Acosh_f64 | Uop::Atanh_f64 | Uop::IsNan_f64 | Uop::IsInf_f64 | Uop::IsDenormalized_f64 |
+                Uop::IsNegativeZero_f64 | Uop::IsIEEE_f64 => false,
+                Uop::Not_z | Uop::Expect_z { .. } | Uop::Ctz_i32 | Uop::Clz_i32 | Uop::Popcount_i32 | Uop::Eqz_i32 |
+                Uop::Complement_i32 | Uop::Ctz_i64 | Uop::Clz_i64 | Uop::Popcount_i64 | Uop::Eqz_i64 |
+                Uop::Complement_i64 | Uop::Complement_word | Uop::Neg_f32 | Uop::Abs_f32 | Uop::Ceil_f32 |
+                Uop::Floor_f32 | Uop::Trunc_f32 | Uop::Nearest_f32 | Uop::Exp_f32 | Uop::Log_f32 | Uop::Sqrt_f32 |
+                Uop::Pow_f32 | Uop::Sin_f32 | Uop::Cos_f32 | Uop::Tan_f32 | Uop::Neg_f64 | Uop::Abs_f64 |
+                Uop::Ceil_f64 | Uop::Floor_f64 | Uop::Trunc_f64 | Uop::Nearest_f64 | Uop::Exp_f64 | Uop::Log_f64 |
+                Uop::Sqrt_f64 | Uop::Pow_f64 | Uop::Sin_f64 | Uop::Cos_f64 | Uop::Tan_f64 | Uop::Wrap_i64_i32 |
+                Uop::Trunc_s_f32_i32 | Uop::Trunc_s_f64_i32 | Uop::Trunc_u_f32_i32 | Uop::Trunc_u_f64_i32 |
+                Uop::Trunc_s_f32_i64 | Uop::Trunc_s_f64_i64 | Uop::Trunc_u_f32_i64 | Uop::Trunc_u_f64_i64 |
+                Uop::Extend_s_i32_i64 | Uop::Extend_u_i32_i64 | Uop::Nez_i32_z | Uop::Extend_u_z_i32 |
+                Uop::Wrap_i32_i8 | Uop::Wrap_i32_i16 | Uop::Extend_s_i8_i32 | Uop::Extend_u_i8_i32 |
+                Uop::Extend_s_i16_i32 | Uop::Extend_u_i16_i32 | Uop::Nez_i64_z | Uop::Extend_u_z_i64 |
+                Uop::Reinterpret_i32_f32 | Uop::Reinterpret_f32_i32 | Uop::Reinterpret_f64_i64 |
+                Uop::Reinterpret_i64_f64 | Uop::Convert_s_i32_f32 | Uop::Convert_u_i32_f32 | Uop::Convert_s_i64_f32 |
+                Uop::Convert_u_i64_f32 | Uop::Convert_s_i32_f64 | Uop::Convert_u_i32_f64 | Uop::Convert_s_i64_f64 |
+                Uop::Convert_u_i64_f64 | Uop::Demote_f64_f32 | Uop::Promote_f32_f64 => true,
+            }
+        }
+
+        fn bop_supported(op: Bop) -> bool {
+            match op {
+                Bop::Atan2_f32 | Bop::Atan2_f64 => false,
+                Bop::And_z | Bop::Or_z | Bop::Eq_z | Bop::Ne_z | Bop::Eq_ptr | Bop::Ne_ptr | Bop::Add_i32 |
+                Bop::Sub_i32 | Bop::Mul_i32 | Bop::Div_s_i32 | Bop::Div_u_i32 | Bop::Rem_s_i32 | Bop::Rem_u_i32 |
+                Bop::And_i32 | Bop::Or_i32 | Bop::Xor_i32 | Bop::Eq_i32 | Bop::Ne_i32 | Bop::Lt_s_i32 |
+                Bop::Lt_u_i32 | Bop::Le_s_i32 | Bop::Le_u_i32 | Bop::Gt_s_i32 | Bop::Gt_u_i32 | Bop::Ge_s_i32 |
+                Bop::Ge_u_i32 | Bop::Shl_i32 | Bop::Shr_i32 | Bop::Shr_u_i32 | Bop::Rotl_i32 | Bop::Rotr_i32 |
+                Bop::Min_s_i32 | Bop::Min_u_i32 | Bop::Max_s_i32 | Bop::Max_u_i32 | Bop::Add_i64 | Bop::Sub_i64 |
+                Bop::Mul_i64 | Bop::Div_s_i64 | Bop::Div_u_i64 | Bop::Rem_s_i64 | Bop::Rem_u_i64 | Bop::And_i64 |
+                Bop::Or_i64 | Bop::Xor_i64 | Bop::Eq_i64 | Bop::Ne_i64 | Bop::Lt_s_i64 | Bop::Lt_u_i64 |
+                Bop::Le_s_i64 | Bop::Le_u_i64 | Bop::Gt_s_i64 | Bop::Gt_u_i64 | Bop::Ge_s_i64 | Bop::Ge_u_i64 |
+                Bop::Shl_i64 | Bop::Shr_i64 | Bop::Shr_u_i64 | Bop::Rotl_i64 | Bop::Rotr_i64 | Bop::Min_s_i64 |
+                Bop::Min_u_i64 | Bop::Max_s_i64 | Bop::Max_u_i64 | Bop::Add_nsw_i32 | Bop::Sub_nsw_i32 |
+                Bop::Mul_nsw_i32 | Bop::Add_nsw_i64 | Bop::Sub_nsw_i64 | Bop::Mul_nsw_i64 | Bop::Min_f32 | Bop::Max_f32 | Bop::Copysign_f32 |
+                Bop::Add_f32 | Bop::Sub_f32 | Bop::Mul_f32 | Bop::Div_f32 | Bop::Rem_f32 | Bop::Eq_f32 | Bop::Ne_f32 |
+                Bop::Lt_f32 | Bop::Le_f32 | Bop::Gt_f32 | Bop::Ge_f32 | Bop::BitEq_f32 | Bop::TotalLt_f32 | Bop::Min_f64 | Bop::Max_f64 |
+                Bop::Copysign_f64 | Bop::Add_f64 | Bop::Sub_f64 | Bop::Mul_f64 | Bop::Div_f64 | Bop::Rem_f64 |
+                Bop::Eq_f64 | Bop::Ne_f64 | Bop::Lt_f64 | Bop::Le_f64 | Bop::Gt_f64 | Bop::Ge_f64 | Bop::BitEq_f64 | Bop::TotalLt_f64 |
+                Bop::Add_i128 | Bop::Sub_i128 | Bop::Mul_i128 | Bop::And_i128 | Bop::Or_i128 | Bop::Xor_i128 |
+                Bop::Eq_i128 | Bop::Ne_i128 | Bop::Shl_i128 | Bop::Shr_i128 | Bop::Shr_u_i128 => true,
+            }
+        }
+
+        // Every op, by operand type.
+        let uops: Vec<(mir::Type, Vec<Uop>)> = vec![
+            (mir::Type::I1, vec![
+                Uop::Not_z, Uop::Expect_z { expected: true }, Uop::Extend_u_z_i32, Uop::Extend_u_z_i64
+            ]),
+            (mir::Type::I8, vec![
+                Uop::Extend_s_i8_i32, Uop::Extend_u_i8_i32
+            ]),
+            (mir::Type::I16, vec![
+                Uop::Extend_s_i16_i32, Uop::Extend_u_i16_i32
+            ]),
+            (mir::Type::I32, vec![
+                Uop::Ctz_i32, Uop::Clz_i32, Uop::Popcount_i32, Uop::Eqz_i32, Uop::Complement_i32,
+                Uop::Extend_s_i32_i64, Uop::Extend_u_i32_i64, Uop::Nez_i32_z, Uop::Reinterpret_i32_f32,
+                Uop::Convert_s_i32_f32, Uop::Convert_u_i32_f32, Uop::Convert_s_i32_f64, Uop::Convert_u_i32_f64,
+                Uop::Wrap_i32_i8, Uop::Wrap_i32_i16
+            ]),
+            (mir::Type::I64, vec![
+                Uop::Ctz_i64, Uop::Clz_i64, Uop::Popcount_i64, Uop::Eqz_i64, Uop::Complement_i64, Uop::Wrap_i64_i32,
+                Uop::Reinterpret_i64_f64, Uop::Convert_s_i64_f32, Uop::Convert_u_i64_f32, Uop::Convert_s_i64_f64,
+                Uop::Convert_u_i64_f64, Uop::Nez_i64_z
+            ]),
+            (mir::Type::word(), vec![
+                Uop::Complement_word
+            ]),
+            (mir::Type::F32, vec![
+                Uop::Neg_f32, Uop::Abs_f32, Uop::Ceil_f32, Uop::Floor_f32, Uop::Trunc_f32, Uop::Nearest_f32,
+                Uop::Exp_f32, Uop::Log_f32, Uop::Sqrt_f32, Uop::Pow_f32, Uop::Logb_f32, Uop::Sin_f32, Uop::Cos_f32,
+                Uop::Tan_f32, Uop::Asin_f32, Uop::Acos_f32, Uop::Atan_f32, Uop::Sinh_f32, Uop::Cosh_f32,
+                Uop::Tanh_f32, Uop::Asinh_f32, Uop::Acosh_f32, Uop::Atanh_f32, Uop::IsNan_f32, Uop::IsInf_f32,
+                Uop::IsDenormalized_f32, Uop::IsNegativeZero_f32, Uop::IsIEEE_f32, Uop::Trunc_s_f32_i32,
+                Uop::Trunc_u_f32_i32, Uop::Trunc_s_f32_i64, Uop::Trunc_u_f32_i64, Uop::Reinterpret_f32_i32,
+                Uop::Promote_f32_f64
+            ]),
+            (mir::Type::F64, vec![
+                Uop::Neg_f64, Uop::Abs_f64, Uop::Ceil_f64, Uop::Floor_f64, Uop::Trunc_f64, Uop::Nearest_f64,
+                Uop::Exp_f64, Uop::Log_f64, Uop::Sqrt_f64, Uop::Pow_f64, Uop::Logb_f64, Uop::Sin_f64, Uop::Cos_f64,
+                Uop::Tan_f64, Uop::Asin_f64, Uop::Acos_f64, Uop::Atan_f64, Uop::Sinh_f64, Uop::Cosh_f64,
+                Uop::Tanh_f64, Uop::Asinh_f64, Uop::Acosh_f64, Uop::Atanh_f64, Uop::IsNan_f64, Uop::IsInf_f64,
+                Uop::IsDenormalized_f64, Uop::IsNegativeZero_f64, Uop::IsIEEE_f64, Uop::Trunc_s_f64_i32,
+                Uop::Trunc_u_f64_i32, Uop::Trunc_s_f64_i64, Uop::Trunc_u_f64_i64, Uop::Reinterpret_f64_i64,
+                Uop::Demote_f64_f32
+            ]),
+        ];
+
+        let bops: Vec<(mir::Type, Vec<Bop>)> = vec![
+            (mir::Type::I1, vec![
+                Bop::And_z, Bop::Or_z, Bop::Eq_z, Bop::Ne_z
+            ]),
+            (mir::Type::Ptr { ty: Box::new(mir::Type::I8) }, vec![
+                Bop::Eq_ptr, Bop::Ne_ptr
+            ]),
+            (mir::Type::I32, vec![
+                Bop::Add_i32, Bop::Sub_i32, Bop::Mul_i32, Bop::Div_s_i32, Bop::Div_u_i32, Bop::Rem_s_i32,
+                Bop::Rem_u_i32, Bop::And_i32, Bop::Or_i32, Bop::Xor_i32, Bop::Eq_i32, Bop::Ne_i32, Bop::Lt_s_i32,
+                Bop::Lt_u_i32, Bop::Le_s_i32, Bop::Le_u_i32, Bop::Gt_s_i32, Bop::Gt_u_i32, Bop::Ge_s_i32,
+                Bop::Ge_u_i32, Bop::Shl_i32, Bop::Shr_i32, Bop::Shr_u_i32, Bop::Rotl_i32, Bop::Rotr_i32,
+                Bop::Min_s_i32, Bop::Min_u_i32, Bop::Max_s_i32, Bop::Max_u_i32, Bop::Add_nsw_i32,
+                Bop::Sub_nsw_i32, Bop::Mul_nsw_i32
+            ]),
+            (mir::Type::I64, vec![
+                Bop::Add_i64, Bop::Sub_i64, Bop::Mul_i64, Bop::Div_s_i64, Bop::Div_u_i64, Bop::Rem_s_i64,
+                Bop::Rem_u_i64, Bop::And_i64, Bop::Or_i64, Bop::Xor_i64, Bop::Eq_i64, Bop::Ne_i64, Bop::Lt_s_i64,
+                Bop::Lt_u_i64, Bop::Le_s_i64, Bop::Le_u_i64, Bop::Gt_s_i64, Bop::Gt_u_i64, Bop::Ge_s_i64,
+                Bop::Ge_u_i64, Bop::Shl_i64, Bop::Shr_i64, Bop::Shr_u_i64, Bop::Rotl_i64, Bop::Rotr_i64,
+                Bop::Min_s_i64, Bop::Min_u_i64, Bop::Max_s_i64, Bop::Max_u_i64, Bop::Add_nsw_i64,
+                Bop::Sub_nsw_i64, Bop::Mul_nsw_i64
+            ]),
+            (mir::Type::I128, vec![
+                Bop::Add_i128, Bop::Sub_i128, Bop::Mul_i128, Bop::And_i128, Bop::Or_i128, Bop::Xor_i128,
+                Bop::Eq_i128, Bop::Ne_i128, Bop::Shl_i128, Bop::Shr_i128, Bop::Shr_u_i128
+            ]),
+            (mir::Type::F32, vec![
+                Bop::Min_f32, Bop::Max_f32, Bop::Copysign_f32, Bop::Atan2_f32, Bop::Add_f32, Bop::Sub_f32,
+                Bop::Mul_f32, Bop::Div_f32, Bop::Rem_f32, Bop::Eq_f32, Bop::Ne_f32, Bop::Lt_f32, Bop::Le_f32,
+                Bop::Gt_f32, Bop::Ge_f32, Bop::BitEq_f32, Bop::TotalLt_f32
+            ]),
+            (mir::Type::F64, vec![
+                Bop::Min_f64, Bop::Max_f64, Bop::Copysign_f64, Bop::Atan2_f64, Bop::Add_f64, Bop::Sub_f64,
+                Bop::Mul_f64, Bop::Div_f64, Bop::Rem_f64, Bop::Eq_f64, Bop::Ne_f64, Bop::Lt_f64, Bop::Le_f64,
+                Bop::Gt_f64, Bop::Ge_f64, Bop::BitEq_f64, Bop::TotalLt_f64
+            ]),
+        ];
+
+        assert_eq!(uops.iter().map(|(_, ops)| ops.len()).sum::<usize>(), 104, "every Uop should be listed");
+        assert_eq!(bops.iter().map(|(_, ops)| ops.len()).sum::<usize>(), 115, "every Bop should be listed");
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+
+        // Translate f(args) = stm, where stm assigns its dst, and report whether the op in stm is supported.
+        let translates = |ty: &mir::Type, ret_type: mir::Type, nargs: usize, stm: &dyn Fn(lir::Exp, Vec<lir::Exp>) -> lir::Stm| {
+            let params: Vec<lir::Param> = (0..nargs).map(|_| lir::Param { ty: ty.clone(), name: Name::fresh("a"), noalias: false, readonly: false, nonnull: false }).collect();
+            let args = params.iter().map(|p| lir::Exp::Temp { ty: p.ty.clone(), name: p.name }).collect();
+            let dst = lir::Exp::Temp { ty: ret_type.clone(), name: Name::fresh("r") };
+
+            let l = lir::Root {
+                externs: vec![],
+                imports: vec![],
+                data: vec![],
+                procs: vec![
+                    lir::Proc {
+                        ret_type,
+                        name: Name::new("f"),
+                        params,
+                        body: vec![stm(dst.clone(), args), lir::Stm::Ret { exp: dst }],
+                        visibility: lir::Visibility::Default,
+                        dso_local: false,
+                        noinline: false,
+                        optnone: false,
+                    }
+                ],
+            };
+
+            match t.translate("test_op_coverage", &l) {
+                Ok(m) => {
+                    m.dispose();
+                    true
+                },
+                Err(CodegenError::UnsupportedOp { .. }) => false,
+                Err(e) => panic!("unexpected error: {}", e),
+            }
+        };
+
+        for (ty, ops) in &uops {
+            for op in ops {
+                let supported = translates(ty, op.get_type(), 1, &|dst, args| lir::Stm::Unary { dst, op: *op, exp: args[0].clone() });
+                assert_eq!(supported, uop_supported(*op), "{:?}", op);
+            }
+        }
+
+        for (ty, ops) in &bops {
+            for op in ops {
+                let supported = translates(ty, op.get_type(), 2, &|dst, args| lir::Stm::Binary { dst, op: *op, e1: args[0].clone(), e2: args[1].clone() });
+                assert_eq!(supported, bop_supported(*op), "{:?}", op);
+            }
+        }
+
+        context.dispose();
+    }
+
+    #[test]
+    fn optnone_untouched_at_o2() {
+        use crate::lir::builder::Builder;
+
+        // f(x) = x * 1 + 0, which the optimizer reduces to x.
+        let proc = |name, optnone| {
+            let mut b = Builder::new(Name::new(name), mir::Type::I32);
+            let x = b.param(mir::Type::I32);
+            let y = b.binary(Bop::Mul_i32, x, lir::Exp::Lit { lit: mir::Lit::I32 { value: 1 } });
+            let z = b.binary(Bop::Add_i32, y, lir::Exp::Lit { lit: mir::Lit::I32 { value: 0 } });
+            b.ret(z);
+            lir::Proc { optnone, ..b.finish() }
+        };
+
+        let l = lir::Root { externs: vec![], imports: vec![], data: vec![], procs: vec![proc("opt", false), proc("noopt", true)] };
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+        let m = t.translate("test_optnone_untouched_at_o2", &l).unwrap();
+        m.optimize(2);
+        let ir = m.print_to_string();
+
+        let body = |name: &str| {
+            let start = ir.find(&format!("@{}(", name)).unwrap();
+            let end = ir[start..].find("\n}").unwrap();
+            ir[start..start + end].to_string()
+        };
+
+        assert!(body("noopt").contains("mul i32") && body("noopt").contains("add i32"), "expected noopt to be unoptimized in {}", ir);
+        assert!(! body("opt").contains("mul i32") && ! body("opt").contains("add i32"), "expected opt to be optimized in {}", ir);
+        assert!(ir.contains("noinline optnone"), "expected noinline and optnone attributes in {}", ir);
+        context.dispose();
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn emit_assembly_for_cpu() {
+        use crate::lir::builder::Builder;
+
+        // f(x) = popcount(x), which is a single instruction only on CPUs with popcnt.
+        let mut f = Builder::new(Name::new("f"), mir::Type::I32);
+        let x = f.param(mir::Type::I32);
+        let z = f.unary(Uop::Popcount_i32, x);
+        f.ret(z);
+
+        let l = lir::Root { externs: vec![], imports: vec![], data: vec![], procs: vec![f.finish()] };
+
+        let context = llvm::Context::new();
+        let mut t = llvm_gen::Translate::new_in_context(context);
+        let m = t.translate("test_emit_assembly_for_cpu", &l).unwrap();
+
+        // The generic x86-64 CPU has no popcnt.
+        let asm = t.emit_assembly(&m, Some("x86_64-unknown-linux-gnu")).unwrap();
+        assert!(! asm.contains("popcnt"), "unexpected popcnt for the generic CPU in {}", asm);
+
+        t.set_cpu("skylake");
+        t.set_features("+avx2");
+        let asm = t.emit_assembly(&m, Some("x86_64-unknown-linux-gnu"));
+        assert!(asm.is_ok(), "expected skylake with +avx2 to be accepted: {:?}", asm);
+        let asm = asm.unwrap();
+        assert!(asm.contains("popcntl"), "expected popcnt for skylake in {}", asm);
+
+        // Features apply without a CPU too.
+        t.set_cpu("");
+        t.set_features("+popcnt");
+        let asm = t.emit_assembly(&m, Some("x86_64-unknown-linux-gnu")).unwrap();
+        assert!(asm.contains("popcntl"), "expected popcnt with +popcnt in {}", asm);
+        context.dispose();
+    }
+
+    #[test]
+    fn duplicate_proc_names() {
+        let proc = |name, value| lir::Proc {
+            ret_type: mir::Type::I32,
+            name: Name::new(name),
+            params: vec![],
+            body: vec![
+                lir::Stm::Ret { exp: lir::Exp::Lit { lit: mir::Lit::I32 { value } } }
+            ],
+            visibility: lir::Visibility::Default,
+            dso_local: false,
+            noinline: false,
+            optnone: false,
+        };
+
+        let l = lir::Root { externs: vec![], imports: vec![], data: vec![], procs: vec![proc("f", 1), proc("g", 2), proc("f", 3)] };
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+        let r = t.translate("test_duplicate_proc_names", &l);
+        assert_eq!(r.err(), Some(CodegenError::DuplicateProcs { names: vec![Name::new("f")] }));
+        context.dispose();
+    }
+
+    #[test]
+    fn closure_call_verifies() {
+        let int_to_int = hir::Type::Fun { ret: Box::new(hir::Type::I32), args: vec![hir::Type::I32] };
+
+        // main() = let k = 10 in (\y -> y + k)(1)
+        let h = hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("main"),
+                    params: vec![],
+                    body: Box::new(hir::Exp::Let {
+                        inits: vec![
+                            hir::Field {
+                                param: hir::Param { name: Name::new("k"), ty: hir::Type::I32 },
+                                exp: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 10 } }),
+                            }
+                        ],
+                        body: Box::new(hir::Exp::Apply {
+                            fun_type: int_to_int.clone(),
+                            fun: Box::new(hir::Exp::Lambda {
+                                ret_type: hir::Type::I32,
+                                params: vec![hir::Param { name: Name::new("y"), ty: hir::Type::I32 }],
+                                body: Box::new(hir::Exp::Binary {
+                                    op: Bop::Add_i32,
+                                    e1: Box::new(hir::Exp::Var { name: Name::new("y"), ty: hir::Type::I32 }),
+                                    e2: Box::new(hir::Exp::Var { name: Name::new("k"), ty: hir::Type::I32 }),
+                                }),
+                            }),
+                            args: vec![hir::Exp::Lit { lit: hir::Lit::I32 { value: 1 } }],
+                        }),
+                    }),
+                },
+            ],
+        };
+
+        let context = llvm::Context::new();
+        let m = translate_in_context("test_closure_call_verifies", &h, context).unwrap();
+        assert_eq!(m.verify(), Ok(()));
+        context.dispose();
+    }
+
+    #[test]
+    fn compile_dumps_each_stage() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let h = hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("main"),
+                    params: vec![],
+                    body: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 7 } }),
+                },
+            ],
+        };
+
+        let dumps = Rc::new(RefCell::new(Vec::new()));
+        let d = dumps.clone();
+
+        let context = llvm::Context::new();
+        let mut opts = CompileOptions::new(context);
+        opts.dump = Some(Box::new(move |stage, text| d.borrow_mut().push((stage.to_string(), text.to_string()))));
+        compile(&h, "test_compile_dumps_each_stage", &opts).unwrap();
+
+        let dumps = dumps.borrow();
+        let stages: Vec<&str> = dumps.iter().map(|(stage, _)| stage.as_str()).collect();
+        assert_eq!(stages, vec!["lifted", "mir", "lir", "llvm"]);
+        assert!(dumps.iter().all(|(_, text)| ! text.is_empty()));
+        context.dispose();
+    }
+
+    #[test]
+    fn overflow_policy() {
+        use crate::hir::ops::Overflow;
+
+        // add(x, y) = x + y
+        let h = hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("add"),
+                    params: vec![
+                        hir::Param { name: Name::new("x"), ty: hir::Type::I32 },
+                        hir::Param { name: Name::new("y"), ty: hir::Type::I32 },
+                    ],
+                    body: Box::new(hir::Exp::Binary {
+                        op: Bop::Add_i32,
+                        e1: Box::new(hir::Exp::Var { name: Name::new("x"), ty: hir::Type::I32 }),
+                        e2: Box::new(hir::Exp::Var { name: Name::new("y"), ty: hir::Type::I32 }),
+                    }),
+                },
+            ],
+        };
+
+        let context = llvm::Context::new();
+        let opts = CompileOptions::new(context);
+        let m = compile(&h, "test_overflow_wrap", &opts).unwrap();
+        assert!(! m.print_to_string().contains("call void @panic()"));
+        assert!(! m.print_to_string().contains("add nsw"));
+        context.dispose();
+
+        let context = llvm::Context::new();
+        let mut opts = CompileOptions::new(context);
+        opts.overflow = Overflow::Trap;
+        let m = compile(&h, "test_overflow_trap", &opts).unwrap();
+        assert!(m.print_to_string().contains("call void @panic()"));
+        assert!(m.print_to_string().contains("call { i32, i1 } @llvm.sadd.with.overflow.i32"));
+        assert_eq!(m.verify(), Ok(()));
+        context.dispose();
+
+        let context = llvm::Context::new();
+        let mut opts = CompileOptions::new(context);
+        opts.overflow = Overflow::Undef;
+        let m = compile(&h, "test_overflow_undef", &opts).unwrap();
+        assert!(m.print_to_string().contains("add nsw i32"));
+        assert_eq!(m.verify(), Ok(()));
+        context.dispose();
+    }
+
+    #[test]
+    fn bounds_check_policy() {
+        use crate::hir::bounds::BoundsChecks;
+
+        let array_ty = hir::Type::Array { ty: Box::new(hir::Type::I32) };
+
+        // set(a, i) = { a[i] = 0; 0 }, with bounds_check as given.
+        let h = |bounds_check| hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("set"),
+                    params: vec![
+                        hir::Param { name: Name::new("a"), ty: array_ty.clone() },
+                        hir::Param { name: Name::new("i"), ty: hir::Type::I32 },
+                    ],
+                    body: Box::new(hir::Exp::Seq {
+                        body: Box::new(hir::Stm::ArrayAssign {
+                            bounds_check,
+                            ty: hir::Type::I32,
+                            array: Box::new(hir::Exp::Var { name: Name::new("a"), ty: array_ty.clone() }),
+                            index: Box::new(hir::Exp::Var { name: Name::new("i"), ty: hir::Type::I32 }),
+                            value: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 0 } }),
+                        }),
+                        exp: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 0 } }),
+                    }),
+                },
+            ],
+        };
+
+        let checked = |bounds_check, policy| {
+            let context = llvm::Context::new();
+            let mut opts = CompileOptions::new(context);
+            opts.bounds_checks = policy;
+            let m = compile(&h(bounds_check), "test_bounds_check_policy", &opts).unwrap();
+            let checked = m.print_to_string().contains("call void @panic()");
+            context.dispose();
+            checked
+        };
+
+        assert!(checked(true, BoundsChecks::Respect));
+        assert!(! checked(false, BoundsChecks::Respect));
+        assert!(! checked(true, BoundsChecks::Never));
+        assert!(checked(false, BoundsChecks::Always));
+    }
+
+    #[test]
+    fn constant_global_initializers() {
+        let point_ty = hir::Type::Struct { fields: vec![
+            hir::Param { name: Name::new("x"), ty: hir::Type::I32 },
+            hir::Param { name: Name::new("y"), ty: hir::Type::I32 },
+        ] };
+        let field = |name, value| hir::Field {
+            param: hir::Param { name: Name::new(name), ty: hir::Type::I32 },
+            exp: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value } }),
+        };
+
+        // var origin = { x: 3, y: 4 }
+        let constant = hir::Def::VarDef {
+            ty: point_ty.clone(),
+            name: Name::new("origin"),
+            exp: Box::new(hir::Exp::StructLit { fields: vec![field("x", 3), field("y", 4)] }),
+            thread_local: false,
+        };
+        // var g = answer()
+        let answer = hir::Def::FunDef {
+            ret_type: hir::Type::I32,
+            name: Name::new("answer"),
+            params: vec![],
+            body: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 42 } }),
+        };
+        let call = hir::Def::VarDef {
+            ty: hir::Type::I32,
+            name: Name::new("g"),
+            exp: Box::new(hir::Exp::Call { fun_type: hir::Type::Fun { ret: Box::new(hir::Type::I32), args: vec![] }, name: Name::new("answer"), args: vec![] }),
+            thread_local: false,
+        };
+
+        assert!(mir_gen::Translate::is_constant_initializer(match &constant { hir::Def::VarDef { exp, .. } => exp, _ => unreachable!() }));
+        assert!(! mir_gen::Translate::is_constant_initializer(match &call { hir::Def::VarDef { exp, .. } => exp, _ => unreachable!() }));
+
+        let context = llvm::Context::new();
+        let opts = CompileOptions::new(context);
+
+        // The struct is allocated statically, and origin points to it.
+        let m = compile(&hir::Root { defs: vec![constant.clone()] }, "test_constant_global", &opts).unwrap();
+        assert!(m.find_global("llvm.global_ctors").is_none());
+        assert!(m.find_function("rivo_init").is_none());
+        assert!(m.verify().is_ok());
+        let ir = m.print_to_string();
+        assert!(ir.contains("i32 3, i32 4"), "{}", ir);
+
+        let m = compile(&hir::Root { defs: vec![constant, answer, call] }, "test_call_global", &opts).unwrap();
+        assert!(m.find_global("llvm.global_ctors").is_some());
+        assert!(m.find_function("rivo_init").is_some());
+
+        // A user def can't take the initializer's name.
+        let rivo_init = hir::Def::FunDef {
+            ret_type: hir::Type::I32,
+            name: Name::new("rivo_init"),
+            params: vec![],
+            body: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 0 } }),
+        };
+        let r = compile(&hir::Root { defs: vec![rivo_init] }, "test_reserved_init", &opts);
+        assert_eq!(r.err(), Some(CompileError::Lower(CodegenError::ReservedName { name: Name::new("rivo_init") })));
+
+        context.dispose();
+    }
+
+    #[test]
+    fn cond_branch_type_mismatch() {
+        // f() = true ? 1 : 2L, where the conditional is an i32.
+        let f = hir::Def::FunDef {
+            ret_type: hir::Type::I32,
+            name: Name::new("f"),
+            params: vec![],
+            body: Box::new(hir::Exp::Cond {
+                ty: hir::Type::I32,
+                cond: Box::new(hir::Exp::Lit { lit: hir::Lit::Bool { value: true } }),
+                if_true: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 1 } }),
+                if_false: Box::new(hir::Exp::Lit { lit: hir::Lit::I64 { value: 2 } }),
+            }),
+        };
+
+        let context = llvm::Context::new();
+        let opts = CompileOptions::new(context);
+        let r = compile(&hir::Root { defs: vec![f] }, "test_cond_mismatch", &opts);
+        assert_eq!(r.err(), Some(CompileError::Lower(CodegenError::TypeMismatch { expected: mir::Type::I32, found: mir::Type::I64 })));
+        context.dispose();
+    }
+
+    #[test]
+    fn i128_ops_rejected_in_hir() {
+        // f() = 1 +i128 2, which HIR can't type, since it has no i128.
+        let f = hir::Def::FunDef {
+            ret_type: hir::Type::I64,
+            name: Name::new("f"),
+            params: vec![],
+            body: Box::new(hir::Exp::Binary {
+                op: Bop::Add_i128,
+                e1: Box::new(hir::Exp::Lit { lit: hir::Lit::I64 { value: 1 } }),
+                e2: Box::new(hir::Exp::Lit { lit: hir::Lit::I64 { value: 2 } }),
+            }),
+        };
+
+        let context = llvm::Context::new();
+        let opts = CompileOptions::new(context);
+        let r = compile(&hir::Root { defs: vec![f] }, "test_i128_in_hir", &opts);
+        assert_eq!(r.err(), Some(CompileError::Lower(CodegenError::UnsupportedOp { op: String::from("Add_i128") })));
+        context.dispose();
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn i128_layout_matches_llvm() {
+        let context = llvm::Context::new();
+        let td = llvm::TargetData::new(Some("x86_64-unknown-linux-gnu")).unwrap();
+
+        let i128_ty = context.i128_type();
+        assert_eq!(mir::Type::I128.size_of(8), td.size_of(i128_ty));
+        assert_eq!(mir::Type::I128.align_of(8), td.align_of(i128_ty));
+
+        // The padding before an i128 field follows its alignment.
+        let s = mir::Type::Struct { fields: vec![mir::Type::I8, mir::Type::I128] };
+        let s_ty = context.structure_type(&[context.i8_type(), i128_ty], false);
+        assert_eq!(s.size_of(8), td.size_of(s_ty));
+        assert_eq!(s.align_of(8), td.align_of(s_ty));
+
+        drop(td);
+        context.dispose();
+    }
+
+    #[test]
+    fn void_call_has_no_store() {
+        use crate::lir::builder::Builder;
+
+        let fun_ptr_ty = mir::Type::Ptr { ty: Box::new(mir::Type::Fun { ret: Box::new(mir::Type::Void), args: vec![] }) };
+
+        let mut nothing = Builder::new(Name::new("nothing"), mir::Type::Void);
+        nothing.ret(lir::Exp::Lit { lit: mir::Lit::Void });
+
+        // The call's dst is a void temp, so there's nothing to store.
+        let mut main = Builder::new(Name::new("main"), mir::Type::I32);
+        main.call(mir::Type::Void, lir::Exp::FunctionAddr { ty: fun_ptr_ty, name: Name::new("nothing") }, vec![]);
+        main.ret(lir::Exp::Lit { lit: mir::Lit::I32 { value: 7 } });
+
+        let l = lir::Root { externs: vec![], imports: vec![], data: vec![], procs: vec![nothing.finish(), main.finish()] };
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+        let m = t.translate("test_void_call_has_no_store", &l).unwrap();
+        let ir = m.print_to_string();
+        assert!(ir.contains("call void @nothing()"), "expected a void call in {}", ir);
+        assert!(! ir.contains("alloca void"), "unexpected void alloca in {}", ir);
+        assert!(! ir.contains("store void"), "unexpected void store in {}", ir);
+        assert_eq!(m.verify(), Ok(()));
+        context.dispose();
+    }
+
+    #[test]
+    fn static_alloca_hoisted_to_entry() {
+        use crate::lir::builder::Builder;
+
+        let i32_lit = |value| lir::Exp::Lit { lit: mir::Lit::I32 { value } };
+
+        // main() { jump L; L: buf = i32[4]; buf[2] = 5; return buf[2]; }
+        let mut main = Builder::new(Name::new("main"), mir::Type::I32);
+        let label = main.label();
+        main.jump(label);
+        main.place(label);
+        let buf = main.alloca(mir::Type::I32, i32_lit(4));
+        let p = main.element_addr(mir::Type::I32, buf.clone(), i32_lit(2));
+        main.store(p.clone(), i32_lit(5));
+        let r = main.load(mir::Type::I32, p);
+        main.ret(r);
+
+        let l = lir::Root { externs: vec![], imports: vec![], data: vec![], procs: vec![main.finish()] };
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+        let m = t.translate("test_static_alloca_hoisted_to_entry", &l).unwrap();
+        let ir = m.print_to_string();
+
+        // The entry block ends with the jump to L.
+        let alloca = ir.find("alloca i32, i32 4").expect(&format!("expected the buffer's alloca in {}", ir));
+        let jump = ir.find("br label").expect(&format!("expected a jump in {}", ir));
+        assert!(alloca < jump, "expected the buffer's alloca in the entry block in {}", ir);
+        assert_eq!(m.verify(), Ok(()));
+        context.dispose();
+    }
+
+    #[test]
+    fn unlifted_lambdas() {
+        let fun_ty = hir::Type::Fun { ret: Box::new(hir::Type::I32), args: vec![hir::Type::I32] };
+
+        // main(y) = (\x -> x + body)(41)
+        let h = |body: hir::Exp| hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("main"),
+                    params: vec![hir::Param { name: Name::new("y"), ty: hir::Type::I32 }],
+                    body: Box::new(hir::Exp::Apply {
+                        fun_type: fun_ty.clone(),
+                        fun: Box::new(hir::Exp::Lambda {
+                            ret_type: hir::Type::I32,
+                            params: vec![hir::Param { name: Name::new("x"), ty: hir::Type::I32 }],
+                            body: Box::new(hir::Exp::Binary {
+                                op: Bop::Add_i32,
+                                e1: Box::new(hir::Exp::Var { name: Name::new("x"), ty: hir::Type::I32 }),
+                                e2: Box::new(body),
+                            }),
+                        }),
+                        args: vec![hir::Exp::Lit { lit: hir::Lit::I32 { value: 41 } }],
+                    }),
+                },
+            ],
+        };
+
+        let context = llvm::Context::new();
+        let mut opts = CompileOptions::new(context);
+        opts.lift_lambdas = false;
+
+        let m = compile(&h(hir::Exp::Lit { lit: hir::Lit::I32 { value: 1 } }), "test_unlifted_lambdas", &opts).unwrap();
+        let ir = m.print_to_string();
+        assert!(! ir.contains("$lifted"), "unexpected lifted function in {}", ir);
+        assert!(ir.contains("define i32 @main$lambda"), "expected the lambda as a function in {}", ir);
+        assert_eq!(m.verify(), Ok(()));
+
+        // Without an environment, the lambda can't capture y.
+        let r = compile(&h(hir::Exp::Var { name: Name::new("y"), ty: hir::Type::I32 }), "test_unlifted_capture", &opts);
+        assert_eq!(r.err(), Some(CompileError::Lift(CodegenError::LambdaCaptures { name: Name::new("main"), vars: vec![Name::new("y")] })));
+        context.dispose();
+    }
+
+    #[test]
+    fn global_section_and_alignment() {
+        let l = lir::Root {
+            externs: vec![],
+            imports: vec![],
+            data: vec![
+                lir::Data {
+                    ty: mir::Type::I32,
+                    name: Name::new("boot_flag"),
+                    init: lir::Init::Lit { lit: mir::Lit::I32 { value: 1 } },
+                    thread_local: false,
+                    visibility: lir::Visibility::Default,
+                    dso_local: false,
+                    section: Some(String::from(".boot")),
+                    alignment: Some(16),
+                    packed: false,
+                },
+            ],
+            procs: vec![],
+        };
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+        let m = t.translate("test_global_section_and_alignment", &l).unwrap();
+        let ir = m.print_to_string();
+        assert!(ir.contains("@boot_flag = global i32 1, section \".boot\", align 16"), "expected the section and alignment in {}", ir);
+        context.dispose();
+    }
+
+    #[test]
+    fn global_invalid_alignment() {
+        let g = Name::new("g");
+        let root = |alignment| lir::Root {
+            externs: vec![],
+            imports: vec![],
+            data: vec![
+                lir::Data { ty: mir::Type::I64, name: g, init: lir::Init::Lit { lit: mir::Lit::I64 { value: 7 } }, thread_local: false, visibility: lir::Visibility::Default, dso_local: false, section: None, alignment: Some(alignment), packed: false }
+            ],
+            procs: vec![],
+        };
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+        // Not a power of two.
+        for &alignment in &[0, 3, 24] {
+            let r = t.translate("test_global_invalid_alignment", &root(alignment));
+            assert_eq!(r.err(), Some(CodegenError::InvalidAlignment { name: g, alignment }));
+        }
+        // Looser than i64's.
+        let r = t.translate("test_global_invalid_alignment", &root(4));
+        assert_eq!(r.err(), Some(CodegenError::InvalidAlignment { name: g, alignment: 4 }));
+        assert!(t.translate("test_global_invalid_alignment", &root(8)).is_ok());
+        context.dispose();
+    }
+
+    #[test]
+    fn global_struct_init_mismatch() {
+        let global = |ty: mir::Type, fields: Vec<lir::Init>| lir::Root {
+            externs: vec![],
+            imports: vec![],
+            data: vec![
+                lir::Data {
+                    ty,
+                    name: Name::new("g"),
+                    init: lir::Init::Struct { fields },
+                    thread_local: false,
+                    visibility: lir::Visibility::Default,
+                    dso_local: false,
+                    section: None,
+                    alignment: None,
+                    packed: false,
+                },
+            ],
+            procs: vec![],
+        };
+        let one = || lir::Init::Lit { lit: mir::Lit::I32 { value: 1 } };
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+
+        // Too few fields for the struct.
+        let pair = mir::Type::Struct { fields: vec![mir::Type::I32, mir::Type::I32] };
+        let r = t.translate("test_struct_init_too_few", &global(pair.clone(), vec![one()]));
+        assert_eq!(r.err(), Some(CodegenError::StructInitMismatch { ty: pair, fields: 1 }));
+
+        // A struct initializer for a global that isn't a struct.
+        let r = t.translate("test_struct_init_not_struct", &global(mir::Type::I32, vec![one()]));
+        assert_eq!(r.err(), Some(CodegenError::StructInitMismatch { ty: mir::Type::I32, fields: 1 }));
+
+        context.dispose();
+    }
+
+    #[test]
+    fn global_packed_struct() {
+        use crate::lir::builder::Builder;
+
+        let g = Name::new("g");
+        let struct_ty = mir::Type::Struct { fields: vec![mir::Type::I8, mir::Type::I32] };
+
+        // main() = g.1, which is at offset 1 when packed.
+        let mut main = Builder::new(Name::new("main"), mir::Type::I32);
+        let p = main.field_addr(struct_ty.clone(), lir::Exp::GlobalAddr { ty: struct_ty.clone(), name: g }, 1);
+        let r = main.load(mir::Type::I32, p);
+        main.ret(r);
+
+        let l = lir::Root {
+            externs: vec![],
+            imports: vec![],
+            data: vec![
+                lir::Data {
+                    ty: struct_ty,
+                    name: g,
+                    init: lir::Init::Struct { fields: vec![
+                        lir::Init::Lit { lit: mir::Lit::I8 { value: 1 } },
+                        lir::Init::Lit { lit: mir::Lit::I32 { value: 2 } },
+                    ] },
+                    thread_local: false,
+                    visibility: lir::Visibility::Default,
+                    dso_local: false,
+                    section: None,
+                    // Looser than the unpacked struct's alignment of 4.
+                    alignment: Some(1),
+                    packed: true,
+                },
+            ],
+            procs: vec![main.finish()],
+        };
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+        let m = t.translate("test_global_packed_struct", &l).unwrap();
+        let ir = m.print_to_string();
+        assert!(ir.contains("@g = global <{ i8, i32 }> <{ i8 1, i32 2 }>, align 1"), "expected a packed global in {}", ir);
+        assert_eq!(m.verify(), Ok(()));
+        context.dispose();
+    }
+
+    fn answer_lir() -> lir::Root {
+        use crate::lir::builder::Builder;
+
+        let mut main = Builder::new(Name::new("main"), mir::Type::I32);
+        main.ret(lir::Exp::Lit { lit: mir::Lit::I32 { value: 42 } });
+
+        lir::Root { externs: vec![], imports: vec![], data: vec![], procs: vec![main.finish()] }
+    }
+
+    #[test]
+    #[should_panic(expected = "module used after its context was disposed")]
+    fn module_outliving_context() {
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+        let m = t.translate("test_module_outliving_context", &answer_lir()).unwrap();
+        context.dispose();
+        m.print_to_string();
+    }
+
+    #[test]
+    #[should_panic(expected = "type used after its context was disposed")]
+    fn type_outliving_context() {
+        let context = llvm::Context::new();
+        let ty = context.i32_type();
+        context.dispose();
+        llvm::Value::undef(ty);
+    }
+
+    #[test]
+    fn fresh_context_after_dispose() {
+        // A fresh context never inherits the disposed state of an earlier one.
+        let first = llvm::Context::new();
+        first.dispose();
+        let second = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(second);
+        let m = t.translate("test_fresh_context_after_dispose", &answer_lir()).unwrap();
+        assert_eq!(m.verify(), Ok(()));
+        second.dispose();
+    }
+
+    #[test]
+    #[should_panic(expected = "context used after it was disposed")]
+    fn translate_after_context_disposed() {
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+        let m = t.translate("test_translate_after_context_disposed_1", &answer_lir()).unwrap();
+        assert_eq!(m.verify(), Ok(()));
+        context.dispose();
+        t.translate("test_translate_after_context_disposed_2", &answer_lir()).unwrap();
+    }
+
+    #[test]
+    fn builder_disposed_once() {
+        use crate::lir::builder::Builder;
+
+        // f() { p = alloca i32; return p } fails the stack escape check partway through translation.
+        let mut f = Builder::new(Name::new("f"), mir::Type::Ptr { ty: Box::new(mir::Type::I32) });
+        let p = f.alloca(mir::Type::I32, lir::Exp::Lit { lit: mir::Lit::I32 { value: 1 } });
+        f.ret(p);
+        let bad = lir::Root { externs: vec![], imports: vec![], data: vec![], procs: vec![f.finish()] };
+
+        let disposed = || llvm::BUILDERS_DISPOSED.with(|n| n.get());
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+
+        let before = disposed();
+        assert!(t.translate("test_builder_disposed_once_1", &bad).is_err());
+        assert_eq!(disposed(), before + 1);
+
+        t.translate("test_builder_disposed_once_2", &answer_lir()).unwrap();
+        assert_eq!(disposed(), before + 2);
+
+        context.dispose();
+    }
+
+    #[test]
+    fn failed_translate_disposes_module() {
+        use crate::lir::builder::Builder;
+
+        let mut f = Builder::new(Name::new("f"), mir::Type::Ptr { ty: Box::new(mir::Type::I32) });
+        let p = f.alloca(mir::Type::I32, lir::Exp::Lit { lit: mir::Lit::I32 { value: 1 } });
+        f.ret(p);
+        let bad = lir::Root { externs: vec![], imports: vec![], data: vec![], procs: vec![f.finish()] };
+
+        let disposed = || llvm::MODULES_DISPOSED.with(|n| n.get());
+
+        let context = llvm::OwnedContext::new();
+        let t = llvm_gen::Translate::new_in_context(*context);
+
+        let before = disposed();
+        assert!(t.translate("test_failed_translate_disposes_module_1", &bad).is_err());
+        assert_eq!(disposed(), before + 1);
+
+        // A module that is returned is the caller's to dispose.
+        let m = t.translate("test_failed_translate_disposes_module_2", &answer_lir()).unwrap();
+        assert_eq!(disposed(), before + 1);
+        m.dispose();
+    }
+
+    #[test]
+    fn owned_context_disposed_on_drop() {
+        let handle = {
+            let context = llvm::OwnedContext::new();
+            *context
+        };
+        assert!(! handle.is_live());
+    }
+
+    #[test]
+    fn type_cache_many_temps() {
+        use crate::lir::builder::Builder;
+
+        let one = lir::Exp::Lit { lit: mir::Lit::I64 { value: 1 } };
+
+        // main() { x = 1; x = x + 1; ... ; p = {x, x}; ...; return 0 } with thousands of temps of two types.
+        let mut main = Builder::new(Name::new("main"), mir::Type::I32);
+        let mut x = main.binary(Bop::Add_i64, one.clone(), one.clone());
+        for _ in 0..2000 {
+            x = main.binary(Bop::Add_i64, x, one.clone());
+            main.build_struct(vec![x.clone(), x.clone()]);
+        }
+        main.ret(lir::Exp::Lit { lit: mir::Lit::I32 { value: 0 } });
+
+        let l = lir::Root { externs: vec![], imports: vec![], data: vec![], procs: vec![main.finish()] };
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+
+        let m = t.translate("test_type_cache_many_temps", &l).unwrap();
+
+        // i32, i64, and {i64, i64}, and maybe a few helper types, rather than one per temp.
+        assert!(t.types_lowered() < 10, "lowered {} types", t.types_lowered());
+        assert_eq!(m.verify(), Ok(()));
+        context.dispose();
+    }
+
+    #[test]
+    fn comparison_into_int_temp() {
+        let f = lir::Proc::parse("
+            proc is_three(%x: i32): i32 {
+                %b: i32 = Eq_i32 %x: i32, i32 3
+                ret %b: i32
+            }
+        ").unwrap();
+
+        let l = lir::Root { externs: vec![], imports: vec![], data: vec![], procs: vec![f] };
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+        let m = t.translate("test_comparison_into_int_temp", &l).unwrap();
+        let ir = m.print_to_string();
+        assert!(ir.contains("zext i1"), "expected the comparison to be extended in {}", ir);
+        assert_eq!(m.verify(), Ok(()));
+        context.dispose();
+    }
+
+    #[test]
+    fn comparison_into_float_temp() {
+        let f = lir::Proc::parse("
+            proc is_three(%x: i32): f64 {
+                %b: f64 = Eq_i32 %x: i32, i32 3
+                ret %b: f64
+            }
+        ").unwrap();
+
+        let l = lir::Root { externs: vec![], imports: vec![], data: vec![], procs: vec![f] };
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+        let r = t.translate("test_comparison_into_float_temp", &l);
+        assert_eq!(r.err(), Some(CodegenError::TypeMismatch { expected: mir::Type::I1, found: mir::Type::F64 }));
+        context.dispose();
+    }
+
+    #[test]
+    fn inline_to_max_depth() {
+        let fun_ty = hir::Type::Fun { ret: Box::new(hir::Type::I32), args: vec![hir::Type::I32] };
+        let x = || hir::Exp::Var { name: Name::new("x"), ty: hir::Type::I32 };
+        let plus_one = |e| hir::Exp::Binary { op: Bop::Add_i32, e1: Box::new(e), e2: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 1 } }) };
+        let call = |f: &str| hir::Exp::Call { fun_type: fun_ty.clone(), name: Name::new(f), args: vec![x()] };
+        let fun = |f: &str, body| hir::Def::FunDef {
+            ret_type: hir::Type::I32,
+            name: Name::new(f),
+            params: vec![hir::Param { name: Name::new("x"), ty: hir::Type::I32 }],
+            body: Box::new(body),
+        };
+
+        // f0(x) = x + 1, fn(x) = f{n-1}(x) + 1, main(x) = f4(x)
+        let mut defs = vec![fun("f0", plus_one(x()))];
+        for i in 1..5 {
+            defs.push(fun(&format!("f{}", i), plus_one(call(&format!("f{}", i - 1)))));
+        }
+        defs.push(fun("main", call("f4")));
+        let h = hir::Root { defs };
+
+        let context = llvm::Context::new();
+        let mut opts = CompileOptions::new(context);
+        opts.max_inline_depth = 2;
+
+        let m = compile(&h, "test_inline_to_max_depth", &opts).unwrap();
+        assert_eq!(m.verify(), Ok(()));
+
+        let main = m.print_to_string().split("define i32 @main").nth(1).unwrap().split("\n}").next().unwrap().to_string();
+        assert!(main.contains("call i32 @f2"), "expected a call to f2 in {}", main);
+        assert!(! main.contains("@f3") && ! main.contains("@f4"), "expected f3 and f4 inlined in {}", main);
+        context.dispose();
+    }
+
+    #[test]
+    fn must_tail_call_returns_directly() {
+        let f = lir::Proc::parse("
+            proc f(%n: i32): i32 {
+                %c: i1 = Eq_i32 %n: i32, i32 0
+                cjump %c: i1, done, again
+            again:
+                %m: i32 = Sub_i32 %n: i32, i32 1
+                %r: i32 = musttail call @f: *fun(i32): i32(%m: i32)
+                ret %r: i32
+            done:
+                ret %n: i32
+            }
+        ").unwrap();
+
+        let l = lir::Root { externs: vec![], imports: vec![], data: vec![], procs: vec![f] };
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+        let m = t.translate("test_must_tail_call_returns_directly", &l).unwrap();
+        let ir = m.print_to_string();
+        let call = ir.lines().position(|line| line.contains("musttail call")).expect("expected a musttail call");
+        assert!(ir.lines().nth(call + 1).unwrap().trim().starts_with("ret i32"), "expected the musttail call to be followed by ret in {}", ir);
+        assert_eq!(m.verify(), Ok(()));
+        context.dispose();
+    }
+
+    #[test]
+    fn compile_reports_check_stage() {
+        let x = Name::new("x");
+
+        // main() { return x } reads x, which is never assigned.
+        let h = hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("main"),
+                    params: vec![],
+                    body: Box::new(hir::Exp::Var { name: x, ty: hir::Type::I32 }),
+                }
+            ]
+        };
+
+        let context = llvm::Context::new();
+        let mut opts = CompileOptions::new(context);
+        opts.check_init = true;
+        let r = compile(&h, "test_compile_reports_check_stage", &opts);
+        assert_eq!(r.err(), Some(CompileError::Check(CodegenError::UninitializedVars { name: Name::new("main"), vars: vec![x] })));
+        context.dispose();
+    }
+
+    #[test]
+    fn compile_uses_translate_options() {
+        let h = hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::Box,
+                    name: Name::new("f"),
+                    params: vec![],
+                    body: Box::new(hir::Exp::Box { ty: hir::Type::I64, exp: Box::new(hir::Exp::Lit { lit: hir::Lit::I64 { value: 1 } }) }),
+                }
+            ]
+        };
+
+        let context = llvm::Context::new();
+        let mut opts = CompileOptions::new(context);
+        opts.translate.box_header_words = 1;
+        let m = compile(&h, "test_compile_uses_translate_options", &opts).unwrap();
+        let ir = m.print_to_string();
+        assert!(ir.contains("@malloc(i64 16)"), "expected room for the header in {}", ir);
+        assert_eq!(m.verify(), Ok(()));
+        context.dispose();
+    }
+
+    #[test]
+    fn undeclared_symbol_in_initializer() {
+        let missing = Name::new("missing");
+        let i32_ptr = mir::Type::Ptr { ty: Box::new(mir::Type::I32) };
+        let fun_ptr = mir::Type::Ptr { ty: Box::new(mir::Type::Fun { ret: Box::new(mir::Type::I32), args: vec![] }) };
+        let root = |ty: mir::Type, init| lir::Root {
+            externs: vec![],
+            imports: vec![],
+            data: vec![
+                lir::Data { ty, name: Name::new("p"), init, thread_local: false, visibility: lir::Visibility::Default, dso_local: false, section: None, alignment: None, packed: false }
+            ],
+            procs: vec![],
+        };
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+
+        let r = t.translate("test_missing_global_addr", &root(i32_ptr.clone(), lir::Init::Lit { lit: mir::Lit::GlobalAddr { ty: i32_ptr.clone(), name: missing } }));
+        assert_eq!(r.err(), Some(CodegenError::UndefinedGlobal { name: missing }));
+
+        let r = t.translate("test_missing_element_addr", &root(i32_ptr.clone(), lir::Init::ElementAddr { name: missing, indices: vec![0, 1] }));
+        assert_eq!(r.err(), Some(CodegenError::UndefinedGlobal { name: missing }));
+
+        let r = t.translate("test_missing_function_addr", &root(fun_ptr.clone(), lir::Init::Lit { lit: mir::Lit::FunctionAddr { ty: fun_ptr.clone(), name: missing } }));
+        assert_eq!(r.err(), Some(CodegenError::UndefinedFunction { name: missing }));
+        context.dispose();
+    }
+
+    #[test]
+    fn convert_without_conversion() {
+        let array_ty = hir::Type::Array { ty: Box::new(hir::Type::I32) };
+
+        // f(a) = a as i32, which has no conversion.
+        let h = hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("f"),
+                    params: vec![hir::Param { name: Name::new("a"), ty: array_ty.clone() }],
+                    body: Box::new(hir::Exp::Convert {
+                        ty: hir::Type::I32,
+                        signedness: Signedness::Signed,
+                        exp: Box::new(hir::Exp::Var { name: Name::new("a"), ty: array_ty.clone() }),
+                    }),
+                }
+            ]
+        };
+
+        let r = mir_gen::Translate::translate(&h);
+        assert_eq!(r.err(), Some(CodegenError::NoConversion { from: mir_gen::Translate::translate_type(&array_ty), to: mir::Type::I32 }));
+    }
+
+    #[test]
+    fn struct_load_idx_invalid_index() {
+        let pair = hir::Type::Struct {
+            fields: vec![
+                hir::Param { name: Name::new("a"), ty: hir::Type::I32 },
+                hir::Param { name: Name::new("b"), ty: hir::Type::I32 },
+            ]
+        };
+
+        // f(s) = s.index
+        let h = |ty: &hir::Type, index| hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("f"),
+                    params: vec![hir::Param { name: Name::new("s"), ty: ty.clone() }],
+                    body: Box::new(hir::Exp::StructLoadIdx {
+                        ty: ty.clone(),
+                        base: Box::new(hir::Exp::Var { name: Name::new("s"), ty: ty.clone() }),
+                        index,
+                    }),
+                }
+            ]
+        };
+
+        let r = mir_gen::Translate::translate(&h(&pair, 2));
+        assert_eq!(r.err(), Some(CodegenError::InvalidFieldIndex { ty: pair.clone(), index: 2 }));
+
+        let r = mir_gen::Translate::translate(&h(&hir::Type::I32, 0));
+        assert_eq!(r.err(), Some(CodegenError::InvalidFieldIndex { ty: hir::Type::I32, index: 0 }));
+    }
+
+    #[test]
+    fn intrinsic_declared_on_use() {
+        use crate::lir::builder::Builder;
+
+        // f(a) = ceil(a), where llvm.ceil.f64 isn't one of the runtime functions.
+        let mut f = Builder::new(Name::new("f"), mir::Type::F64);
+        let a = f.param(mir::Type::F64);
+        let r = f.unary(Uop::Ceil_f64, a);
+        f.ret(r);
+
+        let l = lir::Root {
+            externs: vec![],
+            imports: vec![],
+            data: vec![],
+            procs: vec![f.finish()],
+        };
+
+        let context = llvm::Context::new();
+        let t = llvm_gen::Translate::new_in_context(context);
+        let m = t.translate("test_intrinsic_declared_on_use", &l).unwrap();
+        let ir = m.print_to_string();
+        assert!(ir.contains("declare double @llvm.ceil.f64(double)"), "expected the intrinsic to be declared in {}", ir);
+        assert_eq!(m.verify(), Ok(()));
+        context.dispose();
+    }
+
+    #[test]
+    fn new_array_memset() {
+        // main() = let a = new i32[n] in a[2]
+        let root = |exp| hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("main"),
+                    params: vec![],
+                    body: Box::new(hir::Exp::Let {
+                        inits: vec![
+                            hir::Field {
+                                param: hir::Param { ty: hir::Type::Array { ty: Box::new(hir::Type::I32) }, name: Name::new("a") },
+                                exp: Box::new(exp),
+                            }
+                        ],
+                        body: Box::new(hir::Exp::ArrayLoad {
+                            bounds_check: false,
+                            ty: hir::Type::I32,
+                            array: Box::new(hir::Exp::Var { ty: hir::Type::Array { ty: Box::new(hir::Type::I32) }, name: Name::new("a") }),
+                            index: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 2 } }),
+                        }),
+                    }),
+                }
+            ]
+        };
+        let length = || Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 4 } });
+
+        let context = llvm::Context::new();
+
+        // Whatever malloc returns, the body is zeroed after allocation.
+        let m = translate_in_context("test_new_array_memset", &root(hir::Exp::NewArray { ty: hir::Type::I32, length: length() }), context).unwrap();
+        let ir = m.print_to_string();
+        assert!(ir.contains("call void @llvm.memset.p0i8.i64"), "expected a memset in {}", ir);
+
+        let m = translate_in_context("test_new_array_uninit", &root(hir::Exp::NewArrayUninit { ty: hir::Type::I32, length: length() }), context).unwrap();
+        let ir = m.print_to_string();
+        assert!(! ir.contains("call void @llvm.memset"), "unexpected memset in {}", ir);
+
         context.dispose();
     }
 }
@@ -27,6 +27,18 @@ pub fn alloc() -> mir::Exp {
     }
 }
 
+pub fn memmove() -> mir::Exp {
+    mir::Exp::FunctionAddr {
+        name: Name::new("llvm.memmove.p0i8.p0i8.i64"),
+        ty: mir::Type::Ptr { ty: Box::new(memmove_type()) },
+    }
+}
+
+pub fn memmove_type() -> mir::Type {
+    let byte_ptr = mir::Type::Ref { ty: Box::new(mir::Type::I8) };
+    mir::Type::Fun { ret: Box::new(mir::Type::Void), args: vec![byte_ptr.clone(), byte_ptr, mir::Type::I64, mir::Type::I1] }
+}
+
 pub fn panic() -> mir::Exp {
     mir::Exp::FunctionAddr {
         name: Name::new("panic"),
@@ -34,6 +46,41 @@ pub fn panic() -> mir::Exp {
     }
 }
 
+// llvm.{sadd,ssub,smul}.with.overflow for the given op: returns the result and whether the
+// signed operation overflowed.
+pub fn with_overflow(op: Bop) -> mir::Exp {
+    let (name, ty) = match op {
+        Bop::Add_i32 => ("llvm.sadd.with.overflow.i32", mir::Type::I32),
+        Bop::Sub_i32 => ("llvm.ssub.with.overflow.i32", mir::Type::I32),
+        Bop::Mul_i32 => ("llvm.smul.with.overflow.i32", mir::Type::I32),
+        Bop::Add_i64 => ("llvm.sadd.with.overflow.i64", mir::Type::I64),
+        Bop::Sub_i64 => ("llvm.ssub.with.overflow.i64", mir::Type::I64),
+        Bop::Mul_i64 => ("llvm.smul.with.overflow.i64", mir::Type::I64),
+        _ => panic!("{:?} can't overflow", op),
+    };
+    mir::Exp::FunctionAddr {
+        name: Name::new(name),
+        ty: mir::Type::Ptr { ty: Box::new(with_overflow_type(ty)) },
+    }
+}
+
+pub fn with_overflow_type(ty: mir::Type) -> mir::Type {
+    mir::Type::Fun {
+        ret: Box::new(mir::Type::Struct { fields: vec![ty.clone(), mir::Type::I1] }),
+        args: vec![ty.clone(), ty],
+    }
+}
+
+// Registers a box so the runtime runs its type's finalizer when the box is freed.
+pub fn register_finalizer() -> mir::Exp {
+    let byte_ptr = mir::Type::Ref { ty: Box::new(mir::Type::I8) };
+
+    mir::Exp::FunctionAddr {
+        name: Name::new("rivo_register_finalizer"),
+        ty: mir::Type::Ptr { ty: Box::new(mir::Type::Fun { ret: Box::new(mir::Type::Void), args: vec![byte_ptr] }) },
+    }
+}
+
 pub fn boxer(ty: &mir::Type) -> mir::Exp {
     let name = match ty {
         mir::Type::I1  => "box_bool",
@@ -44,11 +91,9 @@ pub fn boxer(ty: &mir::Type) -> mir::Exp {
         _ => unimplemented!()
     };
 
-    let byte_ptr = mir::Type::Ref { ty: Box::new(mir::Type::I8) };
-
     mir::Exp::FunctionAddr {
         name: Name::new(name),
-        ty: mir::Type::Ptr { ty: Box::new(mir::Type::Fun { ret: Box::new(byte_ptr), args: vec![ty.clone()] }) },
+        ty: mir::Type::Ptr { ty: Box::new(mir::Type::Fun { ret: Box::new(box_type()), args: vec![ty.clone()] }) },
     }
 }
 
@@ -62,10 +107,14 @@ pub fn unboxer(ty: &mir::Type) -> mir::Exp {
         _ => unimplemented!()
     };
 
-    let byte_ptr = mir::Type::Ref { ty: Box::new(mir::Type::I8) };
-
     mir::Exp::FunctionAddr {
         name: Name::new(name),
-        ty: mir::Type::Ptr { ty: Box::new(mir::Type::Fun { ret: Box::new(ty.clone()), args: vec![byte_ptr] }) },
+        ty: mir::Type::Ptr { ty: Box::new(mir::Type::Fun { ret: Box::new(ty.clone()), args: vec![box_type()] }) },
     }
 }
+
+// The box_* and unbox_* functions take and return boxes at mir_gen's Box type, matching
+// their declarations in llvm_gen.
+fn box_type() -> mir::Type {
+    mir_gen::Translate::translate_type(&crate::hir::trees::Type::Box)
+}
@@ -0,0 +1,254 @@
+// Definite assignment analysis for HIR
+// A variable is initialized by being a parameter, a Let binding, or the target of an Assign.
+// A forward data-flow pass tracks which variables are assigned on every path to each point,
+// and reports reads of variables that may not be. Such reads would load undef at codegen,
+// so they're usually frontend bugs.
+
+use std::collections::HashSet;
+
+use super::trees::{Stm, Exp, Def};
+use crate::common::names::*;
+use crate::hir::ops::*;
+
+// The variables assigned on every path here, or None if no path reaches here (e.g., after a Return).
+type Assigned = Option<HashSet<Name>>;
+
+// Check that def reads no variable before assigning it. The error lists the variables
+// that might be, each once, in the order they're first read.
+pub fn check_init(def: &Def) -> Result<(), Vec<Name>> {
+    let mut c = InitCheck { uninit: vec![] };
+
+    match def {
+        Def::FunDef { ret_type, name, params, body } => {
+            let mut s = Some(params.iter().map(|p| p.name).collect());
+            c.check_exp(body, &mut s);
+        },
+        Def::VarDef { ty, name, exp, thread_local } => {
+            let mut s = Some(HashSet::new());
+            c.check_exp(exp, &mut s);
+        },
+        Def::ExternDef { ty, name } => {},
+    }
+
+    if c.uninit.is_empty() {
+        Ok(())
+    }
+    else {
+        Err(c.uninit)
+    }
+}
+
+struct InitCheck {
+    uninit: Vec<Name>,
+}
+
+// Merge the states at the end of two paths.
+fn join(a: Assigned, b: Assigned) -> Assigned {
+    match (a, b) {
+        (None, s) | (s, None) => s,
+        (Some(a), Some(b)) => Some(a.intersection(&b).cloned().collect()),
+    }
+}
+
+fn assign(s: &mut Assigned, x: Name) {
+    if let Some(vars) = s {
+        vars.insert(x);
+    }
+}
+
+impl InitCheck {
+    fn read(&mut self, x: Name, s: &Assigned) {
+        if let Some(vars) = s {
+            if ! vars.contains(&x) && ! self.uninit.contains(&x) {
+                self.uninit.push(x);
+            }
+        }
+    }
+
+    fn check_exps(&mut self, es: &Vec<Exp>, s: &mut Assigned) {
+        for e in es {
+            self.check_exp(e, s);
+        }
+    }
+
+    fn check_exp(&mut self, e: &Exp, s: &mut Assigned) {
+        match e {
+            Exp::Var { name, ty } => self.read(*name, s),
+
+            // e2 might not be evaluated, so its assignments don't count afterward.
+            Exp::Binary { op: Bop::And_z, e1, e2 } | Exp::Binary { op: Bop::Or_z, e1, e2 } => {
+                self.check_exp(e1, s);
+                let mut s2 = s.clone();
+                self.check_exp(e2, &mut s2);
+            },
+            Exp::Binary { op, e1, e2 } => {
+                self.check_exp(e1, s);
+                self.check_exp(e2, s);
+            },
+            Exp::Unary { op, exp } => self.check_exp(exp, s),
+            Exp::Fma { ty, a, b, c } => {
+                self.check_exp(a, s);
+                self.check_exp(b, s);
+                self.check_exp(c, s);
+            },
+
+            Exp::NewArray { ty, length } => self.check_exp(length, s),
+            Exp::NewArrayUninit { ty, length } => self.check_exp(length, s),
+            Exp::ArrayLit { ty, exps } => self.check_exps(exps, s),
+            Exp::ArrayLoad { bounds_check, ty, array, index } => {
+                self.check_exp(array, s);
+                self.check_exp(index, s);
+            },
+            Exp::ArrayLength { array } => self.check_exp(array, s),
+
+            Exp::Lit { lit } => {},
+            Exp::Call { fun_type, name, args } => self.check_exps(args, s),
+            Exp::Global { name, ty } => {},
+            Exp::Function { name, ty } => {},
+
+            Exp::Seq { body, exp } => {
+                self.check_stm(body, s);
+                self.check_exp(exp, s);
+            },
+            Exp::Cond { ty, cond, if_true, if_false } => {
+                self.check_exp(cond, s);
+                let mut t = s.clone();
+                let mut f = s.clone();
+                self.check_exp(if_true, &mut t);
+                self.check_exp(if_false, &mut f);
+                *s = join(t, f);
+            },
+            Exp::Let { inits, body } => {
+                for init in inits {
+                    self.check_exp(&init.exp, s);
+                    assign(s, init.param.name);
+                }
+                self.check_exp(body, s);
+            },
+            // The body runs later, but the variables it captures are read now.
+            Exp::Lambda { ret_type, params, body } => {
+                let mut inner = s.clone();
+                for p in params {
+                    assign(&mut inner, p.name);
+                }
+                self.check_exp(body, &mut inner);
+            },
+            Exp::Apply { fun_type, fun, args } => {
+                self.check_exp(fun, s);
+                self.check_exps(args, s);
+            },
+
+            Exp::StructLit { fields } => {
+                for f in fields {
+                    self.check_exp(&f.exp, s);
+                }
+            },
+            Exp::StructLoad { ty, base, field } => self.check_exp(base, s),
+            Exp::StructLoadIdx { ty, base, index } => self.check_exp(base, s),
+
+            Exp::Box { ty, exp } => self.check_exp(exp, s),
+            Exp::Unbox { ty, exp } => self.check_exp(exp, s),
+            Exp::Cast { ty, exp } => self.check_exp(exp, s),
+            Exp::Convert { ty, signedness, exp } => self.check_exp(exp, s),
+        }
+    }
+
+    fn check_stm(&mut self, stm: &Stm, s: &mut Assigned) {
+        match stm {
+            Stm::IfElse { cond, if_true, if_false, hint } => {
+                self.check_exp(cond, s);
+                let mut t = s.clone();
+                let mut f = s.clone();
+                self.check_stm(if_true, &mut t);
+                self.check_stm(if_false, &mut f);
+                *s = join(t, f);
+            },
+            // The branch might not run, so its assignments don't count afterward.
+            Stm::IfThen { cond, if_true, hint } => {
+                self.check_exp(cond, s);
+                let mut t = s.clone();
+                self.check_stm(if_true, &mut t);
+            },
+            Stm::While { cond, body, hints } => {
+                self.check_exp(cond, s);
+                let mut b = s.clone();
+                self.check_stm(body, &mut b);
+            },
+            Stm::Return { exp } => {
+                self.check_exp(exp, s);
+                *s = None;
+            },
+            Stm::Block { body } => {
+                for stm in body {
+                    self.check_stm(stm, s);
+                }
+            },
+            Stm::Eval { exp } => self.check_exp(exp, s),
+            Stm::Assign { ty, lhs, rhs } => {
+                self.check_exp(rhs, s);
+                assign(s, *lhs);
+            },
+            Stm::ArrayAssign { bounds_check, ty, array, index, value } => {
+                self.check_exp(array, s);
+                self.check_exp(index, s);
+                self.check_exp(value, s);
+            },
+            Stm::ArrayCopy { bounds_check, ty, dst_array, dst_index, src_array, src_index, count } => {
+                self.check_exp(dst_array, s);
+                self.check_exp(dst_index, s);
+                self.check_exp(src_array, s);
+                self.check_exp(src_index, s);
+                self.check_exp(count, s);
+            },
+            Stm::StructAssign { ty, base, field, value } => {
+                self.check_exp(base, s);
+                self.check_exp(value, s);
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hir::trees::*;
+
+    // f(c) = { if (c) x = 1; else <else_branch>; x }
+    fn read_after_if(else_branch: Stm) -> Def {
+        Def::FunDef {
+            ret_type: Type::I32,
+            name: Name::new("f"),
+            params: vec![
+                Param { ty: Type::Bool, name: Name::new("c") }
+            ],
+            body: Box::new(
+                Exp::Seq {
+                    body: Box::new(
+                        Stm::IfElse {
+                            cond: Box::new(Exp::Var { ty: Type::Bool, name: Name::new("c") }),
+                            if_true: Box::new(Stm::Assign { ty: Type::I32, lhs: Name::new("x"), rhs: Box::new(Exp::Lit { lit: Lit::I32 { value: 1 } }) }),
+                            if_false: Box::new(else_branch),
+                            hint: None,
+                        }
+                    ),
+                    exp: Box::new(Exp::Var { ty: Type::I32, name: Name::new("x") }),
+                }
+            ),
+        }
+    }
+
+    #[test]
+    fn test_assigned_in_one_branch() {
+        let def = read_after_if(Stm::Block { body: vec![] });
+        assert_eq!(check_init(&def), Err(vec![Name::new("x")]));
+    }
+
+    #[test]
+    fn test_assigned_or_returned_in_each_branch() {
+        let def = read_after_if(Stm::Assign { ty: Type::I32, lhs: Name::new("x"), rhs: Box::new(Exp::Lit { lit: Lit::I32 { value: 2 } }) });
+        assert_eq!(check_init(&def), Ok(()));
+
+        let def = read_after_if(Stm::Return { exp: Box::new(Exp::Lit { lit: Lit::I32 { value: 0 } }) });
+        assert_eq!(check_init(&def), Ok(()));
+    }
+}
@@ -0,0 +1,68 @@
+// Bounds check policy for HIR
+// Frontends choose per ArrayLoad, ArrayAssign, and ArrayCopy whether to check the index.
+// A build can instead check every access (e.g., for debugging) or none (e.g., for release)
+// by rewriting the flags before lowering.
+
+use super::trees::{Stm, Exp, Def, Root};
+use super::children::{exp_children_mut, stm_children_mut};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BoundsChecks {
+    // Check every array access.
+    Always,
+    // Check no array access.
+    Never,
+    // Use each node's bounds_check flag.
+    Respect,
+}
+
+// root with every bounds_check flag set according to policy.
+pub fn apply_bounds_checks(root: &Root, policy: BoundsChecks) -> Root {
+    let mut root = root.clone();
+
+    let flag = match policy {
+        BoundsChecks::Always => true,
+        BoundsChecks::Never => false,
+        BoundsChecks::Respect => return root,
+    };
+
+    for def in &mut root.defs {
+        match def {
+            Def::VarDef { ty, name, exp, thread_local } => set_exp(exp, flag),
+            Def::FunDef { ret_type, name, params, body } => set_exp(body, flag),
+            Def::ExternDef { ty, name } => {},
+        }
+    }
+
+    root
+}
+
+fn set_exp(e: &mut Exp, flag: bool) {
+    if let Exp::ArrayLoad { bounds_check, .. } = e {
+        *bounds_check = flag;
+    }
+
+    let (es, ss) = exp_children_mut(e);
+    for e in es {
+        set_exp(e, flag);
+    }
+    for s in ss {
+        set_stm(s, flag);
+    }
+}
+
+fn set_stm(s: &mut Stm, flag: bool) {
+    match s {
+        Stm::ArrayAssign { bounds_check, .. } => *bounds_check = flag,
+        Stm::ArrayCopy { bounds_check, .. } => *bounds_check = flag,
+        _ => {},
+    }
+
+    let (es, ss) = stm_children_mut(s);
+    for e in es {
+        set_exp(e, flag);
+    }
+    for s in ss {
+        set_stm(s, flag);
+    }
+}
@@ -6,6 +6,7 @@ use std::collections::HashSet;
 
 use super::trees::{Stm, Exp, Type, Def, Param, Field, Lit, Root};
 use crate::common::names::*;
+use crate::common::errors::CodegenError;
 use crate::hir::ops::*;
 
 // Closure converted expressions and statements.
@@ -22,6 +23,7 @@ mod hircc {
     #[derive(Clone, Debug)]
     pub enum Exp {
         NewArray { ty: Type, length: Box<Exp> },
+        NewArrayUninit { ty: Type, length: Box<Exp> },
         ArrayLit { ty: Type, exps: Vec<Exp> },
         ArrayLoad { bounds_check: bool, ty: Type, array: Box<Exp>, index: Box<Exp> },
         ArrayLength { array: Box<Exp> },
@@ -34,8 +36,10 @@ mod hircc {
 
         Binary { op: Bop, e1: Box<Exp>, e2: Box<Exp> },
         Unary { op: Uop, exp: Box<Exp> },
+        Fma { ty: Type, a: Box<Exp>, b: Box<Exp>, c: Box<Exp> },
 
         Seq { body: Box<Stm>, exp: Box<Exp> },
+        Cond { ty: Type, cond: Box<Exp>, if_true: Box<Exp>, if_false: Box<Exp> },
 
         Let { inits: Vec<Field>, body: Box<Exp> },
         LambdaCC { ret_type: Type, env_param: Param, params: Vec<Param>, body: Box<Exp> },
@@ -44,22 +48,25 @@ mod hircc {
         StructLit { fields: Vec<Field> },
         ClosureLit { fun_type: Type, fun: Box<Exp>, env: Box<Exp> },
         StructLoad { ty: Type, base: Box<Exp>, field: Name },
+        StructLoadIdx { ty: Type, base: Box<Exp>, index: usize },
 
         Box { ty: Type, exp: Box<Exp> },
         Unbox { ty: Type, exp: Box<Exp> },
+        Convert { ty: Type, signedness: Signedness, exp: Box<Exp> },
         Cast { ty: Type, exp: Box<Exp> },
     }
 
     #[derive(Clone, Debug)]
     pub enum Stm {
-        IfElse { cond: Box<Exp>, if_true: Box<Stm>, if_false: Box<Stm> },
-        IfThen { cond: Box<Exp>, if_true: Box<Stm> },
-        While { cond: Box<Exp>, body: Box<Stm> },
+        IfElse { cond: Box<Exp>, if_true: Box<Stm>, if_false: Box<Stm>, hint: Option<BranchHint> },
+        IfThen { cond: Box<Exp>, if_true: Box<Stm>, hint: Option<BranchHint> },
+        While { cond: Box<Exp>, body: Box<Stm>, hints: Vec<LoopHint> },
         Return { exp: Box<Exp> },
         Block { body: Vec<Stm> },
         Eval { exp: Box<Exp> },
         Assign { ty: Type, lhs: Name, rhs: Box<Exp> },
         ArrayAssign { bounds_check: bool, ty: Type, array: Box<Exp>, index: Box<Exp>, value: Box<Exp> },
+        ArrayCopy { bounds_check: bool, ty: Type, dst_array: Box<Exp>, dst_index: Box<Exp>, src_array: Box<Exp>, src_index: Box<Exp>, count: Box<Exp> },
         StructAssign { ty: Type, base: Box<Exp>, field: Name, value: Box<Exp> },
     }
 
@@ -92,6 +99,11 @@ trait FV {
     fn fv(&self) -> HashMap<Name, Type>;
 }
 
+// The free variables of e, with their types.
+pub fn free_vars(e: &Exp) -> HashMap<Name, Type> {
+    e.fv()
+}
+
 impl<A: FV> FV for Vec<A> {
     fn fv(&self) -> HashMap<Name, Type> {
         let mut s = HashMap::new();
@@ -105,13 +117,13 @@ impl<A: FV> FV for Vec<A> {
 impl FV for Stm {
     fn fv(&self) -> HashMap<Name, Type> {
         match self {
-            Stm::IfElse { cond, if_true, if_false } => {
+            Stm::IfElse { cond, if_true, if_false, hint } => {
                 union!(cond.fv(), if_true.fv(), if_false.fv())
             },
-            Stm::IfThen { cond, if_true } => {
+            Stm::IfThen { cond, if_true, hint } => {
                 union!(cond.fv(), if_true.fv())
             },
-            Stm::While { cond, body } => {
+            Stm::While { cond, body, hints } => {
                 union!(cond.fv(), body.fv())
             },
             Stm::Return { exp } => {
@@ -131,6 +143,9 @@ impl FV for Stm {
             Stm::ArrayAssign { bounds_check, ty, array, index, value } => {
                 union!(array.fv(), index.fv(), value.fv())
             },
+            Stm::ArrayCopy { bounds_check, ty, dst_array, dst_index, src_array, src_index, count } => {
+                union!(dst_array.fv(), dst_index.fv(), src_array.fv(), src_index.fv(), count.fv())
+            },
             Stm::StructAssign { ty, base, field, value } => {
                 union!(base.fv(), value.fv())
             },
@@ -142,6 +157,7 @@ impl FV for Exp {
     fn fv(&self) -> HashMap<Name, Type> {
         match self {
             Exp::NewArray { ty, length } => length.fv(),
+            Exp::NewArrayUninit { ty, length } => length.fv(),
             Exp::ArrayLit { ty, exps } => {
                 exps.fv()
             },
@@ -171,13 +187,20 @@ impl FV for Exp {
                 union!(e1.fv(), e2.fv())
             },
             Exp::Unary { op, exp } => exp.fv(),
+            Exp::Fma { ty, a, b, c } => {
+                union!(a.fv(), b.fv(), c.fv())
+            },
             Exp::Box { ty, exp } => exp.fv(),
             Exp::Unbox { ty, exp } => exp.fv(),
+            Exp::Convert { ty, signedness, exp } => exp.fv(),
             Exp::Cast { ty, exp } => exp.fv(),
 
             Exp::Seq { body, exp } => {
                 union!(body.fv(), exp.fv())
             },
+            Exp::Cond { ty, cond, if_true, if_false } => {
+                union!(cond.fv(), if_true.fv(), if_false.fv())
+            },
             Exp::Let { inits, body } => {
                 let mut p = HashSet::new();
                 for init in inits {
@@ -218,6 +241,7 @@ impl FV for Exp {
                 s
             },
             Exp::StructLoad { ty, base, field } => base.fv(),
+            Exp::StructLoadIdx { ty, base, index } => base.fv(),
         }
     }
 }
@@ -255,6 +279,9 @@ impl Substitute for hircc::Exp {
             hircc::Exp::NewArray { ty, length } => {
                 hircc::Exp::NewArray { ty: ty.clone(), length: length.subst(s) }
             },
+            hircc::Exp::NewArrayUninit { ty, length } => {
+                hircc::Exp::NewArrayUninit { ty: ty.clone(), length: length.subst(s) }
+            },
             hircc::Exp::ArrayLit { ty, exps } => {
                 hircc::Exp::ArrayLit { ty: ty.clone(), exps: exps.subst(s) }
             },
@@ -288,12 +315,18 @@ impl Substitute for hircc::Exp {
             hircc::Exp::Unary { op, exp } => {
                 hircc::Exp::Unary { op: *op, exp: exp.subst(s) }
             },
+            hircc::Exp::Fma { ty, a, b, c } => {
+                hircc::Exp::Fma { ty: ty.clone(), a: a.subst(s), b: b.subst(s), c: c.subst(s) }
+            },
             hircc::Exp::Box { ty, exp } => {
                 hircc::Exp::Box { ty: ty.clone(), exp: exp.subst(s) }
             },
             hircc::Exp::Unbox { ty, exp } => {
                 hircc::Exp::Unbox { ty: ty.clone(), exp: exp.subst(s) }
             },
+            hircc::Exp::Convert { ty, signedness, exp } => {
+                hircc::Exp::Convert { ty: ty.clone(), signedness: *signedness, exp: exp.subst(s) }
+            },
             hircc::Exp::Cast { ty, exp } => {
                 hircc::Exp::Cast { ty: ty.clone(), exp: exp.subst(s) }
             },
@@ -301,6 +334,9 @@ impl Substitute for hircc::Exp {
             hircc::Exp::Seq { body, exp } => {
                 hircc::Exp::Seq { body: body.subst(s), exp: exp.subst(s) }
             },
+            hircc::Exp::Cond { ty, cond, if_true, if_false } => {
+                hircc::Exp::Cond { ty: ty.clone(), cond: cond.subst(s), if_true: if_true.subst(s), if_false: if_false.subst(s) }
+            },
 
             hircc::Exp::Let { inits, body } => {
                 let mut s2: Subst = s.clone();
@@ -331,6 +367,9 @@ impl Substitute for hircc::Exp {
             hircc::Exp::StructLoad { ty, base, field } => {
                 hircc::Exp::StructLoad { ty: ty.clone(), base: base.subst(s), field: *field }
             },
+            hircc::Exp::StructLoadIdx { ty, base, index } => {
+                hircc::Exp::StructLoadIdx { ty: ty.clone(), base: base.subst(s), index: *index }
+            },
         }
     }
 }
@@ -338,14 +377,14 @@ impl Substitute for hircc::Exp {
 impl Substitute for hircc::Stm {
     fn subst(&self, s: &Subst) -> hircc::Stm {
         match self {
-            hircc::Stm::IfElse { cond, if_true, if_false } => {
-                hircc::Stm::IfElse { cond: cond.subst(s), if_true: if_true.subst(s), if_false: if_false.subst(s) }
+            hircc::Stm::IfElse { cond, if_true, if_false, hint } => {
+                hircc::Stm::IfElse { cond: cond.subst(s), if_true: if_true.subst(s), if_false: if_false.subst(s), hint: *hint }
             },
-            hircc::Stm::IfThen { cond, if_true } => {
-                hircc::Stm::IfThen { cond: cond.subst(s), if_true: if_true.subst(s) }
+            hircc::Stm::IfThen { cond, if_true, hint } => {
+                hircc::Stm::IfThen { cond: cond.subst(s), if_true: if_true.subst(s), hint: *hint }
             },
-            hircc::Stm::While { cond, body } => {
-                hircc::Stm::While { cond: cond.subst(s), body: body.subst(s) }
+            hircc::Stm::While { cond, body, hints } => {
+                hircc::Stm::While { cond: cond.subst(s), body: body.subst(s), hints: hints.clone() }
             },
             hircc::Stm::Return { exp } => {
                 hircc::Stm::Return { exp: exp.subst(s) }
@@ -362,6 +401,9 @@ impl Substitute for hircc::Stm {
             hircc::Stm::ArrayAssign { bounds_check, ty, array, index, value } => {
                 hircc::Stm::ArrayAssign { bounds_check: *bounds_check, ty: ty.clone(), array: array.subst(s), index: index.subst(s), value: value.subst(s) }
             },
+            hircc::Stm::ArrayCopy { bounds_check, ty, dst_array, dst_index, src_array, src_index, count } => {
+                hircc::Stm::ArrayCopy { bounds_check: *bounds_check, ty: ty.clone(), dst_array: dst_array.subst(s), dst_index: dst_index.subst(s), src_array: src_array.subst(s), src_index: src_index.subst(s), count: count.subst(s) }
+            },
             hircc::Stm::StructAssign { ty, base, field, value } => {
                 hircc::Stm::StructAssign { ty: ty.clone(), base: base.subst(s), field: *field, value: value.subst(s) }
             },
@@ -370,38 +412,64 @@ impl Substitute for hircc::Stm {
 }
 
 pub trait CC<T> {
-    fn convert(&self, fresh_name_generator: &mut FreshNameGenerator, globals: &HashSet<Name>) -> T;
+    fn convert(&self, fresh_name_generator: &mut FreshNameGenerator, globals: &HashSet<Name>, enclosing: Name) -> T;
+}
+
+// Wrap the top-level function name in a lambda that just calls it.
+// The lambda has no free variables, so it converts to a closure with an empty environment.
+fn trampoline(name: Name, ty: &Type, fresh_name_generator: &mut FreshNameGenerator) -> Exp {
+    match ty {
+        Type::Fun { ret, args } => {
+            let params: Vec<Param> = args.iter().map(|a| Param { ty: a.clone(), name: fresh_name_generator.fresh("arg") }).collect();
+
+            Exp::Lambda {
+                ret_type: (**ret).clone(),
+                params: params.clone(),
+                body: Box::new(
+                    Exp::Call {
+                        fun_type: ty.clone(),
+                        name,
+                        args: params.iter().map(|p| Exp::Var { name: p.name, ty: p.ty.clone() }).collect(),
+                    }
+                ),
+            }
+        },
+        _ => unreachable!(),
+    }
 }
 
 impl CC<hircc::Field> for Field {
-    fn convert(&self, fresh_name_generator: &mut FreshNameGenerator, globals: &HashSet<Name>) -> hircc::Field {
+    fn convert(&self, fresh_name_generator: &mut FreshNameGenerator, globals: &HashSet<Name>, enclosing: Name) -> hircc::Field {
         hircc::Field {
             param: self.param.clone(),
-            exp: Box::new(self.exp.convert(fresh_name_generator, globals))
+            exp: Box::new(self.exp.convert(fresh_name_generator, globals, enclosing))
         }
     }
 }
 
 impl CC<hircc::Exp> for Exp {
-    fn convert(&self, fresh_name_generator: &mut FreshNameGenerator, globals: &HashSet<Name>) -> hircc::Exp {
+    fn convert(&self, fresh_name_generator: &mut FreshNameGenerator, globals: &HashSet<Name>, enclosing: Name) -> hircc::Exp {
         match self {
             Exp::NewArray { ty, length } => {
-                hircc::Exp::NewArray { ty: ty.clone(), length: Box::new(length.convert(fresh_name_generator, globals)) }
+                hircc::Exp::NewArray { ty: ty.clone(), length: Box::new(length.convert(fresh_name_generator, globals, enclosing)) }
+            },
+            Exp::NewArrayUninit { ty, length } => {
+                hircc::Exp::NewArrayUninit { ty: ty.clone(), length: Box::new(length.convert(fresh_name_generator, globals, enclosing)) }
             },
             Exp::ArrayLit { ty, exps } => {
-                hircc::Exp::ArrayLit { ty: ty.clone(), exps: exps.iter().map(|e| e.convert(fresh_name_generator, globals)).collect() }
+                hircc::Exp::ArrayLit { ty: ty.clone(), exps: exps.iter().map(|e| e.convert(fresh_name_generator, globals, enclosing)).collect() }
             },
             Exp::ArrayLoad { bounds_check, ty, array, index } => {
-                hircc::Exp::ArrayLoad { bounds_check: *bounds_check, ty: ty.clone(), array: Box::new(array.convert(fresh_name_generator, globals)), index: Box::new(index.convert(fresh_name_generator, globals)) }
+                hircc::Exp::ArrayLoad { bounds_check: *bounds_check, ty: ty.clone(), array: Box::new(array.convert(fresh_name_generator, globals, enclosing)), index: Box::new(index.convert(fresh_name_generator, globals, enclosing)) }
             },
             Exp::ArrayLength { array } => {
-                hircc::Exp::ArrayLength { array: Box::new(array.convert(fresh_name_generator, globals)) }
+                hircc::Exp::ArrayLength { array: Box::new(array.convert(fresh_name_generator, globals, enclosing)) }
             },
             Exp::Lit { lit } => {
                 hircc::Exp::Lit { lit: lit.clone() }
             },
             Exp::Call { fun_type, name, args } => {
-                hircc::Exp::Call { fun_type: fun_type.clone(), name: *name, args: args.iter().map(|e| e.convert(fresh_name_generator, globals)).collect() }
+                hircc::Exp::Call { fun_type: fun_type.clone(), name: *name, args: args.iter().map(|e| e.convert(fresh_name_generator, globals, enclosing)).collect() }
             },
             Exp::Var { name, ty } => {
                 hircc::Exp::Var { name: *name, ty: ty.clone() }
@@ -410,37 +478,61 @@ impl CC<hircc::Exp> for Exp {
                 hircc::Exp::Global { name: *name, ty: ty.clone() }
             },
             Exp::Function { name, ty } => {
-                hircc::Exp::Function { name: *name, ty: ty.clone() }
+                match ty {
+                    // Function values are closures, so a top-level function used as a value
+                    // needs an (empty) environment and code that takes the extra env argument.
+                    Type::Fun { .. } => trampoline(*name, ty, fresh_name_generator).convert(fresh_name_generator, globals, enclosing),
+                    _ => hircc::Exp::Function { name: *name, ty: ty.clone() },
+                }
             },
 
             Exp::Binary { op, e1, e2 } => {
-                hircc::Exp::Binary { op: *op, e1: Box::new(e1.convert(fresh_name_generator, globals)), e2: Box::new(e2.convert(fresh_name_generator, globals)) }
+                hircc::Exp::Binary { op: *op, e1: Box::new(e1.convert(fresh_name_generator, globals, enclosing)), e2: Box::new(e2.convert(fresh_name_generator, globals, enclosing)) }
             },
             Exp::Unary { op, exp } => {
-                hircc::Exp::Unary { op: *op, exp: Box::new(exp.convert(fresh_name_generator, globals)) }
+                hircc::Exp::Unary { op: *op, exp: Box::new(exp.convert(fresh_name_generator, globals, enclosing)) }
+            },
+            Exp::Fma { ty, a, b, c } => {
+                hircc::Exp::Fma {
+                    ty: ty.clone(),
+                    a: Box::new(a.convert(fresh_name_generator, globals, enclosing)),
+                    b: Box::new(b.convert(fresh_name_generator, globals, enclosing)),
+                    c: Box::new(c.convert(fresh_name_generator, globals, enclosing)),
+                }
             },
             Exp::Box { ty, exp } => {
-                hircc::Exp::Box { ty: ty.clone(), exp: Box::new(exp.convert(fresh_name_generator, globals)) }
+                hircc::Exp::Box { ty: ty.clone(), exp: Box::new(exp.convert(fresh_name_generator, globals, enclosing)) }
             },
             Exp::Unbox { ty, exp } => {
-                hircc::Exp::Unbox { ty: ty.clone(), exp: Box::new(exp.convert(fresh_name_generator, globals)) }
+                hircc::Exp::Unbox { ty: ty.clone(), exp: Box::new(exp.convert(fresh_name_generator, globals, enclosing)) }
+            },
+            Exp::Convert { ty, signedness, exp } => {
+                hircc::Exp::Convert { ty: ty.clone(), signedness: *signedness, exp: Box::new(exp.convert(fresh_name_generator, globals, enclosing)) }
             },
             Exp::Cast { ty, exp } => {
-                hircc::Exp::Cast { ty: ty.clone(), exp: Box::new(exp.convert(fresh_name_generator, globals)) }
+                hircc::Exp::Cast { ty: ty.clone(), exp: Box::new(exp.convert(fresh_name_generator, globals, enclosing)) }
             },
 
             Exp::Seq { body, exp } => {
-                hircc::Exp::Seq { body: Box::new(body.convert(fresh_name_generator, globals)), exp: Box::new(exp.convert(fresh_name_generator, globals)) }
+                hircc::Exp::Seq { body: Box::new(body.convert(fresh_name_generator, globals, enclosing)), exp: Box::new(exp.convert(fresh_name_generator, globals, enclosing)) }
+            },
+            Exp::Cond { ty, cond, if_true, if_false } => {
+                hircc::Exp::Cond {
+                    ty: ty.clone(),
+                    cond: Box::new(cond.convert(fresh_name_generator, globals, enclosing)),
+                    if_true: Box::new(if_true.convert(fresh_name_generator, globals, enclosing)),
+                    if_false: Box::new(if_false.convert(fresh_name_generator, globals, enclosing)),
+                }
             },
 
             Exp::Let { inits, body } => {
-                hircc::Exp::Let { inits: inits.iter().map(|f| f.convert(fresh_name_generator, globals)).collect(), body: Box::new(body.convert(fresh_name_generator, globals)) }
+                hircc::Exp::Let { inits: inits.iter().map(|f| f.convert(fresh_name_generator, globals, enclosing)).collect(), body: Box::new(body.convert(fresh_name_generator, globals, enclosing)) }
             },
             Exp::Lambda { ret_type, params, body } => {
                 // The only interesting case is lambda.
 
                 // Create a new name for the environment parameter.
-                let env = fresh_name_generator.fresh("env");
+                let env = fresh_name_generator.derived(&enclosing, "env");
 
                 // Get the free variables of the lambda.
                 // TODO: get the types of the variables!
@@ -487,7 +579,7 @@ impl CC<hircc::Exp> for Exp {
                     });
                 }
 
-                let cc_body = body.convert(fresh_name_generator, globals).subst(&s);
+                let cc_body = body.convert(fresh_name_generator, globals, enclosing).subst(&s);
 
                 hircc::Exp::ClosureLit {
                     fun_type: fun_type.clone(),
@@ -515,50 +607,64 @@ impl CC<hircc::Exp> for Exp {
                 }
             },
             Exp::Apply { fun_type, fun, args } => {
-                hircc::Exp::ApplyCC { fun_type: fun_type.clone(), fun: Box::new(fun.convert(fresh_name_generator, globals)), args: args.iter().map(|e| e.convert(fresh_name_generator, globals)).collect() }
+                hircc::Exp::ApplyCC { fun_type: fun_type.clone(), fun: Box::new(fun.convert(fresh_name_generator, globals, enclosing)), args: args.iter().map(|e| e.convert(fresh_name_generator, globals, enclosing)).collect() }
             },
 
             Exp::StructLit { fields } => {
                 hircc::Exp::StructLit {
-                    fields: fields.iter().map(|f| hircc::Field { param: f.param.clone(), exp: Box::new(f.exp.convert(fresh_name_generator, globals)) }).collect()
+                    fields: fields.iter().map(|f| hircc::Field { param: f.param.clone(), exp: Box::new(f.exp.convert(fresh_name_generator, globals, enclosing)) }).collect()
                 }
             },
             Exp::StructLoad { ty, base, field } => {
-                hircc::Exp::StructLoad { ty: ty.clone(), base: Box::new(base.convert(fresh_name_generator, globals)), field: *field }
+                hircc::Exp::StructLoad { ty: ty.clone(), base: Box::new(base.convert(fresh_name_generator, globals, enclosing)), field: *field }
+            },
+            Exp::StructLoadIdx { ty, base, index } => {
+                hircc::Exp::StructLoadIdx { ty: ty.clone(), base: Box::new(base.convert(fresh_name_generator, globals, enclosing)), index: *index }
             },
         }
     }
 }
 
 impl CC<hircc::Stm> for Stm {
-    fn convert(&self, fresh_name_generator: &mut FreshNameGenerator, globals: &HashSet<Name>) -> hircc::Stm {
+    fn convert(&self, fresh_name_generator: &mut FreshNameGenerator, globals: &HashSet<Name>, enclosing: Name) -> hircc::Stm {
         match self {
-            Stm::IfElse { cond, if_true, if_false } => {
-                hircc::Stm::IfElse { cond: Box::new(cond.convert(fresh_name_generator, globals)), if_true: Box::new(if_true.convert(fresh_name_generator, globals)), if_false: Box::new(if_false.convert(fresh_name_generator, globals)) }
+            Stm::IfElse { cond, if_true, if_false, hint } => {
+                hircc::Stm::IfElse { cond: Box::new(cond.convert(fresh_name_generator, globals, enclosing)), if_true: Box::new(if_true.convert(fresh_name_generator, globals, enclosing)), if_false: Box::new(if_false.convert(fresh_name_generator, globals, enclosing)), hint: *hint }
             },
-            Stm::IfThen { cond, if_true } => {
-                hircc::Stm::IfThen { cond: Box::new(cond.convert(fresh_name_generator, globals)), if_true: Box::new(if_true.convert(fresh_name_generator, globals)) }
+            Stm::IfThen { cond, if_true, hint } => {
+                hircc::Stm::IfThen { cond: Box::new(cond.convert(fresh_name_generator, globals, enclosing)), if_true: Box::new(if_true.convert(fresh_name_generator, globals, enclosing)), hint: *hint }
             },
-            Stm::While { cond, body } => {
-                hircc::Stm::While { cond: Box::new(cond.convert(fresh_name_generator, globals)), body: Box::new(body.convert(fresh_name_generator, globals)) }
+            Stm::While { cond, body, hints } => {
+                hircc::Stm::While { cond: Box::new(cond.convert(fresh_name_generator, globals, enclosing)), body: Box::new(body.convert(fresh_name_generator, globals, enclosing)), hints: hints.clone() }
             },
             Stm::Return { exp } => {
-                hircc::Stm::Return { exp: Box::new(exp.convert(fresh_name_generator, globals)) }
+                hircc::Stm::Return { exp: Box::new(exp.convert(fresh_name_generator, globals, enclosing)) }
             },
             Stm::Block { body } => {
-                hircc::Stm::Block { body: body.iter().map(|e| e.convert(fresh_name_generator, globals)).collect() }
+                hircc::Stm::Block { body: body.iter().map(|e| e.convert(fresh_name_generator, globals, enclosing)).collect() }
             },
             Stm::Eval { exp } => {
-                hircc::Stm::Eval { exp: Box::new(exp.convert(fresh_name_generator, globals)) }
+                hircc::Stm::Eval { exp: Box::new(exp.convert(fresh_name_generator, globals, enclosing)) }
             },
             Stm::Assign { ty, lhs, rhs } => {
-                hircc::Stm::Assign { ty: ty.clone(), lhs: *lhs, rhs: Box::new(rhs.convert(fresh_name_generator, globals)) }
+                hircc::Stm::Assign { ty: ty.clone(), lhs: *lhs, rhs: Box::new(rhs.convert(fresh_name_generator, globals, enclosing)) }
             },
             Stm::ArrayAssign { bounds_check, ty, array, index, value } => {
-                hircc::Stm::ArrayAssign { bounds_check: *bounds_check, ty: ty.clone(), array: Box::new(array.convert(fresh_name_generator, globals)), index: Box::new(index.convert(fresh_name_generator, globals)), value: Box::new(value.convert(fresh_name_generator, globals)) }
+                hircc::Stm::ArrayAssign { bounds_check: *bounds_check, ty: ty.clone(), array: Box::new(array.convert(fresh_name_generator, globals, enclosing)), index: Box::new(index.convert(fresh_name_generator, globals, enclosing)), value: Box::new(value.convert(fresh_name_generator, globals, enclosing)) }
+            },
+            Stm::ArrayCopy { bounds_check, ty, dst_array, dst_index, src_array, src_index, count } => {
+                hircc::Stm::ArrayCopy {
+                    bounds_check: *bounds_check,
+                    ty: ty.clone(),
+                    dst_array: Box::new(dst_array.convert(fresh_name_generator, globals, enclosing)),
+                    dst_index: Box::new(dst_index.convert(fresh_name_generator, globals, enclosing)),
+                    src_array: Box::new(src_array.convert(fresh_name_generator, globals, enclosing)),
+                    src_index: Box::new(src_index.convert(fresh_name_generator, globals, enclosing)),
+                    count: Box::new(count.convert(fresh_name_generator, globals, enclosing)),
+                }
             },
             Stm::StructAssign { ty, base, field, value } => {
-                hircc::Stm::StructAssign { ty: ty.clone(), base: Box::new(base.convert(fresh_name_generator, globals)), field: *field, value: Box::new(value.convert(fresh_name_generator, globals)) }
+                hircc::Stm::StructAssign { ty: ty.clone(), base: Box::new(base.convert(fresh_name_generator, globals, enclosing)), field: *field, value: Box::new(value.convert(fresh_name_generator, globals, enclosing)) }
             },
         }
     }
@@ -566,13 +672,36 @@ impl CC<hircc::Stm> for Stm {
 
 pub struct LambdaLift;
 
+// Where lambda lifting puts the environment in a closure's parameters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EnvPosition {
+    First,
+    Last,
+}
+
+impl EnvPosition {
+    // Add the environment to a parameter or argument list that doesn't include it yet.
+    fn add<T>(self, xs: &mut Vec<T>, env: T) {
+        match self {
+            EnvPosition::First => xs.insert(0, env),
+            EnvPosition::Last => xs.push(env),
+        }
+    }
+}
+
 impl LambdaLift {
-    pub fn lambda_lift(root: &Root) -> Root {
+    pub fn lambda_lift(root: &Root) -> Result<Root, CodegenError> {
+        LambdaLift::lambda_lift_with_env(root, EnvPosition::Last)
+    }
+
+    // Like lambda_lift, but with the environment parameter of each lifted closure
+    // at env_pos instead of at the end.
+    pub fn lambda_lift_with_env(root: &Root, env_pos: EnvPosition) -> Result<Root, CodegenError> {
         let mut defs = Vec::new();
         let mut decls = Vec::new();
 
         let globals: HashSet<Name> = root.defs.iter().map(|def| match def {
-            Def::VarDef { ty, name, exp } => *name,
+            Def::VarDef { ty, name, exp, thread_local } => *name,
             Def::FunDef { ret_type, name, params, body } => *name,
             Def::ExternDef { ty, name } => *name,
         }).collect();
@@ -580,54 +709,55 @@ impl LambdaLift {
         let mut fresh_name_generator = FreshNameGenerator::new("cc");
 
         for def in &root.defs {
-            defs.push(LambdaLift::lambda_lift_def(def, &mut fresh_name_generator, &globals, &mut decls));
+            defs.push(LambdaLift::lambda_lift_def(def, &mut fresh_name_generator, &globals, &mut decls, env_pos)?);
         }
 
         defs.append(&mut decls);
 
-        Root {
+        Ok(Root {
             defs
-        }
+        })
     }
 
-    fn lambda_lift_def(def: &Def, fresh_name_generator: &mut FreshNameGenerator, globals: &HashSet<Name>, decls: &mut Vec<Def>) -> Def {
-        match def {
-            Def::VarDef { ty, name, exp } => {
-                Def::VarDef { ty: ty.lift_type(), name: *name, exp: Box::new(exp.convert(fresh_name_generator, globals).lambda_lift(fresh_name_generator, decls)) }
+    fn lambda_lift_def(def: &Def, fresh_name_generator: &mut FreshNameGenerator, globals: &HashSet<Name>, decls: &mut Vec<Def>, env_pos: EnvPosition) -> Result<Def, CodegenError> {
+        Ok(match def {
+            // Names made for the lambdas in a def are derived from the def's name.
+            Def::VarDef { ty, name, exp, thread_local } => {
+                Def::VarDef { ty: ty.lift_type(env_pos), name: *name, exp: Box::new(exp.convert(fresh_name_generator, globals, *name).lambda_lift(fresh_name_generator, decls, env_pos, *name)?), thread_local: *thread_local }
             },
             Def::FunDef { ret_type, name, params, body } => {
-                Def::FunDef { ret_type: ret_type.lift_type(), name: *name, params: params.lift_type(), body: Box::new(body.convert(fresh_name_generator, globals).lambda_lift(fresh_name_generator, decls)) }
+                Def::FunDef { ret_type: ret_type.lift_type(env_pos), name: *name, params: params.lift_type(env_pos), body: Box::new(body.convert(fresh_name_generator, globals, *name).lambda_lift(fresh_name_generator, decls, env_pos, *name)?) }
             },
             Def::ExternDef { ty, name } => {
                 Def::ExternDef { ty: ty.clone(), name: *name }
             }
-        }
+        })
     }
 }
 
 
 pub trait LL<T> {
-    fn lambda_lift(&self, fresh_name_generator: &mut FreshNameGenerator, decls: &mut Vec<Def>) -> T;
+    fn lambda_lift(&self, fresh_name_generator: &mut FreshNameGenerator, decls: &mut Vec<Def>, env_pos: EnvPosition, enclosing: Name) -> Result<T, CodegenError>;
 }
 
 trait TypeLifter {
-    fn lift_type(&self) -> Self;
+    fn lift_type(&self, env_pos: EnvPosition) -> Self;
 }
 
 impl<A: TypeLifter> TypeLifter for Vec<A> {
-    fn lift_type(&self) -> Vec<A> {
-        self.iter().map(|t| t.lift_type()).collect()
+    fn lift_type(&self, env_pos: EnvPosition) -> Vec<A> {
+        self.iter().map(|t| t.lift_type(env_pos)).collect()
     }
 }
 
 impl TypeLifter for Param {
-    fn lift_type(&self) -> Param {
-        Param { ty: self.ty.lift_type(), name: self.name }
+    fn lift_type(&self, env_pos: EnvPosition) -> Param {
+        Param { ty: self.ty.lift_type(env_pos), name: self.name }
     }
 }
 
 impl TypeLifter for Type {
-    fn lift_type(&self) -> Type {
+    fn lift_type(&self, env_pos: EnvPosition) -> Type {
         match self {
             Type::Fun { ret, args } => {
                 // Function types turn into closure types with the code pointer taking an extract env pointer argument.
@@ -635,22 +765,22 @@ impl TypeLifter for Type {
 
                 let mut new_args = Vec::new();
                 for a in args {
-                    new_args.push(a.lift_type());
+                    new_args.push(a.lift_type(env_pos));
                 }
-                new_args.push(external_env_type.clone());
+                env_pos.add(&mut new_args, external_env_type.clone());
 
                 Type::Struct {
                     fields: vec![
-                        Param { name: Name::new("fun"), ty: Type::Fun { ret: Box::new(ret.lift_type()), args: new_args } },
+                        Param { name: Name::new("fun"), ty: Type::Fun { ret: Box::new(ret.lift_type(env_pos)), args: new_args } },
                         Param { name: Name::new("env"), ty: external_env_type.clone() }
                     ]
                 }
             },
             Type::Struct { fields } => {
-                Type::Struct { fields: fields.lift_type() }
+                Type::Struct { fields: fields.lift_type(env_pos) }
             },
             Type::Array { ty } => {
-                Type::Array { ty: Box::new(ty.lift_type()) }
+                Type::Array { ty: Box::new(ty.lift_type(env_pos)) }
             },
             Type::I8 => Type::I8,
             Type::I16 => Type::I16,
@@ -661,97 +791,119 @@ impl TypeLifter for Type {
             Type::Bool => Type::Bool,
             Type::Void => Type::Void,
             Type::Box => Type::Box,
-            Type::Union { variants } => Type::Union { variants: variants.iter().map(|a| a.lift_type()).collect() },
+            Type::Union { variants } => Type::Union { variants: variants.iter().map(|a| a.lift_type(env_pos)).collect() },
         }
     }
 }
 
 impl LL<Exp> for hircc::Exp {
-    fn lambda_lift(&self, fresh_name_generator: &mut FreshNameGenerator, decls: &mut Vec<Def>) -> Exp {
-        match self {
+    fn lambda_lift(&self, fresh_name_generator: &mut FreshNameGenerator, decls: &mut Vec<Def>, env_pos: EnvPosition, enclosing: Name) -> Result<Exp, CodegenError> {
+        Ok(match self {
             hircc::Exp::NewArray { ty, length } => {
-                Exp::NewArray { ty: ty.lift_type(), length: Box::new(length.lambda_lift(fresh_name_generator, decls)) }
+                Exp::NewArray { ty: ty.lift_type(env_pos), length: Box::new(length.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?) }
+            },
+            hircc::Exp::NewArrayUninit { ty, length } => {
+                Exp::NewArrayUninit { ty: ty.lift_type(env_pos), length: Box::new(length.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?) }
             },
             hircc::Exp::ArrayLit { ty, exps } => {
-                Exp::ArrayLit { ty: ty.lift_type(), exps: exps.iter().map(|e| e.lambda_lift(fresh_name_generator, decls)).collect() }
+                Exp::ArrayLit { ty: ty.lift_type(env_pos), exps: exps.iter().map(|e| e.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)).collect::<Result<_, _>>()? }
             },
             hircc::Exp::ArrayLoad { bounds_check, ty, array, index } => {
-                Exp::ArrayLoad { bounds_check: *bounds_check, ty: ty.lift_type(), array: Box::new(array.lambda_lift(fresh_name_generator, decls)), index: Box::new(index.lambda_lift(fresh_name_generator, decls)) }
+                Exp::ArrayLoad { bounds_check: *bounds_check, ty: ty.lift_type(env_pos), array: Box::new(array.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?), index: Box::new(index.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?) }
             },
             hircc::Exp::ArrayLength { array } => {
-                Exp::ArrayLength { array: Box::new(array.lambda_lift(fresh_name_generator, decls)) }
+                Exp::ArrayLength { array: Box::new(array.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?) }
             },
             hircc::Exp::Lit { lit } => {
                 Exp::Lit { lit: lit.clone() }
             },
             hircc::Exp::Call { fun_type, name, args } => {
-                Exp::Call { fun_type: fun_type.clone(), name: *name, args: args.iter().map(|e| e.lambda_lift(fresh_name_generator, decls)).collect() }
+                Exp::Call { fun_type: fun_type.clone(), name: *name, args: args.iter().map(|e| e.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)).collect::<Result<_, _>>()? }
             },
             hircc::Exp::Var { name, ty } => {
-                Exp::Var { name: *name, ty: ty.lift_type() }
+                Exp::Var { name: *name, ty: ty.lift_type(env_pos) }
             },
             hircc::Exp::Global { name, ty } => {
-                Exp::Global { name: *name, ty: ty.lift_type() }
+                Exp::Global { name: *name, ty: ty.lift_type(env_pos) }
             },
             hircc::Exp::Function { name, ty } => {
-                Exp::Function { name: *name, ty: ty.lift_type() }
+                Exp::Function { name: *name, ty: ty.lift_type(env_pos) }
             },
 
             hircc::Exp::Binary { op, e1, e2 } => {
-                Exp::Binary { op: *op, e1: Box::new(e1.lambda_lift(fresh_name_generator, decls)), e2: Box::new(e2.lambda_lift(fresh_name_generator, decls)) }
+                Exp::Binary { op: *op, e1: Box::new(e1.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?), e2: Box::new(e2.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?) }
             },
             hircc::Exp::Unary { op, exp } => {
-                Exp::Unary { op: *op, exp: Box::new(exp.lambda_lift(fresh_name_generator, decls)) }
+                Exp::Unary { op: *op, exp: Box::new(exp.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?) }
+            },
+            hircc::Exp::Fma { ty, a, b, c } => {
+                Exp::Fma {
+                    ty: ty.lift_type(env_pos),
+                    a: Box::new(a.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?),
+                    b: Box::new(b.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?),
+                    c: Box::new(c.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?),
+                }
             },
             hircc::Exp::Box { ty, exp } => {
-                Exp::Box { ty: ty.lift_type(), exp: Box::new(exp.lambda_lift(fresh_name_generator, decls)) }
+                Exp::Box { ty: ty.lift_type(env_pos), exp: Box::new(exp.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?) }
             },
             hircc::Exp::Unbox { ty, exp } => {
-                Exp::Unbox { ty: ty.lift_type(), exp: Box::new(exp.lambda_lift(fresh_name_generator, decls)) }
+                Exp::Unbox { ty: ty.lift_type(env_pos), exp: Box::new(exp.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?) }
+            },
+            hircc::Exp::Convert { ty, signedness, exp } => {
+                Exp::Convert { ty: ty.lift_type(env_pos), signedness: *signedness, exp: Box::new(exp.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?) }
             },
             hircc::Exp::Cast { ty, exp } => {
-                Exp::Cast { ty: ty.lift_type(), exp: Box::new(exp.lambda_lift(fresh_name_generator, decls)) }
+                Exp::Cast { ty: ty.lift_type(env_pos), exp: Box::new(exp.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?) }
             },
             hircc::Exp::Seq { body, exp } => {
-                Exp::Seq { body: Box::new(body.lambda_lift(fresh_name_generator, decls)), exp: Box::new(exp.lambda_lift(fresh_name_generator, decls)) }
+                Exp::Seq { body: Box::new(body.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?), exp: Box::new(exp.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?) }
+            },
+            hircc::Exp::Cond { ty, cond, if_true, if_false } => {
+                Exp::Cond {
+                    ty: ty.lift_type(env_pos),
+                    cond: Box::new(cond.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?),
+                    if_true: Box::new(if_true.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?),
+                    if_false: Box::new(if_false.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?),
+                }
             },
             hircc::Exp::Let { inits, body } => {
-                Exp::Let { inits: inits.iter().map(|f| Field { param: f.param.lift_type(), exp: Box::new(f.exp.lambda_lift(fresh_name_generator, decls)) }).collect(), body: Box::new(body.lambda_lift(fresh_name_generator, decls)) }
+                Exp::Let { inits: inits.iter().map(|f| Ok(Field { param: f.param.lift_type(env_pos), exp: Box::new(f.exp.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?) })).collect::<Result<_, CodegenError>>()?, body: Box::new(body.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?) }
             },
             hircc::Exp::LambdaCC { ret_type, env_param, params, body } => {
-                let f = fresh_name_generator.fresh("lifted");
+                let f = fresh_name_generator.derived(&enclosing, "lifted");
 
                 // Add a parameter for the environment pointer.
                 // The parameter type is just a void* (an empty struct pointer).
-                let env_param_name = fresh_name_generator.fresh("env");
+                let env_param_name = fresh_name_generator.derived(&enclosing, "env");
                 let external_env_type = Type::Struct { fields: vec![] };
 
                 let mut def_params = params.clone();
-                def_params.push(Param {
+                env_pos.add(&mut def_params, Param {
                     ty: external_env_type.clone(),
                     name: env_param_name,
                 });
 
                 // Create the function type, using the opaque env pointer type.
-                let mut args: Vec<Type> = params.iter().map(|p| p.ty.lift_type()).collect();
-                args.push(external_env_type.clone());
+                let mut args: Vec<Type> = params.iter().map(|p| p.ty.lift_type(env_pos)).collect();
+                env_pos.add(&mut args, external_env_type.clone());
 
                 let fun_type = Type::Fun {
-                    ret: Box::new(ret_type.lift_type()),
+                    ret: Box::new(ret_type.lift_type(env_pos)),
                     args: args
                 };
 
                 // Lift the body.
-                let lifted_body = body.lambda_lift(fresh_name_generator, decls);
+                let lifted_body = body.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?;
 
                 // Cast the env parameter to the more specific type, using the name
                 // that was used for the env parameter during closure conversion.
                 let env_ptr = Exp::Var { ty: external_env_type.clone(), name: env_param_name };
-                let cast = Exp::Cast { ty: env_param.ty.lift_type(), exp: Box::new(env_ptr) };
+                let cast = Exp::Cast { ty: env_param.ty.lift_type(env_pos), exp: Box::new(env_ptr) };
                 let exp = Exp::Let {
                     inits: vec![
                         Field {
-                            param: env_param.lift_type(),
+                            param: env_param.lift_type(env_pos),
                             exp: Box::new(cast)
                         }
                     ],
@@ -760,7 +912,7 @@ impl LL<Exp> for hircc::Exp {
 
                 // Declare the function using the new lifted body with cast.
                 decls.push(Def::FunDef {
-                    ret_type: ret_type.lift_type(),
+                    ret_type: ret_type.lift_type(env_pos),
                     name: f,
                     params: def_params.clone(),
                     body: Box::new(exp),
@@ -770,15 +922,22 @@ impl LL<Exp> for hircc::Exp {
                 Exp::Function { name: f, ty: fun_type }
             },
             hircc::Exp::ApplyCC { fun_type, fun, args } => {
-                // The caller doesn't know the environment type, just that it's a struct.
-                let env_type = Type::Struct { fields: vec![] };
+                let closure_type = fun_type.lift_type(env_pos);
+
+                // Call through the closure's fun field at the type it's stored with,
+                // which takes the environment as an extra argument.
+                let cc_fun_type = match (fun_type, &closure_type) {
+                    (Type::Fun { .. }, Type::Struct { fields }) => fields.iter().find(|f| f.name == Name::new("fun")).map(|f| f.ty.clone()),
+                    _ => None,
+                };
+                let cc_fun_type = cc_fun_type.ok_or_else(|| CodegenError::ApplyNonFunction { name: enclosing, ty: fun_type.clone() })?;
 
-                let closure = fresh_name_generator.fresh("closure");
-                let mut closure_args: Vec<Exp> = args.iter().map(|e| e.lambda_lift(fresh_name_generator, decls)).collect();
-                let closure_type = fun_type.lift_type();
+                let closure = fresh_name_generator.derived(&enclosing, "closure");
+                let mut closure_args: Vec<Exp> = args.iter().map(|e| e.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)).collect::<Result<_, _>>()?;
 
-                // Add environment at the end of the arguments.
-                closure_args.push(
+                // Add the environment to the arguments, where the lifted function expects it.
+                env_pos.add(
+                    &mut closure_args,
                     Exp::StructLoad {
                         ty: closure_type.clone(),
                         base: Box::new(Exp::Var { name: closure, ty: closure_type.clone() }),
@@ -786,23 +945,11 @@ impl LL<Exp> for hircc::Exp {
                     },
                 );
 
-                let cc_fun_type = match fun_type {
-                    Type::Fun { ret, args } => {
-                        let mut new_args = Vec::new();
-                        for a in args {
-                            new_args.push(a.lift_type());
-                        }
-                        new_args.push(env_type.clone());
-                        Type::Fun { ret: Box::new(ret.lift_type()), args: new_args }
-                    },
-                    _ => panic!("ApplyCC type should be a function type")
-                };
-
                 Exp::Let {
                     inits: vec![
                         Field {
                             param: Param { name: closure, ty: closure_type.clone() },
-                            exp: Box::new(fun.lambda_lift(fresh_name_generator, decls)),
+                            exp: Box::new(fun.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?),
                         }
                     ],
                     body: Box::new(
@@ -822,7 +969,7 @@ impl LL<Exp> for hircc::Exp {
             },
             hircc::Exp::StructLit { fields } => {
                 Exp::StructLit {
-                    fields: fields.iter().map(|f| Field { param: f.param.lift_type(), exp: Box::new(f.exp.lambda_lift(fresh_name_generator, decls)) }).collect()
+                    fields: fields.iter().map(|f| Ok(Field { param: f.param.lift_type(env_pos), exp: Box::new(f.exp.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?) })).collect::<Result<_, CodegenError>>()?
                  }
             },
             hircc::Exp::ClosureLit { fun_type, fun, env } => {
@@ -834,12 +981,12 @@ impl LL<Exp> for hircc::Exp {
                     Type::Fun { ret, args } => {
                         let mut new_args = Vec::new();
                         for a in args {
-                            new_args.push(a.lift_type());
+                            new_args.push(a.lift_type(env_pos));
                         }
-                        new_args.push(env_type.clone());
-                        Type::Fun { ret: Box::new(ret.lift_type()), args: new_args }
+                        env_pos.add(&mut new_args, env_type.clone());
+                        Type::Fun { ret: Box::new(ret.lift_type(env_pos)), args: new_args }
                     },
-                    _ => panic!("ApplyCC type should be a function type")
+                    _ => return Err(CodegenError::NotAFunctionType { ty: fun_type.clone() }),
                 };
 
 
@@ -850,56 +997,70 @@ impl LL<Exp> for hircc::Exp {
                                 ty: cc_fun_type.clone(),
                                 name: Name::new("fun"),
                             },
-                            exp: Box::new(fun.lambda_lift(fresh_name_generator, decls)),
+                            exp: Box::new(fun.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?),
                         },
                         Field {
                             param: Param {
-                                ty: env_type.lift_type(),
+                                ty: env_type.lift_type(env_pos),
                                 name: Name::new("env"),
                             },
-                            exp: Box::new(env.lambda_lift(fresh_name_generator, decls)),
+                            exp: Box::new(env.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?),
                         },
                     ]
                 }
             },
             hircc::Exp::StructLoad { ty, base, field } => {
-                Exp::StructLoad { ty: ty.lift_type(), base: Box::new(base.lambda_lift(fresh_name_generator, decls)), field: *field }
+                Exp::StructLoad { ty: ty.lift_type(env_pos), base: Box::new(base.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?), field: *field }
             },
-        }
+            hircc::Exp::StructLoadIdx { ty, base, index } => {
+                Exp::StructLoadIdx { ty: ty.lift_type(env_pos), base: Box::new(base.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?), index: *index }
+            },
+        })
     }
 }
 
 impl LL<Stm> for hircc::Stm {
-    fn lambda_lift(&self, fresh_name_generator: &mut FreshNameGenerator, decls: &mut Vec<Def>) -> Stm {
-        match self {
-            hircc::Stm::IfElse { cond, if_true, if_false } => {
-                Stm::IfElse { cond: Box::new(cond.lambda_lift(fresh_name_generator, decls)), if_true: Box::new(if_true.lambda_lift(fresh_name_generator, decls)), if_false: Box::new(if_false.lambda_lift(fresh_name_generator, decls)) }
+    fn lambda_lift(&self, fresh_name_generator: &mut FreshNameGenerator, decls: &mut Vec<Def>, env_pos: EnvPosition, enclosing: Name) -> Result<Stm, CodegenError> {
+        Ok(match self {
+            hircc::Stm::IfElse { cond, if_true, if_false, hint } => {
+                Stm::IfElse { cond: Box::new(cond.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?), if_true: Box::new(if_true.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?), if_false: Box::new(if_false.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?), hint: *hint }
             },
-            hircc::Stm::IfThen { cond, if_true } => {
-                Stm::IfThen { cond: Box::new(cond.lambda_lift(fresh_name_generator, decls)), if_true: Box::new(if_true.lambda_lift(fresh_name_generator, decls)) }
+            hircc::Stm::IfThen { cond, if_true, hint } => {
+                Stm::IfThen { cond: Box::new(cond.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?), if_true: Box::new(if_true.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?), hint: *hint }
             },
-            hircc::Stm::While { cond, body } => {
-                Stm::While { cond: Box::new(cond.lambda_lift(fresh_name_generator, decls)), body: Box::new(body.lambda_lift(fresh_name_generator, decls)) }
+            hircc::Stm::While { cond, body, hints } => {
+                Stm::While { cond: Box::new(cond.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?), body: Box::new(body.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?), hints: hints.clone() }
             },
             hircc::Stm::Return { exp } => {
-                Stm::Return { exp: Box::new(exp.lambda_lift(fresh_name_generator, decls)) }
+                Stm::Return { exp: Box::new(exp.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?) }
             },
             hircc::Stm::Block { body } => {
-                Stm::Block { body: body.iter().map(|e| e.lambda_lift(fresh_name_generator, decls)).collect() }
+                Stm::Block { body: body.iter().map(|e| e.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)).collect::<Result<_, _>>()? }
             },
             hircc::Stm::Eval { exp } => {
-                Stm::Eval { exp: Box::new(exp.lambda_lift(fresh_name_generator, decls)) }
+                Stm::Eval { exp: Box::new(exp.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?) }
             },
             hircc::Stm::Assign { ty, lhs, rhs } => {
-                Stm::Assign { ty: ty.clone(), lhs: *lhs, rhs: Box::new(rhs.lambda_lift(fresh_name_generator, decls)) }
+                Stm::Assign { ty: ty.clone(), lhs: *lhs, rhs: Box::new(rhs.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?) }
             },
             hircc::Stm::ArrayAssign { bounds_check, ty, array, index, value } => {
-                Stm::ArrayAssign { bounds_check: *bounds_check, ty: ty.clone(), array: Box::new(array.lambda_lift(fresh_name_generator, decls)), index: Box::new(index.lambda_lift(fresh_name_generator, decls)), value: Box::new(value.lambda_lift(fresh_name_generator, decls)) }
+                Stm::ArrayAssign { bounds_check: *bounds_check, ty: ty.clone(), array: Box::new(array.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?), index: Box::new(index.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?), value: Box::new(value.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?) }
+            },
+            hircc::Stm::ArrayCopy { bounds_check, ty, dst_array, dst_index, src_array, src_index, count } => {
+                Stm::ArrayCopy {
+                    bounds_check: *bounds_check,
+                    ty: ty.clone(),
+                    dst_array: Box::new(dst_array.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?),
+                    dst_index: Box::new(dst_index.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?),
+                    src_array: Box::new(src_array.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?),
+                    src_index: Box::new(src_index.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?),
+                    count: Box::new(count.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?),
+                }
             },
             hircc::Stm::StructAssign { ty, base, field, value } => {
-                Stm::StructAssign { ty: ty.clone(), base: Box::new(base.lambda_lift(fresh_name_generator, decls)), field: *field, value: Box::new(value.lambda_lift(fresh_name_generator, decls)) }
+                Stm::StructAssign { ty: ty.clone(), base: Box::new(base.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?), field: *field, value: Box::new(value.lambda_lift(fresh_name_generator, decls, env_pos, enclosing)?) }
             },
-        }
+        })
     }
 }
 
@@ -909,6 +1070,7 @@ mod tests {
     use crate::hir::trees::*;
     use crate::hir::ops::*;
     use crate::common::names::*;
+    use crate::common::errors::CodegenError;
 
     #[test]
     fn test_church_true() {
@@ -938,7 +1100,8 @@ mod tests {
                                 }
                             )
                         }
-                    )
+                    ),
+                    thread_local: false,
                 }
              ]
         };
@@ -1027,7 +1190,7 @@ mod tests {
                                     name: Name::new("fun")
                                 },
                                 exp: Box::new(Exp::Function {
-                                    name: Name::new("lifted.cc.2"),
+                                    name: Name::new("true$lifted.cc.2"),
                                     ty: Type::Fun {
                                         ret: Box::new(Type::Struct {
                                             fields: vec![
@@ -1077,11 +1240,12 @@ mod tests {
                                 })
                             }
                         ]
-                    })
+                    }),
+                    thread_local: false,
                 },
                 Def::FunDef {
                     ret_type: Type::I32,
-                    name: Name::new("lifted.cc.4"),
+                    name: Name::new("true$lifted.cc.4"),
                     params: vec![
                         Param {
                             ty: Type::I32,
@@ -1091,7 +1255,7 @@ mod tests {
                             ty: Type::Struct {
                                 fields: vec![]
                             },
-                            name: Name::new("env.cc.5")
+                            name: Name::new("true$env.cc.5")
                         }
                     ],
                     body: Box::new(Exp::Let {
@@ -1106,7 +1270,7 @@ mod tests {
                                             }
                                         ]
                                     },
-                                    name: Name::new("env.cc.1")
+                                    name: Name::new("true$env.cc.1")
                                 },
                                 exp: Box::new(Exp::Cast {
                                     ty: Type::Struct {
@@ -1118,7 +1282,7 @@ mod tests {
                                         ]
                                     },
                                     exp: Box::new(Exp::Var {
-                                        name: Name::new("env.cc.5"),
+                                        name: Name::new("true$env.cc.5"),
                                         ty: Type::Struct {
                                             fields: vec![]
                                         }
@@ -1136,7 +1300,7 @@ mod tests {
                                 ]
                             },
                             base: Box::new(Exp::Var {
-                                name: Name::new("env.cc.1"),
+                                name: Name::new("true$env.cc.1"),
                                 ty: Type::Struct {
                                     fields: vec![
                                         Param {
@@ -1173,7 +1337,7 @@ mod tests {
                             },
                         ]
                     },
-                    name: Name::new("lifted.cc.2"),
+                    name: Name::new("true$lifted.cc.2"),
                     params: vec![
                         Param {
                             ty: Type::I32,
@@ -1183,7 +1347,7 @@ mod tests {
                             ty: Type::Struct {
                                 fields: vec![]
                             },
-                            name: Name::new("env.cc.3")
+                            name: Name::new("true$env.cc.3")
                         }
                     ],
                     body: Box::new(Exp::Let {
@@ -1193,14 +1357,14 @@ mod tests {
                                     ty: Type::Struct {
                                         fields: vec![]
                                     },
-                                    name: Name::new("env.cc.0")
+                                    name: Name::new("true$env.cc.0")
                                 },
                                 exp: Box::new(Exp::Cast {
                                     ty: Type::Struct {
                                         fields: vec![]
                                     },
                                     exp: Box::new(Exp::Var {
-                                        name: Name::new("env.cc.3"),
+                                        name: Name::new("true$env.cc.3"),
                                         ty: Type::Struct {
                                             fields: vec![]
                                         }
@@ -1224,7 +1388,7 @@ mod tests {
                                         name: Name::new("fun")
                                     },
                                     exp: Box::new(Exp::Function {
-                                        name: Name::new("lifted.cc.4"),
+                                        name: Name::new("true$lifted.cc.4"),
                                         ty: Type::Fun {
                                             ret: Box::new(Type::I32),
                                             args: vec![
@@ -1270,7 +1434,7 @@ mod tests {
             ]
         };
 
-        let lifted = LambdaLift::lambda_lift(&h);
+        let lifted = LambdaLift::lambda_lift(&h).unwrap();
         assert_eq!(lifted, expected);
     }
 
@@ -1302,7 +1466,8 @@ mod tests {
                                 }
                             )
                         }
-                    )
+                    ),
+                    thread_local: false,
                 }
              ]
         };
@@ -1391,7 +1556,7 @@ mod tests {
                                     name: Name::new("fun")
                                 },
                                 exp: Box::new(Exp::Function {
-                                    name: Name::new("lifted.cc.2"),
+                                    name: Name::new("false$lifted.cc.2"),
                                     ty: Type::Fun {
                                         ret: Box::new(Type::Struct {
                                             fields: vec![
@@ -1441,11 +1606,12 @@ mod tests {
                                 })
                             }
                         ]
-                    })
+                    }),
+                    thread_local: false,
                 },
                 Def::FunDef {
                     ret_type: Type::I32,
-                    name: Name::new("lifted.cc.4"),
+                    name: Name::new("false$lifted.cc.4"),
                     params: vec![
                         Param {
                             ty: Type::I32,
@@ -1455,7 +1621,7 @@ mod tests {
                             ty: Type::Struct {
                                 fields: vec![]
                             },
-                            name: Name::new("env.cc.5")
+                            name: Name::new("false$env.cc.5")
                         }
                     ],
                     body: Box::new(Exp::Let {
@@ -1465,14 +1631,14 @@ mod tests {
                                     ty: Type::Struct {
                                         fields: vec![]
                                     },
-                                    name: Name::new("env.cc.1")
+                                    name: Name::new("false$env.cc.1")
                                 },
                                 exp: Box::new(Exp::Cast {
                                     ty: Type::Struct {
                                         fields: vec![]
                                     },
                                     exp: Box::new(Exp::Var {
-                                        name: Name::new("env.cc.5"),
+                                        name: Name::new("false$env.cc.5"),
                                         ty: Type::Struct {
                                             fields: vec![]
                                         }
@@ -1509,7 +1675,7 @@ mod tests {
                             },
                         ]
                     },
-                    name: Name::new("lifted.cc.2"),
+                    name: Name::new("false$lifted.cc.2"),
                     params: vec![
                         Param {
                             ty: Type::I32,
@@ -1519,7 +1685,7 @@ mod tests {
                             ty: Type::Struct {
                                 fields: vec![]
                             },
-                            name: Name::new("env.cc.3")
+                            name: Name::new("false$env.cc.3")
                         }
                     ],
                     body: Box::new(Exp::Let {
@@ -1529,14 +1695,14 @@ mod tests {
                                     ty: Type::Struct {
                                         fields: vec![]
                                     },
-                                    name: Name::new("env.cc.0")
+                                    name: Name::new("false$env.cc.0")
                                 },
                                 exp: Box::new(Exp::Cast {
                                     ty: Type::Struct {
                                         fields: vec![]
                                     },
                                     exp: Box::new(Exp::Var {
-                                        name: Name::new("env.cc.3"),
+                                        name: Name::new("false$env.cc.3"),
                                         ty: Type::Struct {
                                             fields: vec![]
                                         }
@@ -1560,7 +1726,7 @@ mod tests {
                                         name: Name::new("fun")
                                     },
                                     exp: Box::new(Exp::Function {
-                                        name: Name::new("lifted.cc.4"),
+                                        name: Name::new("false$lifted.cc.4"),
                                         ty: Type::Fun {
                                             ret: Box::new(Type::I32),
                                             args: vec![
@@ -1595,7 +1761,7 @@ mod tests {
             ]
         };
 
-        let lifted = LambdaLift::lambda_lift(&h);
+        let lifted = LambdaLift::lambda_lift(&h).unwrap();
         assert_eq!(lifted, expected);
     }
 
@@ -1617,7 +1783,8 @@ mod tests {
                             ],
                             body: Box::new(Exp::Var { name: Name::new("x"), ty: Type::I32 })
                         }
-                    )
+                    ),
+                    thread_local: false,
                 }
              ]
         };
@@ -1664,7 +1831,7 @@ mod tests {
                                         name: Name::new("fun")
                                     },
                                     exp: Box::new(Exp::Function {
-                                        name: Name::new("lifted.cc.1"),
+                                        name: Name::new("id$lifted.cc.1"),
                                         ty: Type::Fun {
                                             ret: Box::new(Type::I32),
                                             args: vec![
@@ -1696,6 +1863,7 @@ mod tests {
                         }
                     ),
                     name: Name::new("id"),
+                    thread_local: false,
                 },
                 Def::FunDef {
                     ret_type: Type::I32,
@@ -1704,10 +1872,10 @@ mod tests {
                             name: Name::new("x"), ty: Type::I32,
                         },
                         Param {
-                            name: Name::new("env.cc.2"), ty: Type::Struct { fields: vec![] },
+                            name: Name::new("id$env.cc.2"), ty: Type::Struct { fields: vec![] },
                         }
                     ],
-                    name: Name::new("lifted.cc.1"),
+                    name: Name::new("id$lifted.cc.1"),
                     body: Box::new(Exp::Let {
                         inits: vec![
                             Field {
@@ -1715,14 +1883,14 @@ mod tests {
                                     ty: Type::Struct {
                                         fields: vec![]
                                     },
-                                    name: Name::new("env.cc.0")
+                                    name: Name::new("id$env.cc.0")
                                 },
                                 exp: Box::new(Exp::Cast {
                                     ty: Type::Struct {
                                         fields: vec![]
                                     },
                                     exp: Box::new(Exp::Var {
-                                        name: Name::new("env.cc.2"),
+                                        name: Name::new("id$env.cc.2"),
                                         ty: Type::Struct {
                                             fields: vec![]
                                         }
@@ -1739,9 +1907,298 @@ mod tests {
             ]
         };
 
-        let lifted = LambdaLift::lambda_lift(&h);
+        let lifted = LambdaLift::lambda_lift(&h).unwrap();
         assert_eq!(lifted, expected);
     }
 
+    #[test]
+    fn test_apply_not_a_function() {
+        let h = Root {
+            defs: vec![
+                // main = 0()
+                Def::FunDef {
+                    ret_type: Type::I32,
+                    name: Name::new("main"),
+                    params: vec![],
+                    body: Box::new(
+                        Exp::Apply {
+                            fun_type: Type::I32,
+                            fun: Box::new(Exp::Lit { lit: Lit::I32 { value: 0 } }),
+                            args: vec![]
+                        }
+                    )
+                }
+            ]
+        };
+
+        assert_eq!(LambdaLift::lambda_lift(&h).err(), Some(CodegenError::ApplyNonFunction { name: Name::new("main"), ty: Type::I32 }));
+    }
+
+    #[test]
+    fn test_env_first() {
+        let h = Root {
+            defs: vec![
+                // main(y) = (\x -> x + y)(1)
+                Def::FunDef {
+                    ret_type: Type::I32,
+                    name: Name::new("main"),
+                    params: vec![
+                        Param { name: Name::new("y"), ty: Type::I32 }
+                    ],
+                    body: Box::new(
+                        Exp::Apply {
+                            fun_type: Type::Fun { ret: Box::new(Type::I32), args: vec![Type::I32] },
+                            fun: Box::new(
+                                Exp::Lambda {
+                                    ret_type: Type::I32,
+                                    params: vec![
+                                        Param { name: Name::new("x"), ty: Type::I32 }
+                                    ],
+                                    body: Box::new(
+                                        Exp::Binary {
+                                            op: Bop::Add_i32,
+                                            e1: Box::new(Exp::Var { name: Name::new("x"), ty: Type::I32 }),
+                                            e2: Box::new(Exp::Var { name: Name::new("y"), ty: Type::I32 }),
+                                        }
+                                    ),
+                                }
+                            ),
+                            args: vec![Exp::Lit { lit: Lit::I32 { value: 1 } }]
+                        }
+                    )
+                }
+            ]
+        };
+
+        let env_type = Type::Struct { fields: vec![] };
+
+        let lifted = LambdaLift::lambda_lift_with_env(&h, EnvPosition::First).unwrap();
+
+        // The lifted lambda takes the environment, then x.
+        let params = lifted.defs.iter().filter_map(|def| match def {
+            Def::FunDef { name, params, .. } if name.to_string().starts_with("main$lifted") => Some(params),
+            _ => None,
+        }).next().unwrap();
+
+        assert_eq!(params.len(), 2);
+        assert_eq!(params[0].ty, env_type);
+        assert_eq!(params[1].name, Name::new("x"));
+
+        // The application passes the environment first too.
+        match &lifted.defs[0] {
+            Def::FunDef { body, .. } => match &**body {
+                Exp::Let { body, .. } => match &**body {
+                    Exp::Apply { fun_type: Type::Fun { args: arg_types, .. }, args, .. } => {
+                        assert_eq!(arg_types, &vec![env_type.clone(), Type::I32]);
+                        match &args[0] {
+                            Exp::StructLoad { field, .. } => assert_eq!(*field, Name::new("env")),
+                            e => panic!("expected the environment, got {:?}", e),
+                        }
+                        assert_eq!(args[1], Exp::Lit { lit: Lit::I32 { value: 1 } });
+                    },
+                    e => panic!("expected an apply, got {:?}", e),
+                },
+                e => panic!("expected a let, got {:?}", e),
+            },
+            def => panic!("expected main, got {:?}", def),
+        }
+    }
+
+    #[test]
+    fn test_lambda_reads_global() {
+        let h = Root {
+            defs: vec![
+                // g = 41
+                Def::VarDef {
+                    ty: Type::I32,
+                    name: Name::new("g"),
+                    exp: Box::new(Exp::Lit { lit: Lit::I32 { value: 41 } }),
+                    thread_local: false,
+                },
+                // main() = (\x -> x + g)(1)
+                Def::FunDef {
+                    ret_type: Type::I32,
+                    name: Name::new("main"),
+                    params: vec![],
+                    body: Box::new(
+                        Exp::Apply {
+                            fun_type: Type::Fun { ret: Box::new(Type::I32), args: vec![Type::I32] },
+                            fun: Box::new(
+                                Exp::Lambda {
+                                    ret_type: Type::I32,
+                                    params: vec![
+                                        Param { name: Name::new("x"), ty: Type::I32 }
+                                    ],
+                                    body: Box::new(
+                                        Exp::Binary {
+                                            op: Bop::Add_i32,
+                                            e1: Box::new(Exp::Var { name: Name::new("x"), ty: Type::I32 }),
+                                            e2: Box::new(Exp::Global { name: Name::new("g"), ty: Type::I32 }),
+                                        }
+                                    ),
+                                }
+                            ),
+                            args: vec![Exp::Lit { lit: Lit::I32 { value: 1 } }]
+                        }
+                    )
+                }
+            ]
+        };
+
+        let lifted = LambdaLift::lambda_lift(&h).unwrap();
+
+        let (params, body) = lifted.defs.iter().filter_map(|def| match def {
+            Def::FunDef { name, params, body, .. } if name.to_string().starts_with("main$lifted") => Some((params, body)),
+            _ => None,
+        }).next().unwrap();
+
+        // The global is not a free variable, so it isn't captured in the environment.
+        assert_eq!(params.len(), 2);
+        assert_eq!(params[1].ty, Type::Struct { fields: vec![] });
+
+        // The lifted body still reads the global directly.
+        assert!(format!("{:?}", body).contains(&format!("{:?}", Exp::Global { name: Name::new("g"), ty: Type::I32 })));
+    }
+
+    #[test]
+    fn test_lifted_name_derived_from_enclosing() {
+        let h = Root {
+            defs: vec![
+                // foo() = (\x -> x)(1)
+                Def::FunDef {
+                    ret_type: Type::I32,
+                    name: Name::new("foo"),
+                    params: vec![],
+                    body: Box::new(
+                        Exp::Apply {
+                            fun_type: Type::Fun { ret: Box::new(Type::I32), args: vec![Type::I32] },
+                            fun: Box::new(
+                                Exp::Lambda {
+                                    ret_type: Type::I32,
+                                    params: vec![
+                                        Param { name: Name::new("x"), ty: Type::I32 }
+                                    ],
+                                    body: Box::new(Exp::Var { name: Name::new("x"), ty: Type::I32 }),
+                                }
+                            ),
+                            args: vec![Exp::Lit { lit: Lit::I32 { value: 1 } }]
+                        }
+                    )
+                }
+            ]
+        };
+
+        let lifted = LambdaLift::lambda_lift(&h).unwrap();
+
+        let names: Vec<String> = lifted.defs.iter().map(|def| match def {
+            Def::FunDef { name, .. } => name.to_string(),
+            def => panic!("expected a function, got {:?}", def),
+        }).collect();
+
+        assert_eq!(names.len(), 2);
+        assert_eq!(names[0], "foo");
+        assert!(names[1].starts_with("foo$lifted"), "expected a name derived from foo, got {}", names[1]);
+    }
+
+    // The name of e's variant. The match has no wildcard, so adding an Exp variant
+    // doesn't compile until it's listed here; then add an example to test_every_exp_lifts.
+    fn exp_variant(e: &Exp) -> &'static str {
+        match e {
+            Exp::NewArray { .. } => "NewArray",
+            Exp::NewArrayUninit { .. } => "NewArrayUninit",
+            Exp::ArrayLit { .. } => "ArrayLit",
+            Exp::ArrayLoad { .. } => "ArrayLoad",
+            Exp::ArrayLength { .. } => "ArrayLength",
+            Exp::Lit { .. } => "Lit",
+            Exp::Call { .. } => "Call",
+            Exp::Var { .. } => "Var",
+            Exp::Global { .. } => "Global",
+            Exp::Function { .. } => "Function",
+            Exp::Binary { .. } => "Binary",
+            Exp::Unary { .. } => "Unary",
+            Exp::Fma { .. } => "Fma",
+            Exp::Seq { .. } => "Seq",
+            Exp::Cond { .. } => "Cond",
+            Exp::Let { .. } => "Let",
+            Exp::Lambda { .. } => "Lambda",
+            Exp::Apply { .. } => "Apply",
+            Exp::StructLit { .. } => "StructLit",
+            Exp::StructLoad { .. } => "StructLoad",
+            Exp::StructLoadIdx { .. } => "StructLoadIdx",
+            Exp::Box { .. } => "Box",
+            Exp::Unbox { .. } => "Unbox",
+            Exp::Cast { .. } => "Cast",
+            Exp::Convert { .. } => "Convert",
+        }
+    }
+
+    #[test]
+    fn test_every_exp_lifts() {
+        let i32_lit = |value| Box::new(Exp::Lit { lit: Lit::I32 { value } });
+        let f64_lit = |value| Box::new(Exp::Lit { lit: Lit::F64 { value } });
+        let x = || Box::new(Exp::Var { name: Name::new("x"), ty: Type::I32 });
+        let a = Param { name: Name::new("a"), ty: Type::I32 };
+        let struct_lit = || Box::new(Exp::StructLit { fields: vec![Field { param: Param { name: Name::new("a"), ty: Type::I32 }, exp: i32_lit(1) }] });
+        let array = || Box::new(Exp::NewArray { ty: Type::I32, length: i32_lit(2) });
+        let f_type = Type::Fun { ret: Box::new(Type::I32), args: vec![] };
+        let id = || Box::new(Exp::Lambda {
+            ret_type: Type::I32,
+            params: vec![Param { name: Name::new("y"), ty: Type::I32 }],
+            body: Box::new(Exp::Binary { op: Bop::Add_i32, e1: Box::new(Exp::Var { name: Name::new("y"), ty: Type::I32 }), e2: x() }),
+        });
+
+        let examples = vec![
+            *array(),
+            Exp::NewArrayUninit { ty: Type::I32, length: i32_lit(2) },
+            Exp::ArrayLit { ty: Type::I32, exps: vec![*i32_lit(1), *x()] },
+            Exp::ArrayLoad { bounds_check: true, ty: Type::I32, array: array(), index: i32_lit(0) },
+            Exp::ArrayLength { array: array() },
+            *i32_lit(1),
+            Exp::Call { fun_type: f_type.clone(), name: Name::new("f"), args: vec![] },
+            *x(),
+            Exp::Global { name: Name::new("g"), ty: Type::I32 },
+            Exp::Function { name: Name::new("f"), ty: f_type.clone() },
+            Exp::Binary { op: Bop::Add_i32, e1: x(), e2: i32_lit(1) },
+            Exp::Unary { op: Uop::Ctz_i32, exp: x() },
+            Exp::Fma { ty: Type::F64, a: f64_lit(1.0), b: f64_lit(2.0), c: f64_lit(3.0) },
+            Exp::Seq { body: Box::new(Stm::Eval { exp: x() }), exp: x() },
+            Exp::Cond { ty: Type::I32, cond: Box::new(Exp::Lit { lit: Lit::Bool { value: true } }), if_true: x(), if_false: i32_lit(0) },
+            Exp::Let { inits: vec![Field { param: a.clone(), exp: x() }], body: Box::new(Exp::Var { name: Name::new("a"), ty: Type::I32 }) },
+            *id(),
+            Exp::Apply { fun_type: Type::Fun { ret: Box::new(Type::I32), args: vec![Type::I32] }, fun: id(), args: vec![*i32_lit(1)] },
+            *struct_lit(),
+            Exp::StructLoad { ty: Type::Struct { fields: vec![a.clone()] }, base: struct_lit(), field: Name::new("a") },
+            Exp::StructLoadIdx { ty: Type::Struct { fields: vec![a.clone()] }, base: struct_lit(), index: 0 },
+            Exp::Box { ty: Type::I32, exp: x() },
+            Exp::Unbox { ty: Type::I32, exp: Box::new(Exp::Box { ty: Type::I32, exp: x() }) },
+            Exp::Cast { ty: Type::I32, exp: x() },
+            Exp::Convert { ty: Type::I64, signedness: Signedness::Signed, exp: x() },
+        ];
+
+        // One example of each variant.
+        let variants: HashSet<&str> = examples.iter().map(exp_variant).collect();
+        assert_eq!(variants.len(), examples.len());
+        assert_eq!(variants.len(), 25);
+
+        for e in examples {
+            let h = Root {
+                defs: vec![
+                    Def::VarDef { ty: Type::I32, name: Name::new("g"), exp: i32_lit(0), thread_local: false },
+                    Def::FunDef { ret_type: Type::I32, name: Name::new("f"), params: vec![], body: i32_lit(0) },
+                    Def::FunDef { ret_type: Type::I32, name: Name::new("main"), params: vec![Param { name: Name::new("x"), ty: Type::I32 }], body: Box::new(e.clone()) },
+                ]
+            };
+
+            let lifted = LambdaLift::lambda_lift(&h).unwrap_or_else(|err| panic!("lifting {} failed: {:?}", exp_variant(&e), err));
+
+            // Closure conversion only changes the shape of function values and applications.
+            match (&e, &lifted.defs[2]) {
+                (Exp::Lambda { .. }, _) | (Exp::Apply { .. }, _) | (Exp::Function { .. }, _) => {},
+                (_, Def::FunDef { body, .. }) => assert_eq!(exp_variant(body), exp_variant(&e)),
+                (_, def) => panic!("expected main, got {:?}", def),
+            }
+        }
+    }
+
     // TODO: add tests where we CALL the function!
 }
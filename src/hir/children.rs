@@ -0,0 +1,128 @@
+// Children of HIR nodes
+// The immediate subexpressions and substatements of an Exp or Stm, so a pass that only
+// cares about a few kinds of node can recurse through the rest without matching each one.
+// Expressions come first and statements second, so a Seq's body is listed after its exp.
+
+use super::trees::{Stm, Exp};
+
+// The immediate subexpressions and substatements of e.
+pub fn exp_children(e: &Exp) -> (Vec<&Exp>, Vec<&Stm>) {
+    match e {
+        Exp::NewArray { ty, length } => (vec![&**length], vec![]),
+        Exp::NewArrayUninit { ty, length } => (vec![&**length], vec![]),
+        Exp::ArrayLit { ty, exps } => (exps.iter().collect(), vec![]),
+        Exp::ArrayLoad { bounds_check, ty, array, index } => (vec![&**array, &**index], vec![]),
+        Exp::ArrayLength { array } => (vec![&**array], vec![]),
+
+        Exp::Lit { lit } => (vec![], vec![]),
+        Exp::Call { fun_type, name, args } => (args.iter().collect(), vec![]),
+        Exp::Var { name, ty } => (vec![], vec![]),
+        Exp::Global { name, ty } => (vec![], vec![]),
+        Exp::Function { name, ty } => (vec![], vec![]),
+
+        Exp::Binary { op, e1, e2 } => (vec![&**e1, &**e2], vec![]),
+        Exp::Unary { op, exp } => (vec![&**exp], vec![]),
+        Exp::Fma { ty, a, b, c } => (vec![&**a, &**b, &**c], vec![]),
+
+        Exp::Seq { body, exp } => (vec![&**exp], vec![&**body]),
+        Exp::Cond { ty, cond, if_true, if_false } => (vec![&**cond, &**if_true, &**if_false], vec![]),
+        Exp::Let { inits, body } => {
+            let mut es: Vec<&Exp> = inits.iter().map(|init| &*init.exp).collect();
+            es.push(&**body);
+            (es, vec![])
+        },
+        Exp::Lambda { ret_type, params, body } => (vec![&**body], vec![]),
+        Exp::Apply { fun_type, fun, args } => {
+            let mut es: Vec<&Exp> = vec![&**fun];
+            es.extend(args.iter());
+            (es, vec![])
+        },
+
+        Exp::StructLit { fields } => (fields.iter().map(|f| &*f.exp).collect(), vec![]),
+        Exp::StructLoad { ty, base, field } => (vec![&**base], vec![]),
+        Exp::StructLoadIdx { ty, base, index } => (vec![&**base], vec![]),
+
+        Exp::Box { ty, exp } => (vec![&**exp], vec![]),
+        Exp::Unbox { ty, exp } => (vec![&**exp], vec![]),
+        Exp::Cast { ty, exp } => (vec![&**exp], vec![]),
+        Exp::Convert { ty, signedness, exp } => (vec![&**exp], vec![]),
+    }
+}
+
+pub fn stm_children(s: &Stm) -> (Vec<&Exp>, Vec<&Stm>) {
+    match s {
+        Stm::IfElse { cond, if_true, if_false, hint } => (vec![&**cond], vec![&**if_true, &**if_false]),
+        Stm::IfThen { cond, if_true, hint } => (vec![&**cond], vec![&**if_true]),
+        Stm::While { cond, body, hints } => (vec![&**cond], vec![&**body]),
+        Stm::Return { exp } => (vec![&**exp], vec![]),
+        Stm::Block { body } => (vec![], body.iter().collect()),
+        Stm::Eval { exp } => (vec![&**exp], vec![]),
+        Stm::Assign { ty, lhs, rhs } => (vec![&**rhs], vec![]),
+        Stm::ArrayAssign { bounds_check, ty, array, index, value } => (vec![&**array, &**index, &**value], vec![]),
+        Stm::ArrayCopy { bounds_check, ty, dst_array, dst_index, src_array, src_index, count } => {
+            (vec![&**dst_array, &**dst_index, &**src_array, &**src_index, &**count], vec![])
+        },
+        Stm::StructAssign { ty, base, field, value } => (vec![&**base, &**value], vec![]),
+    }
+}
+
+// Like exp_children, but mutable, e.g., to rewrite the children in place.
+pub fn exp_children_mut(e: &mut Exp) -> (Vec<&mut Exp>, Vec<&mut Stm>) {
+    match e {
+        Exp::NewArray { ty, length } => (vec![&mut **length], vec![]),
+        Exp::NewArrayUninit { ty, length } => (vec![&mut **length], vec![]),
+        Exp::ArrayLit { ty, exps } => (exps.iter_mut().collect(), vec![]),
+        Exp::ArrayLoad { bounds_check, ty, array, index } => (vec![&mut **array, &mut **index], vec![]),
+        Exp::ArrayLength { array } => (vec![&mut **array], vec![]),
+
+        Exp::Lit { lit } => (vec![], vec![]),
+        Exp::Call { fun_type, name, args } => (args.iter_mut().collect(), vec![]),
+        Exp::Var { name, ty } => (vec![], vec![]),
+        Exp::Global { name, ty } => (vec![], vec![]),
+        Exp::Function { name, ty } => (vec![], vec![]),
+
+        Exp::Binary { op, e1, e2 } => (vec![&mut **e1, &mut **e2], vec![]),
+        Exp::Unary { op, exp } => (vec![&mut **exp], vec![]),
+        Exp::Fma { ty, a, b, c } => (vec![&mut **a, &mut **b, &mut **c], vec![]),
+
+        Exp::Seq { body, exp } => (vec![&mut **exp], vec![&mut **body]),
+        Exp::Cond { ty, cond, if_true, if_false } => (vec![&mut **cond, &mut **if_true, &mut **if_false], vec![]),
+        Exp::Let { inits, body } => {
+            let mut es: Vec<&mut Exp> = inits.iter_mut().map(|init| &mut *init.exp).collect();
+            es.push(&mut **body);
+            (es, vec![])
+        },
+        Exp::Lambda { ret_type, params, body } => (vec![&mut **body], vec![]),
+        Exp::Apply { fun_type, fun, args } => {
+            let mut es: Vec<&mut Exp> = vec![&mut **fun];
+            es.extend(args.iter_mut());
+            (es, vec![])
+        },
+
+        Exp::StructLit { fields } => (fields.iter_mut().map(|f| &mut *f.exp).collect(), vec![]),
+        Exp::StructLoad { ty, base, field } => (vec![&mut **base], vec![]),
+        Exp::StructLoadIdx { ty, base, index } => (vec![&mut **base], vec![]),
+
+        Exp::Box { ty, exp } => (vec![&mut **exp], vec![]),
+        Exp::Unbox { ty, exp } => (vec![&mut **exp], vec![]),
+        Exp::Cast { ty, exp } => (vec![&mut **exp], vec![]),
+        Exp::Convert { ty, signedness, exp } => (vec![&mut **exp], vec![]),
+    }
+}
+
+pub fn stm_children_mut(s: &mut Stm) -> (Vec<&mut Exp>, Vec<&mut Stm>) {
+    match s {
+        Stm::IfElse { cond, if_true, if_false, hint } => (vec![&mut **cond], vec![&mut **if_true, &mut **if_false]),
+        Stm::IfThen { cond, if_true, hint } => (vec![&mut **cond], vec![&mut **if_true]),
+        Stm::While { cond, body, hints } => (vec![&mut **cond], vec![&mut **body]),
+        Stm::Return { exp } => (vec![&mut **exp], vec![]),
+        Stm::Block { body } => (vec![], body.iter_mut().collect()),
+        Stm::Eval { exp } => (vec![&mut **exp], vec![]),
+        Stm::Assign { ty, lhs, rhs } => (vec![&mut **rhs], vec![]),
+        Stm::ArrayAssign { bounds_check, ty, array, index, value } => (vec![&mut **array, &mut **index, &mut **value], vec![]),
+        Stm::ArrayCopy { bounds_check, ty, dst_array, dst_index, src_array, src_index, count } => {
+            (vec![&mut **dst_array, &mut **dst_index, &mut **src_array, &mut **src_index, &mut **count], vec![])
+        },
+        Stm::StructAssign { ty, base, field, value } => (vec![&mut **base, &mut **value], vec![]),
+    }
+}
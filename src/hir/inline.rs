@@ -0,0 +1,350 @@
+// Inlining for HIR
+// With CompileOptions::max_inline_depth set, calls to functions are replaced by their bodies.
+// A call f(a, b) becomes let x' = a, y' = b in body', where x and y are f's params, and every
+// variable bound in body is renamed in body', so it can't be confused with the caller's.
+// That includes the locals body introduces by assigning to them.
+// Calls in an inlined body are inlined in turn, to at most max_depth levels, which also keeps
+// recursive functions from being unrolled forever. Each def can grow by at most budget nodes;
+// once that's used up, the remaining calls are left as real calls.
+// A function whose body contains a Return isn't inlined, since the Return would return from
+// the caller.
+
+use std::collections::HashMap;
+
+use super::trees::{Stm, Exp, Def, Field, Param, Root};
+use super::children::{exp_children, stm_children, exp_children_mut, stm_children_mut};
+use crate::common::names::*;
+
+pub fn inline_calls(root: &Root, max_depth: usize, budget: usize) -> Root {
+    let mut root = root.clone();
+
+    let funs = root.defs.iter().filter_map(|def| match def {
+        Def::FunDef { ret_type, name, params, body } if ! exp_has_return(body) => {
+            Some((*name, (params.clone(), (**body).clone())))
+        },
+        _ => None,
+    }).collect();
+
+    let mut inliner = Inliner {
+        funs,
+        max_depth,
+        budget: 0,
+        fresh_name_generator: FreshNameGenerator::new("inline"),
+    };
+
+    for def in &mut root.defs {
+        inliner.budget = budget;
+        match def {
+            Def::VarDef { ty, name, exp, thread_local } => inliner.inline_exp(exp, 1),
+            Def::FunDef { ret_type, name, params, body } => inliner.inline_exp(body, 1),
+            Def::ExternDef { ty, name } => {},
+        }
+    }
+
+    root
+}
+
+struct Inliner {
+    // The params and bodies of the functions that can be inlined.
+    funs: HashMap<Name, (Vec<Param>, Exp)>,
+    max_depth: usize,
+    // The number of nodes the current def can still grow by.
+    budget: usize,
+    fresh_name_generator: FreshNameGenerator,
+}
+
+impl Inliner {
+    // Inline the calls in e, which is in a body inlined depth - 1 levels deep.
+    fn inline_exp(&mut self, e: &mut Exp, depth: usize) {
+        if depth > self.max_depth {
+            return;
+        }
+
+        {
+            let (es, ss) = exp_children_mut(e);
+            for e in es {
+                self.inline_exp(e, depth);
+            }
+            for s in ss {
+                self.inline_stm(s, depth);
+            }
+        }
+
+        let inlined = match e {
+            Exp::Call { fun_type, name, args } => {
+                match self.funs.get(name).cloned() {
+                    Some((params, body)) => self.inline_call(&params, body, args, depth),
+                    None => None,
+                }
+            },
+            _ => None,
+        };
+
+        if let Some(inlined) = inlined {
+            *e = inlined;
+        }
+    }
+
+    fn inline_stm(&mut self, s: &mut Stm, depth: usize) {
+        let (es, ss) = stm_children_mut(s);
+        for e in es {
+            self.inline_exp(e, depth);
+        }
+        for s in ss {
+            self.inline_stm(s, depth);
+        }
+    }
+
+    // The body of a call, or None if it doesn't fit in the budget or has the wrong number of args.
+    fn inline_call(&mut self, params: &Vec<Param>, mut body: Exp, args: &Vec<Exp>, depth: usize) -> Option<Exp> {
+        if params.len() != args.len() {
+            return None;
+        }
+
+        let size = exp_size(&body);
+        if size > self.budget {
+            return None;
+        }
+        self.budget -= size;
+
+        let mut env = HashMap::new();
+        let mut inits = Vec::new();
+        for (param, arg) in params.iter().zip(args) {
+            let x = self.fresh_name_generator.fresh(&param.name.to_string());
+            env.insert(param.name, x);
+            inits.push(Field {
+                param: Param { ty: param.ty.clone(), name: x },
+                exp: Box::new(arg.clone()),
+            });
+        }
+
+        // Locals assigned in body aren't bound by a Let, so they'd otherwise keep their names
+        // and could overwrite the caller's.
+        let mut locals = Vec::new();
+        assigned_vars(&body, &mut locals);
+        for x in locals {
+            if ! env.contains_key(&x) {
+                let y = self.fresh_name_generator.fresh(&x.to_string());
+                env.insert(x, y);
+            }
+        }
+
+        self.rename_exp(&mut body, &env);
+        self.inline_exp(&mut body, depth + 1);
+
+        Some(Exp::Let { inits, body: Box::new(body) })
+    }
+
+    // Rename the variables in e according to env, giving the variables bound in e fresh names.
+    fn rename_exp(&mut self, e: &mut Exp, env: &HashMap<Name, Name>) {
+        match e {
+            Exp::Var { name, ty } => {
+                if let Some(x) = env.get(&*name) {
+                    *name = *x;
+                }
+            },
+            // The inits are evaluated outside the scope of the variables they bind.
+            Exp::Let { inits, body } => {
+                let mut inner = env.clone();
+                for init in inits {
+                    self.rename_exp(&mut init.exp, env);
+                    let x = self.fresh_name_generator.fresh(&init.param.name.to_string());
+                    inner.insert(init.param.name, x);
+                    init.param.name = x;
+                }
+                self.rename_exp(body, &inner);
+            },
+            Exp::Lambda { ret_type, params, body } => {
+                let mut inner = env.clone();
+                for param in params {
+                    let x = self.fresh_name_generator.fresh(&param.name.to_string());
+                    inner.insert(param.name, x);
+                    param.name = x;
+                }
+                self.rename_exp(body, &inner);
+            },
+            e => {
+                let (es, ss) = exp_children_mut(e);
+                for e in es {
+                    self.rename_exp(e, env);
+                }
+                for s in ss {
+                    self.rename_stm(s, env);
+                }
+            },
+        }
+    }
+
+    fn rename_stm(&mut self, s: &mut Stm, env: &HashMap<Name, Name>) {
+        if let Stm::Assign { ty, lhs, rhs } = s {
+            if let Some(x) = env.get(&*lhs) {
+                *lhs = *x;
+            }
+        }
+
+        let (es, ss) = stm_children_mut(s);
+        for e in es {
+            self.rename_exp(e, env);
+        }
+        for s in ss {
+            self.rename_stm(s, env);
+        }
+    }
+}
+
+// The number of nodes in e.
+fn exp_size(e: &Exp) -> usize {
+    let (es, ss) = exp_children(e);
+    1 + es.into_iter().map(exp_size).sum::<usize>() + ss.into_iter().map(stm_size).sum::<usize>()
+}
+
+fn stm_size(s: &Stm) -> usize {
+    let (es, ss) = stm_children(s);
+    1 + es.into_iter().map(exp_size).sum::<usize>() + ss.into_iter().map(stm_size).sum::<usize>()
+}
+
+fn exp_has_return(e: &Exp) -> bool {
+    let (es, ss) = exp_children(e);
+    es.into_iter().any(exp_has_return) || ss.into_iter().any(stm_has_return)
+}
+
+fn stm_has_return(s: &Stm) -> bool {
+    if let Stm::Return { .. } = s {
+        return true;
+    }
+    let (es, ss) = stm_children(s);
+    es.into_iter().any(exp_has_return) || ss.into_iter().any(stm_has_return)
+}
+
+// The variables assigned in e, each once, in the order they're first assigned.
+fn assigned_vars(e: &Exp, xs: &mut Vec<Name>) {
+    let (es, ss) = exp_children(e);
+    for e in es {
+        assigned_vars(e, xs);
+    }
+    for s in ss {
+        assigned_vars_stm(s, xs);
+    }
+}
+
+fn assigned_vars_stm(s: &Stm, xs: &mut Vec<Name>) {
+    if let Stm::Assign { lhs, .. } = s {
+        if ! xs.contains(lhs) {
+            xs.push(*lhs);
+        }
+    }
+    let (es, ss) = stm_children(s);
+    for e in es {
+        assigned_vars(e, xs);
+    }
+    for s in ss {
+        assigned_vars_stm(s, xs);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hir::trees::Type;
+    use crate::hir::ops::Bop;
+
+    fn var(x: &str) -> Exp {
+        Exp::Var { name: Name::new(x), ty: Type::I32 }
+    }
+
+    fn call(f: &str, arg: Exp) -> Exp {
+        Exp::Call {
+            fun_type: Type::Fun { ret: Box::new(Type::I32), args: vec![Type::I32] },
+            name: Name::new(f),
+            args: vec![arg],
+        }
+    }
+
+    fn fun(f: &str, body: Exp) -> Def {
+        Def::FunDef {
+            ret_type: Type::I32,
+            name: Name::new(f),
+            params: vec![Param { ty: Type::I32, name: Name::new("x") }],
+            body: Box::new(body),
+        }
+    }
+
+    // f0(x) = x + 1, fn(x) = f{n-1}(x) + 1, main(x) = f4(x)
+    fn chain() -> Root {
+        let plus_one = |e| Exp::Binary { op: Bop::Add_i32, e1: Box::new(e), e2: Box::new(Exp::Lit { lit: crate::hir::trees::Lit::I32 { value: 1 } }) };
+
+        let mut defs = vec![fun("f0", plus_one(var("x")))];
+        for i in 1..5 {
+            defs.push(fun(&format!("f{}", i), plus_one(call(&format!("f{}", i - 1), var("x")))));
+        }
+        defs.push(fun("main", call("f4", var("x"))));
+        Root { defs }
+    }
+
+    fn main_body(root: &Root) -> String {
+        match root.defs.last() {
+            Some(Def::FunDef { ret_type, name, params, body }) => format!("{:?}", body),
+            d => panic!("expected main, got {:?}", d),
+        }
+    }
+
+    #[test]
+    fn test_inline_to_depth() {
+        let body = main_body(&inline_calls(&chain(), 2, 1000));
+
+        // f4 and f3 are inlined, but the call to f2 is left as a call.
+        assert!(! body.contains("Name(\"f4\")"), "f4 not inlined in {}", body);
+        assert!(! body.contains("Name(\"f3\")"), "f3 not inlined in {}", body);
+        assert!(body.contains("Name(\"f2\")"), "f2 inlined in {}", body);
+        assert!(! body.contains("Name(\"f1\")"), "f1 inlined in {}", body);
+    }
+
+    #[test]
+    fn test_inline_budget() {
+        // Each body is 4 nodes, so a budget of 5 only covers f4.
+        let body = main_body(&inline_calls(&chain(), 10, 5));
+        assert!(! body.contains("Name(\"f4\")"), "f4 not inlined in {}", body);
+        assert!(body.contains("Name(\"f3\")"), "f3 inlined in {}", body);
+
+        let root = chain();
+        assert_eq!(inline_calls(&root, 0, 1000), root);
+    }
+
+    // { t = e; t }
+    fn assign_t(e: Exp) -> Exp {
+        Exp::Seq {
+            body: Box::new(Stm::Assign { ty: Type::I32, lhs: Name::new("t"), rhs: Box::new(e) }),
+            exp: Box::new(var("t")),
+        }
+    }
+
+    #[test]
+    fn test_inline_renames_assigned_locals() {
+        // f(x) = { t = x; t }, main(x) = { t = f(x); t }
+        let root = Root { defs: vec![fun("f", assign_t(var("x"))), fun("main", assign_t(call("f", var("x"))))] };
+
+        let inlined = inline_calls(&root, 1, 1000);
+        let body = match inlined.defs.last() {
+            Some(Def::FunDef { body, .. }) => body,
+            d => panic!("expected main, got {:?}", d),
+        };
+
+        // The caller's t and f's t, renamed so it doesn't overwrite the caller's.
+        let mut xs = Vec::new();
+        assigned_vars(body, &mut xs);
+        assert_eq!(xs.len(), 2, "{:?}", xs);
+        assert!(xs.contains(&Name::new("t")), "{:?}", xs);
+    }
+
+    #[test]
+    fn test_inline_arity_mismatch() {
+        // main(x) = f(x, x), where f takes one param.
+        let call2 = Exp::Call {
+            fun_type: Type::Fun { ret: Box::new(Type::I32), args: vec![Type::I32, Type::I32] },
+            name: Name::new("f"),
+            args: vec![var("x"), var("x")],
+        };
+        let root = Root { defs: vec![fun("f", var("x")), fun("main", call2)] };
+        assert_eq!(inline_calls(&root, 10, 1000), root);
+    }
+}
@@ -0,0 +1,116 @@
+// Lowering lambdas without closure conversion
+// CompileOptions::lift_lambdas can turn off closure conversion and lambda lifting, e.g., to
+// compare against the lifted output. A lambda is then a plain function pointer rather than
+// a {fun, env} closure: its body becomes a function of its own, named for the enclosing def,
+// and the lambda is replaced by the address of that function. Without an environment, a
+// lambda can't capture the locals of its enclosing function, so that's an error.
+
+use std::collections::HashSet;
+
+use super::trees::{Stm, Exp, Def, Param, Root, Type};
+use super::cc::free_vars;
+use super::children::{exp_children_mut, stm_children_mut};
+use crate::common::names::*;
+use crate::common::errors::CodegenError;
+
+pub fn lower_lambdas(root: &Root) -> Result<Root, CodegenError> {
+    let mut root = root.clone();
+
+    let globals: HashSet<Name> = root.defs.iter().map(|def| match def {
+        Def::VarDef { ty, name, exp, thread_local } => *name,
+        Def::FunDef { ret_type, name, params, body } => *name,
+        Def::ExternDef { ty, name } => *name,
+    }).collect();
+
+    let mut l = Lowering {
+        globals,
+        fresh_name_generator: FreshNameGenerator::new("hir"),
+        decls: Vec::new(),
+        enclosing: Name::new(""),
+    };
+
+    for def in &mut root.defs {
+        match def {
+            Def::VarDef { ty, name, exp, thread_local } => {
+                l.enclosing = *name;
+                l.lower_exp(exp)?;
+            },
+            Def::FunDef { ret_type, name, params, body } => {
+                l.enclosing = *name;
+                l.lower_exp(body)?;
+            },
+            Def::ExternDef { ty, name } => {},
+        }
+    }
+
+    root.defs.append(&mut l.decls);
+
+    Ok(root)
+}
+
+struct Lowering {
+    globals: HashSet<Name>,
+    fresh_name_generator: FreshNameGenerator,
+    // Functions made from lambdas.
+    decls: Vec<Def>,
+    // The def whose lambdas are being lowered.
+    enclosing: Name,
+}
+
+impl Lowering {
+    fn lower_lambda(&mut self, ret_type: &Type, params: &Vec<Param>, body: &mut Exp) -> Result<Exp, CodegenError> {
+        // Lower nested lambdas first, so they don't count as captures of this one.
+        self.lower_exp(body)?;
+
+        let mut captured: Vec<Name> = free_vars(body).keys()
+            .filter(|x| ! self.globals.contains(x) && ! params.iter().any(|p| p.name == **x))
+            .cloned()
+            .collect();
+
+        if ! captured.is_empty() {
+            captured.sort();
+            return Err(CodegenError::LambdaCaptures { name: self.enclosing, vars: captured });
+        }
+
+        let name = self.fresh_name_generator.derived(&self.enclosing, "lambda");
+
+        self.decls.push(Def::FunDef {
+            ret_type: ret_type.clone(),
+            name,
+            params: params.clone(),
+            body: Box::new(body.clone()),
+        });
+
+        Ok(Exp::Function {
+            name,
+            ty: Type::Fun { ret: Box::new(ret_type.clone()), args: params.iter().map(|p| p.ty.clone()).collect() },
+        })
+    }
+
+    fn lower_exp(&mut self, e: &mut Exp) -> Result<(), CodegenError> {
+        if let Exp::Lambda { ret_type, params, body } = e {
+            *e = self.lower_lambda(ret_type, params, body)?;
+            return Ok(());
+        }
+
+        let (es, ss) = exp_children_mut(e);
+        for e in es {
+            self.lower_exp(e)?;
+        }
+        for s in ss {
+            self.lower_stm(s)?;
+        }
+        Ok(())
+    }
+
+    fn lower_stm(&mut self, s: &mut Stm) -> Result<(), CodegenError> {
+        let (es, ss) = stm_children_mut(s);
+        for e in es {
+            self.lower_exp(e)?;
+        }
+        for s in ss {
+            self.lower_stm(s)?;
+        }
+        Ok(())
+    }
+}
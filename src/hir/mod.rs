@@ -1,3 +1,12 @@
 pub mod trees;
+pub mod children;
 pub mod ops;
 pub mod cc;
+pub mod signs;
+pub mod tail;
+pub mod bounds;
+pub mod lambdas;
+pub mod inline;
+pub mod analysis;
+
+pub use self::tail::tail_positions;
@@ -1,10 +1,15 @@
 #![allow(non_camel_case_types)]
 
+use crate::hir::trees::Type;
+
 #[derive(Serialize, Deserialize)]
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Uop {
     // Boolean ops
+    // Not_z is logical not of a Bool. Use Complement_* for bitwise not of an integer.
     Not_z,
+    // Evaluates to its operand, telling the optimizer the operand is usually expected.
+    Expect_z { expected: bool },
 
     // Ops that eval to i32
     Ctz_i32,
@@ -20,6 +25,9 @@ pub enum Uop {
     Eqz_i64,
     Complement_i64,
 
+    // Ops that eval to the word type (i32 or i64, see mir::Type::word)
+    Complement_word,
+
     // Ops that eval to f32
     Neg_f32,
     Abs_f32,
@@ -101,6 +109,21 @@ pub enum Uop {
     Extend_s_i32_i64,
     Extend_u_i32_i64,
 
+    // The narrow integer types convert only to and from i32. mir_gen goes through i32
+    // to convert them to anything else.
+    Wrap_i32_i8,
+    Wrap_i32_i16,
+    Extend_s_i8_i32,
+    Extend_u_i8_i32,
+    Extend_s_i16_i32,
+    Extend_u_i16_i32,
+
+    // Bool conversions. Nez is true iff the operand is nonzero; Extend gives 0 or 1.
+    Nez_i32_z,
+    Extend_u_z_i32,
+    Nez_i64_z,
+    Extend_u_z_i64,
+
     Reinterpret_i32_f32,
     Reinterpret_f32_i32,
     Reinterpret_f64_i64,
@@ -120,7 +143,7 @@ pub enum Uop {
 }
 
 #[derive(Serialize, Deserialize)]
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Bop {
     // Boolean ops
     And_z,
@@ -161,6 +184,11 @@ pub enum Bop {
     Rotl_i32,
     Rotr_i32,
 
+    Min_s_i32,
+    Min_u_i32,
+    Max_s_i32,
+    Max_u_i32,
+
     Add_i64,
     Sub_i64,
     Mul_i64,
@@ -190,6 +218,37 @@ pub enum Bop {
     Rotl_i64,
     Rotr_i64,
 
+    Min_s_i64,
+    Min_u_i64,
+    Max_s_i64,
+    Max_u_i64,
+
+    // Like Add_i32 etc., but signed overflow is undefined (LLVM's nsw), so LLVM may assume
+    // it doesn't happen. mir_gen lowers Add, Sub, and Mul to these under Overflow::Undef.
+    Add_nsw_i32,
+    Sub_nsw_i32,
+    Mul_nsw_i32,
+    Add_nsw_i64,
+    Sub_nsw_i64,
+    Mul_nsw_i64,
+
+    // Only in MIR and LIR, which have an i128 type; mir_gen rejects them in HIR. There's
+    // no i128 division, since LLVM lowers it to a library call.
+    Add_i128,
+    Sub_i128,
+    Mul_i128,
+
+    And_i128,
+    Or_i128,
+    Xor_i128,
+
+    Eq_i128,
+    Ne_i128,
+
+    Shl_i128,
+    Shr_i128,
+    Shr_u_i128,
+
     Min_f32,
     Max_f32,
     // magnitude of left, sign of right (abs x == copysign x 1)
@@ -210,6 +269,12 @@ pub enum Bop {
     Le_f32,
     Gt_f32,
     Ge_f32,
+    // Compares bit patterns. Unlike Eq_f32, -0.0 and +0.0 differ,
+    // and a NaN equals itself if the payloads match.
+    BitEq_f32,
+    // IEEE 754 totalOrder: -NaN < -inf < ... < -0.0 < +0.0 < ... < +inf < +NaN.
+    // Unlike Lt_f32, this is a total order, so it can be used for sorting.
+    TotalLt_f32,
 
     Min_f64,
     Max_f64,
@@ -231,4 +296,137 @@ pub enum Bop {
     Le_f64,
     Gt_f64,
     Ge_f64,
+    // Compares bit patterns, like BitEq_f32.
+    BitEq_f64,
+    // Like TotalLt_f32.
+    TotalLt_f64,
+}
+
+// Expected direction of a conditional branch, passed on to LLVM as branch weights.
+#[derive(Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BranchHint {
+    Likely,
+    Unlikely,
+}
+
+// Hints for optimizing a loop, passed on to LLVM as !llvm.loop metadata.
+#[derive(Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum LoopHint {
+    Unroll(u32),
+    Vectorize(bool),
+}
+
+// Signedness of integer operands, for ops that distinguish them.
+#[derive(Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Signedness {
+    Signed,
+    Unsigned,
+}
+
+// What integer Add, Sub, and Mul do when the result doesn't fit.
+// Wrap keeps the low bits. Trap calls panic. Undef leaves the result unspecified, and
+// LLVM may assume signed overflow doesn't happen.
+#[derive(Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    Wrap,
+    Trap,
+    Undef,
+}
+
+impl Bop {
+    // Whether this is one of the i128 ops, which only MIR and LIR can use.
+    pub fn is_i128(&self) -> bool {
+        match self {
+            Bop::Add_i128 | Bop::Sub_i128 | Bop::Mul_i128 |
+            Bop::And_i128 | Bop::Or_i128 | Bop::Xor_i128 |
+            Bop::Eq_i128 | Bop::Ne_i128 |
+            Bop::Shl_i128 | Bop::Shr_i128 | Bop::Shr_u_i128 => true,
+            _ => false,
+        }
+    }
+
+    // The signedness this op assumes of its operands, or None if it doesn't care.
+    pub fn signedness(&self) -> Option<Signedness> {
+        match self {
+            Bop::Div_s_i32 | Bop::Rem_s_i32 |
+            Bop::Lt_s_i32 | Bop::Le_s_i32 | Bop::Gt_s_i32 | Bop::Ge_s_i32 |
+            Bop::Shr_i32 |
+            Bop::Min_s_i32 | Bop::Max_s_i32 |
+            Bop::Div_s_i64 | Bop::Rem_s_i64 |
+            Bop::Lt_s_i64 | Bop::Le_s_i64 | Bop::Gt_s_i64 | Bop::Ge_s_i64 |
+            Bop::Shr_i64 |
+            Bop::Min_s_i64 | Bop::Max_s_i64 |
+            Bop::Shr_i128 => Some(Signedness::Signed),
+
+            Bop::Div_u_i32 | Bop::Rem_u_i32 |
+            Bop::Lt_u_i32 | Bop::Le_u_i32 | Bop::Gt_u_i32 | Bop::Ge_u_i32 |
+            Bop::Shr_u_i32 |
+            Bop::Min_u_i32 | Bop::Max_u_i32 |
+            Bop::Div_u_i64 | Bop::Rem_u_i64 |
+            Bop::Lt_u_i64 | Bop::Le_u_i64 | Bop::Gt_u_i64 | Bop::Ge_u_i64 |
+            Bop::Shr_u_i64 |
+            Bop::Min_u_i64 | Bop::Max_u_i64 |
+            Bop::Shr_u_i128 => Some(Signedness::Unsigned),
+
+            _ => None,
+        }
+    }
+}
+
+impl Uop {
+    // The operand and result types of a conversion op, or None if the op isn't a conversion.
+    pub fn conversion_types(&self) -> Option<(Type, Type)> {
+        match self {
+            Uop::Wrap_i64_i32 => Some((Type::I64, Type::I32)),
+
+            Uop::Trunc_s_f32_i32 | Uop::Trunc_u_f32_i32 => Some((Type::F32, Type::I32)),
+            Uop::Trunc_s_f64_i32 | Uop::Trunc_u_f64_i32 => Some((Type::F64, Type::I32)),
+            Uop::Trunc_s_f32_i64 | Uop::Trunc_u_f32_i64 => Some((Type::F32, Type::I64)),
+            Uop::Trunc_s_f64_i64 | Uop::Trunc_u_f64_i64 => Some((Type::F64, Type::I64)),
+
+            Uop::Extend_s_i32_i64 | Uop::Extend_u_i32_i64 => Some((Type::I32, Type::I64)),
+
+            Uop::Wrap_i32_i8 => Some((Type::I32, Type::I8)),
+            Uop::Wrap_i32_i16 => Some((Type::I32, Type::I16)),
+            Uop::Extend_s_i8_i32 | Uop::Extend_u_i8_i32 => Some((Type::I8, Type::I32)),
+            Uop::Extend_s_i16_i32 | Uop::Extend_u_i16_i32 => Some((Type::I16, Type::I32)),
+
+            Uop::Nez_i32_z => Some((Type::I32, Type::Bool)),
+            Uop::Extend_u_z_i32 => Some((Type::Bool, Type::I32)),
+            Uop::Nez_i64_z => Some((Type::I64, Type::Bool)),
+            Uop::Extend_u_z_i64 => Some((Type::Bool, Type::I64)),
+
+            Uop::Reinterpret_i32_f32 => Some((Type::I32, Type::F32)),
+            Uop::Reinterpret_f32_i32 => Some((Type::F32, Type::I32)),
+            Uop::Reinterpret_f64_i64 => Some((Type::F64, Type::I64)),
+            Uop::Reinterpret_i64_f64 => Some((Type::I64, Type::F64)),
+
+            Uop::Convert_s_i32_f32 | Uop::Convert_u_i32_f32 => Some((Type::I32, Type::F32)),
+            Uop::Convert_s_i64_f32 | Uop::Convert_u_i64_f32 => Some((Type::I64, Type::F32)),
+            Uop::Convert_s_i32_f64 | Uop::Convert_u_i32_f64 => Some((Type::I32, Type::F64)),
+            Uop::Convert_s_i64_f64 | Uop::Convert_u_i64_f64 => Some((Type::I64, Type::F64)),
+
+            Uop::Demote_f64_f32 => Some((Type::F64, Type::F32)),
+            Uop::Promote_f32_f64 => Some((Type::F32, Type::F64)),
+
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conversion_types() {
+        assert_eq!(Uop::Extend_s_i32_i64.conversion_types(), Some((Type::I32, Type::I64)));
+        assert_eq!(Uop::Demote_f64_f32.conversion_types(), Some((Type::F64, Type::F32)));
+        // Rounding to an integral float isn't a conversion.
+        assert_eq!(Uop::Trunc_f32.conversion_types(), None);
+    }
 }
@@ -0,0 +1,108 @@
+// Signedness validation for HIR
+// HIR integer types don't carry a sign; the sign is chosen per op (Lt_s_i32 vs Lt_u_i32).
+// The frontend can record which variables it declared signed or unsigned, and this pass
+// reports ops that treat such a variable with the other signedness.
+
+use std::collections::HashMap;
+
+use super::trees::{Stm, Exp, Def, Root};
+use super::children::{exp_children, stm_children};
+use crate::common::names::*;
+use crate::common::errors::CodegenError;
+use crate::hir::ops::*;
+
+pub struct SignCheck<'a> {
+    signs: &'a HashMap<Name, Signedness>,
+}
+
+impl<'a> SignCheck<'a> {
+    pub fn check(root: &Root, signs: &'a HashMap<Name, Signedness>) -> Result<(), CodegenError> {
+        let c = SignCheck { signs };
+
+        for def in &root.defs {
+            match def {
+                Def::VarDef { ty, name, exp, thread_local } => c.check_exp(exp)?,
+                Def::FunDef { ret_type, name, params, body } => c.check_exp(body)?,
+                Def::ExternDef { ty, name } => {},
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_operand(&self, op: Bop, e: &Exp) -> Result<(), CodegenError> {
+        if let (Some(expected), Exp::Var { name, ty }) = (op.signedness(), e) {
+            match self.signs.get(name) {
+                Some(s) if *s != expected => return Err(CodegenError::SignMismatch { name: *name, op }),
+                _ => {},
+            }
+        }
+        Ok(())
+    }
+
+    fn check_exp(&self, e: &Exp) -> Result<(), CodegenError> {
+        if let Exp::Binary { op, e1, e2 } = e {
+            self.check_operand(*op, e1)?;
+            self.check_operand(*op, e2)?;
+        }
+
+        let (es, ss) = exp_children(e);
+        for e in es {
+            self.check_exp(e)?;
+        }
+        for s in ss {
+            self.check_stm(s)?;
+        }
+        Ok(())
+    }
+
+    fn check_stm(&self, s: &Stm) -> Result<(), CodegenError> {
+        let (es, ss) = stm_children(s);
+        for e in es {
+            self.check_exp(e)?;
+        }
+        for s in ss {
+            self.check_stm(s)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hir::trees::*;
+
+    #[test]
+    fn test_unsigned_div_of_signed_var() {
+        let x = Name::new("x");
+
+        let root = Root {
+            defs: vec![
+                Def::FunDef {
+                    ret_type: Type::I32,
+                    name: Name::new("f"),
+                    params: vec![
+                        Param { ty: Type::I32, name: x }
+                    ],
+                    body: Box::new(
+                        Exp::Binary {
+                            op: Bop::Div_u_i32,
+                            e1: Box::new(Exp::Var { ty: Type::I32, name: x }),
+                            e2: Box::new(Exp::Lit { lit: Lit::I32 { value: 2 } }),
+                        }
+                    ),
+                }
+            ]
+        };
+
+        let mut signs = HashMap::new();
+        signs.insert(x, Signedness::Signed);
+
+        assert_eq!(SignCheck::check(&root, &signs), Err(CodegenError::SignMismatch { name: x, op: Bop::Div_u_i32 }));
+
+        signs.insert(x, Signedness::Unsigned);
+
+        assert_eq!(SignCheck::check(&root, &signs), Ok(()));
+    }
+}
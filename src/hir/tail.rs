@@ -0,0 +1,127 @@
+// Tail position analysis for HIR
+// A call is in tail position if its result is returned directly from the function,
+// either as the value of the body or as the operand of a Return. Frontends can use
+// this to decide which calls may be compiled as tail calls.
+// Returns inside a Lambda return from the lambda, so lambda bodies are not searched.
+
+use std::collections::HashSet;
+
+use super::trees::{Stm, Exp, Def};
+
+// The calls (Call or Apply) in tail position in a FunDef, by address.
+// The pointers are only valid while def is borrowed and unmodified.
+pub fn tail_positions(def: &Def) -> HashSet<*const Exp> {
+    let mut calls = HashSet::new();
+
+    if let Def::FunDef { ret_type, name, params, body } = def {
+        tail_exp(body, &mut calls);
+    }
+
+    calls
+}
+
+// e's value is the function's result.
+fn tail_exp(e: &Exp, calls: &mut HashSet<*const Exp>) {
+    match e {
+        Exp::Call { .. } | Exp::Apply { .. } => {
+            calls.insert(e as *const Exp);
+        },
+        Exp::Seq { body, exp } => {
+            returns_in_stm(body, calls);
+            tail_exp(exp, calls);
+        },
+        Exp::Cond { ty, cond, if_true, if_false } => {
+            tail_exp(if_true, calls);
+            tail_exp(if_false, calls);
+        },
+        Exp::Let { inits, body } => tail_exp(body, calls),
+        _ => {},
+    }
+}
+
+// Find the Returns in s. Only the operands of those are in tail position.
+fn returns_in_stm(s: &Stm, calls: &mut HashSet<*const Exp>) {
+    match s {
+        Stm::Return { exp } => tail_exp(exp, calls),
+        Stm::IfElse { cond, if_true, if_false, hint } => {
+            returns_in_stm(if_true, calls);
+            returns_in_stm(if_false, calls);
+        },
+        Stm::IfThen { cond, if_true, hint } => returns_in_stm(if_true, calls),
+        Stm::While { cond, body, hints } => returns_in_stm(body, calls),
+        Stm::Block { body } => {
+            for s in body {
+                returns_in_stm(s, calls);
+            }
+        },
+        _ => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::names::*;
+    use crate::hir::ops::*;
+    use crate::hir::trees::*;
+
+    fn call(name: &str) -> Exp {
+        Exp::Call {
+            fun_type: Type::Fun { ret: Box::new(Type::I32), args: vec![] },
+            name: Name::new(name),
+            args: vec![],
+        }
+    }
+
+    #[test]
+    fn test_if_else_returning_calls() {
+        // if (x) return g(); else return h(); 1 + g()
+        let def = Def::FunDef {
+            ret_type: Type::I32,
+            name: Name::new("f"),
+            params: vec![
+                Param { ty: Type::Bool, name: Name::new("x") }
+            ],
+            body: Box::new(
+                Exp::Seq {
+                    body: Box::new(
+                        Stm::IfElse {
+                            cond: Box::new(Exp::Var { ty: Type::Bool, name: Name::new("x") }),
+                            if_true: Box::new(Stm::Return { exp: Box::new(call("g")) }),
+                            if_false: Box::new(Stm::Return { exp: Box::new(call("h")) }),
+                            hint: None,
+                        }
+                    ),
+                    exp: Box::new(
+                        Exp::Binary {
+                            op: Bop::Add_i32,
+                            e1: Box::new(Exp::Lit { lit: Lit::I32 { value: 1 } }),
+                            e2: Box::new(call("g")),
+                        }
+                    ),
+                }
+            ),
+        };
+
+        let (g, h) = match &def {
+            Def::FunDef { body, .. } => match &**body {
+                Exp::Seq { body, .. } => match &**body {
+                    Stm::IfElse { if_true, if_false, .. } => match (&**if_true, &**if_false) {
+                        (Stm::Return { exp: g }, Stm::Return { exp: h }) => (&**g as *const Exp, &**h as *const Exp),
+                        _ => unreachable!(),
+                    },
+                    _ => unreachable!(),
+                },
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        };
+
+        let calls = tail_positions(&def);
+
+        // The call under the addition is not in tail position.
+        assert_eq!(calls.len(), 2);
+        assert!(calls.contains(&g));
+        assert!(calls.contains(&h));
+    }
+}
@@ -11,6 +11,7 @@ pub enum Type {
     I64,
     F32,
     F64,
+    // Lowered to I1. MIR and LIR have no Bool type, so it can't reach codegen.
     Bool,
     Void,
 
@@ -26,6 +27,45 @@ pub enum Type {
     Box,
 }
 
+impl Type {
+    // The size in bytes of a value of this type. Arrays, structs, functions, unions,
+    // and boxes are pointers to heap objects, so they are wordsize bytes. The layout of
+    // the objects themselves is given by the lowered MIR type.
+    pub fn size_of(&self, wordsize: usize) -> usize {
+        match self {
+            Type::I8 | Type::Bool => 1,
+            Type::I16 => 2,
+            Type::I32 | Type::F32 => 4,
+            Type::I64 | Type::F64 => 8,
+            Type::Void => 0,
+            Type::Array { .. } | Type::Struct { .. } | Type::Fun { .. } | Type::Union { .. } | Type::Box => wordsize,
+        }
+    }
+
+    pub fn align_of(&self, wordsize: usize) -> usize {
+        match self {
+            Type::Void => 1,
+            ty => ty.size_of(wordsize),
+        }
+    }
+
+    // The position of the named field in a struct type, which is also its index in the
+    // lowered struct. None if this isn't a struct or has no such field.
+    pub fn field_index(&self, field: &Name) -> Option<usize> {
+        match self {
+            Type::Struct { fields } => fields.iter().position(|f| f.name == *field),
+            _ => None,
+        }
+    }
+
+    pub fn field_type(&self, field: &Name) -> Option<&Type> {
+        match self {
+            Type::Struct { fields } => fields.iter().find(|f| f.name == *field).map(|f| &f.ty),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Param {
@@ -42,6 +82,7 @@ pub enum Lit {
     I64 { value: i64 },
     F32 { value: f32 },
     F64 { value: f64 },
+    // Lowered to Lit::I1.
     Bool { value: bool },
 }
 
@@ -54,7 +95,8 @@ pub struct Root {
 #[derive(Serialize, Deserialize)]
 #[derive(Clone, Debug, PartialEq)]
 pub enum Def {
-    VarDef { ty: Type, name: Name, exp: Box<Exp> },
+    // A thread-local global gets a separate copy per thread.
+    VarDef { ty: Type, name: Name, exp: Box<Exp>, thread_local: bool },
     FunDef { ret_type: Type, name: Name, params: Vec<Param>, body: Box<Exp> },
     ExternDef { ty: Type, name: Name },
 }
@@ -62,7 +104,10 @@ pub enum Def {
 #[derive(Serialize, Deserialize)]
 #[derive(Clone, Debug, PartialEq)]
 pub enum Exp {
+    // New arrays are zero-initialized.
     NewArray { ty: Type, length: Box<Exp> },
+    // Like NewArray, but the elements are left uninitialized.
+    NewArrayUninit { ty: Type, length: Box<Exp> },
     ArrayLit { ty: Type, exps: Vec<Exp> },
     ArrayLoad { bounds_check: bool, ty: Type, array: Box<Exp>, index: Box<Exp> },
     ArrayLength { array: Box<Exp> },
@@ -77,8 +122,12 @@ pub enum Exp {
 
     Binary { op: Bop, e1: Box<Exp>, e2: Box<Exp> },
     Unary { op: Uop, exp: Box<Exp> },
+    // Fused multiply-add: a * b + c with a single rounding. ty should be F32 or F64.
+    Fma { ty: Type, a: Box<Exp>, b: Box<Exp>, c: Box<Exp> },
 
     Seq { body: Box<Stm>, exp: Box<Exp> },
+    // Conditional expression. Both branches must have type ty.
+    Cond { ty: Type, cond: Box<Exp>, if_true: Box<Exp>, if_false: Box<Exp> },
 
     // Before lambda lifting.
     Let { inits: Vec<Field>, body: Box<Exp> },
@@ -89,27 +138,37 @@ pub enum Exp {
     // These are tagged in Ivo, but we make the tag an explicit field in HIR.
     StructLit { fields: Vec<Field> },
     StructLoad { ty: Type, base: Box<Exp>, field: Name },
+    // Load a field by its position in the struct type, skipping name resolution.
+    StructLoadIdx { ty: Type, base: Box<Exp>, index: usize },
 
     // Convert to and from boxed values.
     Box { ty: Type, exp: Box<Exp> },
     Unbox { ty: Type, exp: Box<Exp> },
 
     // Unchecked cast from one type to another.
-    // Should only be used for pointer types.
+    // Should only be used for pointer types, or to reinterpret the bits of a number
+    // as another number type of the same size.
     Cast { ty: Type, exp: Box<Exp> },
+    // Numeric conversion to ty, extending, truncating, or converting between ints and floats.
+    // signedness says how to interpret an integer operand or result.
+    Convert { ty: Type, signedness: Signedness, exp: Box<Exp> },
 }
 
 #[derive(Serialize, Deserialize)]
 #[derive(Clone, Debug, PartialEq)]
 pub enum Stm {
-    IfElse { cond: Box<Exp>, if_true: Box<Stm>, if_false: Box<Stm> },
-    IfThen { cond: Box<Exp>, if_true: Box<Stm> },
-    While { cond: Box<Exp>, body: Box<Stm> },
+    // hint is whether cond is expected to be true.
+    IfElse { cond: Box<Exp>, if_true: Box<Stm>, if_false: Box<Stm>, hint: Option<BranchHint> },
+    IfThen { cond: Box<Exp>, if_true: Box<Stm>, hint: Option<BranchHint> },
+    While { cond: Box<Exp>, body: Box<Stm>, hints: Vec<LoopHint> },
     Return { exp: Box<Exp> },
     Block { body: Vec<Stm> },
     Eval { exp: Box<Exp> },
     Assign { ty: Type, lhs: Name, rhs: Box<Exp> },
     ArrayAssign { bounds_check: bool, ty: Type, array: Box<Exp>, index: Box<Exp>, value: Box<Exp> },
+    // Copy count elements of type ty from src_array[src_index..] to dst_array[dst_index..].
+    // The ranges may overlap.
+    ArrayCopy { bounds_check: bool, ty: Type, dst_array: Box<Exp>, dst_index: Box<Exp>, src_array: Box<Exp>, src_index: Box<Exp>, count: Box<Exp> },
     StructAssign { ty: Type, base: Box<Exp>, field: Name, value: Box<Exp> },
 }
 
@@ -119,3 +178,23 @@ pub struct Field {
     pub param: Param,
     pub exp: Box<Exp>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_field_index() {
+        let ty = Type::Struct {
+            fields: vec![
+                Param { ty: Type::I8, name: Name::new("tag") },
+                Param { ty: Type::F64, name: Name::new("value") },
+            ]
+        };
+
+        assert_eq!(ty.field_index(&Name::new("value")), Some(1));
+        assert_eq!(ty.field_type(&Name::new("value")), Some(&Type::F64));
+        assert_eq!(ty.field_index(&Name::new("missing")), None);
+        assert_eq!(Type::I32.field_type(&Name::new("tag")), None);
+    }
+}
@@ -14,8 +14,10 @@ use std::collections::HashMap;
 use std::collections::HashSet;
 
 use crate::common::names::*;
+use crate::common::errors::CodegenError;
 use crate::gen;
 use crate::hir::trees as hir;
+use crate::lir::trees as lir;
 use crate::llvm;
 
 // TODO: add missing intrinsics, better I/O, etc.
@@ -60,23 +62,27 @@ impl JITManager {
         }
     }
 
-    pub fn add_module(&mut self, name: &str, h: &hir::Root) {
+    pub fn add_module(&mut self, name: &str, h: &hir::Root) -> Result<(), CodegenError> {
         // TODO: just add to roots.
         // Compile the module when the function is requested.
-        let module = gen::translate_in_context(name, h, self.context);
+        let module = gen::translate_in_context(name, h, self.context)?;
 
         // self.modules.push(module);
 
         unsafe_llvm!(
-            core::LLVMRunPassManager(self.pm, module.0)
+            core::LLVMRunPassManager(self.pm, module.raw())
         );
 
         let mut ee = unsafe { mem::zeroed() };
         let mut out = unsafe { mem::zeroed() };
 
-        unsafe_llvm!( execution_engine::LLVMCreateExecutionEngineForModule(&mut ee, module.0, &mut out) );
+        unsafe_llvm!( execution_engine::LLVMCreateExecutionEngineForModule(&mut ee, module.raw(), &mut out) );
+
+        unsafe_llvm!( execution_engine::LLVMRunStaticConstructors(ee) );
 
         self.execution_engines.push(ee);
+
+        Ok(())
     }
 
     fn get_function(&self, name: &str) -> Option<extern "C" fn() -> i32> {
@@ -214,17 +220,32 @@ mod gc {
     }
 }
 
+// The first header word of an object allocated with llvm_gen's box_header_words set.
+// Unsafe because the header words sit just before the object: obj must point to an object
+// allocated with at least header_words header words.
+pub unsafe fn object_header(obj: *const c_void, header_words: usize) -> *mut i64 {
+    (obj as *mut i64).sub(header_words)
+}
+
 pub extern "C" fn panic() {
     panic!("JIT panicked!");
 }
 
+// Called when a proc translated with debug_unreachable falls off its end. file is the
+// proc's name and line the number of statements in its body (see llvm_gen).
+pub extern "C" fn rivo_unreachable(file: *const c_char, line: i32) -> ! {
+    let file = unsafe { std::ffi::CStr::from_ptr(file) };
+    eprintln!("reached unreachable code in {} after statement {}", file.to_string_lossy(), line);
+    std::process::abort();
+}
+
 extern "C" {
     fn LLVMAddSymbol(symbolName: *const c_char, symbolValue: *const c_void);
 }
 
 pub fn run_main(name: &str, h: &hir::Root) -> Result<i32, String> {
     // let context = llvm::Context::global();
-    let context = llvm::Context::new();
+    let context = llvm::OwnedContext::new();
 
     // // Add the runtime functions.
     // unsafe {
@@ -233,8 +254,23 @@ pub fn run_main(name: &str, h: &hir::Root) -> Result<i32, String> {
     //     LLVMAddSymbol(name.as_ptr() as *const c_char, addr);
     // }
 
-    let module = gen::translate_in_context(name, h, context);
+    let module = gen::translate_in_context(name, h, *context).map_err(|e| e.to_string())?;
+
+    run_module(name, module)
+}
 
+// Like run_main, but starting from LIR rather than HIR.
+pub fn run_main_lir(name: &str, l: &lir::Root) -> Result<i32, String> {
+    let context = llvm::OwnedContext::new();
+
+    let module = gen::translate_from_lir_in_context(name, l, *context).map_err(|e| e.to_string())?;
+
+    run_module(name, module)
+}
+
+// Run the function name in the module. The execution engine takes the module and disposes it;
+// the caller disposes the context.
+fn run_module(name: &str, module: llvm::Module) -> Result<i32, String> {
     if cfg!(feature = "optimize") {
         unsafe_llvm!( {
             let pm = core::LLVMCreatePassManager();
@@ -250,7 +286,7 @@ pub fn run_main(name: &str, h: &hir::Root) -> Result<i32, String> {
             // This breaks the control flow. Dunno why.
             // llvm_sys::transforms::scalar::LLVMAddCFGSimplificationPass(pm);
 
-            core::LLVMRunPassManager(pm, module.0);
+            core::LLVMRunPassManager(pm, module.raw());
             core::LLVMDisposePassManager(pm);
         });
     }
@@ -261,34 +297,22 @@ pub fn run_main(name: &str, h: &hir::Root) -> Result<i32, String> {
     let mut out = unsafe { mem::zeroed() };
 
     // hand off the module to the EE.
-    unsafe_llvm!( execution_engine::LLVMCreateExecutionEngineForModule(&mut ee, module.0, &mut out) );
+    unsafe_llvm!( execution_engine::LLVMCreateExecutionEngineForModule(&mut ee, module.raw(), &mut out) );
 
     // Register the runtime functions.
     unsafe_llvm!({
         support::LLVMAddSymbol(CString::new("panic").unwrap().as_ptr(), panic as *mut c_void);
         support::LLVMAddSymbol(CString::new("malloc").unwrap().as_ptr(), gc::malloc as *mut c_void);
         support::LLVMAddSymbol(CString::new("yieldpoint").unwrap().as_ptr(), gc::yieldpoint as *mut c_void);
+        support::LLVMAddSymbol(CString::new("rivo_unreachable").unwrap().as_ptr(), rivo_unreachable as *mut c_void);
     });
 
-    // Initialize the module if there's an init_module function.
-    let init = {
-        let cstr = CString::new("init_module").unwrap();
-        let addr = unsafe_llvm!( execution_engine::LLVMGetFunctionAddress(ee, cstr.as_ptr()) );
-
-        if addr != 0 {
-            let f: extern "C" fn() -> c_void = unsafe { mem::transmute(addr) };
-            Some(f)
-        }
-        else {
-            None
-        }
-    };
-
     let main = {
         let cstr = CString::new(name).unwrap();
         let addr = unsafe_llvm!( execution_engine::LLVMGetFunctionAddress(ee, cstr.as_ptr()) );
 
         if addr == 0 {
+            unsafe_llvm!( execution_engine::LLVMDisposeExecutionEngine(ee) );
             return Err("main not found".to_string());
         }
 
@@ -298,9 +322,9 @@ pub fn run_main(name: &str, h: &hir::Root) -> Result<i32, String> {
 
     use self::gc;
 
-    // Run the module initializer and main.
+    // Run the static constructors, including the module initializer, then main.
     // Put in another function so we can set the low water mark for the GC.
-    fn init_and_run(init: Option<extern "C" fn() -> c_void>, main: extern "C" fn() -> i32) -> i32 {
+    fn init_and_run(ee: execution_engine::LLVMExecutionEngineRef, main: extern "C" fn() -> i32) -> i32 {
         use immix_rust as immix;
 
         if cfg!(feature = "immix") {
@@ -309,76 +333,1139 @@ pub fn run_main(name: &str, h: &hir::Root) -> Result<i32, String> {
             }
         }
 
-        match init {
-            Some(f) => { f(); },
-            None => {},
-        }
+        unsafe_llvm!( execution_engine::LLVMRunStaticConstructors(ee) );
 
         let r = main();
 
         // Let the GC run one last time.
         gc::yieldpoint();
 
-        r
+        r
+    }
+
+    gc::init();
+
+    let res = init_and_run(ee, main);
+
+    unsafe_llvm!( execution_engine::LLVMDisposeExecutionEngine(ee) );
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::names::*;
+    use crate::gen::*;
+    use crate::hir::ops::*;
+    use crate::hir::trees as hir;
+
+    #[ignore]
+    #[test]
+    fn print_env() {
+        use std::env;
+
+        for (key, value) in env::vars() {
+            println!("{}='{}'", key, value);
+        }
+    }
+
+    #[ignore]
+    #[test]
+    #[should_panic]
+    fn panic() {
+        let h = hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::Void,
+                    name: Name::new("main"),
+                    params: vec![],
+                    body: Box::new(hir::Exp::Call {
+                        fun_type: hir::Type::Fun { ret: Box::new(hir::Type::Void), args: vec![] },
+                        name: Name::new("panic"),
+                        args: vec![]
+                    })
+                }
+            ]
+        };
+
+        let r = run_main("main", &h);
+        match r {
+            Ok(_) => {},
+            Err(_) => assert!(false),
+        }
+    }
+
+    #[test]
+    fn struct_lit_and_load() {
+        let h = hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("main"),
+                    params: vec![],
+                    body: Box::new(
+                        hir::Exp::StructLoad {
+                            ty: hir::Type::Struct {
+                                fields: vec![
+                                    hir::Param {
+                                        ty: hir::Type::I32,
+                                        name: Name::new("f"),
+                                    }
+                                ]
+                            },
+                            base: Box::new(hir::Exp::StructLit {
+                                fields: vec![
+                                    hir::Field {
+                                        param: hir::Param {
+                                            ty: hir::Type::I32,
+                                            name: Name::new("f"),
+                                        },
+                                        exp: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 3 }}),
+                                    }
+                                ]
+                            }),
+                            field: Name::new("f"),
+                        }
+                    ),
+                }
+            ]
+        };
+
+        let r = run_main("main", &h);
+        assert_eq!(r, Ok(3));
+    }
+
+    #[test]
+    fn struct_load_by_index() {
+        let h = hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("main"),
+                    params: vec![],
+                    body: Box::new(
+                        hir::Exp::StructLoadIdx {
+                            ty: hir::Type::Struct {
+                                fields: vec![
+                                    hir::Param {
+                                        ty: hir::Type::I32,
+                                        name: Name::new("f"),
+                                    },
+                                    hir::Param {
+                                        ty: hir::Type::I32,
+                                        name: Name::new("g"),
+                                    },
+                                ]
+                            },
+                            base: Box::new(hir::Exp::StructLit {
+                                fields: vec![
+                                    hir::Field {
+                                        param: hir::Param {
+                                            ty: hir::Type::I32,
+                                            name: Name::new("f"),
+                                        },
+                                        exp: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 3 }}),
+                                    },
+                                    hir::Field {
+                                        param: hir::Param {
+                                            ty: hir::Type::I32,
+                                            name: Name::new("g"),
+                                        },
+                                        exp: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 4 }}),
+                                    },
+                                ]
+                            }),
+                            index: 1,
+                        }
+                    ),
+                }
+            ]
+        };
+
+        let r = run_main("main", &h);
+        assert_eq!(r, Ok(4));
+    }
+
+    #[test]
+    fn new_array_is_zeroed() {
+        let array_type = hir::Type::Array { ty: Box::new(hir::Type::I32) };
+
+        let h = hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("main"),
+                    params: vec![],
+                    body: Box::new(
+                        hir::Exp::Let {
+                            inits: vec![
+                                hir::Field {
+                                    param: hir::Param {
+                                        ty: array_type.clone(),
+                                        name: Name::new("a"),
+                                    },
+                                    exp: Box::new(hir::Exp::NewArray {
+                                        ty: hir::Type::I32,
+                                        length: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 4 }}),
+                                    }),
+                                }
+                            ],
+                            body: Box::new(
+                                hir::Exp::ArrayLoad {
+                                    bounds_check: false,
+                                    ty: hir::Type::I32,
+                                    array: Box::new(hir::Exp::Var { ty: array_type.clone(), name: Name::new("a") }),
+                                    index: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 2 }}),
+                                }
+                            ),
+                        }
+                    ),
+                }
+            ]
+        };
+
+        let r = run_main("main", &h);
+        assert_eq!(r, Ok(0));
+    }
+
+    #[test]
+    fn array_copy_overlapping() {
+        let array_type = hir::Type::Array { ty: Box::new(hir::Type::I32) };
+        let a = Box::new(hir::Exp::Var { ty: array_type.clone(), name: Name::new("a") });
+
+        // a = [1, 2, 3, 4, 5]; copy a[0..3] to a[1..4]; a is now [1, 1, 2, 3, 5].
+        let h = hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("main"),
+                    params: vec![],
+                    body: Box::new(
+                        hir::Exp::Let {
+                            inits: vec![
+                                hir::Field {
+                                    param: hir::Param {
+                                        ty: array_type.clone(),
+                                        name: Name::new("a"),
+                                    },
+                                    exp: Box::new(hir::Exp::ArrayLit {
+                                        ty: hir::Type::I32,
+                                        exps: vec![
+                                        hir::Exp::Lit { lit: hir::Lit::I32 { value: 1 }},
+                                        hir::Exp::Lit { lit: hir::Lit::I32 { value: 2 }},
+                                        hir::Exp::Lit { lit: hir::Lit::I32 { value: 3 }},
+                                        hir::Exp::Lit { lit: hir::Lit::I32 { value: 4 }},
+                                        hir::Exp::Lit { lit: hir::Lit::I32 { value: 5 }}
+                                        ],
+                                    }),
+                                }
+                            ],
+                            body: Box::new(
+                                hir::Exp::Seq {
+                                    body: Box::new(
+                                        hir::Stm::ArrayCopy {
+                                            bounds_check: true,
+                                            ty: hir::Type::I32,
+                                            dst_array: a.clone(),
+                                            dst_index: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 1 }}),
+                                            src_array: a.clone(),
+                                            src_index: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 0 }}),
+                                            count: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 3 }}),
+                                        }
+                                    ),
+                                    exp: Box::new(
+                                        hir::Exp::ArrayLoad {
+                                            bounds_check: false,
+                                            ty: hir::Type::I32,
+                                            array: a.clone(),
+                                            index: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 3 }}),
+                                        }
+                                    ),
+                                }
+                            ),
+                        }
+                    ),
+                }
+            ]
+        };
+
+        let r = run_main("main", &h);
+        assert_eq!(r, Ok(3));
+    }
+
+    #[test]
+    fn mutually_recursive_functions() {
+        let fun_ty = hir::Type::Fun { ret: Box::new(hir::Type::I32), args: vec![hir::Type::I32] };
+        let n = || Box::new(hir::Exp::Var { name: Name::new("n"), ty: hir::Type::I32 });
+        let lit = |value| Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value }});
+        let call = |name, arg| Box::new(hir::Exp::Call { fun_type: fun_ty.clone(), name: Name::new(name), args: vec![*arg] });
+
+        // f(n) = n == 0 ? base : other(n - 1)
+        let def = |name, other, base| hir::Def::FunDef {
+            ret_type: hir::Type::I32,
+            name: Name::new(name),
+            params: vec![hir::Param { name: Name::new("n"), ty: hir::Type::I32 }],
+            body: Box::new(hir::Exp::Cond {
+                ty: hir::Type::I32,
+                cond: Box::new(hir::Exp::Binary { op: Bop::Eq_i32, e1: n(), e2: lit(0) }),
+                if_true: lit(base),
+                if_false: call(other, Box::new(hir::Exp::Binary { op: Bop::Sub_i32, e1: n(), e2: lit(1) })),
+            }),
+        };
+
+        // main comes first, so it calls even before even is defined, and even calls odd before odd is.
+        let h = hir::Root {
+            defs: vec![
+                // main() = even(10) * 10 + odd(7)
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("main"),
+                    params: vec![],
+                    body: Box::new(hir::Exp::Binary {
+                        op: Bop::Add_i32,
+                        e1: Box::new(hir::Exp::Binary { op: Bop::Mul_i32, e1: call("even", lit(10)), e2: lit(10) }),
+                        e2: call("odd", lit(7)),
+                    }),
+                },
+                def("even", "odd", 1),
+                def("odd", "even", 0),
+            ]
+        };
+
+        let r = run_main("main", &h);
+        assert_eq!(r, Ok(11));
+    }
+
+    #[test]
+    fn cond_picks_constant() {
+        let h = hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("main"),
+                    params: vec![],
+                    body: Box::new(
+                        hir::Exp::Cond {
+                            ty: hir::Type::I32,
+                            cond: Box::new(hir::Exp::Binary {
+                                op: Bop::Lt_s_i32,
+                                e1: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 1 }}),
+                                e2: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 2 }}),
+                            }),
+                            if_true: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 10 }}),
+                            if_false: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 20 }}),
+                        }
+                    ),
+                }
+            ]
+        };
+
+        let r = run_main("main", &h);
+        assert_eq!(r, Ok(10));
+    }
+
+    #[test]
+    fn complement_word() {
+        // The word type is I64.
+        let h = hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("main"),
+                    params: vec![],
+                    body: Box::new(hir::Exp::Unary {
+                        op: Uop::Wrap_i64_i32,
+                        exp: Box::new(hir::Exp::Unary {
+                            op: Uop::Complement_word,
+                            exp: Box::new(hir::Exp::Lit { lit: hir::Lit::I64 { value: 5 }}),
+                        }),
+                    }),
+                }
+            ]
+        };
+
+        let r = run_main("main", &h);
+        assert_eq!(r, Ok(-6));
+    }
+
+    #[test]
+    fn not_bool() {
+        let h = hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("main"),
+                    params: vec![],
+                    body: Box::new(
+                        hir::Exp::Cond {
+                            ty: hir::Type::I32,
+                            cond: Box::new(hir::Exp::Unary {
+                                op: Uop::Not_z,
+                                exp: Box::new(hir::Exp::Lit { lit: hir::Lit::Bool { value: false }}),
+                            }),
+                            if_true: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 1 }}),
+                            if_false: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 0 }}),
+                        }
+                    ),
+                }
+            ]
+        };
+
+        let r = run_main("main", &h);
+        assert_eq!(r, Ok(1));
+    }
+
+    #[test]
+    fn bit_eq_distinguishes_zeros() {
+        let zero = Box::new(hir::Exp::Lit { lit: hir::Lit::F64 { value: 0.0 }});
+        let neg_zero = Box::new(hir::Exp::Lit { lit: hir::Lit::F64 { value: -0.0 }});
+
+        let h = hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("main"),
+                    params: vec![],
+                    // (0.0 == -0.0 ? 10 : 0) + (bit_eq(0.0, -0.0) ? 1 : 0)
+                    body: Box::new(hir::Exp::Binary {
+                        op: Bop::Add_i32,
+                        e1: Box::new(hir::Exp::Cond {
+                            ty: hir::Type::I32,
+                            cond: Box::new(hir::Exp::Binary { op: Bop::Eq_f64, e1: zero.clone(), e2: neg_zero.clone() }),
+                            if_true: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 10 }}),
+                            if_false: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 0 }}),
+                        }),
+                        e2: Box::new(hir::Exp::Cond {
+                            ty: hir::Type::I32,
+                            cond: Box::new(hir::Exp::Binary { op: Bop::BitEq_f64, e1: zero.clone(), e2: neg_zero.clone() }),
+                            if_true: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 1 }}),
+                            if_false: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 0 }}),
+                        }),
+                    }),
+                }
+            ]
+        };
+
+        let r = run_main("main", &h);
+        assert_eq!(r, Ok(10));
+    }
+
+    #[test]
+    fn nez_i32() {
+        let h = hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("main"),
+                    params: vec![],
+                    body: Box::new(
+                        hir::Exp::Cond {
+                            ty: hir::Type::I32,
+                            cond: Box::new(hir::Exp::Unary {
+                                op: Uop::Nez_i32_z,
+                                exp: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 5 }}),
+                            }),
+                            if_true: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 1 }}),
+                            if_false: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 0 }}),
+                        }
+                    ),
+                }
+            ]
+        };
+
+        let r = run_main("main", &h);
+        assert_eq!(r, Ok(1));
+    }
+
+    #[test]
+    fn extend_bool_i32() {
+        let h = hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("main"),
+                    params: vec![],
+                    body: Box::new(hir::Exp::Unary {
+                        op: Uop::Extend_u_z_i32,
+                        exp: Box::new(hir::Exp::Lit { lit: hir::Lit::Bool { value: true }}),
+                    }),
+                }
+            ]
+        };
+
+        let r = run_main("main", &h);
+        assert_eq!(r, Ok(1));
+    }
+
+    #[test]
+    fn lir_builder_add_mul() {
+        use crate::lir::builder::Builder;
+
+        // f(a, b) = (a + b) * 2
+        let mut f = Builder::new(Name::new("f"), lir::Type::I32);
+        let a = f.param(lir::Type::I32);
+        let b = f.param(lir::Type::I32);
+        let sum = f.binary(Bop::Add_i32, a, b);
+        let two = lir::Exp::Lit { lit: lir::Lit::I32 { value: 2 } };
+        let prod = f.binary(Bop::Mul_i32, sum, two);
+        f.ret(prod);
+
+        // main() = f(3, 4)
+        let mut main = Builder::new(Name::new("main"), lir::Type::I32);
+        let fun = lir::Exp::FunctionAddr {
+            ty: lir::Type::Fun { ret: Box::new(lir::Type::I32), args: vec![lir::Type::I32, lir::Type::I32] },
+            name: Name::new("f"),
+        };
+        let r = main.call(lir::Type::I32, fun, vec![
+            lir::Exp::Lit { lit: lir::Lit::I32 { value: 3 } },
+            lir::Exp::Lit { lit: lir::Lit::I32 { value: 4 } },
+        ]);
+        main.ret(r);
+
+        let l = lir::Root {
+            externs: vec![],
+            imports: vec![],
+            data: vec![],
+            procs: vec![f.finish(), main.finish()],
+        };
+
+        let r = run_main_lir("main", &l);
+        assert_eq!(r, Ok(14));
+    }
+
+    #[test]
+    fn lir_no_params() {
+        use crate::lir::builder::Builder;
+
+        // main() = 7
+        let mut main = Builder::new(Name::new("main"), lir::Type::I32);
+        main.ret(lir::Exp::Lit { lit: lir::Lit::I32 { value: 7 } });
+
+        let l = lir::Root {
+            externs: vec![],
+            imports: vec![],
+            data: vec![],
+            procs: vec![main.finish()],
+        };
+
+        let r = run_main_lir("main", &l);
+        assert_eq!(r, Ok(7));
+    }
+
+    #[test]
+    fn alignof_lit() {
+        use crate::lir::builder::Builder;
+
+        let alignof = |ty| lir::Exp::Lit { lit: lir::Lit::Alignof { ty } };
+
+        // main() = alignof(f64) * 100 + alignof(i8)
+        let mut main = Builder::new(Name::new("main"), lir::Type::I32);
+        let a = main.unary(Uop::Wrap_i64_i32, alignof(lir::Type::F64));
+        let b = main.unary(Uop::Wrap_i64_i32, alignof(lir::Type::I8));
+        let a100 = main.binary(Bop::Mul_i32, a, lir::Exp::Lit { lit: lir::Lit::I32 { value: 100 } });
+        let r = main.binary(Bop::Add_i32, a100, b);
+        main.ret(r);
+
+        let l = lir::Root {
+            externs: vec![],
+            imports: vec![],
+            data: vec![],
+            procs: vec![main.finish()],
+        };
+
+        let r = run_main_lir("main", &l);
+        assert_eq!(r, Ok(801));
+    }
+
+    #[test]
+    fn sizeof_lit() {
+        use crate::lir::builder::Builder;
+
+        let sizeof = |ty| lir::Exp::Lit { lit: lir::Lit::Sizeof { ty } };
+
+        // main() = sizeof({i8, i32}) * 100 + sizeof(i16)
+        let mut main = Builder::new(Name::new("main"), lir::Type::I32);
+        let a = main.unary(Uop::Wrap_i64_i32, sizeof(lir::Type::Struct { fields: vec![lir::Type::I8, lir::Type::I32] }));
+        let b = main.unary(Uop::Wrap_i64_i32, sizeof(lir::Type::I16));
+        let a100 = main.binary(Bop::Mul_i32, a, lir::Exp::Lit { lit: lir::Lit::I32 { value: 100 } });
+        let r = main.binary(Bop::Add_i32, a100, b);
+        main.ret(r);
+
+        let l = lir::Root {
+            externs: vec![],
+            imports: vec![],
+            data: vec![],
+            procs: vec![main.finish()],
+        };
+
+        let r = run_main_lir("main", &l);
+        assert_eq!(r, Ok(802));
+    }
+
+    #[test]
+    fn function_address_in_global() {
+        use crate::lir::builder::Builder;
+
+        let fun_ptr_ty = lir::Type::Ptr { ty: Box::new(lir::Type::Fun { ret: Box::new(lir::Type::I32), args: vec![] }) };
+
+        // answer() = 42
+        let mut answer = Builder::new(Name::new("answer"), lir::Type::I32);
+        answer.ret(lir::Exp::Lit { lit: lir::Lit::I32 { value: 42 } });
+
+        // main() = (*slot)(), where slot is a constant global holding &answer.
+        let mut main = Builder::new(Name::new("main"), lir::Type::I32);
+        let slot = lir::Exp::GlobalAddr { ty: lir::Type::Ptr { ty: Box::new(fun_ptr_ty.clone()) }, name: Name::new("slot") };
+        let f = main.load(fun_ptr_ty.clone(), slot);
+        let r = main.call(lir::Type::I32, f, vec![]);
+        main.ret(r);
+
+        let l = lir::Root {
+            externs: vec![],
+            imports: vec![],
+            data: vec![
+                lir::Data {
+                    ty: fun_ptr_ty.clone(),
+                    name: Name::new("slot"),
+                    init: lir::Init::Lit { lit: lir::Lit::FunctionAddr { ty: fun_ptr_ty.clone(), name: Name::new("answer") } },
+                    thread_local: false,
+                    visibility: lir::Visibility::Default,
+                    dso_local: false,
+                    section: None,
+                    alignment: None,
+                    packed: false,
+                },
+            ],
+            procs: vec![answer.finish(), main.finish()],
+        };
+
+        let r = run_main_lir("main", &l);
+        assert_eq!(r, Ok(42));
+    }
+
+    #[test]
+    fn lir_stack_buffer() {
+        use crate::lir::builder::Builder;
+
+        let i32_lit = |value| lir::Exp::Lit { lit: lir::Lit::I32 { value } };
+
+        // main() { buf = i32[16]; buf[5] = 42; buf[1] = 7; return buf[5]; }
+        let mut main = Builder::new(Name::new("main"), lir::Type::I32);
+        let buf = main.alloca(lir::Type::I32, i32_lit(16));
+        let p5 = main.element_addr(lir::Type::I32, buf.clone(), i32_lit(5));
+        main.store(p5, i32_lit(42));
+        let p1 = main.element_addr(lir::Type::I32, buf.clone(), i32_lit(1));
+        main.store(p1, i32_lit(7));
+        let q = main.element_addr(lir::Type::I32, buf.clone(), i32_lit(5));
+        let r = main.load(lir::Type::I32, q);
+        main.ret(r);
+
+        let l = lir::Root {
+            externs: vec![],
+            imports: vec![],
+            data: vec![],
+            procs: vec![main.finish()],
+        };
+
+        let r = run_main_lir("main", &l);
+        assert_eq!(r, Ok(42));
+    }
+
+    #[test]
+    fn lir_from_text() {
+        // Sum 0 through 4, written in LIR's textual format.
+        let text = "
+            proc main(): i32 {
+                %i: i32 = move i32 0
+                %n: i32 = move i32 0
+                jump loop
+            loop:
+                %c: i1 = Lt_s_i32 %i: i32, i32 5
+                cjump %c: i1, body, done
+            body:
+                %n: i32 = Add_i32 %n: i32, %i: i32
+                %i: i32 = Add_i32 %i: i32, i32 1
+                jump loop
+            done:
+                ret %n: i32
+            }
+        ";
+
+        let l = lir::Root {
+            externs: vec![],
+            imports: vec![],
+            data: vec![],
+            procs: vec![lir::Proc::parse(text).unwrap()],
+        };
+
+        let r = run_main_lir("main", &l);
+        assert_eq!(r, Ok(10));
+    }
+
+    #[test]
+    fn lir_must_tail_cps_loop() {
+        // A loop in continuation-passing style: ping and pong count n down by passing control
+        // back and forth. Without guaranteed tail calls, this would take a million frames
+        // and overflow the stack. They're noinline so the optimizer can't turn the mutual
+        // recursion into a loop itself.
+        let step = |name: &str, next: &str| {
+            let text = format!("
+                proc {}(%n: i32, %acc: i32): i32 noinline {{
+                    %c: i1 = Eq_i32 %n: i32, i32 0
+                    cjump %c: i1, done, again
+                again:
+                    %m: i32 = Sub_i32 %n: i32, i32 1
+                    %a: i32 = Add_i32 %acc: i32, i32 2
+                    %r: i32 = musttail call @{}: *fun(i32, i32): i32(%m: i32, %a: i32)
+                    ret %r: i32
+                done:
+                    ret %acc: i32
+                }}
+            ", name, next);
+            lir::Proc::parse(&text).unwrap()
+        };
+
+        let main = lir::Proc::parse("
+            proc main(): i32 {
+                %r: i32 = call @ping: *fun(i32, i32): i32(i32 1000000, i32 0)
+                ret %r: i32
+            }
+        ").unwrap();
+
+        let l = lir::Root {
+            externs: vec![],
+            imports: vec![],
+            data: vec![],
+            procs: vec![step("ping", "pong"), step("pong", "ping"), main],
+        };
+
+        let r = run_main_lir("main", &l);
+        assert_eq!(r, Ok(2000000));
+    }
+
+    #[test]
+    fn lir_dynamic_stack_buffer() {
+        use crate::lir::builder::Builder;
+
+        let i32_lit = |value| lir::Exp::Lit { lit: lir::Lit::I32 { value } };
+
+        // f(n) { buf = i32[n]; for (i = 0; i < n; i++) buf[i] = i; return buf[n-1]; }
+        let mut f = Builder::new(Name::new("f"), lir::Type::I32);
+        let n = f.param(lir::Type::I32);
+        let buf = f.alloca(lir::Type::I32, n.clone());
+        let i = f.temp(lir::Type::I32);
+        f.mov(&i, i32_lit(0));
+        let top = f.label();
+        let body = f.label();
+        let done = f.label();
+        f.place(top);
+        let c = f.binary(Bop::Lt_s_i32, i.clone(), n.clone());
+        f.cjump(c, body, done);
+        f.place(body);
+        let p = f.element_addr(lir::Type::I32, buf.clone(), i.clone());
+        f.store(p, i.clone());
+        let next = f.binary(Bop::Add_i32, i.clone(), i32_lit(1));
+        f.mov(&i, next);
+        f.jump(top);
+        f.place(done);
+        let last = f.binary(Bop::Sub_i32, n.clone(), i32_lit(1));
+        let q = f.element_addr(lir::Type::I32, buf.clone(), last);
+        let r = f.load(lir::Type::I32, q);
+        f.ret(r);
+
+        // main() = f(10)
+        let mut main = Builder::new(Name::new("main"), lir::Type::I32);
+        let fun = lir::Exp::FunctionAddr {
+            ty: lir::Type::Fun { ret: Box::new(lir::Type::I32), args: vec![lir::Type::I32] },
+            name: Name::new("f"),
+        };
+        let r = main.call(lir::Type::I32, fun, vec![i32_lit(10)]);
+        main.ret(r);
+
+        let l = lir::Root {
+            externs: vec![],
+            imports: vec![],
+            data: vec![],
+            procs: vec![f.finish(), main.finish()],
+        };
+
+        // The last of the 10 elements holds 9.
+        let r = run_main_lir("main", &l);
+        assert_eq!(r, Ok(9));
+    }
+
+    #[test]
+    fn mul_i128() {
+        use crate::lir::builder::Builder;
+
+        let a: i128 = 0x1234_5678_9abc_def0_0fed_cba9_8765_4321;
+        let b: i128 = -0x0000_0000_0000_0003_8000_0000_0000_0001;
+
+        // f(a, b) = a * b == expected
+        let mut f = Builder::new(Name::new("f"), lir::Type::I32);
+        let x = f.param(lir::Type::I128);
+        let y = f.param(lir::Type::I128);
+        let prod = f.binary(Bop::Mul_i128, x, y);
+        let expected = lir::Exp::Lit { lit: lir::Lit::I128 { value: a.wrapping_mul(b) } };
+        let eq = f.binary(Bop::Eq_i128, prod, expected);
+        let r = f.unary(Uop::Extend_u_z_i32, eq);
+        f.ret(r);
+
+        // main() = f(a, b)
+        let mut main = Builder::new(Name::new("main"), lir::Type::I32);
+        let fun = lir::Exp::FunctionAddr {
+            ty: lir::Type::Fun { ret: Box::new(lir::Type::I32), args: vec![lir::Type::I128, lir::Type::I128] },
+            name: Name::new("f"),
+        };
+        let r = main.call(lir::Type::I32, fun, vec![
+            lir::Exp::Lit { lit: lir::Lit::I128 { value: a } },
+            lir::Exp::Lit { lit: lir::Lit::I128 { value: b } },
+        ]);
+        main.ret(r);
+
+        let l = lir::Root {
+            externs: vec![],
+            imports: vec![],
+            data: vec![],
+            procs: vec![f.finish(), main.finish()],
+        };
+
+        let r = run_main_lir("main", &l);
+        assert_eq!(r, Ok(1));
+    }
+
+    #[test]
+    fn struct_argument_by_value() {
+        use crate::lir::builder::Builder;
+
+        // second(s) = s.1; main() = second({10, 20, ...})
+        fn root(n: i32) -> lir::Root {
+            let fields: Vec<lir::Exp> = (1..=n).map(|i| lir::Exp::Lit { lit: lir::Lit::I32 { value: i * 10 } }).collect();
+            let struct_ty = lir::Type::Struct { fields: vec![lir::Type::I32; n as usize] };
+
+            let mut second = Builder::new(Name::new("second"), lir::Type::I32);
+            let s = second.param(struct_ty.clone());
+            let x = second.extract(s, 1);
+            second.ret(x);
+
+            let mut main = Builder::new(Name::new("main"), lir::Type::I32);
+            let s = main.build_struct(fields);
+            let fun = lir::Exp::FunctionAddr {
+                ty: lir::Type::Fun { ret: Box::new(lir::Type::I32), args: vec![struct_ty] },
+                name: Name::new("second"),
+            };
+            let r = main.call(lir::Type::I32, fun, vec![s]);
+            main.ret(r);
+
+            lir::Root { externs: vec![], imports: vec![], data: vec![], procs: vec![second.finish(), main.finish()] }
+        }
+
+        // Two i32s are passed as an aggregate, five in memory.
+        assert_eq!(run_main_lir("main", &root(2)), Ok(20));
+        assert_eq!(run_main_lir("main", &root(5)), Ok(20));
+    }
+
+    #[test]
+    fn struct_lit_two_fields() {
+        let struct_type = hir::Type::Struct {
+            fields: vec![
+                hir::Param { ty: hir::Type::I32, name: Name::new("f") },
+                hir::Param { ty: hir::Type::I32, name: Name::new("g") },
+            ]
+        };
+        let s = Box::new(hir::Exp::Var { ty: struct_type.clone(), name: Name::new("s") });
+
+        // let s = { f: 3, g: 4 } in s.f * 10 + s.g
+        let h = hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("main"),
+                    params: vec![],
+                    body: Box::new(
+                        hir::Exp::Let {
+                            inits: vec![
+                                hir::Field {
+                                    param: hir::Param { ty: struct_type.clone(), name: Name::new("s") },
+                                    exp: Box::new(hir::Exp::StructLit {
+                                        fields: vec![
+                                            hir::Field {
+                                                param: hir::Param { ty: hir::Type::I32, name: Name::new("f") },
+                                                exp: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 3 }}),
+                                            },
+                                            hir::Field {
+                                                param: hir::Param { ty: hir::Type::I32, name: Name::new("g") },
+                                                exp: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 4 }}),
+                                            },
+                                        ]
+                                    }),
+                                }
+                            ],
+                            body: Box::new(
+                                hir::Exp::Binary {
+                                    op: Bop::Add_i32,
+                                    e1: Box::new(hir::Exp::Binary {
+                                        op: Bop::Mul_i32,
+                                        e1: Box::new(hir::Exp::StructLoad { ty: struct_type.clone(), base: s.clone(), field: Name::new("f") }),
+                                        e2: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 10 }}),
+                                    }),
+                                    e2: Box::new(hir::Exp::StructLoad { ty: struct_type.clone(), base: s.clone(), field: Name::new("g") }),
+                                }
+                            ),
+                        }
+                    ),
+                }
+            ]
+        };
+
+        let r = run_main("main", &h);
+        assert_eq!(r, Ok(34));
+    }
+
+    #[test]
+    fn convert_i64_to_i32() {
+        // (2^32 + 7) truncated to i32 is 7.
+        let h = hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("main"),
+                    params: vec![],
+                    body: Box::new(
+                        hir::Exp::Convert {
+                            ty: hir::Type::I32,
+                            signedness: Signedness::Signed,
+                            exp: Box::new(hir::Exp::Lit { lit: hir::Lit::I64 { value: (1i64 << 32) + 7 }}),
+                        }
+                    ),
+                }
+            ]
+        };
+
+        let r = run_main("main", &h);
+        assert_eq!(r, Ok(7));
+    }
+
+    #[test]
+    fn convert_narrow_and_bool() {
+        let convert = |ty, signedness, exp| hir::Exp::Convert { ty, signedness, exp: Box::new(exp) };
+        let main = |body| hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("main"),
+                    params: vec![],
+                    body: Box::new(body),
+                }
+            ]
+        };
+        let i64_lit = |value| hir::Exp::Lit { lit: hir::Lit::I64 { value } };
+        let i16_lit = |value| hir::Exp::Lit { lit: hir::Lit::I16 { value } };
+
+        // 300 wrapped to i8 is 44.
+        let h = main(convert(hir::Type::I32, Signedness::Signed, convert(hir::Type::I8, Signedness::Signed, i64_lit(300))));
+        assert_eq!(run_main("main", &h), Ok(44));
+
+        // -1 as an i8 is 255 unsigned and -1 signed.
+        let h = main(convert(hir::Type::I32, Signedness::Unsigned, convert(hir::Type::I8, Signedness::Signed, i16_lit(-1))));
+        assert_eq!(run_main("main", &h), Ok(255));
+        let h = main(convert(hir::Type::I32, Signedness::Signed, convert(hir::Type::I8, Signedness::Signed, i16_lit(-1))));
+        assert_eq!(run_main("main", &h), Ok(-1));
+
+        // 2^40 is nonzero, though its low 32 bits are all zero.
+        let h = main(convert(hir::Type::I32, Signedness::Signed, convert(hir::Type::I64, Signedness::Unsigned, convert(hir::Type::Bool, Signedness::Unsigned, i64_lit(1 << 40)))));
+        assert_eq!(run_main("main", &h), Ok(1));
+    }
+
+    #[test]
+    fn convert_f32_f64() {
+        // main() = (i32) ((f64) 1.5f * 2.0), where the widening is a Promote_f32_f64.
+        let h = hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("main"),
+                    params: vec![],
+                    body: Box::new(hir::Exp::Convert {
+                        ty: hir::Type::I32,
+                        signedness: Signedness::Signed,
+                        exp: Box::new(hir::Exp::Binary {
+                            op: Bop::Mul_f64,
+                            e1: Box::new(hir::Exp::Convert {
+                                ty: hir::Type::F64,
+                                signedness: Signedness::Signed,
+                                exp: Box::new(hir::Exp::Lit { lit: hir::Lit::F32 { value: 1.5 }}),
+                            }),
+                            e2: Box::new(hir::Exp::Lit { lit: hir::Lit::F64 { value: 2.0 }}),
+                        }),
+                    }),
+                }
+            ]
+        };
+
+        assert_eq!(run_main("main", &h), Ok(3));
+    }
+
+    #[test]
+    fn int_max_signedness() {
+        // As unsigned, -1 is 2^32 - 1, so it's the larger operand.
+        let max = |op| hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("main"),
+                    params: vec![],
+                    body: Box::new(
+                        hir::Exp::Binary {
+                            op,
+                            e1: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: -1 }}),
+                            e2: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 1 }}),
+                        }
+                    ),
+                }
+            ]
+        };
+
+        assert_eq!(run_main("main", &max(Bop::Max_u_i32)), Ok(-1));
+        assert_eq!(run_main("main", &max(Bop::Max_s_i32)), Ok(1));
     }
 
-    gc::init();
-
-    let res = init_and_run(init, main);
+    #[test]
+    fn rem_s_sign_follows_dividend() {
+        // main = rem(a, b), so the divisor isn't a constant in rem.
+        let rem = |a, b| hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("rem"),
+                    params: vec![
+                        hir::Param { ty: hir::Type::I32, name: Name::new("a") },
+                        hir::Param { ty: hir::Type::I32, name: Name::new("b") },
+                    ],
+                    body: Box::new(
+                        hir::Exp::Binary {
+                            op: Bop::Rem_s_i32,
+                            e1: Box::new(hir::Exp::Var { name: Name::new("a"), ty: hir::Type::I32 }),
+                            e2: Box::new(hir::Exp::Var { name: Name::new("b"), ty: hir::Type::I32 }),
+                        }
+                    ),
+                },
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("main"),
+                    params: vec![],
+                    body: Box::new(
+                        hir::Exp::Call {
+                            fun_type: hir::Type::Fun { ret: Box::new(hir::Type::I32), args: vec![hir::Type::I32, hir::Type::I32] },
+                            name: Name::new("rem"),
+                            args: vec![
+                                hir::Exp::Lit { lit: hir::Lit::I32 { value: a }},
+                                hir::Exp::Lit { lit: hir::Lit::I32 { value: b }},
+                            ],
+                        }
+                    ),
+                }
+            ]
+        };
 
-    unsafe_llvm!( execution_engine::LLVMDisposeExecutionEngine(ee) );
+        // Like C, the remainder has the sign of the dividend.
+        assert_eq!(run_main("main", &rem(-7, 3)), Ok(-1));
+        assert_eq!(run_main("main", &rem(7, -3)), Ok(1));
 
-    context.dispose();
+        // srem would overflow here, but the remainder is 0.
+        assert_eq!(run_main("main", &rem(std::i32::MIN, -1)), Ok(0));
+    }
 
-    Ok(res)
-}
+    #[test]
+    fn total_lt_sorts_nan_last() {
+        // main = lt(a, b), where lt(a, b) = a <_total b
+        let total_lt = |a, b| hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("lt"),
+                    params: vec![
+                        hir::Param { ty: hir::Type::F64, name: Name::new("a") },
+                        hir::Param { ty: hir::Type::F64, name: Name::new("b") },
+                    ],
+                    body: Box::new(
+                        hir::Exp::Unary {
+                            op: Uop::Extend_u_z_i32,
+                            exp: Box::new(hir::Exp::Binary {
+                                op: Bop::TotalLt_f64,
+                                e1: Box::new(hir::Exp::Var { name: Name::new("a"), ty: hir::Type::F64 }),
+                                e2: Box::new(hir::Exp::Var { name: Name::new("b"), ty: hir::Type::F64 }),
+                            }),
+                        }
+                    ),
+                },
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("main"),
+                    params: vec![],
+                    body: Box::new(
+                        hir::Exp::Call {
+                            fun_type: hir::Type::Fun { ret: Box::new(hir::Type::I32), args: vec![hir::Type::F64, hir::Type::F64] },
+                            name: Name::new("lt"),
+                            args: vec![
+                                hir::Exp::Lit { lit: hir::Lit::F64 { value: a }},
+                                hir::Exp::Lit { lit: hir::Lit::F64 { value: b }},
+                            ],
+                        }
+                    ),
+                }
+            ]
+        };
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::common::names::*;
-    use crate::gen::*;
-    use crate::hir::ops::*;
-    use crate::hir::trees as hir;
+        let lt = |a: f64, b: f64| run_main("main", &total_lt(a, b)) == Ok(1);
 
-    #[ignore]
-    #[test]
-    fn print_env() {
-        use std::env;
+        let mut values = vec![std::f64::NAN, 1.0, -0.0, 0.0];
+        values.sort_by(|a, b| {
+            if lt(*a, *b) { std::cmp::Ordering::Less }
+            else if lt(*b, *a) { std::cmp::Ordering::Greater }
+            else { std::cmp::Ordering::Equal }
+        });
 
-        for (key, value) in env::vars() {
-            println!("{}='{}'", key, value);
-        }
+        // Compare bits, since NaN != NaN and -0.0 == 0.0.
+        let bits: Vec<u64> = values.iter().map(|v| v.to_bits()).collect();
+        assert_eq!(bits, vec![(-0.0f64).to_bits(), 0.0f64.to_bits(), 1.0f64.to_bits(), std::f64::NAN.to_bits()]);
     }
 
-    #[ignore]
     #[test]
-    #[should_panic]
-    fn panic() {
-        let h = hir::Root {
+    fn int_min_i64_signedness() {
+        let min = |op| hir::Root {
             defs: vec![
                 hir::Def::FunDef {
-                    ret_type: hir::Type::Void,
+                    ret_type: hir::Type::I32,
                     name: Name::new("main"),
                     params: vec![],
-                    body: Box::new(hir::Exp::Call {
-                        fun_type: hir::Type::Fun { ret: Box::new(hir::Type::Void), args: vec![] },
-                        name: Name::new("panic"),
-                        args: vec![]
-                    })
+                    body: Box::new(
+                        hir::Exp::Convert {
+                            ty: hir::Type::I32,
+                            signedness: Signedness::Signed,
+                            exp: Box::new(hir::Exp::Binary {
+                                op,
+                                e1: Box::new(hir::Exp::Lit { lit: hir::Lit::I64 { value: -1 }}),
+                                e2: Box::new(hir::Exp::Lit { lit: hir::Lit::I64 { value: 5 }}),
+                            }),
+                        }
+                    ),
                 }
             ]
         };
 
-        let r = run_main("main", &h);
-        match r {
-            Ok(_) => {},
-            Err(_) => assert!(false),
-        }
+        assert_eq!(run_main("main", &min(Bop::Min_u_i64)), Ok(5));
+        assert_eq!(run_main("main", &min(Bop::Min_s_i64)), Ok(-1));
     }
 
     #[test]
-    fn struct_lit_and_load() {
+    fn fma_single_rounding() {
+        // a * b = 1 - 2^-60 exactly, which rounds to 1.0 in f64. So a * b + c is 0,
+        // but fma(a, b, c) is -2^-60.
+        let a = Box::new(hir::Exp::Lit { lit: hir::Lit::F64 { value: 1.0 + 1.0 / 1073741824.0 }});
+        let b = Box::new(hir::Exp::Lit { lit: hir::Lit::F64 { value: 1.0 - 1.0 / 1073741824.0 }});
+        let c = Box::new(hir::Exp::Lit { lit: hir::Lit::F64 { value: -1.0 }});
+
         let h = hir::Root {
             defs: vec![
                 hir::Def::FunDef {
@@ -386,27 +1473,28 @@ mod tests {
                     name: Name::new("main"),
                     params: vec![],
                     body: Box::new(
-                        hir::Exp::StructLoad {
-                            ty: hir::Type::Struct {
-                                fields: vec![
-                                    hir::Param {
-                                        ty: hir::Type::I32,
-                                        name: Name::new("f"),
-                                    }
-                                ]
-                            },
-                            base: Box::new(hir::Exp::StructLit {
-                                fields: vec![
-                                    hir::Field {
-                                        param: hir::Param {
-                                            ty: hir::Type::I32,
-                                            name: Name::new("f"),
-                                        },
-                                        exp: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 3 }}),
-                                    }
-                                ]
+                        hir::Exp::Cond {
+                            ty: hir::Type::I32,
+                            cond: Box::new(hir::Exp::Binary {
+                                op: Bop::Ne_f64,
+                                e1: Box::new(hir::Exp::Fma {
+                                    ty: hir::Type::F64,
+                                    a: a.clone(),
+                                    b: b.clone(),
+                                    c: c.clone(),
+                                }),
+                                e2: Box::new(hir::Exp::Binary {
+                                    op: Bop::Add_f64,
+                                    e1: Box::new(hir::Exp::Binary {
+                                        op: Bop::Mul_f64,
+                                        e1: a.clone(),
+                                        e2: b.clone(),
+                                    }),
+                                    e2: c.clone(),
+                                }),
                             }),
-                            field: Name::new("f"),
+                            if_true: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 1 }}),
+                            if_false: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 0 }}),
                         }
                     ),
                 }
@@ -414,7 +1502,7 @@ mod tests {
         };
 
         let r = run_main("main", &h);
-        assert_eq!(r, Ok(3));
+        assert_eq!(r, Ok(1));
     }
 
     #[test]
@@ -453,6 +1541,35 @@ mod tests {
         assert_eq!(r, Ok(0));
     }
 
+    #[test]
+    fn seq_body_before_exp() {
+        let x = || Box::new(hir::Exp::Var { name: Name::new("x"), ty: hir::Type::I32 });
+        let assign = |v| hir::Stm::Assign { ty: hir::Type::I32, lhs: Name::new("x"), rhs: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: v } }) };
+
+        // main = { x = 1; x + { x = 5; x } }
+        // The left operand reads x before the Seq assigns it, and the Seq's exp reads it after.
+        let h = hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("main"),
+                    params: vec![],
+                    body: Box::new(hir::Exp::Seq {
+                        body: Box::new(assign(1)),
+                        exp: Box::new(hir::Exp::Binary {
+                            op: Bop::Add_i32,
+                            e1: x(),
+                            e2: Box::new(hir::Exp::Seq { body: Box::new(assign(5)), exp: x() }),
+                        }),
+                    }),
+                },
+            ],
+        };
+
+        let r = run_main("main", &h);
+        assert_eq!(r, Ok(6));
+    }
+
     #[test]
     fn while_loop() {
         let h = hir::Root {
@@ -497,6 +1614,7 @@ mod tests {
                                                 ),
                                             }
                                         ),
+                                        hints: vec![],
                                     }
                                 ]
                             }
@@ -579,6 +1697,7 @@ mod tests {
                                                 ),
                                             }
                                         ),
+                                        hints: vec![],
                                     }
                                 ]
                             }
@@ -670,6 +1789,7 @@ mod tests {
                                     lit: hir::Lit::I64 { value: 1 },
                                 }),
                             }),
+                            hint: None,
                         }),
                         exp: Box::new(hir::Exp::Binary {
                             op: Bop::Mul_i64,
@@ -720,7 +1840,7 @@ mod tests {
         };
 
         let mut jit = JITManager::new();
-        jit.add_module("main", &h);
+        jit.add_module("main", &h).unwrap();
 
         if let Some(f) = jit.get_function("main") {
             let r = f();
@@ -778,6 +1898,7 @@ mod tests {
                                     lit: hir::Lit::I64 { value: 1 },
                                 }),
                             }),
+                            hint: None,
                         }),
                         exp: Box::new(hir::Exp::Binary {
                             op: Bop::Mul_i64,
@@ -889,6 +2010,7 @@ mod tests {
                             }),
                         }),
                     }),
+                    thread_local: false,
                 },
                 hir::Def::FunDef {
                     ret_type: hir::Type::I32,
@@ -933,6 +2055,192 @@ mod tests {
         assert_eq!(r, Ok(99));
     }
 
+    #[test]
+    fn global_initialized_by_call() {
+        let int_fun = hir::Type::Fun { ret: Box::new(hir::Type::I32), args: vec![] };
+
+        let h = hir::Root {
+            defs: vec![
+                // fun answer() = 42
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("answer"),
+                    params: vec![],
+                    body: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 42 } }),
+                },
+                // var g = answer()
+                hir::Def::VarDef {
+                    ty: hir::Type::I32,
+                    name: Name::new("g"),
+                    exp: Box::new(hir::Exp::Call { fun_type: int_fun, name: Name::new("answer"), args: vec![] }),
+                    thread_local: false,
+                },
+                // fun main() = g
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("main"),
+                    params: vec![],
+                    body: Box::new(hir::Exp::Global { name: Name::new("g"), ty: hir::Type::I32 }),
+                },
+            ],
+        };
+
+        // g is zero until the constructor runs.
+        let r = run_main("main", &h);
+        assert_eq!(r, Ok(42));
+    }
+
+    #[test]
+    fn top_level_function_as_closure() {
+        let int_to_int = hir::Type::Fun {
+            ret: Box::new(hir::Type::I32),
+            args: vec![hir::Type::I32],
+        };
+
+        let h = hir::Root {
+            defs: vec![
+                // inc(x) = x + 1
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("inc"),
+                    params: vec![hir::Param { name: Name::new("x"), ty: hir::Type::I32 }],
+                    body: Box::new(hir::Exp::Binary {
+                        op: Bop::Add_i32,
+                        e1: Box::new(hir::Exp::Var { name: Name::new("x"), ty: hir::Type::I32 }),
+                        e2: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 1 } }),
+                    }),
+                },
+                // apply(f, x) = f(x), where f is a closure
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("apply"),
+                    params: vec![
+                        hir::Param { name: Name::new("f"), ty: int_to_int.clone() },
+                        hir::Param { name: Name::new("x"), ty: hir::Type::I32 },
+                    ],
+                    body: Box::new(hir::Exp::Apply {
+                        fun_type: int_to_int.clone(),
+                        fun: Box::new(hir::Exp::Var { name: Name::new("f"), ty: int_to_int.clone() }),
+                        args: vec![hir::Exp::Var { name: Name::new("x"), ty: hir::Type::I32 }],
+                    }),
+                },
+                // main = apply(inc, 41)
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("main"),
+                    params: vec![],
+                    body: Box::new(hir::Exp::Call {
+                        fun_type: hir::Type::Fun {
+                            ret: Box::new(hir::Type::I32),
+                            args: vec![int_to_int.clone(), hir::Type::I32],
+                        },
+                        name: Name::new("apply"),
+                        args: vec![
+                            hir::Exp::Function { name: Name::new("inc"), ty: int_to_int.clone() },
+                            hir::Exp::Lit { lit: hir::Lit::I32 { value: 41 } },
+                        ],
+                    }),
+                },
+            ],
+        };
+
+        let r = run_main("main", &h);
+        assert_eq!(r, Ok(42));
+    }
+
+    #[test]
+    fn compile_closure_program() {
+        let int_to_int = hir::Type::Fun { ret: Box::new(hir::Type::I32), args: vec![hir::Type::I32] };
+
+        // main() = let k = 41 in (\y -> y + k)(1)
+        let h = hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("main"),
+                    params: vec![],
+                    body: Box::new(hir::Exp::Let {
+                        inits: vec![
+                            hir::Field {
+                                param: hir::Param { name: Name::new("k"), ty: hir::Type::I32 },
+                                exp: Box::new(hir::Exp::Lit { lit: hir::Lit::I32 { value: 41 } }),
+                            }
+                        ],
+                        body: Box::new(hir::Exp::Apply {
+                            fun_type: int_to_int.clone(),
+                            fun: Box::new(hir::Exp::Lambda {
+                                ret_type: hir::Type::I32,
+                                params: vec![hir::Param { name: Name::new("y"), ty: hir::Type::I32 }],
+                                body: Box::new(hir::Exp::Binary {
+                                    op: Bop::Add_i32,
+                                    e1: Box::new(hir::Exp::Var { name: Name::new("y"), ty: hir::Type::I32 }),
+                                    e2: Box::new(hir::Exp::Var { name: Name::new("k"), ty: hir::Type::I32 }),
+                                }),
+                            }),
+                            args: vec![hir::Exp::Lit { lit: hir::Lit::I32 { value: 1 } }],
+                        }),
+                    }),
+                },
+            ],
+        };
+
+        let context = llvm::OwnedContext::new();
+        let mut opts = CompileOptions::new(*context);
+        opts.signs = Some(HashMap::new());
+        let m = compile(&h, "main", &opts).unwrap();
+        assert_eq!(run_module("main", m), Ok(42));
+    }
+
+    #[test]
+    fn apply_closure_env_first() {
+        use crate::hir::cc::EnvPosition;
+
+        let var = |x| Box::new(hir::Exp::Var { name: Name::new(x), ty: hir::Type::I32 });
+        let lit = |value| hir::Exp::Lit { lit: hir::Lit::I32 { value } };
+
+        // main() = let k = 100 in (\x y -> x * k - y)(5, 3)
+        // The params aren't symmetric, so passing the env in the wrong place would show.
+        let h = hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("main"),
+                    params: vec![],
+                    body: Box::new(hir::Exp::Let {
+                        inits: vec![
+                            hir::Field {
+                                param: hir::Param { name: Name::new("k"), ty: hir::Type::I32 },
+                                exp: Box::new(lit(100)),
+                            }
+                        ],
+                        body: Box::new(hir::Exp::Apply {
+                            fun_type: hir::Type::Fun { ret: Box::new(hir::Type::I32), args: vec![hir::Type::I32, hir::Type::I32] },
+                            fun: Box::new(hir::Exp::Lambda {
+                                ret_type: hir::Type::I32,
+                                params: vec![
+                                    hir::Param { name: Name::new("x"), ty: hir::Type::I32 },
+                                    hir::Param { name: Name::new("y"), ty: hir::Type::I32 },
+                                ],
+                                body: Box::new(hir::Exp::Binary {
+                                    op: Bop::Sub_i32,
+                                    e1: Box::new(hir::Exp::Binary { op: Bop::Mul_i32, e1: var("x"), e2: var("k") }),
+                                    e2: var("y"),
+                                }),
+                            }),
+                            args: vec![lit(5), lit(3)],
+                        }),
+                    }),
+                },
+            ],
+        };
+
+        let context = llvm::OwnedContext::new();
+        let mut opts = CompileOptions::new(*context);
+        opts.env_pos = EnvPosition::First;
+        let m = compile(&h, "main", &opts).unwrap();
+        assert_eq!(run_module("main", m), Ok(497));
+    }
+
     #[test]
     fn church_false() {
         let h = hir::Root {
@@ -968,6 +2276,7 @@ mod tests {
                             }),
                         }),
                     }),
+                    thread_local: false,
                 },
                 // main = false(77)(99)
                 hir::Def::FunDef {
@@ -1012,4 +2321,108 @@ mod tests {
         let r = run_main("main", &h);
         assert_eq!(r, Ok(99));
     }
+
+    // Run one test in a child process, for tests of code that aborts. The child runs just
+    // that test, with RIVO_JIT_CHILD set so the test runs the aborting code.
+    fn run_in_child(test: &str) -> std::process::Output {
+        std::process::Command::new(std::env::current_exe().unwrap())
+            .args(&[test, "--exact", "--nocapture", "--test-threads=1"])
+            .env("RIVO_JIT_CHILD", "1")
+            .output()
+            .unwrap()
+    }
+
+    #[test]
+    fn debug_unreachable_aborts() {
+        use crate::mir::trees as mir;
+
+        if std::env::var("RIVO_JIT_CHILD").is_ok() {
+            // no_ret falls off its end after one statement.
+            let no_ret = lir::Proc {
+                ret_type: mir::Type::I32,
+                name: Name::new("no_ret"),
+                params: vec![],
+                visibility: lir::Visibility::Default,
+                dso_local: false,
+                noinline: false,
+                optnone: false,
+                body: vec![lir::Stm::Nop],
+            };
+            let main = lir::Proc::parse("
+                proc main(): i32 {
+                    %r: i32 = call @no_ret: *fun(): i32()
+                    ret %r: i32
+                }
+            ").unwrap();
+            let l = lir::Root { externs: vec![], imports: vec![], data: vec![], procs: vec![no_ret, main] };
+
+            let context = llvm::OwnedContext::new();
+            let mut options = TranslateOptions::new();
+            options.debug_unreachable = true;
+            let m = translate_from_lir_with_options("main", &l, *context, &options).unwrap();
+            let _ = run_module("main", m);
+            return;
+        }
+
+        let out = run_in_child("jit::tests::debug_unreachable_aborts");
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(! out.status.success(), "expected the child to abort: {}", stderr);
+        assert!(stderr.contains("reached unreachable code in no_ret after statement 1"), "unexpected output: {}", stderr);
+    }
+
+    #[test]
+    fn overflow_traps() {
+        use crate::hir::ops::Overflow;
+
+        // add(x, y) = x + y; main() = add(a, 1)
+        let h = |a| hir::Root {
+            defs: vec![
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("add"),
+                    params: vec![
+                        hir::Param { name: Name::new("x"), ty: hir::Type::I32 },
+                        hir::Param { name: Name::new("y"), ty: hir::Type::I32 },
+                    ],
+                    body: Box::new(hir::Exp::Binary {
+                        op: Bop::Add_i32,
+                        e1: Box::new(hir::Exp::Var { name: Name::new("x"), ty: hir::Type::I32 }),
+                        e2: Box::new(hir::Exp::Var { name: Name::new("y"), ty: hir::Type::I32 }),
+                    }),
+                },
+                hir::Def::FunDef {
+                    ret_type: hir::Type::I32,
+                    name: Name::new("main"),
+                    params: vec![],
+                    body: Box::new(hir::Exp::Call {
+                        fun_type: hir::Type::Fun { ret: Box::new(hir::Type::I32), args: vec![hir::Type::I32, hir::Type::I32] },
+                        name: Name::new("add"),
+                        args: vec![
+                            hir::Exp::Lit { lit: hir::Lit::I32 { value: a } },
+                            hir::Exp::Lit { lit: hir::Lit::I32 { value: 1 } },
+                        ],
+                    }),
+                },
+            ],
+        };
+        let run = |a| {
+            let context = llvm::OwnedContext::new();
+            let mut opts = CompileOptions::new(*context);
+            opts.overflow = Overflow::Trap;
+            let m = compile(&h(a), "main", &opts).unwrap();
+            run_module("main", m)
+        };
+
+        if std::env::var("RIVO_JIT_CHILD").is_ok() {
+            let _ = run(std::i32::MAX);
+            return;
+        }
+
+        assert_eq!(run(41), Ok(42));
+
+        let out = run_in_child("jit::tests::overflow_traps");
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(! out.status.success(), "expected the child to trap: {}", stderr);
+        assert!(stderr.contains("JIT panicked!"), "unexpected output: {}", stderr);
+    }
 }
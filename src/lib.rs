@@ -40,7 +40,9 @@ pub mod macros {
     macro_rules! unsafe_llvm {
         ($e: expr) => {
             {
-                let mut guard = crate::llvm::wrappers::LLVM_LOCK.lock().unwrap();
+                // The lock guards no data, so a panic while it was held (e.g., a handle used
+                // after its context was disposed) doesn't leave anything inconsistent.
+                let mut guard = crate::llvm::wrappers::LLVM_LOCK.lock().unwrap_or_else(|e| e.into_inner());
                 println!("locked");
                 let v = unsafe {
                     $e
@@ -0,0 +1,167 @@
+// A builder for LIR procs.
+// Writing lir::Proc by hand is verbose, so tests and frontends can use this instead.
+// Each method that computes a value appends a statement that assigns a fresh temp
+// and returns that temp.
+
+use super::trees::*;
+use crate::common::names::*;
+use crate::mir::ops::*;
+use crate::mir::typed::Typed;
+
+pub struct Builder {
+    ret_type: Type,
+    name: Name,
+    params: Vec<Param>,
+    body: Vec<Stm>,
+}
+
+impl Builder {
+    pub fn new(name: Name, ret_type: Type) -> Builder {
+        Builder {
+            ret_type,
+            name,
+            params: vec![],
+            body: vec![],
+        }
+    }
+
+    // Add a parameter and return it as a temp.
+    pub fn param(&mut self, ty: Type) -> Exp {
+        let name = Name::fresh("p.lir");
+        self.params.push(Param { ty: ty.clone(), name, noalias: false, readonly: false, nonnull: false });
+        Exp::Temp { ty, name }
+    }
+
+    pub fn temp(&mut self, ty: Type) -> Exp {
+        Exp::Temp { ty, name: Name::fresh("t.lir") }
+    }
+
+    pub fn mov(&mut self, dst: &Exp, src: Exp) {
+        self.body.push(Stm::Move { dst: dst.clone(), src });
+    }
+
+    pub fn binary(&mut self, op: Bop, e1: Exp, e2: Exp) -> Exp {
+        let dst = self.temp(op.get_type());
+        self.body.push(Stm::Binary { dst: dst.clone(), op, e1, e2 });
+        dst
+    }
+
+    pub fn unary(&mut self, op: Uop, exp: Exp) -> Exp {
+        let dst = self.temp(op.get_type());
+        self.body.push(Stm::Unary { dst: dst.clone(), op, exp });
+        dst
+    }
+
+    // A struct value held in registers rather than memory.
+    pub fn build_struct(&mut self, fields: Vec<Exp>) -> Exp {
+        let ty = Type::Struct { fields: fields.iter().map(|f| f.get_type()).collect() };
+        let dst = self.temp(ty.clone());
+        self.body.push(Stm::BuildStruct { dst: dst.clone(), ty, fields });
+        dst
+    }
+
+    pub fn extract(&mut self, agg: Exp, field: usize) -> Exp {
+        let ty = match agg.get_type() {
+            Type::Struct { fields } => fields[field].clone(),
+            ty => panic!("expected struct type, got {:?}", ty),
+        };
+        let dst = self.temp(ty);
+        self.body.push(Stm::ExtractStructElement { dst: dst.clone(), agg, field });
+        dst
+    }
+
+    // A stack buffer of count values of type ty.
+    pub fn alloca(&mut self, ty: Type, count: Exp) -> Exp {
+        let dst = self.temp(Type::Ptr { ty: Box::new(ty.clone()) });
+        self.body.push(Stm::Alloca { dst: dst.clone(), ty, count });
+        dst
+    }
+
+    pub fn field_addr(&mut self, struct_ty: Type, ptr: Exp, field: usize) -> Exp {
+        let ty = match &struct_ty {
+            Type::Struct { fields } => fields[field].clone(),
+            ty => panic!("expected struct type, got {:?}", ty),
+        };
+        let dst = self.temp(Type::Ptr { ty: Box::new(ty) });
+        self.body.push(Stm::GetStructElementAddr { dst: dst.clone(), struct_ty, ptr, field });
+        dst
+    }
+
+    pub fn element_addr(&mut self, base_ty: Type, ptr: Exp, index: Exp) -> Exp {
+        let dst = self.temp(Type::Ptr { ty: Box::new(base_ty.clone()) });
+        self.body.push(Stm::GetArrayElementAddr { dst: dst.clone(), base_ty, ptr, index });
+        dst
+    }
+
+    pub fn load(&mut self, ty: Type, src_addr: Exp) -> Exp {
+        let dst = self.temp(ty);
+        self.body.push(Stm::Load { dst: dst.clone(), src_addr });
+        dst
+    }
+
+    pub fn store(&mut self, dst_addr: Exp, src: Exp) {
+        self.body.push(Stm::Store { dst_addr, src });
+    }
+
+    pub fn call(&mut self, ret_type: Type, fun: Exp, args: Vec<Exp>) -> Exp {
+        let dst = self.temp(ret_type);
+        self.body.push(Stm::Call { dst: dst.clone(), fun, args, must_tail: false });
+        dst
+    }
+
+    // A guaranteed tail call, which returns the result of fun to the proc's caller.
+    // fun must have the proc's signature.
+    pub fn tail_call(&mut self, fun: Exp, args: Vec<Exp>) -> Exp {
+        let dst = self.temp(self.ret_type.clone());
+        self.body.push(Stm::Call { dst: dst.clone(), fun, args, must_tail: true });
+        self.body.push(Stm::Ret { exp: dst.clone() });
+        dst
+    }
+
+    pub fn invoke(&mut self, ret_type: Type, fun: Exp, args: Vec<Exp>, normal: Name, unwind: Name) -> Exp {
+        let dst = self.temp(ret_type);
+        self.body.push(Stm::Invoke { dst: dst.clone(), fun, args, normal, unwind });
+        dst
+    }
+
+    // The exception value, a pointer and a selector.
+    pub fn landing_pad(&mut self, cleanup: bool, catches: Vec<Exp>) -> Exp {
+        let dst = self.temp(Type::Struct { fields: vec![Type::Ptr { ty: Box::new(Type::I8) }, Type::I32] });
+        self.body.push(Stm::LandingPad { dst: dst.clone(), cleanup, catches });
+        dst
+    }
+
+    pub fn ret(&mut self, exp: Exp) {
+        self.body.push(Stm::Ret { exp });
+    }
+
+    // Make a fresh label. The label isn't in the body until it's placed.
+    pub fn label(&mut self) -> Name {
+        Name::fresh("L.lir")
+    }
+
+    pub fn place(&mut self, label: Name) {
+        self.body.push(Stm::Label { label });
+    }
+
+    pub fn jump(&mut self, label: Name) {
+        self.body.push(Stm::Jump { label, loop_hints: vec![] });
+    }
+
+    pub fn cjump(&mut self, cmp: Exp, if_true: Name, if_false: Name) {
+        self.body.push(Stm::CJump { cmp, if_true, if_false, hint: None });
+    }
+
+    pub fn finish(self) -> Proc {
+        Proc {
+            ret_type: self.ret_type,
+            name: self.name,
+            params: self.params,
+            body: self.body,
+            visibility: Visibility::Default,
+            dso_local: false,
+            noinline: false,
+            optnone: false,
+        }
+    }
+}
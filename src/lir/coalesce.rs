@@ -0,0 +1,287 @@
+// Stack slot coalescing for LIR
+// llvm_gen allocates every temp its own alloca at function entry. Closure conversion and
+// lowering introduce many short-lived temps, so frames grow with the size of the proc,
+// and temps that mem2reg can't promote (e.g., structs passed in memory) keep their slots.
+//
+// This pass renames temps so that temps of the same type that are never live at the same
+// time share a name, and so share an alloca. It computes liveness over the statements of
+// each proc, and two temps interfere if one is assigned while the other is live.
+// Params are left alone, since their names are bound by the signature.
+
+use std::collections::{HashMap, HashSet};
+
+use super::trees::*;
+use super::licm::{def_of, operands, targets};
+use crate::common::names::*;
+
+pub fn coalesce_temps(r: &Root) -> Root {
+    Root {
+        externs: r.externs.clone(),
+        imports: r.imports.clone(),
+        data: r.data.clone(),
+        procs: r.procs.iter().map(coalesce_proc).collect(),
+    }
+}
+
+fn coalesce_proc(p: &Proc) -> Proc {
+    let live_out = liveness(&p.body);
+    let entry = live_in(&p.body, &live_out, 0);
+
+    // The type of each temp. A temp used at more than one type keeps its name.
+    let mut types: HashMap<Name, Type> = HashMap::new();
+    let mut mixed = HashSet::new();
+    for s in &p.body {
+        for e in operands(s).into_iter().chain(dst(s)) {
+            if let Exp::Temp { name, ty } = e {
+                if let Some(t) = types.insert(*name, ty.clone()) {
+                    if t != *ty {
+                        mixed.insert(*name);
+                    }
+                }
+            }
+        }
+    }
+
+    // Temps live at entry would be read before being assigned, so keep them apart too.
+    let fixed: HashSet<Name> = p.params.iter().map(|p| p.name).chain(mixed).chain(entry).collect();
+
+    let mut interferes: HashMap<Name, HashSet<Name>> = HashMap::new();
+    for (i, s) in p.body.iter().enumerate() {
+        if let Some(d) = def_of(s) {
+            for t in &live_out[i] {
+                if *t != d {
+                    interferes.entry(d).or_insert_with(HashSet::new).insert(*t);
+                    interferes.entry(*t).or_insert_with(HashSet::new).insert(d);
+                }
+            }
+        }
+    }
+
+    // Assign temps to slots greedily, in order of first assignment.
+    let mut order = Vec::new();
+    let mut seen = HashSet::new();
+    for d in p.body.iter().filter_map(def_of) {
+        if ! fixed.contains(&d) && seen.insert(d) {
+            order.push(d);
+        }
+    }
+
+    let none = HashSet::new();
+    let mut slots: Vec<(Type, Name, Vec<Name>)> = Vec::new();
+    let mut renamed = HashMap::new();
+
+    for x in order {
+        let ty = &types[&x];
+        let conflicts = interferes.get(&x).unwrap_or(&none);
+
+        match slots.iter_mut().find(|(t, _, members)| t == ty && members.iter().all(|m| ! conflicts.contains(m))) {
+            Some((_, rep, members)) => {
+                renamed.insert(x, *rep);
+                members.push(x);
+            },
+            None => slots.push((ty.clone(), x, vec![x])),
+        }
+    }
+
+    let mut body = p.body.clone();
+    for s in &mut body {
+        for e in exps_mut(s) {
+            if let Exp::Temp { name, .. } = e {
+                if let Some(rep) = renamed.get(name) {
+                    *name = *rep;
+                }
+            }
+        }
+    }
+
+    Proc {
+        ret_type: p.ret_type.clone(),
+        name: p.name,
+        params: p.params.clone(),
+        body,
+        visibility: p.visibility,
+        dso_local: p.dso_local,
+        noinline: p.noinline,
+        optnone: p.optnone,
+    }
+}
+
+// The indices of the statements that can run after each statement.
+pub(super) fn successors(body: &[Stm]) -> Vec<Vec<usize>> {
+    let mut labels = HashMap::new();
+    for (i, s) in body.iter().enumerate() {
+        if let Stm::Label { label } = s {
+            labels.insert(*label, i);
+        }
+    }
+
+    body.iter().enumerate().map(|(i, s)| {
+        let mut next: Vec<usize> = targets(s).iter().filter_map(|l| labels.get(l).cloned()).collect();
+        match s {
+            Stm::Jump { .. } | Stm::CJump { .. } | Stm::Invoke { .. } | Stm::Ret { .. } => {},
+            _ if i + 1 < body.len() => next.push(i + 1),
+            _ => {},
+        }
+        next
+    }).collect()
+}
+
+// The temps live after each statement.
+fn liveness(body: &[Stm]) -> Vec<HashSet<Name>> {
+    let succs = successors(body);
+
+    let mut live_out = vec![HashSet::new(); body.len()];
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+
+        for i in (0..body.len()).rev() {
+            let mut out = HashSet::new();
+            for j in &succs[i] {
+                out.extend(live_in(body, &live_out, *j));
+            }
+            if out != live_out[i] {
+                live_out[i] = out;
+                changed = true;
+            }
+        }
+    }
+
+    live_out
+}
+
+// The temps live before statement i.
+fn live_in(body: &[Stm], live_out: &[HashSet<Name>], i: usize) -> HashSet<Name> {
+    if i >= body.len() {
+        return HashSet::new();
+    }
+
+    let s = &body[i];
+    let mut live = live_out[i].clone();
+    if let Some(d) = def_of(s) {
+        live.remove(&d);
+    }
+    for e in operands(s) {
+        if let Exp::Temp { name, .. } = e {
+            live.insert(*name);
+        }
+    }
+    live
+}
+
+fn dst(s: &Stm) -> Option<&Exp> {
+    match s {
+        Stm::Load { dst, .. } => Some(dst),
+        Stm::Move { dst, .. } => Some(dst),
+        Stm::Call { dst, .. } => Some(dst),
+        Stm::Invoke { dst, .. } => Some(dst),
+        Stm::LandingPad { dst, .. } => Some(dst),
+        Stm::Binary { dst, .. } => Some(dst),
+        Stm::Unary { dst, .. } => Some(dst),
+        Stm::Fma { dst, .. } => Some(dst),
+        Stm::Cast { dst, .. } => Some(dst),
+        Stm::Select { dst, .. } => Some(dst),
+        Stm::BuildStruct { dst, .. } => Some(dst),
+        Stm::ExtractStructElement { dst, .. } => Some(dst),
+        Stm::GetStructElementAddr { dst, .. } => Some(dst),
+        Stm::GetArrayElementAddr { dst, .. } => Some(dst),
+        Stm::New { dst, .. } => Some(dst),
+        Stm::NewHybrid { dst, .. } => Some(dst),
+        Stm::Alloca { dst, .. } => Some(dst),
+        _ => None,
+    }
+}
+
+// Every expression in s, including the one it assigns.
+fn exps_mut(s: &mut Stm) -> Vec<&mut Exp> {
+    match s {
+        Stm::Nop => vec![],
+        Stm::CJump { cmp, .. } => vec![cmp],
+        Stm::Jump { .. } => vec![],
+        Stm::Ret { exp } => vec![exp],
+        Stm::Store { dst_addr, src } => vec![dst_addr, src],
+        Stm::Load { dst, src_addr } => vec![dst, src_addr],
+        Stm::Move { dst, src } => vec![dst, src],
+        Stm::Call { dst, fun, args, .. } | Stm::Invoke { dst, fun, args, .. } => {
+            let mut es = vec![dst, fun];
+            es.extend(args.iter_mut());
+            es
+        },
+        Stm::LandingPad { dst, catches, .. } => {
+            let mut es = vec![dst];
+            es.extend(catches.iter_mut());
+            es
+        },
+        Stm::Binary { dst, e1, e2, .. } => vec![dst, e1, e2],
+        Stm::Unary { dst, exp, .. } => vec![dst, exp],
+        Stm::Fma { dst, a, b, c, .. } => vec![dst, a, b, c],
+        Stm::Cast { dst, exp, .. } => vec![dst, exp],
+        Stm::Select { dst, cond, if_true, if_false } => vec![dst, cond, if_true, if_false],
+        Stm::Assume { cond } => vec![cond],
+        Stm::Label { .. } => vec![],
+        Stm::BuildStruct { dst, fields, .. } => {
+            let mut es = vec![dst];
+            es.extend(fields.iter_mut());
+            es
+        },
+        Stm::ExtractStructElement { dst, agg, .. } => vec![dst, agg],
+        Stm::GetStructElementAddr { dst, ptr, .. } => vec![dst, ptr],
+        Stm::GetArrayElementAddr { dst, ptr, index, .. } => vec![dst, ptr, index],
+        Stm::New { dst, .. } => vec![dst],
+        Stm::NewHybrid { dst, length, .. } => vec![dst, length],
+        Stm::Alloca { dst, count, .. } => vec![dst, count],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lir::builder::Builder;
+    use crate::lir::ops::*;
+
+    fn i64_temps(p: &Proc) -> HashSet<Name> {
+        let mut temps = HashSet::new();
+        for s in &p.body {
+            for e in operands(s).into_iter().chain(dst(s)) {
+                if let Exp::Temp { name, ty: Type::I64 } = e {
+                    temps.insert(*name);
+                }
+            }
+        }
+        temps
+    }
+
+    fn lit(value: i64) -> Exp {
+        Exp::Lit { lit: Lit::I64 { value } }
+    }
+
+    #[test]
+    fn test_sequential_temps_share_slot() {
+        // x = 1 + 2; y = x * 3; return wrap(y)
+        let mut f = Builder::new(Name::new("f"), Type::I32);
+        let x = f.binary(Bop::Add_i64, lit(1), lit(2));
+        let y = f.binary(Bop::Mul_i64, x, lit(3));
+        let r = f.unary(Uop::Wrap_i64_i32, y);
+        f.ret(r);
+        let p = f.finish();
+
+        assert_eq!(i64_temps(&p).len(), 2);
+        assert_eq!(i64_temps(&coalesce_proc(&p)).len(), 1);
+    }
+
+    #[test]
+    fn test_overlapping_temps_keep_slots() {
+        // x = 1 + 2; y = 3 + 4; return wrap(x * y)
+        let mut f = Builder::new(Name::new("f"), Type::I32);
+        let x = f.binary(Bop::Add_i64, lit(1), lit(2));
+        let y = f.binary(Bop::Add_i64, lit(3), lit(4));
+        let z = f.binary(Bop::Mul_i64, x, y);
+        let r = f.unary(Uop::Wrap_i64_i32, z);
+        f.ret(r);
+        let p = f.finish();
+
+        // z can reuse x's slot, but y can't.
+        assert_eq!(i64_temps(&coalesce_proc(&p)).len(), 2);
+    }
+}
@@ -0,0 +1,70 @@
+// Undefined temp check for LIR
+// Params are temps bound to the function's arguments, so a proc that reads a param it didn't
+// declare reads a temp nothing assigns. llvm_gen would give it an alloca and load garbage
+// from it, so check each proc before translating it.
+//
+// This only checks that every temp read is a param or assigned somewhere in the proc, not
+// that it's assigned on every path to the read. Void temps are never stored, so they're
+// read like the void literal and need no definition.
+
+use std::collections::HashSet;
+
+use super::trees::*;
+use super::licm::{def_of, operands};
+use crate::common::names::*;
+use crate::common::errors::CodegenError;
+
+pub fn check_temps_defined(p: &Proc) -> Result<(), CodegenError> {
+    let mut defined: HashSet<Name> = p.params.iter().map(|x| x.name).collect();
+    defined.extend(p.body.iter().filter_map(def_of));
+
+    for s in &p.body {
+        for e in operands(s) {
+            match e {
+                Exp::Temp { ty: Type::Void, .. } => {},
+                Exp::Temp { name, .. } if ! defined.contains(name) => {
+                    return Err(CodegenError::UndefinedTemp { name: p.name, temp: *name });
+                },
+                _ => {},
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lir::builder::Builder;
+    use crate::lir::ops::*;
+
+    #[test]
+    fn test_undeclared_param() {
+        // f(x) = x + y, where y was never declared as a param.
+        let mut f = Builder::new(Name::new("f"), Type::I32);
+        let x = f.param(Type::I32);
+        let y = f.temp(Type::I32);
+        let z = f.binary(Bop::Add_i32, x, y.clone());
+        f.ret(z);
+
+        let temp = match y {
+            Exp::Temp { name, .. } => name,
+            e => panic!("expected a temp, got {:?}", e),
+        };
+        assert_eq!(check_temps_defined(&f.finish()), Err(CodegenError::UndefinedTemp { name: Name::new("f"), temp }));
+    }
+
+    #[test]
+    fn test_params_and_assigned_temps() {
+        // f(x) = { y = x; y + x }
+        let mut f = Builder::new(Name::new("f"), Type::I32);
+        let x = f.param(Type::I32);
+        let y = f.temp(Type::I32);
+        f.mov(&y, x.clone());
+        let z = f.binary(Bop::Add_i32, y, x);
+        f.ret(z);
+
+        assert_eq!(check_temps_defined(&f.finish()), Ok(()));
+    }
+}
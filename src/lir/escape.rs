@@ -0,0 +1,126 @@
+// Stack address escape check for LIR
+// An Alloca buffer lives until its proc returns, so returning a pointer into one leaves the
+// caller with a dangling pointer. This is an easy mistake for a frontend to make, and LLVM
+// doesn't catch it, so llvm_gen checks each proc before translating it.
+//
+// A forward dataflow finds the temps that may hold an address derived from an Alloca,
+// through field and element addresses, moves, casts, and selects. It's flow sensitive,
+// since coalescing can give a stack address and an unrelated pointer the same temp.
+// Addresses stored to memory and loaded back aren't tracked.
+
+use std::collections::HashSet;
+
+use super::trees::*;
+use super::coalesce::successors;
+use super::licm::def_of;
+use crate::common::names::*;
+use crate::common::errors::CodegenError;
+
+pub fn check_stack_escapes(p: &Proc) -> Result<(), CodegenError> {
+    let stack_in = stack_temps(p);
+
+    for (i, s) in p.body.iter().enumerate() {
+        if let Stm::Ret { exp: Exp::Temp { name, .. } } = s {
+            if stack_in[i].contains(name) {
+                return Err(CodegenError::ReturnsStackAddress { name: p.name, temp: *name });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// The temps that may hold a stack address before each statement of p.
+pub(super) fn stack_temps(p: &Proc) -> Vec<HashSet<Name>> {
+    let succs = successors(&p.body);
+
+    // The temps that may hold a stack address before each statement.
+    let mut stack_in: Vec<HashSet<Name>> = vec![HashSet::new(); p.body.len()];
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+
+        for (i, s) in p.body.iter().enumerate() {
+            let out = transfer(s, &stack_in[i]);
+            for j in &succs[i] {
+                for x in &out {
+                    if stack_in[*j].insert(*x) {
+                        changed = true;
+                    }
+                }
+            }
+        }
+    }
+
+    stack_in
+}
+
+// The temps that may hold a stack address after s.
+fn transfer(s: &Stm, stack: &HashSet<Name>) -> HashSet<Name> {
+    let derived = |e: &Exp| match e {
+        Exp::Temp { name, .. } => stack.contains(name),
+        _ => false,
+    };
+
+    let from_stack = match s {
+        Stm::Alloca { .. } => true,
+        Stm::GetStructElementAddr { ptr, .. } => derived(ptr),
+        Stm::GetArrayElementAddr { ptr, .. } => derived(ptr),
+        Stm::Move { src, .. } => derived(src),
+        Stm::Cast { exp, .. } => derived(exp),
+        Stm::Select { if_true, if_false, .. } => derived(if_true) || derived(if_false),
+        _ => false,
+    };
+
+    let mut out = stack.clone();
+    if let Some(d) = def_of(s) {
+        out.remove(&d);
+        if from_stack {
+            out.insert(d);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lir::builder::Builder;
+
+    fn pair() -> Type {
+        Type::Struct { fields: vec![Type::I32, Type::I32] }
+    }
+
+    fn one() -> Exp {
+        Exp::Lit { lit: Lit::I32 { value: 1 } }
+    }
+
+    #[test]
+    fn test_return_local_field_address() {
+        // f() { p = alloca {i32, i32}; return &p.1 }
+        let mut f = Builder::new(Name::new("f"), Type::Ptr { ty: Box::new(Type::I32) });
+        let p = f.alloca(pair(), one());
+        let q = f.field_addr(pair(), p, 1);
+        f.ret(q.clone());
+        let p = f.finish();
+
+        let temp = match q {
+            Exp::Temp { name, .. } => name,
+            e => panic!("expected a temp, got {:?}", e),
+        };
+        assert_eq!(check_stack_escapes(&p), Err(CodegenError::ReturnsStackAddress { name: Name::new("f"), temp }));
+    }
+
+    #[test]
+    fn test_return_local_field_value() {
+        // f() { p = alloca {i32, i32}; return p.1 }
+        let mut f = Builder::new(Name::new("f"), Type::I32);
+        let p = f.alloca(pair(), one());
+        let q = f.field_addr(pair(), p, 1);
+        let v = f.load(Type::I32, q);
+        f.ret(v);
+
+        assert_eq!(check_stack_escapes(&f.finish()), Ok(()));
+    }
+}
@@ -0,0 +1,393 @@
+// Loop-invariant allocation hoisting for LIR
+// Temps are allocated once at function entry, but a New inside a loop allocates on every
+// iteration. Closure conversion builds each closure and its environment with New, so a
+// closure in a loop body is allocated over and over even if what it captures never changes.
+//
+// This pass moves such an allocation, with the statements that initialize it, in front of
+// the loop. The object is then shared by all iterations, so it is hoisted only if:
+// - every field is initialized at most once, with a value that is the same on every iteration,
+// - nothing in the root writes to an object of its type after initializing it (see mutable_types),
+// - each temp holding the object or the address of one of its fields is assigned only once.
+// Objects are assumed to be written only through addresses of their own type.
+
+use std::collections::{HashMap, HashSet};
+
+use super::trees::*;
+use crate::common::names::*;
+use crate::mir::typed::Typed;
+
+pub fn hoist_allocations(r: &Root) -> Root {
+    let mutable = mutable_types(r);
+
+    Root {
+        externs: r.externs.clone(),
+        imports: r.imports.clone(),
+        data: r.data.clone(),
+        procs: r.procs.iter().map(|p| hoist_proc(p, &mutable)).collect(),
+    }
+}
+
+fn hoist_proc(p: &Proc, mutable: &HashSet<Type>) -> Proc {
+    let mut body = p.body.clone();
+
+    // Hoisting out of an inner loop can make an allocation invariant in the enclosing loop,
+    // so repeat until nothing moves.
+    while let Some(next) = hoist_one(&body, mutable) {
+        body = next;
+    }
+
+    Proc {
+        ret_type: p.ret_type.clone(),
+        name: p.name,
+        params: p.params.clone(),
+        body,
+        visibility: p.visibility,
+        dso_local: p.dso_local,
+        noinline: p.noinline,
+        optnone: p.optnone,
+    }
+}
+
+// Hoist one allocation (and its initialization) out of one loop.
+fn hoist_one(body: &[Stm], mutable: &HashSet<Type>) -> Option<Vec<Stm>> {
+    let defs = def_counts(body);
+
+    for (header, latch) in loops(body) {
+        if ! has_preheader(body, header, latch) {
+            continue;
+        }
+
+        let in_loop: HashSet<Name> = body[header..=latch].iter().filter_map(def_of).collect();
+
+        for s in &body[header..=latch] {
+            if let Stm::New { dst: Exp::Temp { name, .. }, ty } = s {
+                if let Some(group) = allocation_group(body, *name, ty, header, latch, &defs, &in_loop, mutable) {
+                    let moved: HashSet<usize> = group.iter().cloned().collect();
+                    let mut next = Vec::with_capacity(body.len());
+                    next.extend(body[..header].iter().cloned());
+                    next.extend(group.iter().map(|i| body[*i].clone()));
+                    next.extend(body.iter().enumerate().skip(header).filter(|(i, _)| ! moved.contains(i)).map(|(_, s)| s.clone()));
+                    return Some(next);
+                }
+            }
+        }
+    }
+
+    None
+}
+
+// The indices, in order, of the New of obj and of the statements that copy it, compute its
+// field addresses, or initialize its fields. None if they can't all be hoisted.
+fn allocation_group(body: &[Stm], obj: Name, ty: &Type, header: usize, latch: usize, defs: &HashMap<Name, usize>, in_loop: &HashSet<Name>, mutable: &HashSet<Type>) -> Option<Vec<usize>> {
+    match ty {
+        Type::Struct { .. } => {},
+        _ => return None,
+    }
+
+    if mutable.contains(ty) {
+        return None;
+    }
+
+    let in_range = |i: usize| header <= i && i <= latch;
+    let once = |s: &Stm| def_of(s).map_or(false, |d| defs.get(&d) == Some(&1));
+
+    // Temps holding the object, and temps holding the address of one of its fields.
+    let mut aliases = HashSet::new();
+    let mut fields = HashMap::new();
+    let mut group = HashSet::new();
+
+    aliases.insert(obj);
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+
+        for (i, s) in body.iter().enumerate() {
+            if group.contains(&i) {
+                continue;
+            }
+
+            let joined = match s {
+                Stm::New { dst: Exp::Temp { name, .. }, .. } => *name == obj,
+                Stm::Move { dst: Exp::Temp { name, .. }, src: Exp::Temp { name: src, .. } } if aliases.contains(src) => {
+                    aliases.insert(*name);
+                    true
+                },
+                Stm::Cast { dst: Exp::Temp { name, .. }, ty: cast_ty, exp: Exp::Temp { name: src, .. } } if aliases.contains(src) => {
+                    // The object can be written through the new type too.
+                    if pointee(cast_ty).map_or(false, |t| mutable.contains(t)) {
+                        return None;
+                    }
+                    aliases.insert(*name);
+                    true
+                },
+                Stm::GetStructElementAddr { dst: Exp::Temp { name, .. }, struct_ty, ptr: Exp::Temp { name: ptr, .. }, field } if aliases.contains(ptr) => {
+                    fields.insert(*name, (struct_ty.clone(), *field));
+                    true
+                },
+                _ => false,
+            };
+
+            if joined {
+                if ! in_range(i) || ! once(s) {
+                    return None;
+                }
+                group.insert(i);
+                changed = true;
+            }
+        }
+    }
+
+    let invariant = |e: &Exp| match e {
+        Exp::Temp { name, .. } => ! in_loop.contains(name),
+        _ => true,
+    };
+
+    let mut initialized = HashSet::new();
+
+    for (i, s) in body.iter().enumerate() {
+        match s {
+            Stm::Store { dst_addr: Exp::Temp { name, .. }, src } if fields.contains_key(name) => {
+                let (struct_ty, field) = &fields[name];
+                if ! in_range(i) || struct_ty != ty || ! invariant(src) || ! initialized.insert(*field) {
+                    return None;
+                }
+                group.insert(i);
+            },
+            Stm::Store { dst_addr: Exp::Temp { name, .. }, .. } if aliases.contains(name) => return None,
+            Stm::GetArrayElementAddr { ptr: Exp::Temp { name, .. }, .. } if aliases.contains(name) => return None,
+            Stm::Load { .. } => {},
+            _ => {
+                // A field address that escapes could be used to write the field later.
+                if operands(s).iter().any(|e| match e { Exp::Temp { name, .. } => fields.contains_key(name), _ => false }) {
+                    return None;
+                }
+            },
+        }
+    }
+
+    // Each hoisted statement must only use temps defined before the loop or earlier in the group.
+    let mut order: Vec<usize> = group.into_iter().collect();
+    order.sort();
+
+    let mut defined = HashSet::new();
+    for i in &order {
+        let s = &body[*i];
+        if operands(s).iter().any(|e| match e { Exp::Temp { name, .. } => in_loop.contains(name) && ! defined.contains(name), _ => false }) {
+            return None;
+        }
+        defined.extend(def_of(s));
+    }
+
+    Some(order)
+}
+
+// Struct types whose objects may be written after they are initialized.
+// These are the types with a field stored through an address that isn't derived from a
+// New in the same proc, and the types of whole structs stored through a pointer.
+fn mutable_types(r: &Root) -> HashSet<Type> {
+    let mut types = HashSet::new();
+
+    for p in &r.procs {
+        let fresh = fresh_temps(&p.body);
+
+        // For each field address, the struct type and whether the struct is freshly allocated.
+        let mut field_addrs: HashMap<Name, (Type, bool)> = HashMap::new();
+
+        for s in &p.body {
+            if let Stm::GetStructElementAddr { dst: Exp::Temp { name, .. }, struct_ty, ptr, .. } = s {
+                let from_new = match ptr {
+                    Exp::Temp { name, .. } => fresh.contains(name),
+                    _ => false,
+                };
+                let entry = field_addrs.entry(*name).or_insert((struct_ty.clone(), from_new));
+                entry.1 = entry.1 && from_new;
+                if entry.0 != *struct_ty {
+                    types.insert(struct_ty.clone());
+                }
+            }
+        }
+
+        for s in &p.body {
+            if let Stm::Store { dst_addr, .. } = s {
+                let field_addr = match dst_addr {
+                    Exp::Temp { name, .. } => field_addrs.get(name),
+                    _ => None,
+                };
+
+                match field_addr {
+                    Some((_, true)) => {},
+                    Some((struct_ty, false)) => { types.insert(struct_ty.clone()); },
+                    None => {},
+                }
+
+                if let Some(ty) = pointee(&dst_addr.get_type()) {
+                    if let Type::Struct { .. } = ty {
+                        types.insert(ty.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    types
+}
+
+// Temps that only ever hold an object allocated by a New in body.
+fn fresh_temps(body: &[Stm]) -> HashSet<Name> {
+    let mut fresh: HashSet<Name> = body.iter().filter_map(def_of).collect();
+
+    loop {
+        let mut next = fresh.clone();
+
+        for s in body {
+            if let Some(d) = def_of(s) {
+                let from_new = match s {
+                    Stm::New { .. } => true,
+                    Stm::Move { src: Exp::Temp { name, .. }, .. } => fresh.contains(name),
+                    Stm::Cast { exp: Exp::Temp { name, .. }, .. } => fresh.contains(name),
+                    _ => false,
+                };
+                if ! from_new {
+                    next.remove(&d);
+                }
+            }
+        }
+
+        if next.len() == fresh.len() {
+            return fresh;
+        }
+
+        fresh = next;
+    }
+}
+
+// A loop is a label at index header with a jump back to it from index latch.
+fn loops(body: &[Stm]) -> Vec<(usize, usize)> {
+    let mut labels = HashMap::new();
+    for (i, s) in body.iter().enumerate() {
+        if let Stm::Label { label } = s {
+            labels.insert(*label, i);
+        }
+    }
+
+    let mut loops = Vec::new();
+    for (i, s) in body.iter().enumerate() {
+        for target in targets(s) {
+            match labels.get(&target) {
+                Some(header) if *header < i => loops.push((*header, i)),
+                _ => {},
+            }
+        }
+    }
+
+    loops
+}
+
+// Statements can be inserted before the header only if the loop is entered by falling
+// into the header and every jump into the loop comes from inside it.
+fn has_preheader(body: &[Stm], header: usize, latch: usize) -> bool {
+    if header > 0 {
+        match body[header - 1] {
+            Stm::Jump { .. } | Stm::CJump { .. } | Stm::Invoke { .. } | Stm::Ret { .. } => return false,
+            _ => {},
+        }
+    }
+
+    let inside: HashSet<Name> = body[header..=latch].iter().filter_map(|s| match s {
+        Stm::Label { label } => Some(*label),
+        _ => None,
+    }).collect();
+
+    body.iter().enumerate().all(|(i, s)| {
+        (header <= i && i <= latch) || targets(s).iter().all(|l| ! inside.contains(l))
+    })
+}
+
+pub(super) fn targets(s: &Stm) -> Vec<Name> {
+    match s {
+        Stm::Jump { label, .. } => vec![*label],
+        Stm::CJump { if_true, if_false, .. } => vec![*if_true, *if_false],
+        Stm::Invoke { normal, unwind, .. } => vec![*normal, *unwind],
+        _ => vec![],
+    }
+}
+
+fn pointee(ty: &Type) -> Option<&Type> {
+    match ty {
+        Type::Ptr { ty } => Some(ty),
+        Type::Ref { ty } => Some(ty),
+        Type::IRef { ty } => Some(ty),
+        _ => None,
+    }
+}
+
+fn def_counts(body: &[Stm]) -> HashMap<Name, usize> {
+    let mut defs = HashMap::new();
+    for d in body.iter().filter_map(def_of) {
+        *defs.entry(d).or_insert(0) += 1;
+    }
+    defs
+}
+
+// The temp assigned by s, if any.
+pub(super) fn def_of(s: &Stm) -> Option<Name> {
+    let dst = match s {
+        Stm::Load { dst, .. } => dst,
+        Stm::Move { dst, .. } => dst,
+        Stm::Call { dst, .. } => dst,
+        Stm::Invoke { dst, .. } => dst,
+        Stm::LandingPad { dst, .. } => dst,
+        Stm::Binary { dst, .. } => dst,
+        Stm::Unary { dst, .. } => dst,
+        Stm::Fma { dst, .. } => dst,
+        Stm::Cast { dst, .. } => dst,
+        Stm::Select { dst, .. } => dst,
+        Stm::BuildStruct { dst, .. } => dst,
+        Stm::ExtractStructElement { dst, .. } => dst,
+        Stm::GetStructElementAddr { dst, .. } => dst,
+        Stm::GetArrayElementAddr { dst, .. } => dst,
+        Stm::New { dst, .. } => dst,
+        Stm::NewHybrid { dst, .. } => dst,
+        Stm::Alloca { dst, .. } => dst,
+        _ => return None,
+    };
+
+    match dst {
+        Exp::Temp { name, .. } => Some(*name),
+        _ => None,
+    }
+}
+
+// The expressions s reads (not the temp it assigns).
+pub(super) fn operands(s: &Stm) -> Vec<&Exp> {
+    match s {
+        Stm::Nop => vec![],
+        Stm::CJump { cmp, .. } => vec![cmp],
+        Stm::Jump { .. } => vec![],
+        Stm::Ret { exp } => vec![exp],
+        Stm::Store { dst_addr, src } => vec![dst_addr, src],
+        Stm::Load { src_addr, .. } => vec![src_addr],
+        Stm::Move { src, .. } => vec![src],
+        Stm::Call { fun, args, .. } | Stm::Invoke { fun, args, .. } => {
+            let mut es = vec![fun];
+            es.extend(args.iter());
+            es
+        },
+        Stm::LandingPad { catches, .. } => catches.iter().collect(),
+        Stm::Binary { e1, e2, .. } => vec![e1, e2],
+        Stm::Unary { exp, .. } => vec![exp],
+        Stm::Fma { a, b, c, .. } => vec![a, b, c],
+        Stm::Cast { exp, .. } => vec![exp],
+        Stm::Select { cond, if_true, if_false, .. } => vec![cond, if_true, if_false],
+        Stm::Assume { cond } => vec![cond],
+        Stm::Label { .. } => vec![],
+        Stm::BuildStruct { fields, .. } => fields.iter().collect(),
+        Stm::ExtractStructElement { agg, .. } => vec![agg],
+        Stm::GetStructElementAddr { ptr, .. } => vec![ptr],
+        Stm::GetArrayElementAddr { ptr, index, .. } => vec![ptr, index],
+        Stm::New { .. } => vec![],
+        Stm::NewHybrid { length, .. } => vec![length],
+        Stm::Alloca { count, .. } => vec![count],
+    }
+}
@@ -1,3 +1,10 @@
+pub mod builder;
+pub mod coalesce;
+pub mod defined;
+pub mod escape;
+pub mod licm;
+pub mod tail;
+pub mod text;
 pub mod trees;
 pub mod typed;
 
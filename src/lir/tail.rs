@@ -0,0 +1,119 @@
+// Guaranteed tail call check for LIR
+// A must_tail Call is translated to an LLVM musttail call, which reuses the caller's frame, so
+// a continuation-passing-style loop runs in constant stack. That's only possible if the callee
+// takes the same arguments and returns the same type as the caller, and nothing happens
+// between the call and the return. LLVM's verifier rejects a musttail call that breaks these
+// rules, but only with a message about the LLVM IR, so check them up front.
+// The caller's frame is gone once the callee runs, so an argument can't point into one of
+// the caller's Alloca buffers either. LLVM doesn't check that at all.
+
+use super::trees::*;
+use super::escape::stack_temps;
+use crate::common::names::*;
+use crate::common::errors::CodegenError;
+use crate::mir::typed::Typed;
+
+pub fn check_must_tail_calls(p: &Proc) -> Result<(), CodegenError> {
+    let expected = Type::Fun {
+        ret: Box::new(p.ret_type.clone()),
+        args: p.params.iter().map(|p| p.ty.clone()).collect(),
+    };
+
+    if ! p.body.iter().any(|s| if let Stm::Call { must_tail: true, .. } = s { true } else { false }) {
+        return Ok(());
+    }
+
+    let stack_in = stack_temps(p);
+
+    for (i, s) in p.body.iter().enumerate() {
+        if let Stm::Call { dst, fun, args, must_tail: true } = s {
+            // Function addresses are typed both as functions and as pointers to them.
+            let found = match fun.get_type() {
+                Type::Ptr { ty } => *ty,
+                ty => ty,
+            };
+            if found != expected {
+                return Err(CodegenError::MustTailSignatureMismatch { name: p.name, expected, found });
+            }
+
+            for arg in args {
+                if let Exp::Temp { name, .. } = arg {
+                    if stack_in[i].contains(name) {
+                        return Err(CodegenError::MustTailPassesStackAddress { name: p.name, temp: *name });
+                    }
+                }
+            }
+
+            match (dst, p.body.get(i+1)) {
+                (Exp::Temp { name: x, .. }, Some(Stm::Ret { exp: Exp::Temp { name: y, .. } })) if x == y => {},
+                _ => return Err(CodegenError::MustTailNotFollowedByRet { name: p.name }),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lir::builder::Builder;
+
+    fn fun_ptr(ret: Type, args: Vec<Type>) -> Type {
+        Type::Ptr { ty: Box::new(Type::Fun { ret: Box::new(ret), args }) }
+    }
+
+    #[test]
+    fn test_tail_call_self() {
+        // f(n) { return musttail f(n) }
+        let mut f = Builder::new(Name::new("f"), Type::I32);
+        let n = f.param(Type::I32);
+        f.tail_call(Exp::FunctionAddr { ty: fun_ptr(Type::I32, vec![Type::I32]), name: Name::new("f") }, vec![n]);
+
+        assert_eq!(check_must_tail_calls(&f.finish()), Ok(()));
+    }
+
+    #[test]
+    fn test_tail_call_signature_mismatch() {
+        // f(n: i32): i32 { return musttail g(n) }, where g returns i64
+        let g_ty = fun_ptr(Type::I64, vec![Type::I32]);
+        let mut f = Builder::new(Name::new("f"), Type::I32);
+        let n = f.param(Type::I32);
+        f.tail_call(Exp::FunctionAddr { ty: g_ty, name: Name::new("g") }, vec![n]);
+
+        assert_eq!(check_must_tail_calls(&f.finish()), Err(CodegenError::MustTailSignatureMismatch {
+            name: Name::new("f"),
+            expected: Type::Fun { ret: Box::new(Type::I32), args: vec![Type::I32] },
+            found: Type::Fun { ret: Box::new(Type::I64), args: vec![Type::I32] },
+        }));
+    }
+
+    #[test]
+    fn test_tail_call_passes_stack_address() {
+        // f(p: *i32): i32 { q = alloca i32; return musttail f(q) }
+        let mut f = Builder::new(Name::new("f"), Type::I32);
+        let ptr = Type::Ptr { ty: Box::new(Type::I32) };
+        f.param(ptr.clone());
+        let q = f.alloca(Type::I32, Exp::Lit { lit: Lit::I32 { value: 1 } });
+        f.tail_call(Exp::FunctionAddr { ty: fun_ptr(Type::I32, vec![ptr]), name: Name::new("f") }, vec![q.clone()]);
+
+        let temp = match q {
+            Exp::Temp { name, .. } => name,
+            e => panic!("expected a temp, got {:?}", e),
+        };
+        assert_eq!(check_must_tail_calls(&f.finish()), Err(CodegenError::MustTailPassesStackAddress { name: Name::new("f"), temp }));
+    }
+
+    #[test]
+    fn test_tail_call_not_followed_by_ret() {
+        let text = "\
+proc f(%n: i32): i32 {
+    %r: i32 = musttail call @f: *fun(i32): i32(%n: i32)
+    %s: i32 = Add_i32 %r: i32, i32 1
+    ret %s: i32
+}
+";
+        let p = Proc::parse(text).unwrap();
+        assert_eq!(check_must_tail_calls(&p), Err(CodegenError::MustTailNotFollowedByRet { name: Name::new("f") }));
+    }
+}
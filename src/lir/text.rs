@@ -0,0 +1,771 @@
+// Textual format for LIR
+// A pseudo-assembly for writing LIR procs by hand, e.g., in tests of the backend, and for
+// reading them in dumps. Proc::to_text prints a proc and Proc::parse reads it back.
+//
+//     proc main(%n: i32): i32 {
+//         %i: i32 = move i32 0
+//         jump loop [unroll 2]
+//     loop:
+//         %c: i1 = Lt_s_i32 %i: i32, %n: i32
+//         cjump %c: i1, body, done likely
+//     body:
+//         %i: i32 = Add_i32 %i: i32, i32 1
+//         jump loop
+//     done:
+//         ret %i: i32
+//     }
+//
+// There's one statement per line, and // starts a comment.
+// Every expression carries its type: temps are %name: T, function addresses @name: T,
+// and global addresses &name: T. Literals are written as their type and value (i32 5),
+// or as void, true, false, null T, sizeof T, alignof T, const @f: T, and const &g: T.
+// Types are i1 ... i128, f32, f64, void, *T, ref T, iref T, {T, T} for structs,
+// union {T, T}, hybrid {T, T; V}, and fun(T, T): R.
+// Binary and unary statements are written with the op's name, e.g., Add_i32 or Neg_f64.
+
+use super::trees::*;
+use super::ops::*;
+use crate::common::names::*;
+
+impl Proc {
+    pub fn to_text(&self) -> String {
+        let params: Vec<String> = self.params.iter().map(param_text).collect();
+
+        let mut s = format!("proc {}({}): {}", self.name, params.join(", "), type_text(&self.ret_type));
+        match self.visibility {
+            Visibility::Default => {},
+            Visibility::Hidden => s.push_str(" hidden"),
+            Visibility::Protected => s.push_str(" protected"),
+        }
+        if self.dso_local {
+            s.push_str(" dso_local");
+        }
+        if self.noinline {
+            s.push_str(" noinline");
+        }
+        if self.optnone {
+            s.push_str(" optnone");
+        }
+        s.push_str(" {\n");
+
+        for stm in &self.body {
+            match stm {
+                Stm::Label { label } => s.push_str(&format!("{}:\n", label)),
+                stm => s.push_str(&format!("    {}\n", stm_text(stm))),
+            }
+        }
+
+        s.push_str("}\n");
+        s
+    }
+
+    pub fn parse(text: &str) -> Result<Proc, String> {
+        let mut lines = text.lines()
+            .map(|line| match line.find("//") {
+                Some(i) => &line[..i],
+                None => line,
+            })
+            .map(tokenize)
+            .filter(|tokens| ! tokens.is_empty());
+
+        let mut p = match lines.next() {
+            Some(tokens) => Parser::new(tokens),
+            None => return Err(String::from("expected a proc")),
+        };
+
+        p.expect("proc")?;
+        let name = p.name()?;
+        p.expect("(")?;
+        let params = p.list(")", |p| p.param())?;
+        p.expect(":")?;
+        let ret_type = p.ty()?;
+
+        let mut proc = Proc {
+            ret_type,
+            name,
+            params,
+            body: vec![],
+            visibility: Visibility::Default,
+            dso_local: false,
+            noinline: false,
+            optnone: false,
+        };
+
+        loop {
+            match p.next()?.as_str() {
+                "hidden" => proc.visibility = Visibility::Hidden,
+                "protected" => proc.visibility = Visibility::Protected,
+                "dso_local" => proc.dso_local = true,
+                "noinline" => proc.noinline = true,
+                "optnone" => proc.optnone = true,
+                "{" => break,
+                t => return Err(format!("expected a proc attribute or {{, got {}", t)),
+            }
+        }
+        p.end()?;
+
+        loop {
+            let mut p = match lines.next() {
+                Some(tokens) => Parser::new(tokens),
+                None => return Err(format!("missing }} at end of proc {}", proc.name)),
+            };
+
+            if p.peek() == Some("}") {
+                p.next()?;
+                p.end()?;
+                break;
+            }
+
+            proc.body.push(p.stm()?);
+            p.end()?;
+        }
+
+        if let Some(tokens) = lines.next() {
+            return Err(format!("unexpected {} after proc {}", tokens.join(" "), proc.name));
+        }
+
+        Ok(proc)
+    }
+}
+
+fn param_text(param: &Param) -> String {
+    let mut s = format!("%{}: {}", param.name, type_text(&param.ty));
+    if param.noalias {
+        s.push_str(" noalias");
+    }
+    if param.readonly {
+        s.push_str(" readonly");
+    }
+    if param.nonnull {
+        s.push_str(" nonnull");
+    }
+    s
+}
+
+fn types_text(tys: &Vec<Type>) -> String {
+    tys.iter().map(type_text).collect::<Vec<String>>().join(", ")
+}
+
+fn type_text(ty: &Type) -> String {
+    match ty {
+        Type::I1 => String::from("i1"),
+        Type::I8 => String::from("i8"),
+        Type::I16 => String::from("i16"),
+        Type::I32 => String::from("i32"),
+        Type::I64 => String::from("i64"),
+        Type::I128 => String::from("i128"),
+        Type::F32 => String::from("f32"),
+        Type::F64 => String::from("f64"),
+        Type::Void => String::from("void"),
+        Type::IRef { ty } => format!("iref {}", type_text(ty)),
+        Type::Ref { ty } => format!("ref {}", type_text(ty)),
+        Type::Ptr { ty } => format!("*{}", type_text(ty)),
+        Type::Hybrid { fields, variant } => format!("hybrid {{{}; {}}}", types_text(fields), type_text(variant)),
+        Type::Struct { fields } => format!("{{{}}}", types_text(fields)),
+        Type::Union { variants } => format!("union {{{}}}", types_text(variants)),
+        Type::Fun { ret, args } => format!("fun({}): {}", types_text(args), type_text(ret)),
+    }
+}
+
+fn lit_text(lit: &Lit) -> String {
+    match lit {
+        Lit::Void => String::from("void"),
+        Lit::Null { ty } => format!("null {}", type_text(ty)),
+        Lit::I1 { value } => format!("{}", value),
+        Lit::I8 { value } => format!("i8 {}", value),
+        Lit::I16 { value } => format!("i16 {}", value),
+        Lit::I32 { value } => format!("i32 {}", value),
+        Lit::I64 { value } => format!("i64 {}", value),
+        Lit::I128 { value } => format!("i128 {}", value),
+        // Debug prints the shortest string that parses back to the same float.
+        Lit::F32 { value } => format!("f32 {:?}", value),
+        Lit::F64 { value } => format!("f64 {:?}", value),
+        Lit::Sizeof { ty } => format!("sizeof {}", type_text(ty)),
+        Lit::Alignof { ty } => format!("alignof {}", type_text(ty)),
+        Lit::FunctionAddr { ty, name } => format!("const @{}: {}", name, type_text(ty)),
+        Lit::GlobalAddr { ty, name } => format!("const &{}: {}", name, type_text(ty)),
+    }
+}
+
+fn exp_text(e: &Exp) -> String {
+    match e {
+        Exp::FunctionAddr { ty, name } => format!("@{}: {}", name, type_text(ty)),
+        Exp::GlobalAddr { ty, name } => format!("&{}: {}", name, type_text(ty)),
+        Exp::Temp { ty, name } => format!("%{}: {}", name, type_text(ty)),
+        Exp::Lit { lit } => lit_text(lit),
+    }
+}
+
+fn exps_text(es: &Vec<Exp>) -> String {
+    es.iter().map(exp_text).collect::<Vec<String>>().join(", ")
+}
+
+fn uop_text(op: &Uop) -> String {
+    match op {
+        Uop::Expect_z { expected } => format!("Expect_z {}", expected),
+        op => format!("{:?}", op),
+    }
+}
+
+fn stm_text(s: &Stm) -> String {
+    match s {
+        Stm::Nop => String::from("nop"),
+        Stm::CJump { cmp, if_true, if_false, hint } => {
+            let hint = match hint {
+                None => "",
+                Some(BranchHint::Likely) => " likely",
+                Some(BranchHint::Unlikely) => " unlikely",
+            };
+            format!("cjump {}, {}, {}{}", exp_text(cmp), if_true, if_false, hint)
+        },
+        Stm::Jump { label, loop_hints } => {
+            if loop_hints.is_empty() {
+                format!("jump {}", label)
+            }
+            else {
+                let hints: Vec<String> = loop_hints.iter().map(|hint| match hint {
+                    LoopHint::Unroll(count) => format!("unroll {}", count),
+                    LoopHint::Vectorize(enable) => format!("vectorize {}", enable),
+                }).collect();
+                format!("jump {} [{}]", label, hints.join(", "))
+            }
+        },
+        Stm::Ret { exp } => format!("ret {}", exp_text(exp)),
+        Stm::Store { dst_addr, src } => format!("store {}, {}", exp_text(dst_addr), exp_text(src)),
+        Stm::Load { dst, src_addr } => format!("{} = load {}", exp_text(dst), exp_text(src_addr)),
+        Stm::Move { dst, src } => format!("{} = move {}", exp_text(dst), exp_text(src)),
+        Stm::Call { dst, fun, args, must_tail } => {
+            let musttail = if *must_tail { "musttail " } else { "" };
+            format!("{} = {}call {}({})", exp_text(dst), musttail, exp_text(fun), exps_text(args))
+        },
+        Stm::Invoke { dst, fun, args, normal, unwind } => {
+            format!("{} = invoke {}({}) to {} unwind {}", exp_text(dst), exp_text(fun), exps_text(args), normal, unwind)
+        },
+        Stm::LandingPad { dst, cleanup, catches } => {
+            let cleanup = if *cleanup { " cleanup" } else { "" };
+            format!("{} = landingpad{} [{}]", exp_text(dst), cleanup, exps_text(catches))
+        },
+        Stm::Binary { dst, op, e1, e2 } => format!("{} = {:?} {}, {}", exp_text(dst), op, exp_text(e1), exp_text(e2)),
+        Stm::Unary { dst, op, exp } => format!("{} = {} {}", exp_text(dst), uop_text(op), exp_text(exp)),
+        Stm::Fma { dst, ty, a, b, c } => {
+            format!("{} = fma {} {}, {}, {}", exp_text(dst), type_text(ty), exp_text(a), exp_text(b), exp_text(c))
+        },
+        Stm::Cast { dst, ty, exp } => format!("{} = cast {} {}", exp_text(dst), type_text(ty), exp_text(exp)),
+        Stm::Select { dst, cond, if_true, if_false } => {
+            format!("{} = select {}, {}, {}", exp_text(dst), exp_text(cond), exp_text(if_true), exp_text(if_false))
+        },
+        Stm::Assume { cond } => format!("assume {}", exp_text(cond)),
+        Stm::Label { label } => format!("{}:", label),
+        Stm::BuildStruct { dst, ty, fields } => format!("{} = struct {} ({})", exp_text(dst), type_text(ty), exps_text(fields)),
+        Stm::ExtractStructElement { dst, agg, field } => format!("{} = extract {}, {}", exp_text(dst), exp_text(agg), field),
+        Stm::GetStructElementAddr { dst, struct_ty, ptr, field } => {
+            format!("{} = fieldaddr {} {}, {}", exp_text(dst), type_text(struct_ty), exp_text(ptr), field)
+        },
+        Stm::GetArrayElementAddr { dst, base_ty, ptr, index } => {
+            format!("{} = elementaddr {} {}, {}", exp_text(dst), type_text(base_ty), exp_text(ptr), exp_text(index))
+        },
+        Stm::New { dst, ty } => format!("{} = new {}", exp_text(dst), type_text(ty)),
+        Stm::NewHybrid { dst, ty, length, zero } => {
+            let zero = if *zero { " zero" } else { "" };
+            format!("{} = newhybrid {} {}{}", exp_text(dst), type_text(ty), exp_text(length), zero)
+        },
+        Stm::Alloca { dst, ty, count } => format!("{} = alloca {} {}", exp_text(dst), type_text(ty), exp_text(count)),
+    }
+}
+
+// Split a line into punctuation and words. Anything else but whitespace is part of a word,
+// so names, numbers (including -1.5e-3 and inf), and op names are each one token.
+fn tokenize(line: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+
+    for c in line.chars() {
+        if c.is_whitespace() || "(){}[],:=*;".contains(c) {
+            if ! word.is_empty() {
+                tokens.push(std::mem::replace(&mut word, String::new()));
+            }
+            if ! c.is_whitespace() {
+                tokens.push(c.to_string());
+            }
+        }
+        else {
+            word.push(c);
+        }
+    }
+
+    if ! word.is_empty() {
+        tokens.push(word);
+    }
+
+    tokens
+}
+
+// Parses a single line.
+struct Parser {
+    tokens: Vec<String>,
+    pos: usize,
+}
+
+impl Parser {
+    fn new(tokens: Vec<String>) -> Parser {
+        Parser { tokens, pos: 0 }
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(|t| t.as_str())
+    }
+
+    fn next(&mut self) -> Result<String, String> {
+        match self.tokens.get(self.pos) {
+            Some(t) => {
+                self.pos += 1;
+                Ok(t.clone())
+            },
+            None => Err(format!("unexpected end of line: {}", self.tokens.join(" "))),
+        }
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        let t = self.next()?;
+        if t == expected {
+            Ok(())
+        }
+        else {
+            Err(format!("expected {}, got {} in: {}", expected, t, self.tokens.join(" ")))
+        }
+    }
+
+    // Consume t if it's next.
+    fn accept(&mut self, t: &str) -> bool {
+        if self.peek() == Some(t) {
+            self.pos += 1;
+            true
+        }
+        else {
+            false
+        }
+    }
+
+    fn end(&self) -> Result<(), String> {
+        match self.peek() {
+            None => Ok(()),
+            Some(t) => Err(format!("unexpected {} in: {}", t, self.tokens.join(" "))),
+        }
+    }
+
+    fn number<T: std::str::FromStr>(&mut self) -> Result<T, String> {
+        let t = self.next()?;
+        t.parse::<T>().map_err(|_| format!("bad number {} in: {}", t, self.tokens.join(" ")))
+    }
+
+    fn bool(&mut self) -> Result<bool, String> {
+        match self.next()?.as_str() {
+            "true" => Ok(true),
+            "false" => Ok(false),
+            t => Err(format!("expected true or false, got {}", t)),
+        }
+    }
+
+    fn name(&mut self) -> Result<Name, String> {
+        let t = self.next()?;
+        if t.len() == 1 && "(){}[],:=*;".contains(&t[..]) {
+            return Err(format!("expected a name, got {} in: {}", t, self.tokens.join(" ")));
+        }
+        Ok(Name::new(&t))
+    }
+
+    // A name with the given sigil, e.g., %x.
+    fn sigil_name(&mut self, sigil: char) -> Result<Name, String> {
+        let t = self.next()?;
+        if t.len() > 1 && t.starts_with(sigil) {
+            Ok(Name::new(&t[1..]))
+        }
+        else {
+            Err(format!("expected {}name, got {} in: {}", sigil, t, self.tokens.join(" ")))
+        }
+    }
+
+    // Items separated by commas, up to and including close.
+    fn list<T, F>(&mut self, close: &str, mut item: F) -> Result<Vec<T>, String>
+        where F: FnMut(&mut Parser) -> Result<T, String>
+    {
+        let mut items = Vec::new();
+        if self.accept(close) {
+            return Ok(items);
+        }
+        loop {
+            items.push(item(self)?);
+            if self.accept(close) {
+                return Ok(items);
+            }
+            self.expect(",")?;
+        }
+    }
+
+    fn param(&mut self) -> Result<Param, String> {
+        let name = self.sigil_name('%')?;
+        self.expect(":")?;
+        let ty = self.ty()?;
+
+        let mut param = Param { ty, name, noalias: false, readonly: false, nonnull: false };
+        loop {
+            match self.peek() {
+                Some("noalias") => param.noalias = true,
+                Some("readonly") => param.readonly = true,
+                Some("nonnull") => param.nonnull = true,
+                _ => return Ok(param),
+            }
+            self.pos += 1;
+        }
+    }
+
+    fn ty(&mut self) -> Result<Type, String> {
+        let t = self.next()?;
+        match t.as_str() {
+            "i1" => Ok(Type::I1),
+            "i8" => Ok(Type::I8),
+            "i16" => Ok(Type::I16),
+            "i32" => Ok(Type::I32),
+            "i64" => Ok(Type::I64),
+            "i128" => Ok(Type::I128),
+            "f32" => Ok(Type::F32),
+            "f64" => Ok(Type::F64),
+            "void" => Ok(Type::Void),
+            "iref" => Ok(Type::IRef { ty: Box::new(self.ty()?) }),
+            "ref" => Ok(Type::Ref { ty: Box::new(self.ty()?) }),
+            "*" => Ok(Type::Ptr { ty: Box::new(self.ty()?) }),
+            "{" => Ok(Type::Struct { fields: self.list("}", |p| p.ty())? }),
+            "union" => {
+                self.expect("{")?;
+                Ok(Type::Union { variants: self.list("}", |p| p.ty())? })
+            },
+            "hybrid" => {
+                self.expect("{")?;
+                let fields = self.list(";", |p| p.ty())?;
+                let variant = self.ty()?;
+                self.expect("}")?;
+                Ok(Type::Hybrid { fields, variant: Box::new(variant) })
+            },
+            "fun" => {
+                self.expect("(")?;
+                let args = self.list(")", |p| p.ty())?;
+                self.expect(":")?;
+                Ok(Type::Fun { ret: Box::new(self.ty()?), args })
+            },
+            t => Err(format!("expected a type, got {} in: {}", t, self.tokens.join(" "))),
+        }
+    }
+
+    // : T
+    fn typed(&mut self) -> Result<Type, String> {
+        self.expect(":")?;
+        self.ty()
+    }
+
+    fn exp(&mut self) -> Result<Exp, String> {
+        match self.peek() {
+            Some(t) if t.starts_with('%') => {
+                let name = self.sigil_name('%')?;
+                Ok(Exp::Temp { name, ty: self.typed()? })
+            },
+            Some(t) if t.starts_with('@') => {
+                let name = self.sigil_name('@')?;
+                Ok(Exp::FunctionAddr { name, ty: self.typed()? })
+            },
+            Some(t) if t.starts_with('&') => {
+                let name = self.sigil_name('&')?;
+                Ok(Exp::GlobalAddr { name, ty: self.typed()? })
+            },
+            _ => Ok(Exp::Lit { lit: self.lit()? }),
+        }
+    }
+
+    fn lit(&mut self) -> Result<Lit, String> {
+        let t = self.next()?;
+        match t.as_str() {
+            "void" => Ok(Lit::Void),
+            "null" => Ok(Lit::Null { ty: self.ty()? }),
+            "true" => Ok(Lit::I1 { value: true }),
+            "false" => Ok(Lit::I1 { value: false }),
+            "i8" => Ok(Lit::I8 { value: self.number()? }),
+            "i16" => Ok(Lit::I16 { value: self.number()? }),
+            "i32" => Ok(Lit::I32 { value: self.number()? }),
+            "i64" => Ok(Lit::I64 { value: self.number()? }),
+            "i128" => Ok(Lit::I128 { value: self.number()? }),
+            "f32" => Ok(Lit::F32 { value: self.number()? }),
+            "f64" => Ok(Lit::F64 { value: self.number()? }),
+            "sizeof" => Ok(Lit::Sizeof { ty: self.ty()? }),
+            "alignof" => Ok(Lit::Alignof { ty: self.ty()? }),
+            "const" => {
+                match self.exp()? {
+                    Exp::FunctionAddr { ty, name } => Ok(Lit::FunctionAddr { ty, name }),
+                    Exp::GlobalAddr { ty, name } => Ok(Lit::GlobalAddr { ty, name }),
+                    e => Err(format!("expected a function or global address after const, got {:?}", e)),
+                }
+            },
+            t => Err(format!("expected an expression, got {} in: {}", t, self.tokens.join(" "))),
+        }
+    }
+
+    fn stm(&mut self) -> Result<Stm, String> {
+        if self.tokens.len() == 2 && self.tokens[1] == ":" {
+            let label = self.name()?;
+            self.expect(":")?;
+            return Ok(Stm::Label { label });
+        }
+
+        match self.peek() {
+            Some("nop") => {
+                self.next()?;
+                Ok(Stm::Nop)
+            },
+            Some("jump") => {
+                self.next()?;
+                let label = self.name()?;
+                let mut loop_hints = Vec::new();
+                if self.accept("[") {
+                    loop_hints = self.list("]", |p| {
+                        match p.next()?.as_str() {
+                            "unroll" => Ok(LoopHint::Unroll(p.number()?)),
+                            "vectorize" => Ok(LoopHint::Vectorize(p.bool()?)),
+                            t => Err(format!("expected a loop hint, got {}", t)),
+                        }
+                    })?;
+                }
+                Ok(Stm::Jump { label, loop_hints })
+            },
+            Some("cjump") => {
+                self.next()?;
+                let cmp = self.exp()?;
+                self.expect(",")?;
+                let if_true = self.name()?;
+                self.expect(",")?;
+                let if_false = self.name()?;
+                let hint = if self.accept("likely") {
+                    Some(BranchHint::Likely)
+                }
+                else if self.accept("unlikely") {
+                    Some(BranchHint::Unlikely)
+                }
+                else {
+                    None
+                };
+                Ok(Stm::CJump { cmp, if_true, if_false, hint })
+            },
+            Some("ret") => {
+                self.next()?;
+                Ok(Stm::Ret { exp: self.exp()? })
+            },
+            Some("store") => {
+                self.next()?;
+                let dst_addr = self.exp()?;
+                self.expect(",")?;
+                Ok(Stm::Store { dst_addr, src: self.exp()? })
+            },
+            Some("assume") => {
+                self.next()?;
+                Ok(Stm::Assume { cond: self.exp()? })
+            },
+            _ => {
+                let dst = self.exp()?;
+                self.expect("=")?;
+                self.assignment(dst)
+            },
+        }
+    }
+
+    // The rest of a statement that assigns dst.
+    fn assignment(&mut self, dst: Exp) -> Result<Stm, String> {
+        let t = self.next()?;
+        match t.as_str() {
+            "load" => Ok(Stm::Load { dst, src_addr: self.exp()? }),
+            "move" => Ok(Stm::Move { dst, src: self.exp()? }),
+            "call" => {
+                let fun = self.exp()?;
+                self.expect("(")?;
+                let args = self.list(")", |p| p.exp())?;
+                Ok(Stm::Call { dst, fun, args, must_tail: false })
+            },
+            "musttail" => {
+                self.expect("call")?;
+                let fun = self.exp()?;
+                self.expect("(")?;
+                let args = self.list(")", |p| p.exp())?;
+                Ok(Stm::Call { dst, fun, args, must_tail: true })
+            },
+            "invoke" => {
+                let fun = self.exp()?;
+                self.expect("(")?;
+                let args = self.list(")", |p| p.exp())?;
+                self.expect("to")?;
+                let normal = self.name()?;
+                self.expect("unwind")?;
+                let unwind = self.name()?;
+                Ok(Stm::Invoke { dst, fun, args, normal, unwind })
+            },
+            "landingpad" => {
+                let cleanup = self.accept("cleanup");
+                self.expect("[")?;
+                let catches = self.list("]", |p| p.exp())?;
+                Ok(Stm::LandingPad { dst, cleanup, catches })
+            },
+            "fma" => {
+                let ty = self.ty()?;
+                let a = self.exp()?;
+                self.expect(",")?;
+                let b = self.exp()?;
+                self.expect(",")?;
+                let c = self.exp()?;
+                Ok(Stm::Fma { dst, ty, a, b, c })
+            },
+            "cast" => {
+                let ty = self.ty()?;
+                Ok(Stm::Cast { dst, ty, exp: self.exp()? })
+            },
+            "select" => {
+                let cond = self.exp()?;
+                self.expect(",")?;
+                let if_true = self.exp()?;
+                self.expect(",")?;
+                let if_false = self.exp()?;
+                Ok(Stm::Select { dst, cond, if_true, if_false })
+            },
+            "struct" => {
+                let ty = self.ty()?;
+                self.expect("(")?;
+                let fields = self.list(")", |p| p.exp())?;
+                Ok(Stm::BuildStruct { dst, ty, fields })
+            },
+            "extract" => {
+                let agg = self.exp()?;
+                self.expect(",")?;
+                Ok(Stm::ExtractStructElement { dst, agg, field: self.number()? })
+            },
+            "fieldaddr" => {
+                let struct_ty = self.ty()?;
+                let ptr = self.exp()?;
+                self.expect(",")?;
+                Ok(Stm::GetStructElementAddr { dst, struct_ty, ptr, field: self.number()? })
+            },
+            "elementaddr" => {
+                let base_ty = self.ty()?;
+                let ptr = self.exp()?;
+                self.expect(",")?;
+                Ok(Stm::GetArrayElementAddr { dst, base_ty, ptr, index: self.exp()? })
+            },
+            "new" => Ok(Stm::New { dst, ty: self.ty()? }),
+            "newhybrid" => {
+                let ty = self.ty()?;
+                let length = self.exp()?;
+                let zero = self.accept("zero");
+                Ok(Stm::NewHybrid { dst, ty, length, zero })
+            },
+            "alloca" => {
+                let ty = self.ty()?;
+                Ok(Stm::Alloca { dst, ty, count: self.exp()? })
+            },
+            "Expect_z" => {
+                let expected = self.bool()?;
+                Ok(Stm::Unary { dst, op: Uop::Expect_z { expected }, exp: self.exp()? })
+            },
+            _ => {
+                // The other ops are unit variants, named as in the source.
+                let op_name = serde_json::Value::String(t.clone());
+                if let Ok(op) = serde_json::from_value::<Bop>(op_name.clone()) {
+                    let e1 = self.exp()?;
+                    self.expect(",")?;
+                    Ok(Stm::Binary { dst, op, e1, e2: self.exp()? })
+                }
+                else if let Ok(op) = serde_json::from_value::<Uop>(op_name) {
+                    Ok(Stm::Unary { dst, op, exp: self.exp()? })
+                }
+                else {
+                    Err(format!("unknown op {} in: {}", t, self.tokens.join(" ")))
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUM: &str = "\
+proc main(): i32 {
+    %i: i32 = move i32 0
+    %n: i32 = move i32 0
+    jump loop [unroll 2]
+loop:
+    %c: i1 = Lt_s_i32 %i: i32, i32 5
+    cjump %c: i1, body, done likely
+body:
+    %n: i32 = Add_i32 %n: i32, %i: i32
+    %i: i32 = Add_i32 %i: i32, i32 1
+    jump loop
+done:
+    ret %n: i32
+}
+";
+
+    #[test]
+    fn test_round_trip() {
+        let p = Proc::parse(SUM).unwrap();
+        assert_eq!(p.body.len(), 11);
+        assert_eq!(p.to_text(), SUM);
+    }
+
+    #[test]
+    fn test_round_trip_everything() {
+        let text = "\
+proc f(%p: *{i32, f64} noalias nonnull, %h: ref hybrid {i64; i8} readonly): {*i8, i32} hidden dso_local noinline optnone {
+    %a: *{i32, f64} = alloca {i32, f64} i32 4
+    %q: *f64 = fieldaddr {i32, f64} %p: *{i32, f64}, 1
+    %x: f64 = load %q: *f64
+    %y: f64 = fma f64 %x: f64, f64 -1.5, f64 0.001
+    %z: f64 = Expect_z true %y: f64
+    %w: f64 = Neg_f64 %z: f64
+    store %q: *f64, %w: f64
+    %e: iref i8 = elementaddr hybrid {i64; i8} %h: ref hybrid {i64; i8}, i64 3
+    %s: {i32, f64} = struct {i32, f64} (i32 7, %w: f64)
+    %t: i32 = extract %s: {i32, f64}, 0
+    %u: union {i32, f32} = cast union {i32, f32} %t: i32
+    %b: i1 = Eq_i32 %t: i32, i32 7
+    assume %b: i1
+    %v: i32 = select %b: i1, sizeof {i32, f64}, alignof f64
+    %o: ref hybrid {i64; i8} = newhybrid hybrid {i64; i8} i64 10 zero
+    %r: ref {i32} = new {i32}
+    %g: *fun(i32): void = move const @g: *fun(i32): void
+    %k: void = invoke %g: *fun(i32): void(%v: i32) to ok unwind catch
+ok:
+    %m: i8 = load &table: *i8
+    cjump false, done, catch unlikely
+catch:
+    %exn: {*i8, i32} = landingpad cleanup [null *i8]
+    ret %exn: {*i8, i32}
+done:
+    nop
+    %call: void = call @g: *fun(i32): void(i32 -3)
+    %none: {*i8, i32} = struct {*i8, i32} (null *i8, i32 0)
+    ret %none: {*i8, i32}
+again:
+    %again: {*i8, i32} = musttail call @f: *fun(*{i32, f64}, ref hybrid {i64; i8}): {*i8, i32}(%p: *{i32, f64}, %h: ref hybrid {i64; i8})
+    ret %again: {*i8, i32}
+}
+";
+        let p = Proc::parse(text).unwrap();
+        assert_eq!(p.to_text(), text);
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let text = "\
+proc f(): i32 {
+    %x: i32 = Frob_i32 i32 1, i32 2
+    ret %x: i32
+}
+";
+        assert!(Proc::parse(text).is_err());
+        assert!(Proc::parse("proc f(): i32 {").is_err());
+    }
+}
@@ -10,58 +10,150 @@ use crate::common::names::*;
 
 // These are the same as mir trees, so just use them.
 pub use crate::mir::trees::Lit;
-pub use crate::mir::trees::Param;
-pub use crate::mir::trees::Data;
 pub use crate::mir::trees::Type;
 
+// Like mir::Data, but the initializer can refer to other globals.
+#[derive(Clone, Debug)]
+pub struct Data {
+    pub ty: Type,
+    pub name: Name,
+    pub init: Init,
+    pub thread_local: bool,
+    pub visibility: Visibility,
+    // As for Proc.
+    pub dso_local: bool,
+    // The section to put the global in, e.g., for a boot section, rather than the default for its kind.
+    pub section: Option<String>,
+    // Alignment in bytes, if stricter than the type's. Must be a power of two.
+    pub alignment: Option<u32>,
+    // Lay out a struct global without padding between its fields, with alignment 1 unless
+    // alignment is given. Field addresses are computed from the packed layout, but the
+    // whole struct can't be loaded or stored as a value of ty.
+    pub packed: bool,
+}
+
+// Symbol visibility, as in LLVM.
+// Hidden symbols aren't exported from the shared library that defines them.
+// Protected symbols are exported, but references in the defining library can't be preempted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Visibility {
+    Default,
+    Hidden,
+    Protected,
+}
+
+#[derive(Clone, Debug)]
+pub enum Init {
+    Lit { lit: Lit },
+    // As for mir::Init::Struct.
+    Struct { fields: Vec<Init> },
+    // Address of an element of the global name, computed at compile time.
+    // The indices are as for an LLVM getelementptr, so the first usually is 0.
+    ElementAddr { name: Name, indices: Vec<usize> },
+}
+
+// Like mir::Param, but with LLVM parameter attributes.
+// The attributes only make sense for pointer params.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Param {
+    pub ty: Type,
+    pub name: Name,
+    pub noalias: bool,
+    pub readonly: bool,
+    pub nonnull: bool,
+}
+
 #[derive(Clone, Debug)]
 pub struct Root {
+    // Functions defined in other modules.
     pub externs: Vec<Param>,
+    // Globals defined in other modules.
+    pub imports: Vec<Param>,
     pub data: Vec<Data>,
     pub procs: Vec<Proc>
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Hash)]
 pub struct Proc {
     pub ret_type: Type,
     pub name: Name,
     pub params: Vec<Param>,
-    pub body: Vec<Stm>
+    pub body: Vec<Stm>,
+    pub visibility: Visibility,
+    // Set if the symbol can't be preempted by a definition in another module.
+    // Hidden and Protected symbols are always dso_local. LLVM's C API can't mark a Default
+    // visibility symbol dso_local, so llvm_gen rejects that combination.
+    pub dso_local: bool,
+    // Keep the optimizer away from the proc, e.g., to isolate a miscompile.
+    // optnone implies noinline, since LLVM requires both.
+    pub noinline: bool,
+    pub optnone: bool,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Hash)]
 pub enum Stm {
     Nop,
 
-    CJump { cmp: Exp, if_true: Name, if_false: Name },
-    Jump { label: Name },
+    CJump { cmp: Exp, if_true: Name, if_false: Name, hint: Option<BranchHint> },
+    Jump { label: Name, loop_hints: Vec<LoopHint> },
     Ret { exp: Exp },
 
     Store { dst_addr: Exp, src: Exp },
     Load { dst: Exp, src_addr: Exp },
     Move { dst: Exp, src: Exp },
 
-    Call { dst: Exp, fun: Exp, args: Vec<Exp> },
+    // If fun returns void, dst should be a Void temp, which is never stored to.
+    // A must_tail call is a guaranteed tail call (LLVM's musttail), e.g., for continuation-passing
+    // style: fun must have the same signature as the proc, no argument may point into the proc's
+    // Alloca buffers, and the call must be immediately followed by a Ret of dst. See lir::tail.
+    Call { dst: Exp, fun: Exp, args: Vec<Exp>, must_tail: bool },
+    // A call that continues at normal if fun returns and at unwind if it throws.
+    // The unwind label should be followed by a LandingPad.
+    Invoke { dst: Exp, fun: Exp, args: Vec<Exp>, normal: Name, unwind: Name },
+    // Must be the first statement after an unwind label. dst gets the exception, an { i8*, i32 }.
+    // The landing pad catches exceptions matching catches (type info globals), and is
+    // entered for any exception if cleanup is set.
+    LandingPad { dst: Exp, cleanup: bool, catches: Vec<Exp> },
 
     Binary { dst: Exp, op: Bop, e1: Exp, e2: Exp },
     Unary { dst: Exp, op: Uop, exp: Exp },
+    Fma { dst: Exp, ty: Type, a: Exp, b: Exp, c: Exp },
 
     // Bitcast
     Cast { dst: Exp, ty: Type, exp: Exp },
 
+    Select { dst: Exp, cond: Exp, if_true: Exp, if_false: Exp },
+
+    // Tells the optimizer that cond is true. cond should have type I1.
+    Assume { cond: Exp },
+
     Label { label: Name },
 
+    // Build a struct value from its fields without allocating on the heap.
+    // Small structs are built in registers (see llvm_gen's max_register_aggregate_bytes).
+    // ty should be a Struct type with the types of the fields.
+    BuildStruct { dst: Exp, ty: Type, fields: Vec<Exp> },
+    // Read a field of a struct value, such as one made by BuildStruct.
+    ExtractStructElement { dst: Exp, agg: Exp, field: usize },
+
     // Address of a struct field entry.
     GetStructElementAddr { dst: Exp, struct_ty: Type, ptr: Exp, field: usize },
 
-    // Address of an array entry.
+    // Address of an array entry. ptr is usually a Hybrid, but a Ptr to base_ty,
+    // e.g., a buffer from Alloca, is indexed directly.
     GetArrayElementAddr { dst: Exp, base_ty: Type, ptr: Exp, index: Exp },
 
     New { dst: Exp, ty: Type },
-    NewHybrid { dst: Exp, ty: Type, length: Exp },
+    NewHybrid { dst: Exp, ty: Type, length: Exp, zero: bool },
+
+    // Reserve a stack buffer of count values of type ty, separate from the temps.
+    // dst gets a Ptr to the first value. The buffer lives until the proc returns.
+    // count may be computed at runtime, but then each execution reserves a new buffer,
+    // so avoid dynamic counts in loops.
+    Alloca { dst: Exp, ty: Type, count: Exp },
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Hash)]
 pub enum Exp {
     FunctionAddr { ty: Type, name: Name },
     GlobalAddr { ty: Type, name: Name },
@@ -30,6 +30,10 @@ pub fn init() {
             llvm::target::LLVMInitializeX86AsmPrinter();
             llvm::target::LLVMInitializeX86AsmParser();
 
+            // The host's target, so code can be emitted for it when it isn't x86.
+            llvm::target::LLVM_InitializeNativeTarget();
+            llvm::target::LLVM_InitializeNativeAsmPrinter();
+
             llvm::execution_engine::LLVMLinkInMCJIT();
         });
 
@@ -75,6 +79,89 @@ mod test {
         // module.dump();
     }
 
+    #[test]
+    pub fn function_metadata() {
+        // Module::new uses the global context, so build the metadata there too.
+        let context = Context::global();
+        let module = Module::new("md");
+
+        let function_type = Type::function(Type::i32(), &[], false);
+        let function = module.add_function("f", function_type);
+
+        let node = context.md_node(&[context.md_string("int"), context.md_int(32)]);
+        function.set_metadata("rivo.type", node);
+
+        let ir = module.print_to_string();
+        assert!(ir.contains("!rivo.type"), "missing metadata kind in {}", ir);
+        assert!(ir.contains("!{!\"int\", i64 32}"), "missing metadata node in {}", ir);
+    }
+
+    #[test]
+    pub fn bitcode_round_trip() {
+        let module = Module::new("bc");
+
+        let function_type = Type::function(Type::i32(), &[], false);
+        module.add_function("f", function_type);
+        module.add_function("g", function_type);
+
+        let bytes = module.write_bitcode_to_memory();
+        assert!(! bytes.is_empty());
+
+        let parsed = Module::parse_bitcode(Context::global(), &bytes).unwrap();
+        assert_eq!(parsed.function_count(), module.function_count());
+        assert_eq!(parsed.function_count(), 2);
+        parsed.dispose();
+
+        assert!(Module::parse_bitcode(Context::global(), b"not bitcode").is_none());
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    pub fn emit_assembly() {
+        let context = Context::global();
+        let module = Module::new("asm");
+        let builder = context.new_builder();
+
+        // f(x) = g + x. g is in memory, so x86 can't use lea instead of add.
+        let g = module.add_global("g", Type::i32());
+        g.set_initializer(Value::i32(7));
+        let f = module.add_function("f", Type::function(Type::i32(), &[Type::i32()], false));
+        builder.position_at_end(context.append_bb(f, "entry"));
+        let v = builder.load(g, "v");
+        let sum = builder.add(v, f.get_param(0), "sum");
+        builder.ret(sum);
+
+        let asm = module.emit_assembly(None).unwrap();
+        assert!(asm.lines().any(|s| s.trim_start().starts_with("add")), "expected an add instruction in {}", asm);
+
+        assert!(module.emit_assembly(Some("not-a-triple")).is_err());
+    }
+
+    #[test]
+    pub fn global_ctors() {
+        let context = Context::global();
+        let module = Module::new("ctors");
+        let builder = context.new_builder();
+
+        let void_fun = Type::function(Type::void(), &[], false);
+        let mut funs = Vec::new();
+        for name in &["init_a", "init_b", "fini"] {
+            let f = module.add_function(name, void_fun);
+            builder.position_at_end(context.append_bb(f, "entry"));
+            builder.ret_void();
+            funs.push(f);
+        }
+
+        module.add_global_ctor(funs[0], 65535);
+        module.add_global_ctor(funs[1], 100);
+        module.add_global_dtor(funs[2], 65535);
+
+        let ir = module.print_to_string();
+        assert!(ir.contains("@llvm.global_ctors = appending global [2 x { i32, void ()*, i8* }] [{ i32, void ()*, i8* } { i32 65535, void ()* @init_a, i8* null }, { i32, void ()*, i8* } { i32 100, void ()* @init_b, i8* null }]"), "{}", ir);
+        assert!(ir.contains("@llvm.global_dtors = appending global [1 x { i32, void ()*, i8* }] [{ i32, void ()*, i8* } { i32 65535, void ()* @fini, i8* null }]"), "{}", ir);
+        assert!(module.verify().is_ok());
+    }
+
     // #[test]
     // pub fn test_bc() {
     //     let context = Context::new();
@@ -7,21 +7,83 @@ use llvm_sys::prelude::*;
 use llvm_sys as llvm;
 
 use std::ffi::CString;
+use std::mem;
+use std::ops::Deref;
 use std::ptr;
 use ::libc::{c_uint, c_ulonglong, c_double};
 use std::sync::Mutex;
+use std::collections::HashSet;
 
 lazy_static! {
     pub static ref LLVM_LOCK: Mutex<()> = Mutex::new(());
+    static ref CONTEXTS: Mutex<ContextRegistry> = Mutex::new(ContextRegistry::new());
+}
+
+// The wrappers are Copy, so the borrow checker can't stop a handle from being used after
+// its Context is disposed. Instead, each context gets a generation, and every handle made
+// from the context carries it. The registry tracks which generations are live, and raw(),
+// which every call into LLVM goes through, panics on a handle from a disposed context
+// rather than reading freed memory.
+// Generations aren't reused, unlike the addresses of disposed contexts.
+struct ContextRegistry {
+    next_generation: u64,
+    // Generations of contexts from Context::new that haven't been disposed.
+    live: HashSet<u64>,
+}
+
+impl ContextRegistry {
+    fn new() -> ContextRegistry {
+        ContextRegistry {
+            // 0 is Generation::GLOBAL.
+            next_generation: 1,
+            live: HashSet::new(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Generation(u64);
+
+impl Generation {
+    // The global context, which is never disposed, and handles made from it.
+    const GLOBAL: Generation = Generation(0);
+
+    pub fn is_live(self) -> bool {
+        self == Generation::GLOBAL || CONTEXTS.lock().unwrap().live.contains(&self.0)
+    }
 }
 
 // Don't implement Clone or Copy for any wrapper that also implements Drop.
-#[derive(Copy, Clone, Debug, PartialEq)] pub struct Context(pub LLVMContextRef);
-#[derive(Copy, Clone, Debug, PartialEq)] pub struct Builder(pub LLVMBuilderRef);
-#[derive(Copy, Clone, Debug, PartialEq)] pub struct Module(pub LLVMModuleRef);
-#[derive(Copy, Clone, Debug, PartialEq)] pub struct Type(pub LLVMTypeRef);
-#[derive(Copy, Clone, Debug, PartialEq)] pub struct Value(pub LLVMValueRef);
-#[derive(Copy, Clone, Debug, PartialEq)] pub struct BB(pub LLVMBasicBlockRef);
+#[derive(Copy, Clone, Debug, PartialEq)] pub struct Context(LLVMContextRef, Generation);
+// Builder is disposed when dropped. Contexts and modules are disposed explicitly.
+#[derive(Debug, PartialEq)] pub struct Builder(LLVMBuilderRef, Generation);
+#[derive(Copy, Clone, Debug, PartialEq)] pub struct Module(LLVMModuleRef, Generation);
+#[derive(Copy, Clone, Debug, PartialEq)] pub struct Type(LLVMTypeRef, Generation);
+#[derive(Copy, Clone, Debug, PartialEq)] pub struct Value(LLVMValueRef, Generation);
+#[derive(Copy, Clone, Debug, PartialEq)] pub struct BB(LLVMBasicBlockRef, Generation);
+#[derive(Copy, Clone, Debug, PartialEq)] pub struct MetadataNode(LLVMValueRef, Generation);
+
+// The LLVM pointer of a handle, checking that its context hasn't been disposed.
+macro_rules! raw_handle {
+    ($handle: ident, $raw: ty, $message: expr) => {
+        impl $handle {
+            pub fn raw(&self) -> $raw {
+                if ! self.1.is_live() {
+                    panic!($message);
+                }
+                self.0
+            }
+        }
+    }
+}
+
+raw_handle!(Context, LLVMContextRef, "context used after it was disposed");
+raw_handle!(Builder, LLVMBuilderRef, "builder used after its context was disposed");
+raw_handle!(Module, LLVMModuleRef, "module used after its context was disposed");
+raw_handle!(Type, LLVMTypeRef, "type used after its context was disposed");
+raw_handle!(Value, LLVMValueRef, "value used after its context was disposed");
+raw_handle!(BB, LLVMBasicBlockRef, "basic block used after its context was disposed");
+raw_handle!(MetadataNode, LLVMValueRef, "metadata used after its context was disposed");
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum IntPredicate {
@@ -47,6 +109,52 @@ impl IntPredicate {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TlsModel {
+    GeneralDynamic, LocalDynamic, InitialExec, LocalExec,
+}
+
+impl TlsModel {
+    fn to_internal(&self) -> llvm::LLVMThreadLocalMode {
+        match self {
+            TlsModel::GeneralDynamic => llvm::LLVMThreadLocalMode::LLVMGeneralDynamicTLSModel,
+            TlsModel::LocalDynamic => llvm::LLVMThreadLocalMode::LLVMLocalDynamicTLSModel,
+            TlsModel::InitialExec => llvm::LLVMThreadLocalMode::LLVMInitialExecTLSModel,
+            TlsModel::LocalExec => llvm::LLVMThreadLocalMode::LLVMLocalExecTLSModel,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Visibility {
+    Default, Hidden, Protected,
+}
+
+impl Visibility {
+    fn to_internal(&self) -> llvm::LLVMVisibility {
+        match self {
+            Visibility::Default => llvm::LLVMVisibility::LLVMDefaultVisibility,
+            Visibility::Hidden => llvm::LLVMVisibility::LLVMHiddenVisibility,
+            Visibility::Protected => llvm::LLVMVisibility::LLVMProtectedVisibility,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Linkage {
+    External, Internal, Appending,
+}
+
+impl Linkage {
+    fn to_internal(&self) -> llvm::LLVMLinkage {
+        match self {
+            Linkage::External => llvm::LLVMLinkage::LLVMExternalLinkage,
+            Linkage::Internal => llvm::LLVMLinkage::LLVMInternalLinkage,
+            Linkage::Appending => llvm::LLVMLinkage::LLVMAppendingLinkage,
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum RealPredicate {
     False, True,
@@ -85,143 +193,339 @@ macro_rules! c_bool {
 }
 
 impl Value {
+    // Constant getelementptr, usable in global initializers.
+    pub fn const_gep(global: Value, indices: &[Value]) -> Value {
+        let mut is: Vec<LLVMValueRef> = indices.iter().map(|v| v.raw()).collect();
+        Value(unsafe_llvm!( llvm::core::LLVMConstInBoundsGEP(global.raw(), is.as_mut_ptr(), indices.len() as c_uint) ), global.1)
+    }
+
+    pub fn set_thread_local(&self, model: TlsModel) {
+        unsafe_llvm!({
+            llvm::core::LLVMSetThreadLocal(self.raw(), c_bool!(true));
+            llvm::core::LLVMSetThreadLocalMode(self.raw(), model.to_internal());
+        })
+    }
+
+    pub fn set_visibility(&self, visibility: Visibility) {
+        unsafe_llvm!( llvm::core::LLVMSetVisibility(self.raw(), visibility.to_internal()) );
+    }
+
+    pub fn set_section(&self, section: &str) {
+        let cstr = CString::new(section).unwrap();
+        unsafe_llvm!( llvm::core::LLVMSetSection(self.raw(), cstr.as_ptr()) );
+    }
+
+    pub fn set_alignment(&self, bytes: u32) {
+        unsafe_llvm!( llvm::core::LLVMSetAlignment(self.raw(), bytes as c_uint) );
+    }
+
+    pub fn set_linkage(&self, linkage: Linkage) {
+        unsafe_llvm!( llvm::core::LLVMSetLinkage(self.raw(), linkage.to_internal()) );
+    }
+
+    // Constant aggregates, usable in global initializers.
+    pub fn const_struct(fields: &[Value], is_packed: bool) -> Value {
+        crate::llvm::init();
+        let mut vs: Vec<LLVMValueRef> = fields.iter().map(|v| v.raw()).collect();
+        Value(unsafe_llvm!( llvm::core::LLVMConstStruct(vs.as_mut_ptr(), fields.len() as c_uint, c_bool!(is_packed)) ), Generation::GLOBAL)
+    }
+
+    // Like const_struct, but of the struct type ty rather than a new type made from the fields.
+    pub fn const_named_struct(ty: Type, fields: &[Value]) -> Value {
+        let mut vs: Vec<LLVMValueRef> = fields.iter().map(|v| v.raw()).collect();
+        Value(unsafe_llvm!( llvm::core::LLVMConstNamedStruct(ty.raw(), vs.as_mut_ptr(), fields.len() as c_uint) ), ty.1)
+    }
+
+    pub fn const_array(element_type: Type, elements: &[Value]) -> Value {
+        crate::llvm::init();
+        let mut vs: Vec<LLVMValueRef> = elements.iter().map(|v| v.raw()).collect();
+        Value(unsafe_llvm!( llvm::core::LLVMConstArray(element_type.raw(), vs.as_mut_ptr(), elements.len() as c_uint) ), element_type.1)
+    }
+
+    pub fn set_personality(&self, personality: Value) {
+        unsafe_llvm!( llvm::core::LLVMSetPersonalityFn(self.raw(), personality.raw()) );
+    }
+
+    // Clauses and cleanup are only for landingpad instructions.
+    pub fn add_clause(&self, clause: Value) {
+        unsafe_llvm!( llvm::core::LLVMAddClause(self.raw(), clause.raw()) );
+    }
+
+    pub fn set_cleanup(&self, cleanup: bool) {
+        unsafe_llvm!( llvm::core::LLVMSetCleanup(self.raw(), c_bool!(cleanup)) );
+    }
+
     pub fn set_initializer(&self, v: Value) {
-        unsafe_llvm!( llvm::core::LLVMSetInitializer(self.0, v.0) );
+        unsafe_llvm!( llvm::core::LLVMSetInitializer(self.raw(), v.raw()) );
+    }
+
+    pub fn undef(ty: Type) -> Value {
+        crate::llvm::init();
+        Value(
+            unsafe_llvm!( llvm::core::LLVMGetUndef(ty.raw()) ),
+            ty.1
+        )
     }
 
     pub fn null(ty: Type) -> Value {
         crate::llvm::init();
         Value(
-            unsafe_llvm!( llvm::core::LLVMConstNull(ty.0) )
+            unsafe_llvm!( llvm::core::LLVMConstNull(ty.raw()) ),
+            ty.1
         )
     }
     pub fn i1(v: bool) -> Value {
         crate::llvm::init();
         let ty = Type::i1(); // get the type outside the critical section to avoid deadlock
         Value(
-            unsafe_llvm!( llvm::core::LLVMConstInt(ty.0, c_bool!(v), c_bool!(true)) )
+            unsafe_llvm!( llvm::core::LLVMConstInt(ty.raw(), c_bool!(v), c_bool!(true)) ),
+            ty.1
         )
     }
     pub fn i8(v: i8) -> Value {
         crate::llvm::init();
         let ty = Type::i8(); // get the type outside the critical section to avoid deadlock
         Value(
-            unsafe_llvm!( llvm::core::LLVMConstInt(ty.0, v as c_ulonglong, c_bool!(true)) )
+            unsafe_llvm!( llvm::core::LLVMConstInt(ty.raw(), v as c_ulonglong, c_bool!(true)) ),
+            ty.1
         )
     }
     pub fn i16(v: i16) -> Value {
         crate::llvm::init();
         let ty = Type::i16(); // get the type outside the critical section to avoid deadlock
         Value(
-            unsafe_llvm!( llvm::core::LLVMConstInt(ty.0, v as c_ulonglong, c_bool!(true)) )
+            unsafe_llvm!( llvm::core::LLVMConstInt(ty.raw(), v as c_ulonglong, c_bool!(true)) ),
+            ty.1
         )
     }
     pub fn i32(v: i32) -> Value {
         crate::llvm::init();
         let ty = Type::i32(); // get the type outside the critical section to avoid deadlock
         Value(
-            unsafe_llvm!( llvm::core::LLVMConstInt(ty.0, v as c_ulonglong, c_bool!(true)) )
+            unsafe_llvm!( llvm::core::LLVMConstInt(ty.raw(), v as c_ulonglong, c_bool!(true)) ),
+            ty.1
         )
     }
     pub fn i64(v: i64) -> Value {
         crate::llvm::init();
         let ty = Type::i64(); // get the type outside the critical section to avoid deadlock
         Value(
-            unsafe_llvm!( llvm::core::LLVMConstInt(ty.0, v as c_ulonglong, c_bool!(true)) )
+            unsafe_llvm!( llvm::core::LLVMConstInt(ty.raw(), v as c_ulonglong, c_bool!(true)) ),
+            ty.1
+        )
+    }
+    // LLVMConstInt only takes 64 bits, so pass the value as two words, low word first.
+    pub fn i128(v: i128) -> Value {
+        crate::llvm::init();
+        let ty = Type::i128();
+        let words = [v as u64, (v >> 64) as u64];
+        Value(
+            unsafe_llvm!( llvm::core::LLVMConstIntOfArbitraryPrecision(ty.raw(), 2, words.as_ptr()) ),
+            ty.1
         )
     }
     pub fn float(v: f32) -> Value {
         crate::llvm::init();
         let ty = Type::float(); // get the type outside the critical section to avoid deadlock
         Value(
-            unsafe_llvm!( llvm::core::LLVMConstReal(ty.0, v as c_double) )
+            unsafe_llvm!( llvm::core::LLVMConstReal(ty.raw(), v as c_double) ),
+            ty.1
         )
     }
     pub fn double(v: f64) -> Value {
         crate::llvm::init();
         let ty = Type::double(); // get the type outside the critical section to avoid deadlock
         Value(
-            unsafe_llvm!( llvm::core::LLVMConstReal(ty.0, v as c_double) )
+            unsafe_llvm!( llvm::core::LLVMConstReal(ty.raw(), v as c_double) ),
+            ty.1
         )
     }
 
+    // Attach a metadata node to an instruction or a global (including functions)
+    // under the given kind, e.g. "rivo.type". Custom kinds are registered on first use.
+    pub fn set_metadata(&self, kind: &str, node: MetadataNode) {
+        unsafe_llvm!({
+            let context = llvm::core::LLVMGetTypeContext(llvm::core::LLVMTypeOf(self.raw()));
+            let kind_id = llvm::core::LLVMGetMDKindIDInContext(context, kind.as_ptr() as *const _, kind.len() as c_uint);
+
+            if ! llvm::core::LLVMIsAGlobalObject(self.raw()).is_null() {
+                llvm::core::LLVMGlobalSetMetadata(self.raw(), kind_id, llvm::core::LLVMValueAsMetadata(node.raw()));
+            }
+            else {
+                llvm::core::LLVMSetMetadata(self.raw(), kind_id, node.raw());
+            }
+        })
+    }
+
+    // Add an enum attribute (e.g., "noalias") to the i'th parameter of a function.
+    pub fn add_param_attribute(&self, context: &Context, i: usize, name: &str) {
+        unsafe_llvm!({
+            let kind = llvm::core::LLVMGetEnumAttributeKindForName(name.as_ptr() as *const _, name.len());
+            assert!(kind != 0, "no attribute named {}", name);
+            let attr = llvm::core::LLVMCreateEnumAttribute(context.raw(), kind, 0);
+            // Index 0 is the return value, so params start at 1.
+            llvm::core::LLVMAddAttributeAtIndex(self.raw(), (i + 1) as c_uint, attr);
+        })
+    }
+
+    // Add an enum attribute (e.g., "noinline") to the function itself.
+    pub fn add_function_attribute(&self, context: &Context, name: &str) {
+        unsafe_llvm!({
+            let kind = llvm::core::LLVMGetEnumAttributeKindForName(name.as_ptr() as *const _, name.len());
+            assert!(kind != 0, "no attribute named {}", name);
+            let attr = llvm::core::LLVMCreateEnumAttribute(context.raw(), kind, 0);
+            llvm::core::LLVMAddAttributeAtIndex(self.raw(), llvm::LLVMAttributeFunctionIndex, attr);
+        })
+    }
+
+    // Like add_param_attribute, but on a call instruction rather than a function.
+    pub fn add_call_param_attribute(&self, context: &Context, i: usize, name: &str) {
+        unsafe_llvm!({
+            let kind = llvm::core::LLVMGetEnumAttributeKindForName(name.as_ptr() as *const _, name.len());
+            assert!(kind != 0, "no attribute named {}", name);
+            let attr = llvm::core::LLVMCreateEnumAttribute(context.raw(), kind, 0);
+            llvm::core::LLVMAddCallSiteAttribute(self.raw(), (i + 1) as c_uint, attr);
+        })
+    }
+
     pub fn get_param(&self, i: usize) -> Value {
         Value(
-            unsafe_llvm!( llvm::core::LLVMGetParam(self.0, i as c_uint) )
+            unsafe_llvm!( llvm::core::LLVMGetParam(self.raw(), i as c_uint) ),
+            self.1
         )
     }
 
+    // The basic blocks of a function, entry first.
+    pub fn basic_blocks(&self) -> Vec<BB> {
+        let mut bbs = vec![];
+        let mut bb = unsafe_llvm!( llvm::core::LLVMGetFirstBasicBlock(self.raw()) );
+        while ! bb.is_null() {
+            bbs.push(BB(bb, self.1));
+            bb = unsafe_llvm!( llvm::core::LLVMGetNextBasicBlock(bb) );
+        }
+        bbs
+    }
+
+    pub fn get_operand(&self, i: usize) -> Value {
+        Value(unsafe_llvm!( llvm::core::LLVMGetOperand(self.raw(), i as c_uint) ), self.1)
+    }
+
+    pub fn is_constant(&self) -> bool {
+        unsafe_llvm!( llvm::core::LLVMIsConstant(self.raw()) ) != 0
+    }
+
+    pub fn is_alloca(&self) -> bool {
+        ! unsafe_llvm!( llvm::core::LLVMIsAAllocaInst(self.raw()) ).is_null()
+    }
+
+    // Unlink an instruction from its block, so it can be inserted elsewhere with Builder::insert.
+    pub fn remove_from_parent(&self) {
+        unsafe_llvm!( llvm::core::LLVMInstructionRemoveFromParent(self.raw()) )
+    }
+
+    pub fn set_name(&self, name: &str) {
+        let cstr = CString::new(name).unwrap();
+        unsafe_llvm!( llvm::core::LLVMSetValueName(self.raw(), cstr.as_ptr()) )
+    }
+
+    pub fn type_of(&self) -> Type {
+        Type(unsafe_llvm!( llvm::core::LLVMTypeOf(self.raw()) ), self.1)
+    }
+
+    pub fn const_bitcast(&self, ty: Type) -> Value {
+        Value(unsafe_llvm!( llvm::core::LLVMConstBitCast(self.raw(), ty.raw()) ), self.1)
+    }
+
+    pub fn const_int_to_ptr(&self, ty: Type) -> Value {
+        Value(unsafe_llvm!( llvm::core::LLVMConstIntToPtr(self.raw(), ty.raw()) ), self.1)
+    }
+
+    // v must have the same type as self.
+    pub fn replace_all_uses_with(&self, v: Value) {
+        unsafe_llvm!( llvm::core::LLVMReplaceAllUsesWith(self.raw(), v.raw()) )
+    }
+
+    pub fn delete_function(&self) {
+        unsafe_llvm!( llvm::core::LLVMDeleteFunction(self.raw()) )
+    }
+
     pub fn dump(&self) {
-        unsafe_llvm!( llvm::core::LLVMDumpValue(self.0) )
+        unsafe_llvm!( llvm::core::LLVMDumpValue(self.raw()) )
     }
 }
 
 impl Type {
     pub fn dump(&self) {
-        unsafe_llvm!( llvm::core::LLVMDumpType(self.0) )
+        unsafe_llvm!( llvm::core::LLVMDumpType(self.raw()) )
     }
 
     pub fn i1() -> Type {
         crate::llvm::init();
-        Type(unsafe_llvm!( llvm::core::LLVMInt1Type() ))
+        Type(unsafe_llvm!( llvm::core::LLVMInt1Type() ), Generation::GLOBAL)
     }
 
     pub fn i8() -> Type {
         crate::llvm::init();
-        Type(unsafe_llvm!( llvm::core::LLVMInt8Type() ))
+        Type(unsafe_llvm!( llvm::core::LLVMInt8Type() ), Generation::GLOBAL)
     }
 
     pub fn i16() -> Type {
         crate::llvm::init();
-        Type(unsafe_llvm!( llvm::core::LLVMInt16Type() ))
+        Type(unsafe_llvm!( llvm::core::LLVMInt16Type() ), Generation::GLOBAL)
     }
 
     pub fn i32() -> Type {
         crate::llvm::init();
-        Type(unsafe_llvm!( llvm::core::LLVMInt32Type() ))
+        Type(unsafe_llvm!( llvm::core::LLVMInt32Type() ), Generation::GLOBAL)
     }
 
     pub fn i64() -> Type {
         crate::llvm::init();
-        Type(unsafe_llvm!( llvm::core::LLVMInt64Type() ))
+        Type(unsafe_llvm!( llvm::core::LLVMInt64Type() ), Generation::GLOBAL)
     }
+
+    pub fn i128() -> Type {
+        crate::llvm::init();
+        Type(unsafe_llvm!( llvm::core::LLVMInt128Type() ), Generation::GLOBAL)
+    }
+
     pub fn float() -> Type {
         crate::llvm::init();
-        Type(unsafe_llvm!( llvm::core::LLVMFloatType() ))
+        Type(unsafe_llvm!( llvm::core::LLVMFloatType() ), Generation::GLOBAL)
     }
 
     pub fn double() -> Type {
         crate::llvm::init();
-        Type(unsafe_llvm!( llvm::core::LLVMDoubleType() ))
+        Type(unsafe_llvm!( llvm::core::LLVMDoubleType() ), Generation::GLOBAL)
     }
 
     pub fn void() -> Type {
         crate::llvm::init();
-        Type(unsafe_llvm!( llvm::core::LLVMVoidType() ))
+        Type(unsafe_llvm!( llvm::core::LLVMVoidType() ), Generation::GLOBAL)
     }
 
     pub fn function(ret: Type, param_types: &[Type], is_var_arg: bool) -> Type {
         crate::llvm::init();
         let n = param_types.len() as c_uint;
-        let mut tys: Vec<LLVMTypeRef> = param_types.iter().map(|ty| ty.0).collect();
-        Type(unsafe_llvm!( llvm::core::LLVMFunctionType(ret.0, tys.as_mut_ptr(), n, c_bool!(is_var_arg)) ))
+        let mut tys: Vec<LLVMTypeRef> = param_types.iter().map(|ty| ty.raw()).collect();
+        Type(unsafe_llvm!( llvm::core::LLVMFunctionType(ret.raw(), tys.as_mut_ptr(), n, c_bool!(is_var_arg)) ), ret.1)
     }
 
     pub fn array(element_type: Type, n: usize) -> Type {
         crate::llvm::init();
-        Type(unsafe_llvm!( llvm::core::LLVMArrayType(element_type.0, n as c_uint) ))
+        Type(unsafe_llvm!( llvm::core::LLVMArrayType(element_type.raw(), n as c_uint) ), element_type.1)
     }
 
     pub fn pointer(ty: Type) -> Type {
         crate::llvm::init();
-        Type(unsafe_llvm!( llvm::core::LLVMPointerType(ty.0, 0) ))
+        Type(unsafe_llvm!( llvm::core::LLVMPointerType(ty.raw(), 0) ), ty.1)
     }
 
     pub fn structure(element_types: &[Type], is_packed: bool) -> Type {
         crate::llvm::init();
         let n = element_types.len() as c_uint;
-        let mut tys: Vec<LLVMTypeRef> = element_types.iter().map(|ty| ty.0).collect();
-        Type(unsafe_llvm!( llvm::core::LLVMStructType(tys.as_mut_ptr(), n, c_bool!(is_packed)) ))
+        let mut tys: Vec<LLVMTypeRef> = element_types.iter().map(|ty| ty.raw()).collect();
+        Type(unsafe_llvm!( llvm::core::LLVMStructType(tys.as_mut_ptr(), n, c_bool!(is_packed)) ), Generation::GLOBAL)
     }
 }
 
@@ -229,48 +533,222 @@ impl Module {
     pub fn new(name: &str) -> Module {
         crate::llvm::init();
         let cstr = CString::new(name).unwrap();
-        Module(unsafe_llvm!( llvm::core::LLVMModuleCreateWithName(cstr.as_ptr()) ))
+        Module(unsafe_llvm!( llvm::core::LLVMModuleCreateWithName(cstr.as_ptr()) ), Generation::GLOBAL)
     }
 
     pub fn dispose(&self) {
-        unsafe_llvm!( llvm::core::LLVMDisposeModule(self.0) );
+        unsafe_llvm!( llvm::core::LLVMDisposeModule(self.raw()) );
+    }
+
+    // The module, recording that it uses types and values from context, so it can't be used
+    // once context is disposed. Values from the module are bound to context too.
+    pub fn bound_to(self, context: &Context) -> Module {
+        Module(self.0, context.1)
+    }
+
+    // Panics if the context the module is bound to has been disposed.
+    pub fn check_live(&self) {
+        self.raw();
+    }
+
+    pub fn is_live(&self) -> bool {
+        self.1.is_live()
     }
 
     pub fn add_function(&self, name: &str, ty: Type) -> Value {
         let cstr = CString::new(name).unwrap();
-        Value(unsafe_llvm!( llvm::core::LLVMAddFunction(self.0, cstr.as_ptr(), ty.0) ))
+        Value(unsafe_llvm!( llvm::core::LLVMAddFunction(self.raw(), cstr.as_ptr(), ty.raw()) ), self.1)
     }
 
     pub fn add_global(&self, name: &str, ty: Type) -> Value {
         let cstr = CString::new(name).unwrap();
-        Value(unsafe_llvm!( llvm::core::LLVMAddGlobal(self.0, ty.0, cstr.as_ptr()) ))
+        Value(unsafe_llvm!( llvm::core::LLVMAddGlobal(self.raw(), ty.raw(), cstr.as_ptr()) ), self.1)
     }
 
     pub fn dump(&self) {
-        unsafe_llvm!( llvm::core::LLVMDumpModule(self.0) )
+        unsafe_llvm!( llvm::core::LLVMDumpModule(self.raw()) )
+    }
+
+    // Register f, a void function with no params, to run when the module is loaded
+    // (a constructor) or unloaded (a destructor). Constructors with lower priorities run
+    // first, and destructors with lower priorities run last.
+    pub fn add_global_ctor(&self, f: Value, priority: i32) {
+        self.append_structor("llvm.global_ctors", f, priority);
+    }
+
+    pub fn add_global_dtor(&self, f: Value, priority: i32) {
+        self.append_structor("llvm.global_dtors", f, priority);
+    }
+
+    // The ctor and dtor lists are arrays of { priority, function, associated data } with
+    // appending linkage. A constant array can't grow, so replace the global with a longer one.
+    // The data is a global the entry is tied to, e.g., for COMDATs; null means none.
+    fn append_structor(&self, name: &str, f: Value, priority: i32) {
+        let null = Value::null(Type::pointer(Type::i8()));
+        let entry = Value::const_struct(&[Value::i32(priority), f, null], false);
+
+        let mut entries = Vec::new();
+        if let Some(old) = self.find_global(name) {
+            unsafe_llvm!({
+                let array = llvm::core::LLVMGetInitializer(old.raw());
+                for i in 0..llvm::core::LLVMGetNumOperands(array) {
+                    entries.push(Value(llvm::core::LLVMGetOperand(array, i as c_uint), self.1));
+                }
+                llvm::core::LLVMDeleteGlobal(old.raw());
+            });
+        }
+        entries.push(entry);
+
+        let array = Value::const_array(entry.type_of(), &entries);
+        let global = self.add_global(name, array.type_of());
+        global.set_linkage(Linkage::Appending);
+        global.set_initializer(array);
+    }
+
+    // Run the standard module passes for the given -O level (0 to 3).
+    pub fn optimize(&self, opt_level: u32) {
+        unsafe_llvm!({
+            let pmb = llvm::transforms::pass_manager_builder::LLVMPassManagerBuilderCreate();
+            llvm::transforms::pass_manager_builder::LLVMPassManagerBuilderSetOptLevel(pmb, opt_level as c_uint);
+            let pm = llvm::core::LLVMCreatePassManager();
+            llvm::transforms::pass_manager_builder::LLVMPassManagerBuilderPopulateModulePassManager(pmb, pm);
+            llvm::core::LLVMRunPassManager(pm, self.raw());
+            llvm::core::LLVMDisposePassManager(pm);
+            llvm::transforms::pass_manager_builder::LLVMPassManagerBuilderDispose(pmb);
+        })
+    }
+
+    pub fn print_to_string(&self) -> String {
+        unsafe_llvm!({
+            let p = llvm::core::LLVMPrintModuleToString(self.raw());
+            let s = std::ffi::CStr::from_ptr(p).to_string_lossy().into_owned();
+            llvm::core::LLVMDisposeMessage(p);
+            s
+        })
     }
 
     pub fn write_bitcode_to_file(&self, file: &str) {
         let cstr = CString::new(file).unwrap();
-        unsafe_llvm!( llvm::bit_writer::LLVMWriteBitcodeToFile(self.0, cstr.as_ptr()) );
+        unsafe_llvm!( llvm::bit_writer::LLVMWriteBitcodeToFile(self.raw(), cstr.as_ptr()) );
+    }
+
+    pub fn write_bitcode_to_memory(&self) -> Vec<u8> {
+        unsafe_llvm!({
+            let buf = llvm::bit_writer::LLVMWriteBitcodeToMemoryBuffer(self.raw());
+            let p = llvm::core::LLVMGetBufferStart(buf) as *const u8;
+            let n = llvm::core::LLVMGetBufferSize(buf);
+            let bytes = std::slice::from_raw_parts(p, n).to_vec();
+            llvm::core::LLVMDisposeMemoryBuffer(buf);
+            bytes
+        })
+    }
+
+    // Run the LLVM verifier, returning its report if the module is malformed.
+    pub fn verify(&self) -> Result<(), String> {
+        unsafe_llvm!({
+            let mut p = ptr::null_mut();
+            let failed = llvm::analysis::LLVMVerifyModule(self.raw(), llvm::analysis::LLVMVerifierFailureAction::LLVMReturnStatusAction, &mut p);
+            let s = std::ffi::CStr::from_ptr(p).to_string_lossy().into_owned();
+            llvm::core::LLVMDisposeMessage(p);
+            if failed != 0 {
+                Err(s)
+            }
+            else {
+                Ok(())
+            }
+        })
+    }
+
+    // Compile the module to assembly text for triple, or for the host if triple is None.
+    pub fn emit_assembly(&self, triple: Option<&str>) -> Result<String, String> {
+        self.emit_assembly_for_cpu(triple, "", "")
+    }
+
+    // Like emit_assembly, but for a specific CPU (e.g., "skylake") and features (e.g., "+avx2").
+    // Empty strings mean the generic CPU and its default features.
+    pub fn emit_assembly_for_cpu(&self, triple: Option<&str>, cpu: &str, features: &str) -> Result<String, String> {
+        use llvm::target_machine::*;
+
+        let tm = target_machine(triple, cpu, features)?;
+
+        unsafe_llvm!({
+            let mut err = ptr::null_mut();
+            let mut buf = ptr::null_mut();
+            let failed = LLVMTargetMachineEmitToMemoryBuffer(tm, self.raw(), LLVMCodeGenFileType::LLVMAssemblyFile, &mut err, &mut buf);
+            LLVMDisposeTargetMachine(tm);
+            if failed != 0 {
+                return Err(message(err));
+            }
+
+            let p = llvm::core::LLVMGetBufferStart(buf) as *const u8;
+            let n = llvm::core::LLVMGetBufferSize(buf);
+            let s = String::from_utf8_lossy(std::slice::from_raw_parts(p, n)).into_owned();
+            llvm::core::LLVMDisposeMemoryBuffer(buf);
+            Ok(s)
+        })
+    }
+
+    // Returns None if the bytes are not valid bitcode.
+    pub fn parse_bitcode(context: Context, bytes: &[u8]) -> Option<Module> {
+        let name = CString::new("bitcode").unwrap();
+        unsafe_llvm!({
+            let buf = llvm::core::LLVMCreateMemoryBufferWithMemoryRange(bytes.as_ptr() as *const _, bytes.len(), name.as_ptr(), c_bool!(false));
+            let mut m = ptr::null_mut();
+            let failed = llvm::bit_reader::LLVMParseBitcodeInContext2(context.raw(), buf, &mut m);
+            llvm::core::LLVMDisposeMemoryBuffer(buf);
+            if failed != 0 { None } else { Some(Module(m, context.1)) }
+        })
+    }
+
+    // Link src into self. This consumes src. Returns false if linking failed.
+    pub fn link(&self, src: Module) -> bool {
+        unsafe_llvm!( llvm::linker::LLVMLinkModules2(self.raw(), src.raw()) ) == 0
+    }
+
+    pub fn function_count(&self) -> usize {
+        unsafe_llvm!({
+            let mut n = 0;
+            let mut f = llvm::core::LLVMGetFirstFunction(self.raw());
+            while ! f.is_null() {
+                n += 1;
+                f = llvm::core::LLVMGetNextFunction(f);
+            }
+            n
+        })
     }
 
     pub fn get_named_global(&self, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
         Value(unsafe_llvm!({
-            let p = llvm::core::LLVMGetNamedGlobal(self.0, cstr.as_ptr());
+            let p = llvm::core::LLVMGetNamedGlobal(self.raw(), cstr.as_ptr());
             assert!(! p.is_null(), "no global named {}", name);
             p
-        }))
+        }), self.1)
     }
 
     pub fn get_named_function(&self, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
         Value(unsafe_llvm!({
-            let p = llvm::core::LLVMGetNamedFunction(self.0, cstr.as_ptr());
+            let p = llvm::core::LLVMGetNamedFunction(self.raw(), cstr.as_ptr());
             assert!(! p.is_null(), "no function named {}", name);
             p
-        }))
+        }), self.1)
+    }
+
+    pub fn find_global(&self, name: &str) -> Option<Value> {
+        let cstr = CString::new(name).unwrap();
+        unsafe_llvm!({
+            let p = llvm::core::LLVMGetNamedGlobal(self.raw(), cstr.as_ptr());
+            if p.is_null() { None } else { Some(Value(p, self.1)) }
+        })
+    }
+
+    pub fn find_function(&self, name: &str) -> Option<Value> {
+        let cstr = CString::new(name).unwrap();
+        unsafe_llvm!({
+            let p = llvm::core::LLVMGetNamedFunction(self.raw(), cstr.as_ptr());
+            if p.is_null() { None } else { Some(Value(p, self.1)) }
+        })
     }
 
 }
@@ -278,167 +756,243 @@ impl Module {
 impl Context {
     pub fn new() -> Context {
         crate::llvm::init();
-        Context(unsafe_llvm!( llvm::core::LLVMContextCreate() ))
+        let generation = {
+            let mut registry = CONTEXTS.lock().unwrap();
+            let generation = registry.next_generation;
+            registry.next_generation += 1;
+            registry.live.insert(generation);
+            Generation(generation)
+        };
+
+        Context(unsafe_llvm!( llvm::core::LLVMContextCreate() ), generation)
+    }
+
+    // Panics if the context has been disposed.
+    pub fn check_live(&self) {
+        self.raw();
     }
+
+    pub fn is_live(&self) -> bool {
+        self.1.is_live()
+    }
+    // Metadata operands. Use md_node to combine them into a node that can be attached.
+    pub fn md_string(&self, s: &str) -> Value {
+        Value(unsafe_llvm!( llvm::core::LLVMMDStringInContext(self.raw(), s.as_ptr() as *const _, s.len() as c_uint) ), self.1)
+    }
+
+    pub fn md_int(&self, v: i64) -> Value {
+        let ty = self.i64_type(); // get the type outside the critical section to avoid deadlock
+        Value(unsafe_llvm!( llvm::core::LLVMConstInt(ty.raw(), v as c_ulonglong, c_bool!(true)) ), self.1)
+    }
+
+    pub fn md_i32(&self, v: i32) -> Value {
+        let ty = self.i32_type(); // get the type outside the critical section to avoid deadlock
+        Value(unsafe_llvm!( llvm::core::LLVMConstInt(ty.raw(), v as c_ulonglong, c_bool!(true)) ), self.1)
+    }
+
+    pub fn md_bool(&self, v: bool) -> Value {
+        let ty = self.i1_type(); // get the type outside the critical section to avoid deadlock
+        Value(unsafe_llvm!( llvm::core::LLVMConstInt(ty.raw(), v as c_ulonglong, c_bool!(false)) ), self.1)
+    }
+
+    pub fn md_node(&self, operands: &[Value]) -> MetadataNode {
+        let mut vs: Vec<LLVMValueRef> = operands.iter().map(|v| v.raw()).collect();
+        MetadataNode(unsafe_llvm!( llvm::core::LLVMMDNodeInContext(self.raw(), vs.as_mut_ptr(), vs.len() as c_uint) ), self.1)
+    }
+
+    // LLVM only recognizes an !llvm.loop node whose first operand is the node itself.
+    // Build it with a temporary first operand and then replace the temporary.
+    pub fn md_loop_id(&self, hints: &[MetadataNode]) -> MetadataNode {
+        MetadataNode(unsafe_llvm!({
+            let tmp = llvm::debuginfo::LLVMTemporaryMDNode(self.raw(), ptr::null_mut(), 0);
+            let mut ops: Vec<LLVMMetadataRef> = vec![tmp];
+            ops.extend(hints.iter().map(|h| llvm::core::LLVMValueAsMetadata(h.raw())));
+            let id = llvm::core::LLVMMDNodeInContext2(self.raw(), ops.as_mut_ptr(), ops.len() as c_uint);
+            llvm::debuginfo::LLVMMetadataReplaceAllUsesWith(tmp, id);
+            llvm::core::LLVMMetadataAsValue(self.raw(), id)
+        }), self.1)
+    }
+
     pub fn global() -> Context {
         crate::llvm::init();
-        Context(unsafe_llvm!( llvm::core::LLVMGetGlobalContext() ))
+        Context(unsafe_llvm!( llvm::core::LLVMGetGlobalContext() ), Generation::GLOBAL)
     }
+    // Disposes the modules in the context too. Handles from the context can't be used after this.
     pub fn dispose(&self) {
-        unsafe_llvm!( llvm::core::LLVMContextDispose(self.0) );
+        let context = self.raw();
+        assert!(self.1 != Generation::GLOBAL, "the global context can't be disposed");
+        CONTEXTS.lock().unwrap().live.remove(&(self.1).0);
+        unsafe_llvm!( llvm::core::LLVMContextDispose(context) );
     }
     pub fn new_builder(&self) -> Builder {
-        Builder(unsafe_llvm!( llvm::core::LLVMCreateBuilderInContext(self.0) ))
+        Builder(unsafe_llvm!( llvm::core::LLVMCreateBuilderInContext(self.raw()) ), self.1)
     }
     pub fn void_type(&self) -> Type {
-        Type(unsafe_llvm!( llvm::core::LLVMVoidTypeInContext(self.0) ))
+        Type(unsafe_llvm!( llvm::core::LLVMVoidTypeInContext(self.raw()) ), self.1)
     }
     pub fn label_type(&self) -> Type {
-        Type(unsafe_llvm!( llvm::core::LLVMLabelTypeInContext(self.0) ))
+        Type(unsafe_llvm!( llvm::core::LLVMLabelTypeInContext(self.raw()) ), self.1)
     }
     pub fn token_type(&self) -> Type {
-        Type(unsafe_llvm!( llvm::core::LLVMTokenTypeInContext(self.0) ))
+        Type(unsafe_llvm!( llvm::core::LLVMTokenTypeInContext(self.raw()) ), self.1)
     }
     pub fn metadata_type(&self) -> Type {
-        Type(unsafe_llvm!( llvm::core::LLVMMetadataTypeInContext(self.0) ))
+        Type(unsafe_llvm!( llvm::core::LLVMMetadataTypeInContext(self.raw()) ), self.1)
     }
 
     pub fn i1_type(&self) -> Type {
-        Type(unsafe_llvm!( llvm::core::LLVMInt1TypeInContext(self.0) ))
+        Type(unsafe_llvm!( llvm::core::LLVMInt1TypeInContext(self.raw()) ), self.1)
     }
 
     pub fn i8_type(&self) -> Type {
-        Type(unsafe_llvm!( llvm::core::LLVMInt8TypeInContext(self.0) ))
+        Type(unsafe_llvm!( llvm::core::LLVMInt8TypeInContext(self.raw()) ), self.1)
     }
 
     pub fn i16_type(&self) -> Type {
-        Type(unsafe_llvm!( llvm::core::LLVMInt16TypeInContext(self.0) ))
+        Type(unsafe_llvm!( llvm::core::LLVMInt16TypeInContext(self.raw()) ), self.1)
     }
 
     pub fn i32_type(&self) -> Type {
-        Type(unsafe_llvm!( llvm::core::LLVMInt32TypeInContext(self.0) ))
+        Type(unsafe_llvm!( llvm::core::LLVMInt32TypeInContext(self.raw()) ), self.1)
     }
 
     pub fn i64_type(&self) -> Type {
-        Type(unsafe_llvm!( llvm::core::LLVMInt64TypeInContext(self.0) ))
+        Type(unsafe_llvm!( llvm::core::LLVMInt64TypeInContext(self.raw()) ), self.1)
+    }
+
+    pub fn i128_type(&self) -> Type {
+        Type(unsafe_llvm!( llvm::core::LLVMInt128TypeInContext(self.raw()) ), self.1)
     }
     pub fn float_type(&self) -> Type {
-        Type(unsafe_llvm!( llvm::core::LLVMFloatTypeInContext(self.0) ))
+        Type(unsafe_llvm!( llvm::core::LLVMFloatTypeInContext(self.raw()) ), self.1)
     }
 
     pub fn double_type(&self) -> Type {
-        Type(unsafe_llvm!( llvm::core::LLVMDoubleTypeInContext(self.0) ))
+        Type(unsafe_llvm!( llvm::core::LLVMDoubleTypeInContext(self.raw()) ), self.1)
     }
 
     pub fn function_type(&self, ret: Type, param_types: &[Type], is_var_arg: bool) -> Type {
         let n = param_types.len() as c_uint;
-        let mut tys: Vec<LLVMTypeRef> = param_types.iter().map(|ty| ty.0).collect();
-        Type(unsafe_llvm!( llvm::core::LLVMFunctionType(ret.0, tys.as_mut_ptr(), n, c_bool!(is_var_arg)) ))
+        let mut tys: Vec<LLVMTypeRef> = param_types.iter().map(|ty| ty.raw()).collect();
+        Type(unsafe_llvm!( llvm::core::LLVMFunctionType(ret.raw(), tys.as_mut_ptr(), n, c_bool!(is_var_arg)) ), self.1)
     }
 
     pub fn array_type(&self, element_type: Type, n: usize) -> Type {
-        Type(unsafe_llvm!( llvm::core::LLVMArrayType(element_type.0, n as c_uint) ))
+        Type(unsafe_llvm!( llvm::core::LLVMArrayType(element_type.raw(), n as c_uint) ), self.1)
     }
 
     pub fn pointer_type(&self, ty: Type) -> Type {
-        Type(unsafe_llvm!( llvm::core::LLVMPointerType(ty.0, 0) ))
+        Type(unsafe_llvm!( llvm::core::LLVMPointerType(ty.raw(), 0) ), self.1)
     }
 
     pub fn structure_type(&self, element_types: &[Type], is_packed: bool) -> Type {
         let n = element_types.len() as c_uint;
-        let mut tys: Vec<LLVMTypeRef> = element_types.iter().map(|ty| ty.0).collect();
-        Type(unsafe_llvm!( llvm::core::LLVMStructTypeInContext(self.0, tys.as_mut_ptr(), n, c_bool!(is_packed)) ))
+        let mut tys: Vec<LLVMTypeRef> = element_types.iter().map(|ty| ty.raw()).collect();
+        Type(unsafe_llvm!( llvm::core::LLVMStructTypeInContext(self.raw(), tys.as_mut_ptr(), n, c_bool!(is_packed)) ), self.1)
     }
 
     pub fn append_bb(&self, function: Value, name: &str) -> BB {
         let cstr = CString::new(name).unwrap();
-        BB(unsafe_llvm!( llvm::core::LLVMAppendBasicBlockInContext(self.0, function.0, cstr.as_ptr()) ))
+        BB(unsafe_llvm!( llvm::core::LLVMAppendBasicBlockInContext(self.raw(), function.raw(), cstr.as_ptr()) ), self.1)
+    }
+}
+
+impl BB {
+    pub fn first_instruction(&self) -> Option<Value> {
+        let insn = unsafe_llvm!( llvm::core::LLVMGetFirstInstruction(self.raw()) );
+        if insn.is_null() { None } else { Some(Value(insn, self.1)) }
+    }
+
+    pub fn instructions(&self) -> Vec<Value> {
+        let mut insns = vec![];
+        let mut insn = unsafe_llvm!( llvm::core::LLVMGetFirstInstruction(self.raw()) );
+        while ! insn.is_null() {
+            insns.push(Value(insn, self.1));
+            insn = unsafe_llvm!( llvm::core::LLVMGetNextInstruction(insn) );
+        }
+        insns
     }
 }
 
 impl Builder {
     pub fn new() -> Builder {
         crate::llvm::init();
-        Builder(unsafe_llvm!( llvm::core::LLVMCreateBuilder() ))
-    }
-
-    pub fn dispose(&self) {
-        unsafe_llvm!( llvm::core::LLVMDisposeBuilder(self.0) );
+        Builder(unsafe_llvm!( llvm::core::LLVMCreateBuilder() ), Generation::GLOBAL)
     }
 
     pub fn position(&self, bb: BB, insn: Value) {
-        unsafe_llvm!( llvm::core::LLVMPositionBuilder(self.0, bb.0, insn.0) )
+        unsafe_llvm!( llvm::core::LLVMPositionBuilder(self.raw(), bb.raw(), insn.raw()) )
     }
     pub fn position_before(&self, insn: Value) {
-        unsafe_llvm!( llvm::core::LLVMPositionBuilderBefore(self.0, insn.0) )
+        unsafe_llvm!( llvm::core::LLVMPositionBuilderBefore(self.raw(), insn.raw()) )
     }
 
     pub fn position_at_end(&self, bb: BB) {
-        unsafe_llvm!( llvm::core::LLVMPositionBuilderAtEnd(self.0, bb.0) )
+        unsafe_llvm!( llvm::core::LLVMPositionBuilderAtEnd(self.raw(), bb.raw()) )
     }
 
     pub fn get_insert_block(&self) -> BB {
-        BB(unsafe_llvm!( llvm::core::LLVMGetInsertBlock(self.0) ))
+        BB(unsafe_llvm!( llvm::core::LLVMGetInsertBlock(self.raw()) ), self.1)
     }
 
     pub fn clear_insertion_position(&self) {
-        unsafe_llvm!( llvm::core::LLVMClearInsertionPosition(self.0) )
+        unsafe_llvm!( llvm::core::LLVMClearInsertionPosition(self.raw()) )
     }
 
     pub fn insert(&self, insn: Value) {
-        unsafe_llvm!( llvm::core::LLVMInsertIntoBuilder(self.0, insn.0) )
+        unsafe_llvm!( llvm::core::LLVMInsertIntoBuilder(self.raw(), insn.raw()) )
     }
 
     pub fn insert_with_name(&self, insn: Value, name: &str) {
         let cstr = CString::new(name).unwrap();
-        unsafe_llvm!( llvm::core::LLVMInsertIntoBuilderWithName(self.0, insn.0, cstr.as_ptr()) )
+        unsafe_llvm!( llvm::core::LLVMInsertIntoBuilderWithName(self.raw(), insn.raw(), cstr.as_ptr()) )
     }
 
     pub fn set_current_debug_location(&self, l: Value) {
-        unsafe_llvm!( llvm::core::LLVMSetCurrentDebugLocation(self.0, l.0) )
+        unsafe_llvm!( llvm::core::LLVMSetCurrentDebugLocation(self.raw(), l.raw()) )
     }
 
     pub fn get_current_debug_location(&self) -> Value {
-        Value(unsafe_llvm!( llvm::core::LLVMGetCurrentDebugLocation(self.0) ))
+        Value(unsafe_llvm!( llvm::core::LLVMGetCurrentDebugLocation(self.raw()) ), self.1)
     }
 
     pub fn set_inst_debug_location(&self, l: Value) {
-        unsafe_llvm!( llvm::core::LLVMSetInstDebugLocation(self.0, l.0) )
+        unsafe_llvm!( llvm::core::LLVMSetInstDebugLocation(self.raw(), l.raw()) )
     }
 
     pub fn ret_void(&self) -> Value {
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildRetVoid(self.0) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildRetVoid(self.raw()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn ret(&self, v: Value) -> Value {
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildRet(self.0, v.0) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildRet(self.raw(), v.raw()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn aggregate_ret(&self, vs: &[Value]) -> Value {
-        let mut a: Vec<LLVMValueRef> = vs.iter().map(|v| v.0).collect();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildAggregateRet(self.0, a.as_mut_ptr(), vs.len() as u32) ));
+        let mut a: Vec<LLVMValueRef> = vs.iter().map(|v| v.raw()).collect();
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildAggregateRet(self.raw(), a.as_mut_ptr(), vs.len() as u32) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn br(&self, dest: BB) -> Value {
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildBr(self.0, dest.0) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildBr(self.raw(), dest.raw()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn cond_br(&self, i: Value, t: BB, e: BB) -> Value {
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildCondBr(self.0, i.0, t.0, e.0) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildCondBr(self.raw(), i.raw(), t.raw(), e.raw()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn switch(&self, i: Value, e: BB, ncases: usize) -> Value {
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildSwitch(self.0, i.0, e.0, ncases as u32) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildSwitch(self.raw(), i.raw(), e.raw(), ncases as u32) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
@@ -448,7 +1002,16 @@ impl Builder {
 // LLVMValueRef 	LLVMBuildInvoke (LLVMBuilderRef, LLVMValueRef Fn, LLVMValueRef *Args, unsigned NumArgs, LLVMBasicBlockRef Then, LLVMBasicBlockRef Catch, const char *Name)
 
     pub fn unreachable(&self) -> Value {
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildUnreachable(self.0) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildUnreachable(self.raw()) ), self.1);
+        insn.dump(); eprintln!();
+        insn
+    }
+
+    // A pointer to the first character of a new private global holding s and a NUL.
+    pub fn global_string_ptr(&self, s: &str, name: &str) -> Value {
+        let sstr = CString::new(s).unwrap();
+        let cstr = CString::new(name).unwrap();
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildGlobalStringPtr(self.raw(), sstr.as_ptr(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
@@ -462,7 +1025,7 @@ impl Builder {
 // LLVMValueRef 	LLVMBuildCatchSwitch (LLVMBuilderRef B, LLVMValueRef ParentPad, LLVMBasicBlockRef UnwindBB, unsigned NumHandlers, const char *Name)
 
     pub fn add_case(&self, switch: Value, on_val: Value, dest: BB) {
-        unsafe_llvm!( llvm::core::LLVMAddCase(switch.0, on_val.0, dest.0) )
+        unsafe_llvm!( llvm::core::LLVMAddCase(switch.raw(), on_val.raw(), dest.raw()) )
     }
 //
 // void 	LLVMAddDestination (LLVMValueRef IndirectBr, LLVMBasicBlockRef Dest)
@@ -484,179 +1047,179 @@ impl Builder {
 
     pub fn add(&self, left: Value, right: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildAdd(self.0, left.0, right.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildAdd(self.raw(), left.raw(), right.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn nsw_add(&self, left: Value, right: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildNSWAdd(self.0, left.0, right.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildNSWAdd(self.raw(), left.raw(), right.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn nuw_add(&self, left: Value, right: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildNUWAdd(self.0, left.0, right.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildNUWAdd(self.raw(), left.raw(), right.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn fadd(&self, left: Value, right: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildFAdd(self.0, left.0, right.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildFAdd(self.raw(), left.raw(), right.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn sub(&self, left: Value, right: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildSub(self.0, left.0, right.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildSub(self.raw(), left.raw(), right.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn nsw_sub(&self, left: Value, right: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildNSWSub(self.0, left.0, right.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildNSWSub(self.raw(), left.raw(), right.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn nuw_sub(&self, left: Value, right: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildNUWSub(self.0, left.0, right.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildNUWSub(self.raw(), left.raw(), right.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn fsub(&self, left: Value, right: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildFSub(self.0, left.0, right.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildFSub(self.raw(), left.raw(), right.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn mul(&self, left: Value, right: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildMul(self.0, left.0, right.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildMul(self.raw(), left.raw(), right.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn nsw_mul(&self, left: Value, right: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildNSWMul(self.0, left.0, right.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildNSWMul(self.raw(), left.raw(), right.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn nuw_mul(&self, left: Value, right: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildNUWMul(self.0, left.0, right.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildNUWMul(self.raw(), left.raw(), right.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn fmul(&self, left: Value, right: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildFMul(self.0, left.0, right.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildFMul(self.raw(), left.raw(), right.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn udiv(&self, left: Value, right: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildUDiv(self.0, left.0, right.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildUDiv(self.raw(), left.raw(), right.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn exact_udiv(&self, left: Value, right: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildExactUDiv(self.0, left.0, right.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildExactUDiv(self.raw(), left.raw(), right.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn sdiv(&self, left: Value, right: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildSDiv(self.0, left.0, right.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildSDiv(self.raw(), left.raw(), right.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn exact_sdiv(&self, left: Value, right: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildExactSDiv(self.0, left.0, right.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildExactSDiv(self.raw(), left.raw(), right.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn fdiv(&self, left: Value, right: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildFDiv(self.0, left.0, right.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildFDiv(self.raw(), left.raw(), right.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn urem(&self, left: Value, right: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildURem(self.0, left.0, right.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildURem(self.raw(), left.raw(), right.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
     pub fn srem(&self, left: Value, right: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildSRem(self.0, left.0, right.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildSRem(self.raw(), left.raw(), right.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn frem(&self, left: Value, right: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildFRem(self.0, left.0, right.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildFRem(self.raw(), left.raw(), right.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn shl(&self, left: Value, right: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildShl(self.0, left.0, right.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildShl(self.raw(), left.raw(), right.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
     pub fn lshr(&self, left: Value, right: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildLShr(self.0, left.0, right.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildLShr(self.raw(), left.raw(), right.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
     pub fn ashr(&self, left: Value, right: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildAShr(self.0, left.0, right.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildAShr(self.raw(), left.raw(), right.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn and(&self, left: Value, right: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildAnd(self.0, left.0, right.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildAnd(self.raw(), left.raw(), right.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn or(&self, left: Value, right: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildOr(self.0, left.0, right.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildOr(self.raw(), left.raw(), right.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn xor(&self, left: Value, right: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildXor(self.0, left.0, right.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildXor(self.raw(), left.raw(), right.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
@@ -664,32 +1227,32 @@ impl Builder {
 
     pub fn neg(&self, v: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildNeg(self.0, v.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildNeg(self.raw(), v.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn nsw_neg(&self, v: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildNSWNeg(self.0, v.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildNSWNeg(self.raw(), v.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
     pub fn nuw_neg(&self, v: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildNUWNeg(self.0, v.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildNUWNeg(self.raw(), v.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
     pub fn fneg(&self, v: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildFNeg(self.0, v.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildFNeg(self.raw(), v.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
     pub fn not(&self, v: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildNot(self.0, v.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildNot(self.raw(), v.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
@@ -705,57 +1268,62 @@ impl Builder {
 
     pub fn alloca(&self, ty: Type, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildAlloca(self.0, ty.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildAlloca(self.raw(), ty.raw(), cstr.as_ptr()) ), self.1);
+        insn.dump(); eprintln!();
+        insn
+    }
+
+    pub fn array_alloca(&self, ty: Type, count: Value, name: &str) -> Value {
+        let cstr = CString::new(name).unwrap();
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildArrayAlloca(self.raw(), ty.raw(), count.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
-// LLVMValueRef 	LLVMBuildArrayAlloca (LLVMBuilderRef, LLVMTypeRef Ty, LLVMValueRef Val, const char *Name)
-//
 // LLVMValueRef 	LLVMBuildFree (LLVMBuilderRef, LLVMValueRef PointerVal)
 //
 
 
     pub fn load(&self, ptr: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildLoad(self.0, ptr.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildLoad(self.raw(), ptr.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn store(&self, v: Value, ptr: Value) -> Value {
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildStore(self.0, v.0, ptr.0) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildStore(self.raw(), v.raw(), ptr.raw()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn get_element_pointer(&self, ptr: Value, indices: &[Value], name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let mut is: Vec<LLVMValueRef> = indices.iter().map(|v| v.0).collect();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildGEP(self.0, ptr.0, is.as_mut_ptr(), indices.len() as u32, cstr.as_ptr()) ));
+        let mut is: Vec<LLVMValueRef> = indices.iter().map(|v| v.raw()).collect();
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildGEP(self.raw(), ptr.raw(), is.as_mut_ptr(), indices.len() as u32, cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn get_in_bounds_element_pointer(&self, ptr: Value, indices: &[Value], name: &str) -> Value {
         eprint!("GEP ptr: ");
-        unsafe_llvm!( llvm::core::LLVMDumpValue(ptr.0) );
+        unsafe_llvm!( llvm::core::LLVMDumpValue(ptr.raw()) );
         eprintln!();
         for i in indices {
             eprint!("GEP index: ");
-            unsafe_llvm!( llvm::core::LLVMDumpValue(i.0) );
+            unsafe_llvm!( llvm::core::LLVMDumpValue(i.raw()) );
             eprintln!();
         }
         let cstr = CString::new(name).unwrap();
-        let mut is: Vec<LLVMValueRef> = indices.iter().map(|v| v.0).collect();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildInBoundsGEP(self.0, ptr.0, is.as_mut_ptr(), indices.len() as u32, cstr.as_ptr()) ));
+        let mut is: Vec<LLVMValueRef> = indices.iter().map(|v| v.raw()).collect();
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildInBoundsGEP(self.raw(), ptr.raw(), is.as_mut_ptr(), indices.len() as u32, cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn get_struct_element_pointer(&self, ptr: Value, index: usize, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildStructGEP(self.0, ptr.0, index as u32, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildStructGEP(self.raw(), ptr.raw(), index as u32, cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
@@ -772,82 +1340,82 @@ impl Builder {
 
     pub fn trunc(&self, v: Value, dst_ty: Type, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildTrunc(self.0, v.0, dst_ty.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildTrunc(self.raw(), v.raw(), dst_ty.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn zext(&self, v: Value, dst_ty: Type, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildZExt(self.0, v.0, dst_ty.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildZExt(self.raw(), v.raw(), dst_ty.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn sext(&self, v: Value, dst_ty: Type, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildSExt(self.0, v.0, dst_ty.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildSExt(self.raw(), v.raw(), dst_ty.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn fp_to_ui(&self, v: Value, dst_ty: Type, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildFPToUI(self.0, v.0, dst_ty.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildFPToUI(self.raw(), v.raw(), dst_ty.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn fp_to_si(&self, v: Value, dst_ty: Type, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildFPToUI(self.0, v.0, dst_ty.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildFPToUI(self.raw(), v.raw(), dst_ty.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn ui_to_fp(&self, v: Value, dst_ty: Type, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildUIToFP(self.0, v.0, dst_ty.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildUIToFP(self.raw(), v.raw(), dst_ty.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn si_to_fp(&self, v: Value, dst_ty: Type, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildSIToFP(self.0, v.0, dst_ty.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildSIToFP(self.raw(), v.raw(), dst_ty.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn fptrunc(&self, v: Value, dst_ty: Type, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildFPTrunc(self.0, v.0, dst_ty.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildFPTrunc(self.raw(), v.raw(), dst_ty.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn fpext(&self, v: Value, dst_ty: Type, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildFPExt(self.0, v.0, dst_ty.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildFPExt(self.raw(), v.raw(), dst_ty.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn ptr_to_int(&self, v: Value, dst_ty: Type, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildPtrToInt(self.0, v.0, dst_ty.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildPtrToInt(self.raw(), v.raw(), dst_ty.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
     pub fn int_to_ptr(&self, v: Value, dst_ty: Type, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildIntToPtr(self.0, v.0, dst_ty.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildIntToPtr(self.raw(), v.raw(), dst_ty.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
     pub fn bitcast(&self, v: Value, dst_ty: Type, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildBitCast(self.0, v.0, dst_ty.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildBitCast(self.raw(), v.raw(), dst_ty.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
@@ -865,36 +1433,114 @@ impl Builder {
 
     pub fn icmp(&self, pred: IntPredicate, left: Value, right: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildICmp(self.0, pred.to_internal(), left.0, right.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildICmp(self.raw(), pred.to_internal(), left.raw(), right.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn fcmp(&self, pred: RealPredicate, left: Value, right: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildFCmp(self.0, pred.to_internal(), left.0, right.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildFCmp(self.raw(), pred.to_internal(), left.raw(), right.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn phi(&self, ty: Type, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildPhi(self.0, ty.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildPhi(self.raw(), ty.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn call(&self, fun: Value, argv: &[Value], name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let mut vs: Vec<LLVMValueRef> = argv.iter().map(|v| v.0).collect();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildCall(self.0, fun.0, vs.as_mut_ptr(), argv.len() as u32, cstr.as_ptr()) ));
+        let mut vs: Vec<LLVMValueRef> = argv.iter().map(|v| v.raw()).collect();
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildCall(self.raw(), fun.raw(), vs.as_mut_ptr(), argv.len() as u32, cstr.as_ptr()) ), self.1);
+        insn.dump(); eprintln!();
+        insn
+    }
+
+    // A musttail call, which LLVM either emits as a jump or rejects in the verifier.
+    // The C API can only mark calls tail, so parse a template function that makes a musttail
+    // call of fun's type, clone the call, and point the clone at fun and argv.
+    // The caller must build the ret right after it.
+    pub fn musttail_call(&self, fun: Value, argv: &[Value], name: &str) -> Value {
+        let insn = Value(unsafe_llvm!({
+            let fun_ty = llvm::core::LLVMGetElementType(llvm::core::LLVMTypeOf(fun.raw()));
+            let ret_ty = llvm::core::LLVMGetReturnType(fun_ty);
+            let mut param_tys = vec![ptr::null_mut(); llvm::core::LLVMCountParamTypes(fun_ty) as usize];
+            llvm::core::LLVMGetParamTypes(fun_ty, param_tys.as_mut_ptr());
+
+            let ret = message(llvm::core::LLVMPrintTypeToString(ret_ty));
+            let params: Vec<String> = param_tys.iter().map(|ty| message(llvm::core::LLVMPrintTypeToString(*ty))).collect();
+            let args: Vec<String> = params.iter().enumerate().map(|(i, ty)| format!("{} %a{}", ty, i)).collect();
+            let is_void = llvm::core::LLVMGetTypeKind(ret_ty) == llvm::LLVMTypeKind::LLVMVoidTypeKind;
+            let (call, ret_insn) = if is_void { ("", "ret void".to_string()) } else { ("%r = ", format!("ret {} %r", ret)) };
+
+            let text = format!("declare {} @callee({})\ndefine {} @template({}) {{\n  {}musttail call {} @callee({})\n  {}\n}}\n",
+                ret, params.join(", "), ret, args.join(", "), call, ret, args.join(", "), ret_insn);
+
+            let buf_name = CString::new("musttail").unwrap();
+            let buf = llvm::core::LLVMCreateMemoryBufferWithMemoryRangeCopy(text.as_ptr() as *const _, text.len(), buf_name.as_ptr());
+            let mut template = ptr::null_mut();
+            let mut err = ptr::null_mut();
+            // The parser takes ownership of buf.
+            if llvm::ir_reader::LLVMParseIRInContext(llvm::core::LLVMGetTypeContext(fun_ty), buf, &mut template, &mut err) != 0 {
+                panic!("could not parse musttail template: {}\n{}", message(err), text);
+            }
+
+            let template_fun = llvm::core::LLVMGetNamedFunction(template, CString::new("template").unwrap().as_ptr());
+            let template_call = llvm::core::LLVMGetFirstInstruction(llvm::core::LLVMGetFirstBasicBlock(template_fun));
+
+            // The callee is the last operand of a call.
+            let insn = llvm::core::LLVMInstructionClone(template_call);
+            for (i, v) in argv.iter().enumerate() {
+                llvm::core::LLVMSetOperand(insn, i as c_uint, v.raw());
+            }
+            llvm::core::LLVMSetOperand(insn, argv.len() as c_uint, fun.raw());
+
+            let cstr = CString::new(if is_void { "" } else { name }).unwrap();
+            llvm::core::LLVMInsertIntoBuilderWithName(self.raw(), insn, cstr.as_ptr());
+            llvm::core::LLVMDisposeModule(template);
+            insn
+        }), self.1);
+        insn.dump(); eprintln!();
+        insn
+    }
+
+    pub fn invoke(&self, fun: Value, argv: &[Value], then: BB, catch: BB, name: &str) -> Value {
+        let cstr = CString::new(name).unwrap();
+        let mut vs: Vec<LLVMValueRef> = argv.iter().map(|v| v.raw()).collect();
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildInvoke(self.raw(), fun.raw(), vs.as_mut_ptr(), argv.len() as u32, then.raw(), catch.raw(), cstr.as_ptr()) ), self.1);
+        insn.dump(); eprintln!();
+        insn
+    }
+
+    // The personality is set on the function, not here, so none is passed.
+    pub fn landing_pad(&self, ty: Type, nclauses: usize, name: &str) -> Value {
+        let cstr = CString::new(name).unwrap();
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildLandingPad(self.raw(), ty.raw(), ptr::null_mut(), nclauses as c_uint, cstr.as_ptr()) ), self.1);
+        insn.dump(); eprintln!();
+        insn
+    }
+
+    pub fn insert_value(&self, agg: Value, v: Value, index: usize, name: &str) -> Value {
+        let cstr = CString::new(name).unwrap();
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildInsertValue(self.raw(), agg.raw(), v.raw(), index as c_uint, cstr.as_ptr()) ), self.1);
+        insn.dump(); eprintln!();
+        insn
+    }
+
+    pub fn extract_value(&self, agg: Value, index: usize, name: &str) -> Value {
+        let cstr = CString::new(name).unwrap();
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildExtractValue(self.raw(), agg.raw(), index as c_uint, cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn select(&self, i: Value, t: Value, e: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildSelect(self.0, i.0, t.0, e.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildSelect(self.raw(), i.raw(), t.raw(), e.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
@@ -913,14 +1559,14 @@ impl Builder {
 
     pub fn is_null(&self, v: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildIsNull(self.0, v.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildIsNull(self.raw(), v.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
 
     pub fn is_not_null(&self, v: Value, name: &str) -> Value {
         let cstr = CString::new(name).unwrap();
-        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildIsNotNull(self.0, v.0, cstr.as_ptr()) ));
+        let insn = Value(unsafe_llvm!( llvm::core::LLVMBuildIsNotNull(self.raw(), v.raw(), cstr.as_ptr()) ), self.1);
         insn.dump(); eprintln!();
         insn
     }
@@ -946,29 +1592,147 @@ impl Builder {
 // void 	LLVMSetCmpXchgFailureOrdering (LLVMValueRef CmpXchgInst, LLVMAtomicOrdering Ordering)
 }
 
-// impl Drop for Context {
-//     fn drop(&mut self) {
-//         unsafe_llvm!({
-//             llvm::core::LLVMContextDispose(self.0);
-//         }
-//     }
-// }
-//
-// impl Drop for Builder {
-//     fn drop(&mut self) {
-//         unsafe_llvm!({
-//             llvm::core::LLVMDisposeBuilder(self.0);
-//         }
-//     }
-// }
-//
-// impl Drop for Module {
-//     fn drop(&mut self) {
-//         unsafe_llvm!({
-//             llvm::core::LLVMDisposeModule(self.0);
-//         }
-//     }
-// }
+// Take ownership of an error message from LLVM.
+unsafe fn message(p: *mut ::libc::c_char) -> String {
+    let s = std::ffi::CStr::from_ptr(p).to_string_lossy().into_owned();
+    llvm::core::LLVMDisposeMessage(p);
+    s
+}
+
+// A target machine for triple, or for the host if triple is None. The caller must dispose it.
+fn target_machine(triple: Option<&str>, cpu: &str, features: &str) -> Result<llvm::target_machine::LLVMTargetMachineRef, String> {
+    use llvm::target_machine::*;
+
+    crate::llvm::init();
+
+    let triple = match triple {
+        Some(t) => CString::new(t).unwrap(),
+        None => unsafe_llvm!( CString::new(message(LLVMGetDefaultTargetTriple())).unwrap() ),
+    };
+    let cpu = CString::new(cpu).unwrap();
+    let features = CString::new(features).unwrap();
+
+    unsafe_llvm!({
+        let mut target = ptr::null_mut();
+        let mut err = ptr::null_mut();
+        if LLVMGetTargetFromTriple(triple.as_ptr(), &mut target, &mut err) != 0 {
+            return Err(message(err));
+        }
+
+        Ok(LLVMCreateTargetMachine(target, triple.as_ptr(), cpu.as_ptr(), features.as_ptr(),
+            LLVMCodeGenOptLevel::LLVMCodeGenLevelDefault, LLVMRelocMode::LLVMRelocDefault, LLVMCodeModel::LLVMCodeModelDefault))
+    })
+}
+
+// The sizes and alignments a target's datalayout gives types, so the layouts MIR computes
+// can be checked against LLVM's.
+pub struct TargetData(llvm::target::LLVMTargetDataRef);
+
+impl TargetData {
+    // The datalayout for triple, or for the host if triple is None.
+    pub fn new(triple: Option<&str>) -> Result<TargetData, String> {
+        let tm = target_machine(triple, "", "")?;
+        unsafe_llvm!({
+            let td = llvm::target_machine::LLVMCreateTargetDataLayout(tm);
+            llvm::target_machine::LLVMDisposeTargetMachine(tm);
+            Ok(TargetData(td))
+        })
+    }
+
+    pub fn size_of(&self, ty: Type) -> usize {
+        unsafe_llvm!( llvm::target::LLVMABISizeOfType(self.0, ty.raw()) as usize )
+    }
+
+    pub fn align_of(&self, ty: Type) -> usize {
+        unsafe_llvm!( llvm::target::LLVMABIAlignmentOfType(self.0, ty.raw()) as usize )
+    }
+}
+
+impl Drop for TargetData {
+    fn drop(&mut self) {
+        unsafe_llvm!( llvm::target::LLVMDisposeTargetData(self.0) );
+    }
+}
+
+// Builders and owned modules disposed on this thread, so tests can check each is disposed exactly once.
+#[cfg(test)]
+thread_local! {
+    pub static BUILDERS_DISPOSED: std::cell::Cell<usize> = std::cell::Cell::new(0);
+    pub static MODULES_DISPOSED: std::cell::Cell<usize> = std::cell::Cell::new(0);
+}
+
+impl Drop for Builder {
+    fn drop(&mut self) {
+        // The builder doesn't belong to its context, so dispose it even if the context is gone.
+        unsafe_llvm!( llvm::core::LLVMDisposeBuilder(self.0) );
+        #[cfg(test)]
+        BUILDERS_DISPOSED.with(|n| n.set(n.get() + 1));
+    }
+}
+
+// Context and Module are Copy, since values from them are passed around freely, so they
+// can't be dropped automatically. OwnedContext and OwnedModule own one and dispose it when
+// dropped, so it is freed on every path out of a function, including early returns.
+// Borrow the Copy handle through Deref.
+pub struct OwnedContext(Context);
+
+impl OwnedContext {
+    pub fn new() -> OwnedContext {
+        OwnedContext(Context::new())
+    }
+}
+
+impl Deref for OwnedContext {
+    type Target = Context;
+
+    fn deref(&self) -> &Context {
+        &self.0
+    }
+}
+
+impl Drop for OwnedContext {
+    fn drop(&mut self) {
+        // The context may already have been disposed through the handle.
+        if self.0.is_live() {
+            self.0.dispose();
+        }
+    }
+}
+
+// Don't dispose the module through the handle; use into_module to take it back.
+pub struct OwnedModule(Module);
+
+impl OwnedModule {
+    pub fn new(module: Module) -> OwnedModule {
+        OwnedModule(module)
+    }
+
+    // Give up ownership, e.g. to return the module. The caller must dispose it.
+    pub fn into_module(self) -> Module {
+        let module = self.0;
+        mem::forget(self);
+        module
+    }
+}
+
+impl Deref for OwnedModule {
+    type Target = Module;
+
+    fn deref(&self) -> &Module {
+        &self.0
+    }
+}
+
+impl Drop for OwnedModule {
+    fn drop(&mut self) {
+        // Disposing the context disposed the module with it.
+        if self.0.is_live() {
+            self.0.dispose();
+            #[cfg(test)]
+            MODULES_DISPOSED.with(|n| n.set(n.get() + 1));
+        }
+    }
+}
 
 
 
@@ -990,7 +1754,7 @@ mod transform {
 
         pub fn run(&mut self, module: Module) {
             unsafe_llvm!(
-                llvm_sys::core::LLVMRunPassManager(self.0, module.0)
+                llvm_sys::core::LLVMRunPassManager(self.0, module.raw())
             );
         }
 
@@ -1042,7 +1806,7 @@ mod execution_engine {
             let mut out = unsafe { mem::zeroed() };
 
             unsafe {
-                llvm_sys::execution_engine::LLVMCreateExecutionEngineForModule(&mut ee, module.0, &mut out);
+                llvm_sys::execution_engine::LLVMCreateExecutionEngineForModule(&mut ee, module.raw(), &mut out);
             }
 
             ExecutionEngine(ee)
@@ -1,3 +1,5 @@
+use std::hash::{Hash, Hasher};
+
 use crate::common::names::Name;
 
 use crate::mir::ops::*;
@@ -13,7 +15,18 @@ pub struct Root {
 pub struct Data {
     pub ty: Type,
     pub name: Name,
-    pub init: Lit,
+    pub init: Init,
+    pub thread_local: bool,
+}
+
+// A constant initial value for a global.
+#[derive(Clone, Debug)]
+pub enum Init {
+    Lit { lit: Lit },
+    // A struct whose fields are all constants, e.g., a statically allocated object.
+    Struct { fields: Vec<Init> },
+    // The address of the global name, cast to the type of the global being initialized.
+    GlobalAddr { name: Name },
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -35,8 +48,9 @@ pub enum Stm {
     Nop,
 
     // Following LLVM, we don't fall-through to the next instruction, but have two branch targets.
-    CJump { cond: Box<Exp>, if_true: Name, if_false: Name },
-    Jump { label: Name },
+    CJump { cond: Box<Exp>, if_true: Name, if_false: Name, hint: Option<BranchHint> },
+    // loop_hints is non-empty only on the backedge of a loop.
+    Jump { label: Name, loop_hints: Vec<LoopHint> },
     Label { label: Name },
 
     // ty should be the type of rhs
@@ -64,8 +78,13 @@ pub enum Exp {
 
     Binary { op: Bop, e1: Box<Exp>, e2: Box<Exp> },
     Unary { op: Uop, exp: Box<Exp> },
+    // a * b + c with a single rounding. ty should be F32 or F64.
+    Fma { ty: Type, a: Box<Exp>, b: Box<Exp>, c: Box<Exp> },
     Cast { ty: Type, exp: Box<Exp> },
 
+    // Evaluates both branches, then picks one. cond should have type I1.
+    Select { cond: Box<Exp>, if_true: Box<Exp>, if_false: Box<Exp> },
+
     Lit { lit: Lit },
 
     // ty should be a ptr to fun type
@@ -78,13 +97,21 @@ pub enum Exp {
     // ptr has type Ref { struct_ty }, result is type IRef
     GetStructElementAddr { struct_ty: Type, ptr: Box<Exp>, field: usize },
 
+    // A field of a struct value, such as the result of a call that returns a struct.
+    // agg has type Struct, result is the field's type
+    ExtractStructElement { agg: Box<Exp>, field: usize },
+    // A struct value with the given fields, e.g., for a struct that never needs an address.
+    // Result is type Struct with the fields' types
+    BuildStruct { fields: Vec<Exp> },
+
     // Address of an array entry.
     // We've already used GetStructElementAddr to get an IRef to the internal array.
     // ptr has type [I]Ref { Array { base_ty } }, result is type IRef
     GetArrayElementAddr { base_ty: Type, ptr: Box<Exp>, index: Box<Exp> },
 
     New { ty: Type },
-    NewHybrid { ty: Type, length: Box<Exp> },
+    // If zero is set, the variant part is zero-filled after allocation.
+    NewHybrid { ty: Type, length: Box<Exp>, zero: bool },
 }
 
 // MIR types are based on MuVM types, but simplified
@@ -114,6 +141,7 @@ pub enum Type {
     I16,
     I32,
     I64,
+    I128,
 
     F32,
     F64,
@@ -146,6 +174,89 @@ impl Type {
     pub fn word() -> Type {
         Type::I64
     }
+
+    // The size in bytes of a value of this type, with pointers wordsize bytes.
+    // Struct fields are aligned to their own alignment, and the size is padded to the
+    // struct's alignment so elements of an array of structs stay aligned.
+    // A Hybrid's size is its fixed fields (the header, e.g., an array's length) and one
+    // element of the variant part.
+    pub fn size_of(&self, wordsize: usize) -> usize {
+        match self {
+            Type::I1 | Type::I8 => 1,
+            Type::I16 => 2,
+            Type::I32 | Type::F32 => 4,
+            Type::I64 | Type::F64 => 8,
+            Type::I128 => 16,
+            Type::Void => 0,
+            Type::IRef { .. } | Type::Ref { .. } | Type::Ptr { .. } => wordsize,
+            Type::Hybrid { fields, variant } => {
+                let n = Type::fields_end(fields, wordsize);
+                let n = Type::align_up(n, variant.align_of(wordsize)) + variant.size_of(wordsize);
+                Type::align_up(n, self.align_of(wordsize))
+            },
+            Type::Struct { fields } => {
+                Type::align_up(Type::fields_end(fields, wordsize), self.align_of(wordsize))
+            },
+            Type::Union { variants } => {
+                let n = variants.iter().map(|v| v.size_of(wordsize)).max().unwrap_or(0);
+                Type::align_up(n, self.align_of(wordsize))
+            },
+            Type::Fun { .. } => panic!("function types have no size"),
+        }
+    }
+
+    pub fn align_of(&self, wordsize: usize) -> usize {
+        match self {
+            Type::Hybrid { fields, variant } => {
+                fields.iter().map(|f| f.align_of(wordsize)).fold(variant.align_of(wordsize), std::cmp::max)
+            },
+            Type::Struct { fields } => {
+                fields.iter().map(|f| f.align_of(wordsize)).fold(1, std::cmp::max)
+            },
+            Type::Union { variants } => {
+                variants.iter().map(|v| v.align_of(wordsize)).fold(1, std::cmp::max)
+            },
+            Type::Void => 1,
+            // LLVM's x86-64 datalayout doesn't give i128 its own alignment, so it falls back
+            // to i64's.
+            Type::I128 => 8,
+            Type::Fun { .. } => panic!("function types have no alignment"),
+            ty => ty.size_of(wordsize),
+        }
+    }
+
+    // The zero value of this type. Null also stands for the zero value of a struct or
+    // union. Hybrids and functions aren't values, so they have no zero.
+    // Every zero value is all zero bits, so zeroing memory with memset agrees with this.
+    pub fn zero_lit(&self) -> Option<Lit> {
+        match self {
+            Type::I1 => Some(Lit::I1 { value: false }),
+            Type::I8 => Some(Lit::I8 { value: 0 }),
+            Type::I16 => Some(Lit::I16 { value: 0 }),
+            Type::I32 => Some(Lit::I32 { value: 0 }),
+            Type::I64 => Some(Lit::I64 { value: 0 }),
+            Type::I128 => Some(Lit::I128 { value: 0 }),
+            Type::F32 => Some(Lit::F32 { value: 0.0 }),
+            Type::F64 => Some(Lit::F64 { value: 0.0 }),
+            Type::Void => Some(Lit::Void),
+            Type::IRef { .. } | Type::Ref { .. } | Type::Ptr { .. } => Some(Lit::Null { ty: self.clone() }),
+            Type::Struct { .. } | Type::Union { .. } => Some(Lit::Null { ty: self.clone() }),
+            Type::Hybrid { .. } | Type::Fun { .. } => None,
+        }
+    }
+
+    // The offset just past the last of fields, without padding at the end.
+    pub fn fields_end(fields: &[Type], wordsize: usize) -> usize {
+        let mut n = 0;
+        for f in fields {
+            n = Type::align_up(n, f.align_of(wordsize)) + f.size_of(wordsize);
+        }
+        n
+    }
+
+    pub fn align_up(n: usize, align: usize) -> usize {
+        (n + align - 1) / align * align
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -157,7 +268,97 @@ pub enum Lit {
     I16 { value: i16 },
     I32 { value: i32 },
     I64 { value: i64 },
+    I128 { value: i128 },
     F32 { value: f32 },
     F64 { value: f64 },
     Sizeof { ty: Type },
+    // The ABI alignment of ty in bytes, as a word.
+    Alignof { ty: Type },
+    // The address of a function or global, as a constant. ty is the pointer type.
+    // Unlike Exp::FunctionAddr and Exp::GlobalAddr, these can be used in global initializers,
+    // e.g., to build a vtable.
+    FunctionAddr { ty: Type, name: Name },
+    GlobalAddr { ty: Type, name: Name },
+}
+
+impl Lit {
+    // Equality for deduplicating constants, e.g., in CSE or a constant pool. Unlike ==, floats
+    // are compared by their bits, so -0.0 and 0.0 are different constants, and a NaN is the
+    // same constant as itself. Use this rather than == to merge literals.
+    pub fn bit_eq(&self, other: &Lit) -> bool {
+        match (self, other) {
+            (Lit::F32 { value: a }, Lit::F32 { value: b }) => a.to_bits() == b.to_bits(),
+            (Lit::F64 { value: a }, Lit::F64 { value: b }) => a.to_bits() == b.to_bits(),
+            (a, b) => a == b,
+        }
+    }
+}
+
+// Floats aren't Hash, so hash their bits. This is consistent with bit_eq, but not with ==.
+// It's only used to detect changed procs.
+impl Hash for Lit {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Lit::Void => {},
+            Lit::Null { ty } => ty.hash(state),
+            Lit::I1 { value } => value.hash(state),
+            Lit::I8 { value } => value.hash(state),
+            Lit::I16 { value } => value.hash(state),
+            Lit::I32 { value } => value.hash(state),
+            Lit::I64 { value } => value.hash(state),
+            Lit::I128 { value } => value.hash(state),
+            Lit::F32 { value } => value.to_bits().hash(state),
+            Lit::F64 { value } => value.to_bits().hash(state),
+            Lit::Sizeof { ty } => ty.hash(state),
+            Lit::Alignof { ty } => ty.hash(state),
+            Lit::FunctionAddr { ty, name } | Lit::GlobalAddr { ty, name } => {
+                ty.hash(state);
+                name.hash(state);
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_padded_struct_layout() {
+        let ty = Type::Struct { fields: vec![Type::I8, Type::I64] };
+        assert_eq!(ty.size_of(8), 16);
+        assert_eq!(ty.align_of(8), 8);
+
+        // Padding at the end keeps arrays of the struct aligned.
+        let ty = Type::Struct { fields: vec![Type::I64, Type::I8] };
+        assert_eq!(ty.size_of(8), 16);
+
+        let ty = Type::Struct { fields: vec![Type::I8, Type::Ptr { ty: Box::new(Type::I8) }] };
+        assert_eq!(ty.size_of(4), 8);
+        assert_eq!(ty.align_of(4), 4);
+    }
+
+    #[test]
+    fn test_zero_lit() {
+        assert_eq!(Type::F32.zero_lit(), Some(Lit::F32 { value: 0.0 }));
+        assert_eq!(Type::I1.zero_lit(), Some(Lit::I1 { value: false }));
+
+        let ty = Type::Struct { fields: vec![Type::I8, Type::I64] };
+        assert_eq!(ty.zero_lit(), Some(Lit::Null { ty: ty.clone() }));
+        assert_eq!(Type::Fun { ret: Box::new(Type::Void), args: vec![] }.zero_lit(), None);
+    }
+
+    #[test]
+    fn test_lit_bit_eq() {
+        // == merges the zeros, but they're different constants: 1/-0.0 is -inf.
+        assert_eq!(Lit::F64 { value: -0.0 }, Lit::F64 { value: 0.0 });
+        assert!(! Lit::F64 { value: -0.0 }.bit_eq(&Lit::F64 { value: 0.0 }));
+        assert!(! Lit::F32 { value: -0.0 }.bit_eq(&Lit::F32 { value: 0.0 }));
+
+        assert!(Lit::F64 { value: std::f64::NAN }.bit_eq(&Lit::F64 { value: std::f64::NAN }));
+        assert!(Lit::F32 { value: 1.5 }.bit_eq(&Lit::F32 { value: 1.5 }));
+        assert!(! Lit::F32 { value: 1.5 }.bit_eq(&Lit::F64 { value: 1.5 }));
+        assert!(Lit::I32 { value: 7 }.bit_eq(&Lit::I32 { value: 7 }));
+    }
 }
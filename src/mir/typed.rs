@@ -10,8 +10,8 @@ impl Typed for Stm {
         // Check the types.
         match self {
             Stm::Nop => {},
-            Stm::CJump { cond, if_true, if_false } => {},
-            Stm::Jump { label } => {},
+            Stm::CJump { cond, if_true, if_false, hint } => {},
+            Stm::Jump { label, loop_hints } => {},
             Stm::Label { label } => {},
             Stm::Move { ty, lhs, rhs } => {
                 // ty should be the type of rhs
@@ -68,18 +68,37 @@ impl Typed for Exp {
             }
             Exp::Binary { op, e1, e2 } => op.get_type(),
             Exp::Unary { op, exp } => op.get_type(),
-            Exp::Cast { ty, exp } => {
-                // Both should be pointer types.
-                assert!(match exp.get_type() {
-                    Type::Ptr { .. } => true,
-                    Type::Ref { .. } => true,
-                    Type::IRef { .. } => true,
-                    _ => false }, "can only cast from ptr types, got {:?}", exp.get_type());
+            Exp::Fma { ty, a, b, c } => {
                 assert!(match ty {
+                    Type::F32 => true,
+                    Type::F64 => true,
+                    _ => false }, "fma must have a float type, got {:?}", ty);
+                assert_eq!(&a.get_type(), ty);
+                assert_eq!(&b.get_type(), ty);
+                assert_eq!(&c.get_type(), ty);
+                ty.clone()
+            },
+            Exp::Select { cond, if_true, if_false } => {
+                assert_eq!(cond.get_type(), Type::I1);
+                assert_eq!(if_true.get_type(), if_false.get_type(), "select branches must have the same type");
+                if_true.get_type()
+            },
+            Exp::Cast { ty, exp } => {
+                // Both should be pointer types, or numbers of the same size.
+                // Numeric conversions that change the size are Uops.
+                let is_ptr = |t: &Type| match t {
                     Type::Ptr { .. } => true,
                     Type::Ref { .. } => true,
                     Type::IRef { .. } => true,
-                    _ => false }, "can only cast to ptr types, got {:?}", exp.get_type());
+                    _ => false };
+                let from = exp.get_type();
+                match (bits(&from), bits(ty)) {
+                    (Some(n), Some(m)) => assert_eq!(n, m, "can only cast between numbers of the same size, got {:?} to {:?}", from, ty),
+                    _ => {
+                        assert!(is_ptr(&from), "can only cast from ptr types, got {:?}", from);
+                        assert!(is_ptr(ty), "can only cast to ptr types, got {:?}", ty);
+                    },
+                }
                 ty.clone()
             },
             Exp::New { ty } => {
@@ -88,7 +107,7 @@ impl Typed for Exp {
                     _ => true }, "can only allocate non-hybrid types with New, got {:?}", ty);
                 Type::Ref { ty: box ty.clone() }
             },
-            Exp::NewHybrid { ty, length } => {
+            Exp::NewHybrid { ty, length, zero } => {
                 assert_eq!(length.get_type(), Type::I64);
                 assert!(match ty {
                     Type::Hybrid { .. } => true,
@@ -111,6 +130,18 @@ impl Typed for Exp {
                 assert!(match ty { Type::Fun { .. } => false, _ => true }, "temporary variables cannot be fun type, got {:?}", ty);
                 ty.clone()
             },
+            Exp::BuildStruct { fields } => {
+                Type::Struct { fields: fields.iter().map(|f| f.get_type()).collect() }
+            },
+            Exp::ExtractStructElement { agg, field } => {
+                match agg.get_type() {
+                    Type::Struct { fields } => match fields.get(*field) {
+                        Some(ty) => ty.clone(),
+                        _ => panic!("ill-typed expression {:#?}, field {} does not exist", self, field)
+                    },
+                    ty => panic!("can only extract fields from struct values, got {:?}", ty)
+                }
+            },
             Exp::GetStructElementAddr { struct_ty: Type::Struct { fields }, ptr, field } => {
                 assert_eq!(Type::Ref { ty: Box::new(Type::Struct { fields: fields.clone() }) }, ptr.get_type());
                 match fields.get(*field) {
@@ -131,6 +162,21 @@ impl Typed for Exp {
     }
 }
 
+// The size of a number type in bits, or None if ty isn't a number.
+fn bits(ty: &Type) -> Option<usize> {
+    match ty {
+        Type::I1 => Some(1),
+        Type::I8 => Some(8),
+        Type::I16 => Some(16),
+        Type::I32 => Some(32),
+        Type::I64 => Some(64),
+        Type::I128 => Some(128),
+        Type::F32 => Some(32),
+        Type::F64 => Some(64),
+        _ => None,
+    }
+}
+
 impl Typed for Lit {
     fn get_type(&self) -> Type {
         match self {
@@ -144,9 +190,13 @@ impl Typed for Lit {
             Lit::I16 { value } => Type::I16,
             Lit::I32 { value } => Type::I32,
             Lit::I64 { value } => Type::I64,
+            Lit::I128 { value } => Type::I128,
             Lit::F32 { value } => Type::F32,
             Lit::F64 { value } => Type::F64,
             Lit::Sizeof { ty } => Type::word(),
+            Lit::Alignof { ty } => Type::word(),
+            Lit::FunctionAddr { ty, name } => ty.clone(),
+            Lit::GlobalAddr { ty, name } => ty.clone(),
         }
     }
 }
@@ -155,6 +205,7 @@ impl Typed for Uop {
     fn get_type(&self) -> Type {
         match self {
             Uop::Not_z => Type::I1,
+            Uop::Expect_z { expected } => Type::I1,
 
             Uop::Ctz_i32 => Type::I32,
             Uop::Clz_i32 => Type::I32,
@@ -168,6 +219,8 @@ impl Typed for Uop {
             Uop::Eqz_i64 => Type::I64,
             Uop::Complement_i64 => Type::I64,
 
+            Uop::Complement_word => Type::word(),
+
             Uop::Neg_f32 => Type::F32,
             Uop::Abs_f32 => Type::F32,
             Uop::Ceil_f32 => Type::F32,    // round toward pos
@@ -240,6 +293,18 @@ impl Typed for Uop {
             Uop::Trunc_u_f64_i64 => Type::I64,
             Uop::Extend_s_i32_i64 => Type::I64,
             Uop::Extend_u_i32_i64 => Type::I64,
+
+            Uop::Wrap_i32_i8 => Type::I8,
+            Uop::Wrap_i32_i16 => Type::I16,
+            Uop::Extend_s_i8_i32 => Type::I32,
+            Uop::Extend_u_i8_i32 => Type::I32,
+            Uop::Extend_s_i16_i32 => Type::I32,
+            Uop::Extend_u_i16_i32 => Type::I32,
+
+            Uop::Nez_i32_z => Type::I1,
+            Uop::Extend_u_z_i32 => Type::I32,
+            Uop::Nez_i64_z => Type::I1,
+            Uop::Extend_u_z_i64 => Type::I64,
             Uop::Reinterpret_i32_f32 => Type::F32,
             Uop::Reinterpret_f32_i32 => Type::I32,
             Uop::Reinterpret_f64_i64 => Type::I64,
@@ -298,6 +363,11 @@ impl Typed for Bop {
             Bop::Rotl_i32 => Type::I32,
             Bop::Rotr_i32 => Type::I32,
 
+            Bop::Min_s_i32 => Type::I32,
+            Bop::Min_u_i32 => Type::I32,
+            Bop::Max_s_i32 => Type::I32,
+            Bop::Max_u_i32 => Type::I32,
+
             Bop::Add_i64 => Type::I64,
             Bop::Sub_i64 => Type::I64,
             Bop::Mul_i64 => Type::I64,
@@ -327,6 +397,33 @@ impl Typed for Bop {
             Bop::Rotl_i64 => Type::I64,
             Bop::Rotr_i64 => Type::I64,
 
+            Bop::Min_s_i64 => Type::I64,
+            Bop::Min_u_i64 => Type::I64,
+            Bop::Max_s_i64 => Type::I64,
+            Bop::Max_u_i64 => Type::I64,
+
+            Bop::Add_nsw_i32 => Type::I32,
+            Bop::Sub_nsw_i32 => Type::I32,
+            Bop::Mul_nsw_i32 => Type::I32,
+            Bop::Add_nsw_i64 => Type::I64,
+            Bop::Sub_nsw_i64 => Type::I64,
+            Bop::Mul_nsw_i64 => Type::I64,
+
+            Bop::Add_i128 => Type::I128,
+            Bop::Sub_i128 => Type::I128,
+            Bop::Mul_i128 => Type::I128,
+
+            Bop::And_i128 => Type::I128,
+            Bop::Or_i128 => Type::I128,
+            Bop::Xor_i128 => Type::I128,
+
+            Bop::Eq_i128 => Type::I1,
+            Bop::Ne_i128 => Type::I1,
+
+            Bop::Shl_i128 => Type::I128,
+            Bop::Shr_i128 => Type::I128,
+            Bop::Shr_u_i128 => Type::I128,
+
             Bop::Min_f32 => Type::F32,
             Bop::Max_f32 => Type::F32,
             Bop::Copysign_f32 => Type::F32,
@@ -345,6 +442,8 @@ impl Typed for Bop {
             Bop::Le_f32 => Type::I1,
             Bop::Gt_f32 => Type::I1,
             Bop::Ge_f32 => Type::I1,
+            Bop::BitEq_f32 => Type::I1,
+            Bop::TotalLt_f32 => Type::I1,
 
             Bop::Min_f64 => Type::F64,
             Bop::Max_f64 => Type::F64,
@@ -364,6 +463,8 @@ impl Typed for Bop {
             Bop::Le_f64 => Type::I1,
             Bop::Gt_f64 => Type::I1,
             Bop::Ge_f64 => Type::I1,
+            Bop::BitEq_f64 => Type::I1,
+            Bop::TotalLt_f64 => Type::I1,
         }
     }
 }